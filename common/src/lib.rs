@@ -1,13 +1,22 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::{fmt::Display, ops::Deref};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, ops::Deref, str::FromStr};
 use uuid::Uuid;
 
 pub type ChunkIndex = usize;
 pub type FileTableIndex = usize;
 
+/// Default smallest chunk, in bytes. Every chunking path starts from these
+/// defaults so files chunked anywhere deduplicate against each other.
+pub const DEFAULT_MIN_CHUNK_SIZE: u32 = 512;
+/// Default target chunk size, in bytes.
+pub const DEFAULT_AVG_CHUNK_SIZE: u32 = 1024;
+/// Default largest chunk, in bytes.
+pub const DEFAULT_MAX_CHUNK_SIZE: u32 = 2048;
+
 use directories::UserDirs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 const DIFF_SYNC_DIR_NAME: &str = "Diff";
 
@@ -49,6 +58,13 @@ impl Deref for ChunkID {
     }
 }
 
+impl From<blake3::Hash> for ChunkID {
+    /// Wraps the BLAKE3 digest of a chunk's content.
+    fn from(hash: blake3::Hash) -> Self {
+        ChunkID(hash)
+    }
+}
+
 impl AsRef<[u8; 32]> for ChunkID {
     /// Allows treating the ID as a raw 32-byte array for cryptographic operations
     /// or database storage.
@@ -63,8 +79,10 @@ impl AsRef<[u8; 32]> for ChunkID {
 /// constant for the lifetime of the file's tracking. This allows the system
 /// to follow a file even if its metadata on the filesystem changes.
 ///
-/// Internally uses a **UUID v4** (Randomly generated).
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+/// Internally uses a **UUID v4** (Randomly generated), or a **UUID v5**
+/// derived from the file's path (see [`FileID::from_path_seed`]).
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct FileID(Uuid);
 
 impl Default for FileID {
@@ -95,9 +113,48 @@ impl Display for FileID {
     }
 }
 
+impl FromStr for FileID {
+    type Err = uuid::Error;
+
+    /// Parses a `FileID` back from its string form, as stored in the database.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s).map(FileID)
+    }
+}
+
 impl FileID {
     /// Generates a new random UUID v4 and wraps it in a `FileID`.
     pub fn new() -> Self {
         FileID(Uuid::new_v4())
     }
+
+    /// Derives a `FileID` from `path` as a name-based UUID v5 in `namespace`.
+    ///
+    /// The same path and namespace always yield the same id, so a store rebuilt
+    /// from scratch tracks each file under the id it had before. The tradeoff is
+    /// that the id belongs to the path rather than the file: once a file is
+    /// moved, deriving again gives a different id.
+    pub fn from_path_seed(path: &Path, namespace: &Uuid) -> Self {
+        FileID(Uuid::new_v5(namespace, path.as_os_str().as_encoded_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_seeded_ids_are_stable() {
+        let namespace = Uuid::new_v4();
+        let path = Path::new("/sync/notes.txt");
+
+        let id = FileID::from_path_seed(path, &namespace);
+        assert_eq!(id, FileID::from_path_seed(path, &namespace));
+        assert_eq!(id.get_version_num(), 5);
+        assert_ne!(
+            id,
+            FileID::from_path_seed(Path::new("/sync/other.txt"), &namespace)
+        );
+        assert_ne!(id, FileID::from_path_seed(path, &Uuid::new_v4()));
+    }
 }
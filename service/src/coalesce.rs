@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Holding events back for a window that depends on their kind.
+//!
+//! The debouncer applies one timeout to every event. Creates and removes are
+//! worth acting on right away, while a file being written keeps raising
+//! modifies, so [`EventCoalescer`] holds each event per path until its kind's
+//! window from [`DebounceConfig`] has passed without a newer event of the
+//! same kind. A modify arriving in the meantime replaces the pending one.
+use std::{
+    collections::HashMap,
+    mem::{Discriminant, discriminant},
+    time::Instant,
+};
+
+use camino::Utf8PathBuf;
+use notify_debouncer_full::notify::event::{EventKind, ModifyKind};
+
+use crate::{DebounceConfig, OsEvent};
+
+/// A single path's event waiting for its window to pass.
+struct Pending {
+    event: OsEvent,
+    due: Instant,
+}
+
+/// Second debounce stage after the watcher, with a window per event kind.
+pub struct EventCoalescer {
+    config: DebounceConfig,
+    /// Keyed by path and the kind's top-level variant.
+    pending: HashMap<(Utf8PathBuf, Discriminant<EventKind>), Pending>,
+}
+
+impl EventCoalescer {
+    pub fn new(config: DebounceConfig) -> Self {
+        Self {
+            config,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Uses `config` for events pushed from now on; pending events keep
+    /// their due times.
+    pub fn set_config(&mut self, config: DebounceConfig) {
+        self.config = config;
+    }
+
+    /// Queues `events`, one entry per path.
+    ///
+    /// Each is due its kind's window after it happened, less what the
+    /// watcher already waited. A remove drops any pending modify of the same
+    /// path, since there is nothing left to re-index.
+    pub fn push(&mut self, events: Vec<OsEvent>) {
+        let already_waited = self.config.watcher_timeout();
+        for OsEvent { kind, paths, time } in events {
+            let hold = self.config.window_for(&kind).saturating_sub(already_waited);
+            for path in paths {
+                if matches!(kind, EventKind::Remove(_)) {
+                    self.pending
+                        .retain(|(pending, kind), _| *pending != path || !is_modify(kind));
+                }
+                let key = (path.clone(), discriminant(&kind));
+                let event = OsEvent {
+                    kind,
+                    paths: vec![path],
+                    time,
+                };
+                self.pending.insert(
+                    key,
+                    Pending {
+                        event,
+                        due: time + hold,
+                    },
+                );
+            }
+        }
+    }
+
+    /// When the next pending event falls due, if any is pending.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.due).min()
+    }
+
+    /// Removes and returns the events due at `now`, oldest due first.
+    pub fn drain_due(&mut self, now: Instant) -> Vec<OsEvent> {
+        let keys: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.due <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut due: Vec<Pending> = keys
+            .iter()
+            .filter_map(|key| self.pending.remove(key))
+            .collect();
+        due.sort_by_key(|pending| pending.due);
+        due.into_iter().map(|pending| pending.event).collect()
+    }
+}
+
+fn is_modify(kind: &Discriminant<EventKind>) -> bool {
+    *kind == discriminant(&EventKind::Modify(ModifyKind::Any))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify_debouncer_full::notify::event::{CreateKind, DataChange, RemoveKind};
+    use std::time::Duration;
+
+    fn event(kind: EventKind, path: &str, time: Instant) -> OsEvent {
+        OsEvent {
+            kind,
+            paths: vec![Utf8PathBuf::from(path)],
+            time,
+        }
+    }
+
+    #[test]
+    fn test_modifies_collapse_while_create_passes_promptly() {
+        let config = DebounceConfig {
+            create_ms: 50,
+            modify_ms: 1000,
+            remove_ms: 50,
+        };
+        let mut coalescer = EventCoalescer::new(config);
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        let start = Instant::now();
+
+        // A burst of writes to one file, one batch per watcher timeout
+        for i in 0..5 {
+            let at = start + Duration::from_millis(50 * i);
+            coalescer.push(vec![event(modify, "/sync/log.txt", at)]);
+        }
+        let create_at = start + Duration::from_millis(200);
+        coalescer.push(vec![event(
+            EventKind::Create(CreateKind::File),
+            "/sync/new.txt",
+            create_at,
+        )]);
+
+        // The create has already waited its window in the watcher
+        assert_eq!(coalescer.next_due(), Some(create_at));
+        let ready = coalescer.drain_due(create_at);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].paths, ["/sync/new.txt"]);
+
+        // The modifies come out once, a full window after the last of them
+        assert!(
+            coalescer
+                .drain_due(start + Duration::from_millis(500))
+                .is_empty()
+        );
+        let last_modify = start + Duration::from_millis(200);
+        let due = last_modify + Duration::from_millis(950);
+        assert_eq!(coalescer.next_due(), Some(due));
+        let ready = coalescer.drain_due(due);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].kind, modify);
+        assert_eq!(ready[0].time, last_modify);
+        assert_eq!(coalescer.next_due(), None);
+    }
+
+    #[test]
+    fn test_remove_drops_pending_modify() {
+        let mut coalescer = EventCoalescer::new(DebounceConfig::default());
+        let now = Instant::now();
+        coalescer.push(vec![
+            event(EventKind::Modify(ModifyKind::Any), "/sync/a.txt", now),
+            event(EventKind::Remove(RemoveKind::File), "/sync/a.txt", now),
+        ]);
+
+        let ready = coalescer.drain_due(now + Duration::from_secs(10));
+        assert_eq!(ready.len(), 1);
+        assert!(matches!(ready[0].kind, EventKind::Remove(_)));
+    }
+}
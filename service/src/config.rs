@@ -0,0 +1,724 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Service configuration and its on-disk lifecycle.
+use std::{ffi::OsString, fs, io, path::Path, path::PathBuf, time::Duration};
+
+use camino::Utf8Path;
+use notify_debouncer_full::notify::{self, EventKind};
+use serde::{Deserialize, Serialize};
+use store::{ChunkConfig, DataStoreError};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::events::wildcard_match;
+
+#[derive(Error, Debug)]
+pub enum ServiceError {
+    #[error("Could not read config: {0}")]
+    ConfigRead(#[source] io::Error),
+    #[error("Could not parse config: {0}")]
+    ConfigParse(#[from] toml::de::Error),
+    #[error("Could not write config: {0}")]
+    ConfigWrite(#[source] io::Error),
+    #[error("Watcher Error: {0}")]
+    Watch(#[from] notify::Error),
+    #[error("Store Error: {0}")]
+    Store(#[from] DataStoreError),
+    #[error("Index engine config has version {found}, expected {expected}")]
+    EngineConfigVersion { found: u32, expected: u32 },
+    #[error("Index engine config {} does not match its checksum", path.display())]
+    EngineConfigChecksum { path: PathBuf },
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+}
+
+/// How long to wait for a file to settle after each kind of event, in milliseconds.
+///
+/// The watcher debounces everything over the shortest of the three; an
+/// [`EventCoalescer`](crate::EventCoalescer) then holds each kind for the
+/// rest of its own window.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DebounceConfig {
+    pub create_ms: u64,
+    /// Editors and downloads write in bursts, so modifies wait the longest.
+    pub modify_ms: u64,
+    pub remove_ms: u64,
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self {
+            create_ms: 100,
+            modify_ms: 500,
+            remove_ms: 100,
+        }
+    }
+}
+
+impl DebounceConfig {
+    /// The debounce for the watcher itself, the shortest of the windows.
+    pub fn watcher_timeout(&self) -> Duration {
+        Duration::from_millis(self.create_ms.min(self.modify_ms).min(self.remove_ms))
+    }
+
+    /// The window for events of `kind`. Kinds other than create and remove
+    /// are treated as modifies.
+    pub fn window_for(&self, kind: &EventKind) -> Duration {
+        let ms = match kind {
+            EventKind::Create(_) => self.create_ms,
+            EventKind::Remove(_) => self.remove_ms,
+            _ => self.modify_ms,
+        };
+        Duration::from_millis(ms)
+    }
+
+    /// The same window for every kind, as the single `debounce_ms` of older
+    /// configs meant.
+    pub fn uniform(ms: u64) -> Self {
+        Self {
+            create_ms: ms,
+            modify_ms: ms,
+            remove_ms: ms,
+        }
+    }
+}
+
+/// Reads `debounce` as a table, or as the plain number of milliseconds older
+/// configs stored under `debounce_ms`.
+fn deserialize_debounce<'de, D>(deserializer: D) -> Result<DebounceConfig, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Debounce {
+        Legacy(u64),
+        Windows(DebounceConfig),
+    }
+
+    Ok(match Debounce::deserialize(deserializer)? {
+        Debounce::Legacy(ms) => DebounceConfig::uniform(ms),
+        Debounce::Windows(config) => config,
+    })
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+    pub chunk_config: ChunkConfig,
+    pub sync_dir: Vec<PathBuf>,
+    /// Also read from the `debounce_ms = N` of older configs.
+    #[serde(alias = "debounce_ms", deserialize_with = "deserialize_debounce")]
+    pub debounce: DebounceConfig,
+    /// Maximum number of event batches queued before falling back to a rescan.
+    pub event_queue_cap: usize,
+    /// File name patterns to leave out of syncing, e.g. `*.swp`. `*` matches any run of characters.
+    pub ignore: Vec<String>,
+    /// Namespace for deriving file ids from paths. Unset, new files get random ids.
+    ///
+    /// With a namespace, a lost database can be rebuilt with the same ids for
+    /// every file that has not moved since; see [`FileID::from_path_seed`].
+    ///
+    /// [`FileID::from_path_seed`]: common::FileID::from_path_seed
+    pub file_id_namespace: Option<Uuid>,
+    /// How many directory levels below each sync directory to watch. `0`
+    /// watches only the sync directory itself; unset watches everything.
+    ///
+    /// Directories past the limit, or created after watching started, raise
+    /// no events and are only picked up by a rescan.
+    pub max_watch_depth: Option<usize>,
+    /// Window, in milliseconds, over which re-indexing the files of one batch
+    /// is spread out; see [`IndexJitter`](crate::IndexJitter). `0` disables jitter.
+    pub index_jitter_ms: u64,
+    /// Failures in a row after which a file is no longer re-indexed until it
+    /// is cleared; see [`DataStore::dead_letters`](store::DataStore::dead_letters).
+    pub index_max_attempts: u32,
+    /// Sync set newly indexed files are stored in; see
+    /// [`DataStore::with_sync_set`](store::DataStore::with_sync_set). Only read at startup.
+    pub sync_set: String,
+    /// Free bytes that must remain on the database's disk after each write;
+    /// see [`FreeSpaceGuard`](store::FreeSpaceGuard). `0` disables the check.
+    /// Only read at startup.
+    pub min_free_bytes: u64,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            chunk_config: ChunkConfig::default(),
+            sync_dir: Vec::default(),
+            debounce: DebounceConfig::default(),
+            event_queue_cap: 1024,
+            ignore: Vec::default(),
+            file_id_namespace: None,
+            max_watch_depth: None,
+            index_jitter_ms: 0,
+            index_max_attempts: 3,
+            sync_set: store::DEFAULT_SYNC_SET.to_string(),
+            min_free_bytes: 0,
+        }
+    }
+}
+
+/// Name of the directory holding the service's own files inside a sync directory.
+pub const CONFIG_DIR_NAME: &str = ".config";
+
+impl ServiceConfig {
+    /// Whether changes to `path` should be left out of syncing.
+    ///
+    /// The service's own [`CONFIG_DIR_NAME`] directory sits inside the sync
+    /// directory by default and is always excluded, as is any file whose name
+    /// matches one of the `ignore` patterns.
+    pub fn is_path_excluded(&self, path: &Utf8Path) -> bool {
+        let is_internal = path
+            .components()
+            .any(|component| component.as_str() == CONFIG_DIR_NAME);
+        let is_ignored = path.file_name().is_some_and(|name| {
+            self.ignore
+                .iter()
+                .any(|pattern| wildcard_match(pattern, name))
+        });
+
+        is_internal || is_ignored
+    }
+
+    /// Rejects settings that parse but cannot work.
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        let ChunkConfig {
+            min_chunk_size,
+            avg_chunk_size,
+            max_chunk_size,
+            ..
+        } = self.chunk_config;
+        if !(min_chunk_size <= avg_chunk_size && avg_chunk_size <= max_chunk_size) {
+            return Err(ServiceError::InvalidConfig(format!(
+                "chunk sizes must satisfy min <= avg <= max, got {min_chunk_size}/{avg_chunk_size}/{max_chunk_size}"
+            )));
+        }
+        self.chunk_config
+            .validate()
+            .map_err(|e| ServiceError::InvalidConfig(e.to_string()))?;
+        if self.index_max_attempts == 0 {
+            return Err(ServiceError::InvalidConfig(
+                "index_max_attempts must be at least 1".to_string(),
+            ));
+        }
+        if self.debounce.watcher_timeout().is_zero() {
+            return Err(ServiceError::InvalidConfig(
+                "debounce windows must be at least 1 ms".to_string(),
+            ));
+        }
+        if self.event_queue_cap == 0 {
+            return Err(ServiceError::InvalidConfig(
+                "event_queue_cap must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Environment variable naming the config file to use instead of the default.
+pub const CONFIG_PATH_ENV: &str = "SKIE_CONFIG";
+
+/// Pick the config file: an explicit `--config` argument, then
+/// [`CONFIG_PATH_ENV`], then `config.toml` in the default sync directory's
+/// [`CONFIG_DIR_NAME`]. Empty values count as unset.
+pub fn resolve_config_path(arg: Option<PathBuf>, env: Option<OsString>) -> PathBuf {
+    arg.filter(|path| !path.as_os_str().is_empty())
+        .or_else(|| env.filter(|value| !value.is_empty()).map(PathBuf::from))
+        .unwrap_or_else(|| {
+            common::get_default_sync_path()
+                .join(CONFIG_DIR_NAME)
+                .join("config.toml")
+        })
+}
+
+/// Load the config at `config_path`, writing the defaults first if it does not exist.
+///
+/// The parent directory is created when missing and, on Windows, hidden.
+pub fn load_or_init_config(config_path: &Path) -> Result<ServiceConfig, ServiceError> {
+    if let Some(config_dir) = config_path.parent()
+        && !config_dir.exists()
+    {
+        fs::create_dir_all(config_dir).map_err(ServiceError::ConfigWrite)?;
+
+        // Windows: Hide the directory itself
+        #[cfg(windows)]
+        {
+            let mut cmd = std::process::Command::new("attrib");
+            cmd.arg("+h").arg(config_dir);
+            let _ = cmd.status();
+        }
+    }
+
+    if !config_path.exists() {
+        let config = ServiceConfig::default();
+        let config_string =
+            toml::to_string(&config).map_err(|e| ServiceError::ConfigWrite(io::Error::other(e)))?;
+        fs::write(config_path, config_string).map_err(ServiceError::ConfigWrite)?;
+        return Ok(config);
+    }
+
+    let contents = fs::read_to_string(config_path).map_err(ServiceError::ConfigRead)?;
+    Ok(toml::from_str::<ServiceConfig>(&contents)?)
+}
+
+/// Version written at the top of the index engine config file.
+pub const ENGINE_CONFIG_VERSION: u32 = 1;
+
+/// File name of the index engine config inside its directory.
+pub const ENGINE_CONFIG_FILE_NAME: &str = "index.toml";
+
+/// Default upper bound on indexing threads. Handhelds may want fewer.
+pub const INDEX_ENGINE_MAX_THREADS: usize = 8;
+
+/// Index parameters the store was actually built with.
+///
+/// Unlike [`ServiceConfig`], which users edit, this records what the existing
+/// index was chunked with, so a changed chunk configuration can be told apart
+/// from the one on disk after a restart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IndexEngineConfig {
+    /// Format version; must be the first key so it heads the file.
+    pub version: u32,
+    /// Threads used for hashing; at least one. Replaced on startup when
+    /// `auto_threads` is set; see [`IndexEngineConfig::resolve_threads`].
+    #[serde(default = "default_num_threads")]
+    pub num_threads: usize,
+    /// Size `num_threads` to the machine instead of using it as written.
+    #[serde(default = "default_auto_threads")]
+    pub auto_threads: bool,
+    /// Upper bound for `num_threads` when it is picked automatically.
+    #[serde(default = "default_max_threads")]
+    pub max_threads: usize,
+    pub chunk_config: ChunkConfig,
+}
+
+fn default_num_threads() -> usize {
+    1
+}
+
+fn default_auto_threads() -> bool {
+    true
+}
+
+fn default_max_threads() -> usize {
+    INDEX_ENGINE_MAX_THREADS
+}
+
+impl Default for IndexEngineConfig {
+    fn default() -> Self {
+        Self {
+            version: ENGINE_CONFIG_VERSION,
+            num_threads: default_num_threads(),
+            auto_threads: default_auto_threads(),
+            max_threads: default_max_threads(),
+            chunk_config: ChunkConfig::default(),
+        }
+    }
+}
+
+impl IndexEngineConfig {
+    /// Rejects a thread count of zero, which rayon would silently read as
+    /// "one thread per core", and an unsupported normalization level.
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        if self.num_threads == 0 {
+            return Err(ServiceError::InvalidConfig(
+                "num_threads must be at least 1; set auto_threads to match the machine".to_string(),
+            ));
+        }
+        self.chunk_config
+            .validate()
+            .map_err(|e| ServiceError::InvalidConfig(e.to_string()))
+    }
+
+    /// With `auto_threads` set, sets `num_threads` to the available
+    /// parallelism, capped at `max_threads`; otherwise keeps it as configured.
+    ///
+    /// Never picks less than one thread, even with a cap of zero or when the
+    /// parallelism cannot be queried. Returns the resulting count.
+    pub fn resolve_threads(&mut self) -> usize {
+        if self.auto_threads {
+            let available = std::thread::available_parallelism().map_or(1, |n| n.get());
+            self.num_threads = available.min(self.max_threads).max(1);
+        }
+        self.num_threads
+    }
+}
+
+/// Key holding the checksum of the rest of `index.toml`.
+const ENGINE_CONFIG_CHECKSUM_KEY: &str = "checksum";
+
+/// Load `index.toml` from `engine_dir`, writing it first if it does not exist.
+///
+/// On first run the file records `chunk_config`, the chunking the service is
+/// about to index with, and defaults for the rest. A file written by a
+/// different format version is rejected rather than guessed at, and one whose
+/// settings do not match their recorded checksum fails with
+/// [`ServiceError::EngineConfigChecksum`].
+///
+/// The file is returned as recorded, even if `chunk_config` has changed since;
+/// see [`record_engine_chunking`].
+pub fn load_engine_config(
+    engine_dir: &Path,
+    chunk_config: ChunkConfig,
+) -> Result<IndexEngineConfig, ServiceError> {
+    let path = engine_dir.join(ENGINE_CONFIG_FILE_NAME);
+    if !path.exists() {
+        let config = IndexEngineConfig {
+            chunk_config,
+            ..Default::default()
+        };
+        save_engine_config(engine_dir, &config)?;
+        return Ok(config);
+    }
+
+    let contents = fs::read_to_string(&path).map_err(ServiceError::ConfigRead)?;
+    let mut table = toml::from_str::<toml::Table>(&contents)?;
+    let checksum = table.remove(ENGINE_CONFIG_CHECKSUM_KEY);
+    let config: IndexEngineConfig = toml::Value::Table(table).try_into()?;
+    if config.version != ENGINE_CONFIG_VERSION {
+        return Err(ServiceError::EngineConfigVersion {
+            found: config.version,
+            expected: ENGINE_CONFIG_VERSION,
+        });
+    }
+    let expected = engine_config_checksum(&engine_config_body(&config)?);
+    if checksum.as_ref().and_then(toml::Value::as_str) != Some(expected.as_str()) {
+        return Err(ServiceError::EngineConfigChecksum { path });
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+/// Write `config` to `index.toml` in `engine_dir`, creating the directory if needed.
+///
+/// The checksum of the settings goes right under the version header.
+pub fn save_engine_config(
+    engine_dir: &Path,
+    config: &IndexEngineConfig,
+) -> Result<(), ServiceError> {
+    fs::create_dir_all(engine_dir).map_err(ServiceError::ConfigWrite)?;
+    let body = engine_config_body(config)?;
+    let checksum = engine_config_checksum(&body);
+    let (header, settings) = body.split_once('\n').unwrap_or((&body, ""));
+    let contents = format!("{header}\n{ENGINE_CONFIG_CHECKSUM_KEY} = \"{checksum}\"\n{settings}");
+    fs::write(engine_dir.join(ENGINE_CONFIG_FILE_NAME), contents).map_err(ServiceError::ConfigWrite)
+}
+
+/// Records `chunk_config` as the chunking the index is built with, rewriting
+/// `index.toml` in `engine_dir` if `config` says otherwise.
+///
+/// The service always indexes with its own chunk config, so a mismatch means
+/// the index is about to be re-chunked. Returns whether there was one.
+pub fn record_engine_chunking(
+    engine_dir: &Path,
+    config: &mut IndexEngineConfig,
+    chunk_config: ChunkConfig,
+) -> Result<bool, ServiceError> {
+    if !config.chunk_config.affects_chunking(&chunk_config) {
+        return Ok(false);
+    }
+    config.chunk_config = chunk_config;
+    save_engine_config(engine_dir, config)?;
+    Ok(true)
+}
+
+/// `config` as written to `index.toml`, without its checksum.
+fn engine_config_body(config: &IndexEngineConfig) -> Result<String, ServiceError> {
+    toml::to_string(config).map_err(|e| ServiceError::ConfigWrite(io::Error::other(e)))
+}
+
+fn engine_config_checksum(body: &str) -> String {
+    blake3::hash(body.as_bytes()).to_hex().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_config_path_resolution_order() {
+        let default = resolve_config_path(None, None);
+        assert!(default.ends_with(Path::new(CONFIG_DIR_NAME).join("config.toml")));
+        assert_eq!(resolve_config_path(None, Some(OsString::new())), default);
+
+        let from_env = resolve_config_path(None, Some("/etc/skie/work.toml".into()));
+        assert_eq!(from_env, PathBuf::from("/etc/skie/work.toml"));
+
+        let from_arg = resolve_config_path(
+            Some(PathBuf::from("test.toml")),
+            Some("/etc/skie/work.toml".into()),
+        );
+        assert_eq!(from_arg, PathBuf::from("test.toml"));
+    }
+
+    #[test]
+    fn test_missing_config_is_initialized() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join(".config").join("config.toml");
+
+        let config = load_or_init_config(&config_path).unwrap();
+        assert_eq!(config.debounce, DebounceConfig::default());
+        assert!(config_path.exists(), "Defaults should be written to disk");
+    }
+
+    #[test]
+    fn test_malformed_config_is_parse_error() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "debounce = \"soon\"").unwrap();
+
+        let err = load_or_init_config(&config_path).unwrap_err();
+        assert!(matches!(err, ServiceError::ConfigParse(_)));
+    }
+
+    #[test]
+    fn test_legacy_debounce_ms_is_loaded() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "sync_dir = [\"/data/sync\"]\ndebounce_ms = 750\nevent_queue_cap = 16\n",
+        )
+        .unwrap();
+
+        let loaded = load_or_init_config(&config_path).unwrap();
+        assert_eq!(loaded.debounce, DebounceConfig::uniform(750));
+        assert_eq!(loaded.event_queue_cap, 16);
+
+        // Saved again, it is written in the current form
+        let saved = toml::to_string(&loaded).unwrap();
+        assert!(saved.contains("[debounce]") && !saved.contains("debounce_ms"));
+    }
+
+    #[test]
+    fn test_valid_config_is_loaded() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let config = ServiceConfig {
+            debounce: DebounceConfig {
+                modify_ms: 1200,
+                ..Default::default()
+            },
+            sync_dir: vec![PathBuf::from("/data/sync")],
+            ..Default::default()
+        };
+        fs::write(&config_path, toml::to_string(&config).unwrap()).unwrap();
+
+        let loaded = load_or_init_config(&config_path).unwrap();
+        assert_eq!(loaded.debounce.modify_ms, 1200);
+        assert_eq!(loaded.sync_dir, vec![PathBuf::from("/data/sync")]);
+    }
+
+    #[test]
+    fn test_chunk_defaults_agree_across_configs() {
+        let store_defaults = ChunkConfig::default();
+        let engine_defaults = IndexEngineConfig::default().chunk_config;
+        let service_defaults = ServiceConfig::default().chunk_config;
+        for config in [engine_defaults, service_defaults] {
+            assert_eq!(config.min_chunk_size, store_defaults.min_chunk_size);
+            assert_eq!(config.avg_chunk_size, store_defaults.avg_chunk_size);
+            assert_eq!(config.max_chunk_size, store_defaults.max_chunk_size);
+        }
+        assert_eq!(
+            store_defaults.avg_chunk_size,
+            common::DEFAULT_AVG_CHUNK_SIZE
+        );
+    }
+
+    #[test]
+    fn test_engine_config_first_run_records_effective_chunking() {
+        let dir = TempDir::new().unwrap();
+        let engine_dir = dir.path().join("engine_config");
+        let chunk_config = ChunkConfig {
+            min_chunk_size: 2048,
+            avg_chunk_size: 8192,
+            max_chunk_size: 32768,
+            ..Default::default()
+        };
+
+        let config = load_engine_config(&engine_dir, chunk_config).unwrap();
+        assert_eq!(config.version, ENGINE_CONFIG_VERSION);
+        assert_eq!(config.chunk_config, chunk_config);
+
+        let written = fs::read_to_string(engine_dir.join(ENGINE_CONFIG_FILE_NAME)).unwrap();
+        assert!(written.starts_with("version = 1\nchecksum = "));
+        // Once written, loading returns what the file records
+        let reloaded = load_engine_config(&engine_dir, ChunkConfig::default()).unwrap();
+        assert_eq!(reloaded.chunk_config, chunk_config);
+    }
+
+    #[test]
+    fn test_engine_config_checksum_mismatch_rejected() {
+        let dir = TempDir::new().unwrap();
+        save_engine_config(dir.path(), &IndexEngineConfig::default()).unwrap();
+        let path = dir.path().join(ENGINE_CONFIG_FILE_NAME);
+        let written = fs::read_to_string(&path).unwrap();
+
+        let edited = written.replace("max_threads = 8", "max_threads = 2");
+        assert_ne!(edited, written);
+        fs::write(&path, edited).unwrap();
+        let err = load_engine_config(dir.path(), ChunkConfig::default()).unwrap_err();
+        assert!(matches!(err, ServiceError::EngineConfigChecksum { path: p } if p == path));
+
+        let unchecked = written
+            .lines()
+            .filter(|line| !line.starts_with("checksum"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(&path, unchecked).unwrap();
+        assert!(matches!(
+            load_engine_config(dir.path(), ChunkConfig::default()),
+            Err(ServiceError::EngineConfigChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_engine_config_records_changed_chunking() {
+        let dir = TempDir::new().unwrap();
+        let mut config = load_engine_config(dir.path(), ChunkConfig::default()).unwrap();
+        assert!(!record_engine_chunking(dir.path(), &mut config, ChunkConfig::default()).unwrap());
+
+        let larger = ChunkConfig {
+            min_chunk_size: 4096,
+            avg_chunk_size: 16384,
+            max_chunk_size: 65536,
+            ..Default::default()
+        };
+        assert!(record_engine_chunking(dir.path(), &mut config, larger).unwrap());
+        assert_eq!(config.chunk_config, larger);
+        let reloaded = load_engine_config(dir.path(), ChunkConfig::default()).unwrap();
+        assert_eq!(reloaded.chunk_config, larger);
+    }
+
+    #[test]
+    fn test_engine_config_existing_is_loaded() {
+        let dir = TempDir::new().unwrap();
+        let saved = IndexEngineConfig {
+            chunk_config: ChunkConfig {
+                min_chunk_size: 4096,
+                avg_chunk_size: 16384,
+                max_chunk_size: 65536,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        save_engine_config(dir.path(), &saved).unwrap();
+
+        let loaded = load_engine_config(dir.path(), ChunkConfig::default()).unwrap();
+        assert_eq!(loaded.chunk_config.avg_chunk_size, 16384);
+        assert_eq!(loaded.chunk_config.max_chunk_size, 65536);
+    }
+
+    #[test]
+    fn test_engine_config_version_mismatch_rejected() {
+        let dir = TempDir::new().unwrap();
+        let future = IndexEngineConfig {
+            version: ENGINE_CONFIG_VERSION + 1,
+            ..Default::default()
+        };
+        save_engine_config(dir.path(), &future).unwrap();
+
+        let err = load_engine_config(dir.path(), ChunkConfig::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            ServiceError::EngineConfigVersion { found, expected }
+                if found == ENGINE_CONFIG_VERSION + 1 && expected == ENGINE_CONFIG_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_engine_config_zero_threads_rejected() {
+        let dir = TempDir::new().unwrap();
+        let config = IndexEngineConfig {
+            num_threads: 0,
+            ..Default::default()
+        };
+        save_engine_config(dir.path(), &config).unwrap();
+
+        let err = load_engine_config(dir.path(), ChunkConfig::default()).unwrap_err();
+        assert!(matches!(err, ServiceError::InvalidConfig(_)));
+        assert!(IndexEngineConfig::default().validate().is_ok());
+        assert!(IndexEngineConfig::default().num_threads >= 1);
+    }
+
+    #[test]
+    fn test_engine_config_fixed_thread_count_is_kept() {
+        let dir = TempDir::new().unwrap();
+        let config = IndexEngineConfig {
+            num_threads: 3,
+            auto_threads: false,
+            ..Default::default()
+        };
+        save_engine_config(dir.path(), &config).unwrap();
+
+        let mut loaded = load_engine_config(dir.path(), ChunkConfig::default()).unwrap();
+        assert_eq!(loaded.resolve_threads(), 3);
+        assert_eq!(loaded.num_threads, 3);
+    }
+
+    #[test]
+    fn test_engine_config_normalization_level_out_of_range_rejected() {
+        let mut config = IndexEngineConfig::default();
+        config.chunk_config.normalization_level = 3;
+        assert!(config.validate().is_ok());
+
+        config.chunk_config.normalization_level = 4;
+        assert!(matches!(
+            config.validate(),
+            Err(ServiceError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_path_excluded() {
+        let config = ServiceConfig {
+            ignore: vec!["*.swp".to_string(), "~$*".to_string()],
+            ..Default::default()
+        };
+
+        assert!(config.is_path_excluded(Utf8Path::new("/sync/.config/config.toml")));
+        assert!(config.is_path_excluded(Utf8Path::new("/sync/a/.config/index.db")));
+        assert!(config.is_path_excluded(Utf8Path::new("/sync/notes.txt.swp")));
+        assert!(config.is_path_excluded(Utf8Path::new("/sync/docs/~$report.docx")));
+
+        assert!(!config.is_path_excluded(Utf8Path::new("/sync/notes.txt")));
+        assert!(!config.is_path_excluded(Utf8Path::new("/sync/.configs/notes.txt")));
+        // Patterns match file names, not directories along the way
+        assert!(!config.is_path_excluded(Utf8Path::new("/sync/x.swp/notes.txt")));
+    }
+
+    #[test]
+    fn test_engine_config_thread_change_needs_no_reindex() {
+        let recorded = IndexEngineConfig::default();
+        let more_threads = IndexEngineConfig {
+            num_threads: 4,
+            ..recorded
+        };
+        assert_ne!(more_threads, recorded);
+        assert!(
+            !more_threads
+                .chunk_config
+                .affects_chunking(&recorded.chunk_config)
+        );
+    }
+
+    #[test]
+    fn test_auto_threads_is_capped_and_nonzero() {
+        let mut config = IndexEngineConfig::default();
+        assert!(config.auto_threads);
+        let threads = config.resolve_threads();
+        assert!(threads >= 1);
+        assert!(threads <= INDEX_ENGINE_MAX_THREADS);
+        assert_eq!(config.num_threads, threads);
+
+        config.max_threads = 1;
+        assert_eq!(config.resolve_threads(), 1);
+
+        config.max_threads = 0;
+        assert_eq!(config.resolve_threads(), 1);
+    }
+}
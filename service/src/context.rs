@@ -0,0 +1,426 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Single entry point wiring the service's config, store and reactor together.
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use store::{DataStore, FreeSpaceGuard, SqlitePragmas};
+
+use crate::{
+    ENGINE_CONFIG_FILE_NAME, IndexEngineConfig, Reactor, ServiceConfig, ServiceError,
+    load_engine_config, load_or_init_config, record_engine_chunking,
+};
+
+/// File name of the index database, kept next to the config file.
+pub const DB_FILE_NAME: &str = "index.db";
+
+/// Directory holding the index engine config, next to the config file.
+pub const ENGINE_CONFIG_DIR: &str = "engine_config";
+
+/// What [`ServiceContext::reload_config`] changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConfigReload {
+    /// The sync directories or watcher settings changed, so the watcher must be rebuilt.
+    pub watcher_changed: bool,
+    /// Chunk boundaries moved; files indexed before the reload will not dedup
+    /// against new ones until they are re-indexed.
+    pub chunking_changed: bool,
+}
+
+/// The loaded config together with the store and reactor built from it.
+pub struct ServiceContext {
+    config_path: PathBuf,
+    config: ServiceConfig,
+    engine_config: IndexEngineConfig,
+    store: Arc<DataStore>,
+    reactor: Reactor,
+}
+
+impl ServiceContext {
+    /// Loads the config at `config_path` and opens the store beside it.
+    ///
+    /// The database lives at [`DB_FILE_NAME`] in the config's directory and is
+    /// created and migrated if it does not exist yet. The index engine config
+    /// is read from [`ENGINE_CONFIG_DIR`] in the same directory; if it records
+    /// different chunking than the config, the mismatch is logged and the
+    /// config's chunking recorded instead, as that is what indexing uses.
+    pub async fn init(config_path: &Path) -> Result<Self, ServiceError> {
+        let config = load_or_init_config(config_path)?;
+        config.validate()?;
+        let config_dir = config_path.parent().unwrap_or_else(|| Path::new("."));
+        let engine_dir = config_dir.join(ENGINE_CONFIG_DIR);
+        let mut engine_config = load_engine_config(&engine_dir, config.chunk_config)?;
+        if record_engine_chunking(&engine_dir, &mut engine_config, config.chunk_config)? {
+            log::warn!(
+                "{ENGINE_CONFIG_FILE_NAME} recorded different chunking than {}; indexing with the latter",
+                config_path.display()
+            );
+        }
+        engine_config.resolve_threads();
+
+        let db_path = config_dir.join(DB_FILE_NAME);
+        let url = format!("sqlite://{}?mode=rwc", db_path.display());
+        let mut store = DataStore::with_options(&url, SqlitePragmas::default())
+            .await?
+            .with_sync_set(config.sync_set.clone());
+        if config.min_free_bytes > 0 {
+            store = store.with_free_space_guard(FreeSpaceGuard {
+                data_dir: config_dir.to_path_buf(),
+                min_free_bytes: config.min_free_bytes,
+            });
+        }
+        let store = Arc::new(store);
+        if store.record_chunk_protocol(&config.chunk_config).await? {
+            let stale = store.reindex_all_needed().await?.len();
+            log::warn!("Chunk config changed since the last run; {stale} files need re-indexing");
+        }
+        let reactor = Reactor::new(store.clone(), config.chunk_config)
+            .with_file_id_namespace(config.file_id_namespace)
+            .with_max_index_attempts(config.index_max_attempts);
+
+        Ok(Self {
+            config_path: config_path.to_path_buf(),
+            config,
+            engine_config,
+            store,
+            reactor,
+        })
+    }
+
+    /// Re-reads the config file and applies it, e.g. on SIGHUP.
+    ///
+    /// The new config must parse and pass [`ServiceConfig::validate`];
+    /// otherwise the error is returned and the current config stays in effect.
+    /// The reactor is rebuilt with the new settings, and changed chunking is
+    /// recorded in the index engine config. Watching is left to the caller, as
+    /// reported by [`ConfigReload::watcher_changed`].
+    pub fn reload_config(&mut self) -> Result<ConfigReload, ServiceError> {
+        let config = load_or_init_config(&self.config_path)?;
+        config.validate()?;
+
+        let old = &self.config;
+        let change = ConfigReload {
+            watcher_changed: config.sync_dir != old.sync_dir
+                || config.debounce != old.debounce
+                || config.event_queue_cap != old.event_queue_cap
+                || config.max_watch_depth != old.max_watch_depth,
+            chunking_changed: config.chunk_config.affects_chunking(&old.chunk_config),
+        };
+        let engine_dir = self
+            .config_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(ENGINE_CONFIG_DIR);
+        record_engine_chunking(&engine_dir, &mut self.engine_config, config.chunk_config)?;
+        self.reactor = Reactor::new(self.store.clone(), config.chunk_config)
+            .with_file_id_namespace(config.file_id_namespace)
+            .with_max_index_attempts(config.index_max_attempts);
+        self.config = config;
+        Ok(change)
+    }
+
+    pub fn config(&self) -> &ServiceConfig {
+        &self.config
+    }
+
+    /// The index parameters recorded for the existing store.
+    pub fn engine_config(&self) -> &IndexEngineConfig {
+        &self.engine_config
+    }
+
+    pub fn store(&self) -> &Arc<DataStore> {
+        &self.store
+    }
+
+    pub fn reactor(&self) -> &Reactor {
+        &self.reactor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventForwarder, OsEvent};
+    use camino::Utf8PathBuf;
+    use notify_debouncer_full::{
+        DebouncedEvent,
+        notify::{
+            Event,
+            event::{CreateKind, EventKind, ModifyKind, RemoveKind},
+        },
+    };
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Instant,
+    };
+    use store::{Fetch, PathEntry};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_init_builds_working_context() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join(".config").join("config.toml");
+
+        let context = ServiceContext::init(&config_path).await.unwrap();
+        assert_eq!(context.config().debounce.modify_ms, 500);
+        assert!(config_path.with_file_name(DB_FILE_NAME).exists());
+        assert_eq!(
+            context.engine_config().version,
+            crate::ENGINE_CONFIG_VERSION
+        );
+        assert_eq!(context.store().physical_chunk_count().await.unwrap(), 0);
+
+        let file = Utf8PathBuf::from_path_buf(dir.path().join("notes.txt")).unwrap();
+        std::fs::write(&file, b"hello from the reactor\n".repeat(100)).unwrap();
+        let event = OsEvent {
+            kind: EventKind::Create(CreateKind::File),
+            paths: vec![file.clone()],
+            time: Instant::now(),
+        };
+        context.reactor().process_events(&[event]).await.unwrap();
+
+        let entry: PathEntry = context.store().fetch_by(&file).await.unwrap();
+        assert_eq!(entry.path, file.as_str());
+        assert!(context.store().physical_chunk_count().await.unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_init_records_the_chunking_it_indexes_with() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let engine_dir = dir.path().join(ENGINE_CONFIG_DIR);
+        let recorded = IndexEngineConfig {
+            chunk_config: store::ChunkConfig {
+                min_chunk_size: 4096,
+                avg_chunk_size: 16384,
+                max_chunk_size: 65536,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        crate::save_engine_config(&engine_dir, &recorded).unwrap();
+
+        let context = ServiceContext::init(&config_path).await.unwrap();
+        let indexed_with = context.config().chunk_config;
+        assert_ne!(indexed_with, recorded.chunk_config);
+        assert_eq!(context.engine_config().chunk_config, indexed_with);
+        let reloaded = load_engine_config(&engine_dir, recorded.chunk_config).unwrap();
+        assert_eq!(reloaded.chunk_config, indexed_with);
+    }
+
+    #[tokio::test]
+    async fn test_failing_file_is_dead_lettered() {
+        let dir = TempDir::new().unwrap();
+        let context = ServiceContext::init(&dir.path().join("config.toml"))
+            .await
+            .unwrap();
+        let parent = dir.path().join("blocked");
+        let file = Utf8PathBuf::from_path_buf(parent.join("stuck.txt")).unwrap();
+        let event = || OsEvent {
+            kind: EventKind::Modify(ModifyKind::Any),
+            paths: vec![file.clone()],
+            time: Instant::now(),
+        };
+
+        // A regular file stands where the parent directory should be, so every
+        // attempt fails without failing the batch
+        std::fs::write(&parent, b"not a directory").unwrap();
+        for _ in 0..4 {
+            context.reactor().process_events(&[event()]).await.unwrap();
+        }
+        let dead = context.store().dead_letters(3).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].attempts, 3, "dead letters are not retried");
+
+        // Once an operator clears it, the next event indexes it again
+        std::fs::remove_file(&parent).unwrap();
+        std::fs::create_dir(&parent).unwrap();
+        std::fs::write(&file, b"back again").unwrap();
+        assert!(
+            context
+                .store()
+                .clear_index_failure(file.as_str())
+                .await
+                .unwrap()
+        );
+        context.reactor().process_events(&[event()]).await.unwrap();
+        let entry: PathEntry = context.store().fetch_by(&file).await.unwrap();
+        assert_eq!(entry.path, file.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_reload_applies_valid_and_keeps_old_on_error() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join(".config").join("config.toml");
+        let mut context = ServiceContext::init(&config_path).await.unwrap();
+
+        let mut config = ServiceConfig {
+            ignore: vec!["*.tmp".to_string()],
+            ..Default::default()
+        };
+        config.chunk_config.avg_chunk_size = 1536;
+        std::fs::write(&config_path, toml::to_string(&config).unwrap()).unwrap();
+        let change = context.reload_config().unwrap();
+        assert!(change.chunking_changed);
+        assert!(!change.watcher_changed);
+        assert_eq!(context.config().ignore, ["*.tmp"]);
+        assert_eq!(context.engine_config().chunk_config.avg_chunk_size, 1536);
+
+        // Unparsable and invalid configs are both rejected
+        std::fs::write(&config_path, "debounce = \"soon\"").unwrap();
+        assert!(context.reload_config().is_err());
+        config.chunk_config.min_chunk_size = 4096;
+        config.debounce.modify_ms = 100;
+        std::fs::write(&config_path, toml::to_string(&config).unwrap()).unwrap();
+        assert!(matches!(
+            context.reload_config(),
+            Err(ServiceError::InvalidConfig(_))
+        ));
+        assert_eq!(context.config().debounce.modify_ms, 500);
+        assert_eq!(context.config().chunk_config.avg_chunk_size, 1536);
+    }
+
+    #[tokio::test]
+    async fn test_min_free_bytes_guards_the_store() {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let config = ServiceConfig {
+            min_free_bytes: u64::MAX / 2,
+            ..Default::default()
+        };
+        std::fs::write(&config_path, toml::to_string(&config).unwrap()).unwrap();
+        let context = ServiceContext::init(&config_path).await.unwrap();
+
+        let err = context
+            .store()
+            .ingest_bytes("blob", "/blob", b"no room for this", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            store::DataStoreError::InsufficientSpace { .. }
+        ));
+        assert_eq!(context.store().physical_chunk_count().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_remove_event_untracks_file() {
+        let dir = TempDir::new().unwrap();
+        let context = ServiceContext::init(&dir.path().join("config.toml"))
+            .await
+            .unwrap();
+        let file = Utf8PathBuf::from_path_buf(dir.path().join("gone.txt")).unwrap();
+        std::fs::write(&file, b"short-lived").unwrap();
+        let event = |kind| OsEvent {
+            kind,
+            paths: vec![file.clone()],
+            time: Instant::now(),
+        };
+
+        context
+            .reactor()
+            .process_events(&[event(EventKind::Create(CreateKind::File))])
+            .await
+            .unwrap();
+        std::fs::remove_file(&file).unwrap();
+        context
+            .reactor()
+            .process_events(&[event(EventKind::Remove(RemoveKind::File))])
+            .await
+            .unwrap();
+
+        let lookup: Result<PathEntry, _> = context.store().fetch_by(&file).await;
+        assert!(matches!(lookup, Err(store::DataStoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_vanished_file_is_removed_not_failed() {
+        let dir = TempDir::new().unwrap();
+        let context = ServiceContext::init(&dir.path().join("config.toml"))
+            .await
+            .unwrap();
+        let file = Utf8PathBuf::from_path_buf(dir.path().join("brief.txt")).unwrap();
+        std::fs::write(&file, b"here for a moment").unwrap();
+        let modified = || OsEvent {
+            kind: EventKind::Modify(ModifyKind::Any),
+            paths: vec![file.clone()],
+            time: Instant::now(),
+        };
+        context
+            .reactor()
+            .process_events(&[modified()])
+            .await
+            .unwrap();
+        let store = context.store();
+        store
+            .record_index_failure(file.as_str(), "earlier flake")
+            .await
+            .unwrap();
+
+        // Deleted before the modify event was handled, with no remove event
+        std::fs::remove_file(&file).unwrap();
+        context
+            .reactor()
+            .process_events(&[modified()])
+            .await
+            .unwrap();
+
+        let lookup: Result<PathEntry, _> = store.fetch_by(&file).await;
+        assert!(matches!(lookup, Err(store::DataStoreError::NotFound)));
+        assert!(store.index_failure(file.as_str()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_leads_to_rescan() {
+        let dir = TempDir::new().unwrap();
+        let context = ServiceContext::init(&dir.path().join(".config").join("config.toml"))
+            .await
+            .unwrap();
+        let sync_dir = dir.path().join("sync");
+        std::fs::create_dir(&sync_dir).unwrap();
+        let config = ServiceConfig {
+            sync_dir: vec![sync_dir.clone()],
+            ..Default::default()
+        };
+        let path = |name: &str| Utf8PathBuf::from_path_buf(sync_dir.join(name)).unwrap();
+
+        // Tracked before the overflow, then deleted while events were dropped
+        let gone = path("gone.txt");
+        std::fs::write(&gone, b"about to vanish").unwrap();
+        let created = OsEvent {
+            kind: EventKind::Create(CreateKind::File),
+            paths: vec![gone.clone()],
+            time: Instant::now(),
+        };
+        context.reactor().process_events(&[created]).await.unwrap();
+        std::fs::remove_file(&gone).unwrap();
+
+        // A queue of one: the second batch does not fit and is dropped
+        let (sender, queue) = crossbeam_channel::bounded(1);
+        let needs_rescan = Arc::new(AtomicBool::new(false));
+        let mut forwarder = EventForwarder::new(
+            Arc::new(AtomicBool::new(false)),
+            needs_rescan.clone(),
+            sender,
+        );
+        let (kept, dropped) = (path("kept.txt"), path("dropped.txt"));
+        for file in [&kept, &dropped] {
+            std::fs::write(file, file.as_str().repeat(50)).unwrap();
+            let event =
+                Event::new(EventKind::Create(CreateKind::File)).add_path(file.clone().into());
+            forwarder.handle_event(Ok(vec![DebouncedEvent::new(event, Instant::now())]));
+        }
+        assert_eq!(queue.len(), 1);
+        assert!(needs_rescan.swap(false, Ordering::AcqRel));
+
+        context.reactor().rescan(&config).await.unwrap();
+        for file in [&kept, &dropped] {
+            let entry: PathEntry = context.store().fetch_by(file).await.unwrap();
+            assert_eq!(entry.path, file.as_str());
+        }
+        let lookup: Result<PathEntry, _> = context.store().fetch_by(&gone).await;
+        assert!(matches!(lookup, Err(store::DataStoreError::NotFound)));
+    }
+}
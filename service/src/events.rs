@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Turning raw debounced notify events into the [`OsEvent`]s the service acts on.
+//!
+//! Everything here is pure, so tests can drive it with synthetic events instead
+//! of a real filesystem watcher.
+use notify_debouncer_full::{
+    DebouncedEvent,
+    notify::event::{DataChange, EventKind, ModifyKind, RemoveKind, RenameMode},
+};
+
+use crate::{OsEvent, ServiceConfig};
+
+/// Filters a batch from the watcher and converts it into [`OsEvent`]s.
+///
+/// Only create/modify/remove events are kept. Exclusions (see
+/// [`ServiceConfig::is_path_excluded`]) are applied per path after
+/// [`build_events_iter`], so the temporary file of an atomic save can be
+/// ignored without losing the update to the file it replaces.
+pub fn process_batch(events: Vec<DebouncedEvent>, cfg: &ServiceConfig) -> Vec<OsEvent> {
+    let relevant = events.into_iter().filter(|debounced_event| {
+        // Only care about data-changing events
+        matches!(
+            debounced_event.event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        )
+    });
+
+    build_events_iter(relevant)
+        .filter_map(|mut event| {
+            event.paths.retain(|path| !cfg.is_path_excluded(path));
+            (!event.paths.is_empty()).then_some(event)
+        })
+        .collect()
+}
+
+/// Converts debounced events into [`OsEvent`]s, pairing up atomic updates.
+///
+/// Editors commonly save by writing a temporary file and renaming it over the
+/// original, or by deleting and recreating the file. Both are reported as a
+/// content change of the target path:
+/// * a rename `from -> to` becomes a removal of `from` and a modification of `to`;
+/// * a removal followed by a creation of the same path becomes one modification.
+pub fn build_events_iter(
+    events: impl IntoIterator<Item = DebouncedEvent>,
+) -> impl Iterator<Item = OsEvent> {
+    let mut out: Vec<OsEvent> = Vec::new();
+
+    for event in events {
+        let mut event = OsEvent::from(event);
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let to = event.paths.pop().expect("rename has a target");
+                let time = event.time;
+                event.kind = EventKind::Remove(RemoveKind::Any);
+                out.push(event);
+                out.push(OsEvent {
+                    kind: modified(),
+                    paths: vec![to],
+                    time,
+                });
+            }
+            EventKind::Create(_) if event.paths.len() == 1 => {
+                let removed = out.iter().rposition(|prev| {
+                    matches!(prev.kind, EventKind::Remove(_)) && prev.paths == event.paths
+                });
+                if let Some(index) = removed {
+                    out.remove(index);
+                    event.kind = modified();
+                }
+                out.push(event);
+            }
+            _ => out.push(event),
+        }
+    }
+
+    out.into_iter()
+}
+
+fn modified() -> EventKind {
+    EventKind::Modify(ModifyKind::Data(DataChange::Content))
+}
+
+/// Matches `name` against `pattern`, where `*` stands for any run of characters.
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*` at all, so the whole name must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify_debouncer_full::notify::event::{CreateKind, Event};
+    use std::time::Instant;
+
+    fn debounced(kind: EventKind, paths: &[&str]) -> DebouncedEvent {
+        let event = paths
+            .iter()
+            .fold(Event::new(kind), |event, path| event.add_path(path.into()));
+        DebouncedEvent::new(event, Instant::now())
+    }
+
+    #[test]
+    fn test_process_batch_filters_and_pairs() {
+        let cfg = ServiceConfig {
+            ignore: vec!["*.swp".to_string(), ".~*.tmp".to_string()],
+            ..Default::default()
+        };
+        let create = EventKind::Create(CreateKind::File);
+        let remove = EventKind::Remove(RemoveKind::File);
+        let events = vec![
+            debounced(create, &["/sync/.config/config.toml"]),
+            debounced(create, &["/sync/notes.txt.swp"]),
+            // Atomic save through a temp file renamed over the original
+            debounced(create, &["/sync/.~doc.txt.tmp"]),
+            debounced(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["/sync/.~doc.txt.tmp", "/sync/doc.txt"],
+            ),
+            // Atomic save by deleting and recreating the file
+            debounced(remove, &["/sync/a.txt"]),
+            debounced(create, &["/sync/a.txt"]),
+            debounced(create, &["/sync/b.txt"]),
+        ];
+
+        let os_events = process_batch(events, &cfg);
+        let summary: Vec<(EventKind, &str)> = os_events
+            .iter()
+            .flat_map(|e| e.paths.iter().map(|p| (e.kind, p.as_str())))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (modified(), "/sync/doc.txt"),
+                (modified(), "/sync/a.txt"),
+                (create, "/sync/b.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.swp", "a.swp"));
+        assert!(wildcard_match("~*", "~lock"));
+        assert!(wildcard_match("a*b*c", "axxbyyc"));
+        assert!(wildcard_match("exact", "exact"));
+        assert!(!wildcard_match("exact", "exactly"));
+        assert!(!wildcard_match("*.swp", "a.swpx"));
+        assert!(!wildcard_match("a*b*c", "acb"));
+    }
+}
@@ -1,5 +1,19 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+mod coalesce;
+mod config;
+mod context;
+mod events;
+mod schedule;
+mod watcher;
+
+pub use coalesce::*;
+pub use config::*;
+pub use context::*;
+pub use events::*;
+pub use schedule::*;
+pub use watcher::*;
+
 use anyhow::Result;
 use camino::Utf8PathBuf;
 use common::FileID;
@@ -8,9 +22,12 @@ use notify_debouncer_full::{
     notify::event::{Event, EventKind},
 };
 use std::time::Instant;
-use std::{io::Cursor, sync::Arc};
-use store::{ChunkConfig, ChunkedSource, DataStore, FileTableEntry, Persist, chunk_source};
-use plugin::Plugin;
+use std::{
+    io::{self, Cursor},
+    sync::Arc,
+};
+use store::{ChunkConfig, DataStore, DataStoreError, Fetch, PathEntry};
+use uuid::Uuid;
 
 pub struct OsEvent {
     pub kind: EventKind,
@@ -35,6 +52,8 @@ impl From<DebouncedEvent> for OsEvent {
 pub struct Reactor {
     store: Arc<DataStore>,
     chunk_config: ChunkConfig,
+    file_id_namespace: Option<Uuid>,
+    max_index_attempts: u32,
 }
 
 impl Reactor {
@@ -43,16 +62,35 @@ impl Reactor {
         Reactor {
             store,
             chunk_config,
+            file_id_namespace: None,
+            max_index_attempts: 3,
         }
     }
 
+    /// Derive the ids of newly tracked files from their paths in `namespace`
+    /// instead of generating random ones. See [`FileID::from_path_seed`].
+    pub fn with_file_id_namespace(mut self, namespace: Option<Uuid>) -> Self {
+        self.file_id_namespace = namespace;
+        self
+    }
+
+    /// Stop re-indexing a file after it failed `attempts` times in a row.
+    pub fn with_max_index_attempts(mut self, attempts: u32) -> Self {
+        self.max_index_attempts = attempts;
+        self
+    }
+
     /// Process a batch of OS file events.
+    ///
+    /// A file that fails to index is recorded in the store rather than
+    /// failing the batch, and skipped once it has failed
+    /// `max_index_attempts` times in a row.
     pub async fn process_events(&self, events: &[OsEvent]) -> Result<()> {
         for ev in events {
             for path in &ev.paths {
                 match ev.kind {
                     EventKind::Create(_) | EventKind::Modify(_) => {
-                        self.handle_upsert(path).await?;
+                        self.index_with_retry_limit(path).await?;
                     }
                     EventKind::Remove(_) => {
                         self.handle_remove(path).await?;
@@ -64,49 +102,142 @@ impl Reactor {
         Ok(())
     }
 
+    /// Reconciles the store with the sync directories of `config`, for when
+    /// watcher events were dropped.
+    ///
+    /// Files that [`DataStore::needs_reindex`] reports as changed are indexed
+    /// and tracked files that no longer exist are removed. Excluded paths are
+    /// left alone, and unreadable directories are logged and skipped.
+    pub async fn rescan(&self, config: &ServiceConfig) -> Result<()> {
+        let mut present = Vec::new();
+        let mut pending = config.sync_dir.clone();
+        while let Some(dir) = pending.pop() {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::warn!("Rescan skipped {}: {e}", dir.display());
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let Ok(path) = Utf8PathBuf::from_path_buf(entry.path()) else {
+                    continue;
+                };
+                if config.is_path_excluded(&path) {
+                    continue;
+                }
+                match entry.file_type() {
+                    Ok(kind) if kind.is_dir() => pending.push(path.into_std_path_buf()),
+                    Ok(kind) if kind.is_file() => present.push(path),
+                    _ => {}
+                }
+            }
+        }
+
+        for path in &present {
+            // A file that vanished or cannot be checked is left to indexing to report
+            if !matches!(
+                self.store.needs_reindex(path.as_std_path()).await,
+                Ok(false)
+            ) {
+                self.index_with_retry_limit(path).await?;
+            }
+        }
+        for file in self.store.list_files(None).await? {
+            let path = Utf8PathBuf::from(file.path);
+            let watched = config
+                .sync_dir
+                .iter()
+                .any(|dir| path.as_std_path().starts_with(dir));
+            if watched && !config.is_path_excluded(&path) && !path.exists() {
+                self.handle_remove(&path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn index_with_retry_limit(&self, path: &Utf8PathBuf) -> Result<()> {
+        let max_attempts = i64::from(self.max_index_attempts);
+        if let Some(failure) = self.store.index_failure(path.as_str()).await?
+            && failure.attempts >= max_attempts
+        {
+            log::debug!("Skipping {path}, dead-lettered after {max_attempts} failures");
+            return Ok(());
+        }
+
+        if let Err(e) = self.handle_upsert(path).await {
+            let attempts = self
+                .store
+                .record_index_failure(path.as_str(), &e.to_string())
+                .await?;
+            if attempts >= max_attempts {
+                log::error!("Giving up on {path} after {attempts} failures: {e}");
+            } else {
+                log::warn!("Indexing {path} failed (attempt {attempts}): {e}");
+            }
+        }
+        Ok(())
+    }
+
     /// Handle creation or modification of a file: read, chunk, and persist.
+    ///
+    /// A file deleted before it could be read is handled as a removal; its
+    /// remove event may have been coalesced away or dropped.
     async fn handle_upsert(&self, path: &Utf8PathBuf) -> Result<()> {
         // Check file metadata in blocking task
-        let metadata = std::fs::metadata(path)?;
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return self.handle_remove(path).await,
+            Err(e) => return Err(e.into()),
+        };
 
         if !metadata.is_file() {
             return Ok(());
         }
+        self.store.ensure_free_space(metadata.len())?;
 
-        let data = tokio::fs::read(path).await?;
-
-        // Read file contents
-
-        // Generate a new FileID
-        let file_id = FileID::new();
-
-        // Perform CDC chunking
-        let reader = Cursor::new(data);
-        let ChunkedSource {
-            chunks,
-            file_sections,
-            file_hash,
-        } = chunk_source(&file_id, reader, Some(self.chunk_config))?;
-
-        // Persist deduplicated chunks
-        self.store.store_all(chunks).await?;
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return self.handle_remove(path).await,
+            Err(e) => return Err(e.into()),
+        };
 
-        // Persist or update file metadata
-        let entry = FileTableEntry {
-            file_id: file_id.to_string(),
-            name: path.file_name().map(|n| n.to_string()).unwrap_or_default(),
-            path: path.to_string(),
-            hash: file_hash,
+        // Reuse the FileID already tracked for this path, or start tracking it
+        let file_id = match self.store.fetch_by(path).await {
+            Ok(PathEntry { file_id, .. }) => file_id.parse::<FileID>()?,
+            Err(DataStoreError::NotFound) => match &self.file_id_namespace {
+                Some(namespace) => FileID::from_path_seed(path.as_std_path(), namespace),
+                None => FileID::new(),
+            },
+            Err(e) => return Err(e.into()),
         };
-        self.store.store(entry).await?;
 
-        // Persist file sections mapping
-        self.store.store_all(file_sections).await?;
+        // Chunk and persist chunks, file metadata and sections in one go
+        let name = path.file_name().map(|n| n.to_string()).unwrap_or_default();
+        self.store
+            .index_and_store(
+                &file_id,
+                &name,
+                path.as_str(),
+                Cursor::new(data),
+                Some(self.chunk_config),
+            )
+            .await?;
         Ok(())
     }
 
-    /// Handle removal of a file: delete from store.
+    /// Handle removal of a file: stop tracking it and forget its index
+    /// failures. Its chunks stay, since other files may share them.
     async fn handle_remove(&self, path: &Utf8PathBuf) -> Result<()> {
-        todo!()
+        self.store.clear_index_failure(path.as_str()).await?;
+        let file_id = match self.store.fetch_by(path).await {
+            Ok(PathEntry { file_id, .. }) => file_id.parse::<FileID>()?,
+            Err(DataStoreError::NotFound) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut tx = self.store.transaction().await?;
+        tx.delete_file(&file_id).await?;
+        tx.commit().await?;
+        Ok(())
     }
 }
@@ -1,101 +1,142 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use anyhow::Result;
-use common::*;
-use crossbeam_channel::unbounded;
-use diff_d::OsEvent;
-use notify_debouncer_full::{
-    new_debouncer,
-    notify::{EventKind, RecursiveMode},
+use crossbeam_channel::{Receiver, select};
+use diff_d::{
+    CONFIG_PATH_ENV, EventCoalescer, IndexJitter, IndexQueue, ServiceConfig, ServiceContext,
+    Watcher, process_batch, resolve_config_path,
+};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
 };
-use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf, time::Duration};
-use store::ChunkConfig;
 
-#[derive(Deserialize, Serialize)]
-struct ServiceConfig {
-    chunk_config: ChunkConfig,
-    sync_dir: Vec<PathBuf>,
-    debounce_ms: u64,
-}
+/// How often to check whether a restore replaced the database file.
+const DB_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
-impl Default for ServiceConfig {
-    fn default() -> Self {
-        Self {
-            chunk_config: ChunkConfig::default(),
-            sync_dir: Vec::default(),
-            debounce_ms: 500,
-        }
-    }
-}
+/// How often to check whether the watcher dropped events and a rescan is due.
+const RESCAN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let app_config = {
-        let sync_dir = get_default_sync_path();
-        let config_dir = sync_dir.join(".config");
-        let config_path = config_dir.join("config.toml");
+    let config_path = resolve_config_path(config_arg(), std::env::var_os(CONFIG_PATH_ENV));
+    let mut context = ServiceContext::init(&config_path).await?;
+
+    // Large chunks are hashed on the global rayon pool; size it once at startup
+    rayon_core::ThreadPoolBuilder::new()
+        .num_threads(context.engine_config().num_threads)
+        .build_global()?;
 
-        // 1. Ensure the sync dir and its internal .config dir exist
-        if !config_dir.exists() {
-            fs::create_dir_all(&config_dir)?;
+    let mut watcher = start_watcher(context.config())?;
+    let mut jitter = index_jitter(context.config());
+    let mut coalescer = EventCoalescer::new(context.config().debounce);
+    let mut queue = IndexQueue::new();
+    let reload = reload_requests()?;
+    let db_check = crossbeam_channel::tick(DB_CHECK_INTERVAL);
+    let rescan_check = crossbeam_channel::tick(RESCAN_CHECK_INTERVAL);
 
-            // Windows: Hide the directory itself
-            #[cfg(windows)]
-            {
-                let mut cmd = std::process::Command::new("attrib");
-                cmd.arg("+h").arg(&config_dir);
-                let _ = cmd.status();
+    loop {
+        let due = coalescer
+            .next_due()
+            .map_or_else(crossbeam_channel::never, crossbeam_channel::at);
+        let start = queue
+            .next_start()
+            .map_or_else(crossbeam_channel::never, crossbeam_channel::at);
+        select! {
+            recv(watcher.events()) -> batch => {
+                let Ok(events) = batch? else {
+                    break;
+                };
+                //TODO Need to handle a special case where the sync directory is deleted while skie is running.
+                coalescer.push(process_batch(events, context.config()));
+            }
+            recv(due) -> _ => {
+                queue.push(jitter.schedule(coalescer.drain_due(Instant::now())));
             }
+            recv(start) -> _ => {
+                let events = queue.drain_due(Instant::now());
+                if let Err(e) = context.reactor().process_events(&events).await {
+                    log::error!("Processing {} events failed: {e}", events.len());
+                }
+            }
+            recv(rescan_check) -> _ => {
+                if watcher.take_rescan_flag() {
+                    log::warn!("Watcher events were dropped, rescanning the sync directories");
+                    if let Err(e) = context.reactor().rescan(context.config()).await {
+                        log::error!("Rescan failed: {e}");
+                    }
+                }
+            }
+            recv(db_check) -> _ => {
+                if let Err(e) = context.store().reopen_if_replaced().await {
+                    log::error!("Checking the database file failed: {e}");
+                }
+            }
+            recv(reload) -> _ => match context.reload_config() {
+                Ok(change) => {
+                    log::info!("Reloaded config from {}", config_path.display());
+                    jitter = index_jitter(context.config());
+                    coalescer.set_config(context.config().debounce);
+                    if change.watcher_changed {
+                        watcher = start_watcher(context.config())?;
+                    }
+                    if change.chunking_changed {
+                        log::warn!("Chunk sizes changed; re-index to keep deduplicating against existing files");
+                    }
+                }
+                Err(e) => log::error!("Config reload failed, keeping the current config: {e}"),
+            },
         }
+    }
 
-        if !config_path.exists() {
-            let config = ServiceConfig::default();
-            let config_string = toml::to_string(&config)?;
-            fs::write(&config_path, config_string)?;
-            config
-        } else {
-            let contents = fs::read(&config_path)?;
-            toml::from_slice::<ServiceConfig>(&contents)?
-        }
-    };
+    Ok(())
+}
 
-    let (event_sender, event_receiver) = unbounded();
+/// The value of a `--config <path>` or `--config=<path>` argument, if given.
+fn config_arg() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
 
-    let mut debounder = new_debouncer(
-        Duration::from_millis(app_config.debounce_ms),
-        None,
-        event_sender,
-    )
-    .unwrap();
+fn index_jitter(config: &ServiceConfig) -> IndexJitter {
+    IndexJitter::new(Duration::from_millis(config.index_jitter_ms))
+}
 
-    for dir in &app_config.sync_dir {
-        debounder.watch(&dir, RecursiveMode::Recursive)?;
+fn start_watcher(config: &ServiceConfig) -> Result<Watcher> {
+    let mut watcher = Watcher::new(config.debounce.watcher_timeout(), config.event_queue_cap)?;
+    for dir in &config.sync_dir {
+        watcher.watch_to_depth(dir, config.max_watch_depth)?;
     }
+    Ok(watcher)
+}
 
-    while let Ok(events) = event_receiver.recv()? {
-        //TODO Need to handle a special case where the sync directory is deleted while skie is running.
-        let events_iter = events
-            .into_iter()
-            .filter(|debounced_event| {
-                // 1. Only care about data-changing events
-                let is_valid_kind = matches!(
-                    debounced_event.event.kind,
-                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
-                );
-
-                // 2. Ignore anything inside the .config folder
-                let is_not_internal = !debounced_event
-                    .event
-                    .paths
-                    .iter()
-                    .any(|path| path.components().any(|c| c.as_os_str() == ".config"));
+/// Delivers a message for every SIGHUP, the conventional request to reload the config.
+#[cfg(unix)]
+fn reload_requests() -> Result<Receiver<()>> {
+    use tokio::signal::unix::{SignalKind, signal};
 
-                is_valid_kind && is_not_internal
-            })
-            .peekable();
-        let os_events: Vec<OsEvent> = events_iter.map(|event| event.into()).collect();
-    }
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if sender.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(receiver)
+}
 
-    Ok(())
+/// There is no SIGHUP outside Unix, so no reload is ever requested.
+#[cfg(not(unix))]
+fn reload_requests() -> Result<Receiver<()>> {
+    Ok(crossbeam_channel::never())
 }
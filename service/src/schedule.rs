@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Spreading re-index work out over time.
+//!
+//! A bulk operation such as `git checkout` touches thousands of files at once,
+//! and the debouncer then hands them all over in one batch. Starting every
+//! re-index immediately spikes the CPU, so each file is instead given its own
+//! start time, offset by a jitter within a configurable window, and waits in
+//! an [`IndexQueue`] until then.
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::{Duration, Instant},
+};
+
+use camino::Utf8Path;
+
+use crate::OsEvent;
+
+/// A single file's event together with when its re-index should start.
+pub struct ScheduledEvent {
+    pub start: Instant,
+    pub event: OsEvent,
+}
+
+/// Assigns jittered start times to events, one file at a time.
+pub struct IndexJitter {
+    window: Duration,
+    seed: RandomState,
+}
+
+impl IndexJitter {
+    /// Jitter start times by up to `window`. A zero window schedules every
+    /// event at the time it happened.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seed: RandomState::new(),
+        }
+    }
+
+    /// The delay for `path`, somewhere in `[0, window)`.
+    ///
+    /// Derived from a hash of the path with a per-scheduler random seed, so
+    /// repeated events for one file get the same slot instead of reordering.
+    pub fn delay_for(&self, path: &Utf8Path) -> Duration {
+        let window = self.window.as_nanos() as u64;
+        if window == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.seed.hash_one(path) % window)
+    }
+
+    /// Splits `events` into one event per path and orders them by start time.
+    pub fn schedule(&self, events: Vec<OsEvent>) -> Vec<ScheduledEvent> {
+        let mut scheduled: Vec<ScheduledEvent> = events
+            .into_iter()
+            .flat_map(|OsEvent { kind, paths, time }| {
+                paths.into_iter().map(move |path| ScheduledEvent {
+                    start: time + self.delay_for(&path),
+                    event: OsEvent {
+                        kind,
+                        paths: vec![path],
+                        time,
+                    },
+                })
+            })
+            .collect();
+        scheduled.sort_by_key(|scheduled| scheduled.start);
+        scheduled
+    }
+}
+
+/// Scheduled events waiting for their start time.
+#[derive(Default)]
+pub struct IndexQueue {
+    /// Ordered by start time.
+    pending: Vec<ScheduledEvent>,
+}
+
+impl IndexQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `scheduled` alongside the events already waiting.
+    pub fn push(&mut self, scheduled: Vec<ScheduledEvent>) {
+        self.pending.extend(scheduled);
+        self.pending.sort_by_key(|scheduled| scheduled.start);
+    }
+
+    /// When the next queued event may start, if any is queued.
+    pub fn next_start(&self) -> Option<Instant> {
+        self.pending.first().map(|scheduled| scheduled.start)
+    }
+
+    /// Removes and returns the events whose start time has come by `now`,
+    /// earliest first.
+    pub fn drain_due(&mut self, now: Instant) -> Vec<OsEvent> {
+        let due = self
+            .pending
+            .partition_point(|scheduled| scheduled.start <= now);
+        self.pending
+            .drain(..due)
+            .map(|scheduled| scheduled.event)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camino::Utf8PathBuf;
+    use notify_debouncer_full::notify::event::{CreateKind, EventKind};
+
+    fn created(count: usize, time: Instant) -> Vec<OsEvent> {
+        (0..count)
+            .map(|i| OsEvent {
+                kind: EventKind::Create(CreateKind::File),
+                paths: vec![Utf8PathBuf::from(format!("/sync/checkout/{i}.rs"))],
+                time,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_simultaneous_events_spread_across_window() {
+        let window = Duration::from_secs(1);
+        let now = Instant::now();
+        let scheduled = IndexJitter::new(window).schedule(created(200, now));
+        assert_eq!(scheduled.len(), 200);
+
+        // Every start falls in the window, and each tenth of it gets some
+        let mut buckets = [0usize; 10];
+        for item in &scheduled {
+            let delay = item.start - now;
+            assert!(delay < window);
+            buckets[(delay.as_millis() / 100) as usize] += 1;
+        }
+        assert!(buckets.iter().all(|&count| count > 0), "{buckets:?}");
+        assert!(scheduled.windows(2).all(|w| w[0].start <= w[1].start));
+    }
+
+    #[test]
+    fn test_zero_window_starts_immediately() {
+        let now = Instant::now();
+        let scheduled = IndexJitter::new(Duration::ZERO).schedule(created(5, now));
+        assert!(scheduled.iter().all(|item| item.start == now));
+    }
+
+    #[test]
+    fn test_queue_releases_events_at_their_start() {
+        let now = Instant::now();
+        let window = Duration::from_secs(1);
+        let mut queue = IndexQueue::new();
+        queue.push(IndexJitter::new(window).schedule(created(50, now)));
+        assert_eq!(queue.len(), 50);
+
+        let first = queue.next_start().unwrap();
+        assert!(first >= now && first < now + window);
+
+        let halfway = queue.drain_due(now + window / 2);
+        assert!(
+            queue
+                .next_start()
+                .is_none_or(|next| next > now + window / 2)
+        );
+        let rest = queue.drain_due(now + window);
+        assert_eq!(halfway.len() + rest.len(), 50);
+        assert!(queue.is_empty());
+        assert!(queue.next_start().is_none());
+    }
+}
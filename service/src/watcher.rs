@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Filesystem watcher with runtime pause/resume.
+//!
+//! While paused, debounced events are dropped rather than buffered. Anything
+//! that changed in the meantime is picked up by the next rescan, so buffering
+//! would only hold memory for events that are about to be superseded.
+//!
+//! The event queue is bounded. When it is full the batch is dropped and the
+//! watcher is flagged as needing a rescan, instead of blocking the notify
+//! thread (which would make the OS drop events anyway).
+//!
+//! `notify` watches a tree either fully or not at all, so a depth limit is
+//! implemented by registering a non-recursive watch on each directory down to
+//! that depth.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use crossbeam_channel::{Receiver, Sender, TrySendError, bounded};
+use notify_debouncer_full::{
+    DebounceEventHandler, DebounceEventResult, Debouncer, RecommendedCache, new_debouncer,
+    notify::{self, RecommendedWatcher, RecursiveMode},
+};
+
+use crate::ServiceError;
+
+/// Forwards debounced events to a channel unless the watcher is paused.
+pub struct EventForwarder {
+    paused: Arc<AtomicBool>,
+    needs_rescan: Arc<AtomicBool>,
+    sender: Sender<DebounceEventResult>,
+}
+
+impl EventForwarder {
+    pub fn new(
+        paused: Arc<AtomicBool>,
+        needs_rescan: Arc<AtomicBool>,
+        sender: Sender<DebounceEventResult>,
+    ) -> Self {
+        Self {
+            paused,
+            needs_rescan,
+            sender,
+        }
+    }
+}
+
+impl DebounceEventHandler for EventForwarder {
+    fn handle_event(&mut self, event: DebounceEventResult) {
+        if self.paused.load(Ordering::Acquire) {
+            return;
+        }
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(event) {
+            // The consumer is behind; a rescan will reconcile what was dropped
+            self.needs_rescan.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// A debounced watcher over the sync directories.
+pub struct Watcher {
+    debouncer: Debouncer<RecommendedWatcher, RecommendedCache>,
+    paused: Arc<AtomicBool>,
+    needs_rescan: Arc<AtomicBool>,
+    events: Receiver<DebounceEventResult>,
+}
+
+impl Watcher {
+    /// Create a watcher that coalesces events over `debounce` and queues at
+    /// most `queue_cap` event batches.
+    pub fn new(debounce: Duration, queue_cap: usize) -> Result<Self, ServiceError> {
+        let (sender, events) = bounded(queue_cap);
+        let paused = Arc::new(AtomicBool::new(false));
+        let needs_rescan = Arc::new(AtomicBool::new(false));
+        let forwarder = EventForwarder::new(paused.clone(), needs_rescan.clone(), sender);
+        let debouncer = new_debouncer(debounce, None, forwarder)?;
+
+        Ok(Self {
+            debouncer,
+            paused,
+            needs_rescan,
+            events,
+        })
+    }
+
+    /// Start watching `path` recursively.
+    pub fn watch(&mut self, path: &Path) -> Result<(), ServiceError> {
+        self.debouncer.watch(path, RecursiveMode::Recursive)?;
+        Ok(())
+    }
+
+    /// Start watching `path` and the directories up to `max_depth` levels
+    /// below it. `None` watches the whole tree, like [`Watcher::watch`].
+    pub fn watch_to_depth(
+        &mut self,
+        path: &Path,
+        max_depth: Option<usize>,
+    ) -> Result<(), ServiceError> {
+        let Some(max_depth) = max_depth else {
+            return self.watch(path);
+        };
+        let dirs = dirs_to_depth(path, max_depth).map_err(notify::Error::io)?;
+        for dir in dirs {
+            self.debouncer.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
+        Ok(())
+    }
+
+    /// Stop emitting events until [`Watcher::resume`] is called.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Resume emitting events after a [`Watcher::pause`].
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Returns whether events were dropped because the queue was full, and
+    /// clears the flag. A `true` result means the sync dirs must be rescanned.
+    pub fn take_rescan_flag(&self) -> bool {
+        self.needs_rescan.swap(false, Ordering::AcqRel)
+    }
+
+    /// The channel on which debounced event batches are delivered.
+    pub fn events(&self) -> &Receiver<DebounceEventResult> {
+        &self.events
+    }
+}
+
+/// `root` and every directory at most `max_depth` levels below it.
+///
+/// Symlinks are not followed, so a link back up the tree cannot loop.
+fn dirs_to_depth(root: &Path, max_depth: usize) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    let mut pending = vec![(root.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        if depth < max_depth {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    pending.push((entry.path(), depth + 1));
+                }
+            }
+        }
+        dirs.push(dir);
+    }
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify_debouncer_full::{
+        DebouncedEvent,
+        notify::{Event, EventKind, event::CreateKind},
+    };
+    use std::time::Instant;
+
+    fn create_event(path: &str) -> DebounceEventResult {
+        let event = Event::new(EventKind::Create(CreateKind::File)).add_path(path.into());
+        Ok(vec![DebouncedEvent::new(event, Instant::now())])
+    }
+
+    #[test]
+    fn test_pause_drops_and_resume_forwards() {
+        let (sender, receiver) = bounded(4);
+        let paused = Arc::new(AtomicBool::new(false));
+        let needs_rescan = Arc::new(AtomicBool::new(false));
+        let mut forwarder = EventForwarder::new(paused.clone(), needs_rescan, sender);
+
+        paused.store(true, Ordering::Release);
+        forwarder.handle_event(create_event("/sync/dropped.txt"));
+        assert!(
+            receiver.try_recv().is_err(),
+            "Paused events must be dropped"
+        );
+
+        paused.store(false, Ordering::Release);
+        forwarder.handle_event(create_event("/sync/kept.txt"));
+        let events = receiver.try_recv().unwrap().unwrap();
+        assert_eq!(events[0].event.paths[0].to_str(), Some("/sync/kept.txt"));
+    }
+
+    #[test]
+    fn test_dirs_to_depth_stops_at_limit() {
+        let root = tempfile::TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("a/b/c")).unwrap();
+        fs::create_dir_all(root.path().join("x")).unwrap();
+        fs::write(root.path().join("a/file.txt"), "not a dir").unwrap();
+
+        let mut dirs = dirs_to_depth(root.path(), 1).unwrap();
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            [
+                root.path().to_path_buf(),
+                root.path().join("a"),
+                root.path().join("x")
+            ]
+        );
+        assert_eq!(dirs_to_depth(root.path(), 0).unwrap(), [root.path()]);
+        assert_eq!(dirs_to_depth(root.path(), 3).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_events_past_depth_limit_are_not_delivered() -> Result<(), ServiceError> {
+        let root = tempfile::TempDir::new().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("a/deep")).unwrap();
+
+        let mut watcher = Watcher::new(Duration::from_millis(50), 16)?;
+        watcher.watch_to_depth(&root, Some(1))?;
+        fs::write(root.join("a/deep/ignored.txt"), "too deep").unwrap();
+        fs::write(root.join("a/seen.txt"), "in range").unwrap();
+
+        let mut paths = Vec::new();
+        while let Ok(batch) = watcher.events().recv_timeout(Duration::from_secs(2)) {
+            for event in batch.unwrap() {
+                paths.extend(event.event.paths.clone());
+            }
+            if paths.contains(&root.join("a/seen.txt")) {
+                break;
+            }
+        }
+        assert!(paths.contains(&root.join("a/seen.txt")));
+        assert!(
+            !paths
+                .iter()
+                .any(|path| path.starts_with(root.join("a/deep")))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_watcher_pause_resume_flag() -> Result<(), ServiceError> {
+        let watcher = Watcher::new(Duration::from_millis(50), 16)?;
+        assert!(!watcher.is_paused());
+        watcher.pause();
+        assert!(watcher.is_paused());
+        watcher.resume();
+        assert!(!watcher.is_paused());
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_queue_sets_rescan_flag() {
+        let (sender, receiver) = bounded(2);
+        let paused = Arc::new(AtomicBool::new(false));
+        let needs_rescan = Arc::new(AtomicBool::new(false));
+        let mut forwarder = EventForwarder::new(paused, needs_rescan.clone(), sender);
+
+        forwarder.handle_event(create_event("/sync/1.txt"));
+        forwarder.handle_event(create_event("/sync/2.txt"));
+        assert!(!needs_rescan.load(Ordering::Acquire));
+
+        // The third batch overflows the queue instead of blocking
+        forwarder.handle_event(create_event("/sync/3.txt"));
+        assert!(needs_rescan.load(Ordering::Acquire));
+        assert_eq!(receiver.len(), 2);
+    }
+}
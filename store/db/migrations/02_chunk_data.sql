@@ -0,0 +1,3 @@
+-- 4. Content: the raw bytes of each chunk, needed to reconstruct files.
+-- Rows created before this migration have no data and read back empty.
+ALTER TABLE chunks ADD COLUMN data BLOB NOT NULL DEFAULT x'';
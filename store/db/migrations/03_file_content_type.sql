@@ -0,0 +1,3 @@
+-- 5. Content type hint sniffed from the first bytes of the file (e.g. `image/png`).
+-- NULL when the type could not be detected.
+ALTER TABLE files ADD COLUMN content_type TEXT;
@@ -0,0 +1,4 @@
+-- 6. Integrity of the bytes as stored, independent of the content hash.
+-- `checksum_algo` names the algorithm; rows without one are not verified.
+ALTER TABLE chunks ADD COLUMN stored_checksum INTEGER;
+ALTER TABLE chunks ADD COLUMN checksum_algo TEXT;
@@ -0,0 +1,4 @@
+-- 7. Bookkeeping refreshed whenever a file's sections are rewritten.
+-- `last_indexed_at` is in milliseconds since the Unix epoch; NULL if never indexed.
+ALTER TABLE files ADD COLUMN chunk_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN last_indexed_at INTEGER;
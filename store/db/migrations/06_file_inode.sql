@@ -0,0 +1,5 @@
+-- 8. Device and inode number of a file's path, recorded on Unix only.
+-- Lets a scan recognize hard links to a file that is already indexed.
+ALTER TABLE files ADD COLUMN device INTEGER;
+ALTER TABLE files ADD COLUMN inode INTEGER;
+CREATE INDEX idx_files_inode ON files(device, inode);
@@ -0,0 +1,5 @@
+-- 9. Application key-value store for small values that do not warrant a table of their own.
+CREATE TABLE IF NOT EXISTS meta (
+    key   TEXT PRIMARY KEY,
+    value BLOB NOT NULL
+);
@@ -0,0 +1,8 @@
+-- 10. Paths whose indexing failed, with how often in a row. Cleared when the
+-- path is indexed successfully; rows that reach the retry limit are dead letters.
+CREATE TABLE IF NOT EXISTS failed_index (
+    path           TEXT PRIMARY KEY,
+    attempts       INTEGER NOT NULL,
+    last_error     TEXT NOT NULL,
+    last_failed_at INTEGER NOT NULL
+);
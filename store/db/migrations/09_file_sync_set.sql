@@ -0,0 +1,3 @@
+-- 11. Named sync set each file belongs to. Chunks stay shared across sets.
+ALTER TABLE files ADD COLUMN sync_set TEXT NOT NULL DEFAULT 'default';
+CREATE INDEX IF NOT EXISTS idx_files_sync_set ON files(sync_set);
@@ -0,0 +1,3 @@
+-- 12. Adler-32 of the whole file, computed while indexing. NULL for files
+-- whose content was not read when their sections were last written.
+ALTER TABLE files ADD COLUMN quick_checksum INTEGER;
@@ -1,15 +1,99 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use crate::{DataStore, Fetch, Persist, Result};
+use crate::{ChunkMetadata, DataStore, DataStoreError, Fetch, Length, Persist, Result};
 use async_trait::async_trait;
 use common::ChunkID;
+use std::collections::{HashMap, HashSet};
+use xxhash_rust::xxh3::xxh3_64;
 
-const INSERT_QUERY: &str = "INSERT OR IGNORE INTO chunks (hash, size) VALUES ($1, $2)";
+/// Inserts a chunk with its data. A row that already exists keeps its data,
+/// unless it holds none, as rows recorded by
+/// [`DataStore::index_to_sink`](crate::DataStore::index_to_sink) do; those are filled in.
+pub(crate) const INSERT_QUERY: &str = "INSERT INTO chunks (hash, size, data, stored_checksum, checksum_algo) VALUES ($1, $2, $3, $4, $5) \
+    ON CONFLICT(hash) DO UPDATE SET data = excluded.data, stored_checksum = excluded.stored_checksum, checksum_algo = excluded.checksum_algo \
+    WHERE length(chunks.data) <> chunks.size";
 
-#[derive(sqlx::FromRow)]
+/// Marker for the algorithm behind `stored_checksum`.
+pub(crate) const CHECKSUM_ALGO: &str = "xxh3-64";
+
+/// Fast checksum of the bytes exactly as they are written to the `data` column.
+///
+/// Unlike the chunk `hash`, which identifies the plaintext for deduplication,
+/// this covers the stored representation, so corruption is caught before the
+/// bytes are transformed back.
+pub(crate) fn stored_checksum(stored: &[u8]) -> i64 {
+    xxh3_64(stored) as i64
+}
+
+/// Checks stored chunk bytes against the checksum recorded when they were written.
+///
+/// Rows without a checksum, or with an algorithm this build does not know,
+/// are accepted as they are.
+pub(crate) fn verify_stored(
+    hash: &[u8],
+    stored: &[u8],
+    checksum: Option<i64>,
+    algo: Option<&str>,
+) -> Result<()> {
+    match (checksum, algo) {
+        (Some(checksum), Some(CHECKSUM_ALGO)) if checksum != stored_checksum(stored) => {
+            Err(DataStoreError::Corrupt {
+                hash: hash.to_vec(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
 pub struct ChunkTableEntry {
     pub hash: Vec<u8>,
     pub size: i64,
+    pub data: Vec<u8>,
+}
+
+impl ChunkTableEntry {
+    /// The row storing `data` as the chunk described by `meta`.
+    ///
+    /// `data` is expected to be `meta.length` bytes long. Fails with
+    /// [`DataStoreError::SectionBoundOutOfRange`] if the length does not fit
+    /// the `size` column.
+    pub fn from_metadata(meta: &ChunkMetadata, data: Vec<u8>) -> Result<Self> {
+        debug_assert_eq!(data.len() as u64, meta.length);
+        Ok(Self {
+            hash: meta.hash.clone(),
+            size: Length::new(meta.length)?.get() as i64,
+            data,
+        })
+    }
+}
+
+/// A chunk row together with its stored-bytes checksum.
+#[derive(sqlx::FromRow)]
+struct StoredChunkRow {
+    hash: Vec<u8>,
+    size: i64,
+    data: Vec<u8>,
+    stored_checksum: Option<i64>,
+    checksum_algo: Option<String>,
+}
+
+impl TryFrom<StoredChunkRow> for ChunkTableEntry {
+    type Error = DataStoreError;
+
+    fn try_from(row: StoredChunkRow) -> Result<Self> {
+        verify_stored(
+            &row.hash,
+            &row.data,
+            row.stored_checksum,
+            row.checksum_algo.as_deref(),
+        )?;
+        Ok(Self {
+            hash: row.hash,
+            size: row.size,
+            data: row.data,
+        })
+    }
 }
 
 impl PartialEq for ChunkTableEntry {
@@ -18,16 +102,75 @@ impl PartialEq for ChunkTableEntry {
     }
 }
 
+/// Hashes looked up per query by [`DataStore::have_mask`] and
+/// [`DataStore::stored_checksums`], well under SQLite's bind limit.
+const HAVE_MASK_BATCH: usize = 500;
+
+/// One bit per queried hash, in query order: set if the chunk is held.
+///
+/// Bits are packed least significant first, eight to a byte, so a mask over
+/// `n` hashes takes `n.div_ceil(8)` bytes on the wire.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HaveMask {
+    len: usize,
+    bits: Vec<u8>,
+}
+
+impl HaveMask {
+    fn with_len(len: usize) -> Self {
+        Self {
+            len,
+            bits: vec![0; len.div_ceil(8)],
+        }
+    }
+
+    /// Rebuilds a mask of `len` bits from [`HaveMask::as_bytes`].
+    pub fn from_bytes(len: usize, bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == len.div_ceil(8)).then(|| Self {
+            len,
+            bits: bytes.to_vec(),
+        })
+    }
+
+    /// Number of hashes the mask covers.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the `index`th queried hash is held. Out of range is `false`.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.bits[index / 8] |= 1 << (index % 8);
+    }
+
+    /// The packed bits, for sending to a peer.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
 #[async_trait]
 impl Persist<ChunkTableEntry> for DataStore {
     async fn store_all(&self, items: Vec<ChunkTableEntry>) -> Result<()> {
-        let mut tx = self.pool.begin().await?;
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
 
         for item in items {
             // "OR IGNORE" is the secret sauce for deduplication
+            let checksum = stored_checksum(&item.data);
             sqlx::query(INSERT_QUERY)
                 .bind(item.hash)
                 .bind(item.size)
+                .bind(item.data)
+                .bind(checksum)
+                .bind(CHECKSUM_ALGO)
                 .execute(&mut *tx)
                 .await?;
         }
@@ -37,10 +180,14 @@ impl Persist<ChunkTableEntry> for DataStore {
     }
 
     async fn store(&self, item: ChunkTableEntry) -> Result<()> {
+        let checksum = stored_checksum(&item.data);
         sqlx::query(INSERT_QUERY)
             .bind(item.hash)
             .bind(item.size)
-            .execute(&self.pool)
+            .bind(item.data)
+            .bind(checksum)
+            .bind(CHECKSUM_ALGO)
+            .execute(&self.pool())
             .await?;
         Ok(())
     }
@@ -49,8 +196,9 @@ impl Persist<ChunkTableEntry> for DataStore {
 #[async_trait]
 impl Fetch<ChunkID, ChunkTableEntry> for DataStore {
     async fn fetch_by(&self, key: &ChunkID) -> Result<ChunkTableEntry> {
-        let mut results = self.fetch_many(&[*key]).await?;
-        results.pop().ok_or(crate::DataStoreError::NotFound)
+        self.try_fetch_chunk(key)
+            .await?
+            .ok_or(crate::DataStoreError::NotFound)
     }
 
     async fn fetch_many(&self, keys: &[ChunkID]) -> Result<Vec<ChunkTableEntry>> {
@@ -65,19 +213,142 @@ impl Fetch<ChunkID, ChunkTableEntry> for DataStore {
             .join(",");
 
         let sql = format!(
-            "SELECT hash, size FROM chunks WHERE hash IN ({})",
+            "SELECT hash, size, data, stored_checksum, checksum_algo FROM chunks WHERE hash IN ({})",
             placeholders
         );
 
         // 2. Bind the hashes (as Vec<u8> since ChunkID likely wraps or converts to that)
-        let mut query = sqlx::query_as::<_, ChunkTableEntry>(&sql);
+        let mut query = sqlx::query_as::<_, StoredChunkRow>(&sql);
         for id in keys {
             // Assuming ChunkID can be converted to bytes for the BLOB column
             query = query.bind((*id).as_bytes().to_vec());
         }
 
-        let entries = query.fetch_all(&self.pool).await?;
-        Ok(entries)
+        let rows = query.fetch_all(&self.pool()).await?;
+        rows.into_iter().map(ChunkTableEntry::try_from).collect()
+    }
+}
+
+impl DataStore {
+    /// Fetches a chunk, returning `Ok(None)` if it is not stored.
+    pub async fn try_fetch_chunk(&self, id: &ChunkID) -> Result<Option<ChunkTableEntry>> {
+        let mut results = self.fetch_many(&[*id]).await?;
+        Ok(results.pop())
+    }
+
+    /// Which of `hashes` have their data stored, as a mask in the same order.
+    ///
+    /// Rows recorded for an external sink (size but no data) count as absent,
+    /// as in [`ChunkSink::has_chunk`](crate::ChunkSink::has_chunk). Duplicate
+    /// hashes get the same bit.
+    pub async fn have_mask(&self, hashes: &[Vec<u8>]) -> Result<HaveMask> {
+        let mut mask = HaveMask::with_len(hashes.len());
+
+        for (batch_index, batch) in hashes.chunks(HAVE_MASK_BATCH).enumerate() {
+            let placeholders = (1..=batch.len())
+                .map(|i| format!("${}", i))
+                .collect::<Vec<_>>()
+                .join(",");
+            let sql = format!(
+                "SELECT hash FROM chunks WHERE length(data) = size AND hash IN ({})",
+                placeholders
+            );
+
+            let mut query = sqlx::query_scalar::<_, Vec<u8>>(&sql);
+            for hash in batch {
+                query = query.bind(hash.as_slice());
+            }
+            let present: HashSet<Vec<u8>> =
+                query.fetch_all(&self.pool()).await?.into_iter().collect();
+
+            let offset = batch_index * HAVE_MASK_BATCH;
+            for (i, hash) in batch.iter().enumerate() {
+                if present.contains(hash) {
+                    mask.set(offset + i);
+                }
+            }
+        }
+        Ok(mask)
+    }
+
+    /// The xxh3 checksums of those of `hashes` whose data is stored, keyed by hash.
+    ///
+    /// [`chunk_source_reusing`](crate::chunk_source_reusing) and
+    /// [`chunk_source_warm`](crate::chunk_source_warm) compare these with the
+    /// bytes they read before reusing a prior hash. Rows without data, or with
+    /// a checksum from another algorithm, are left out.
+    pub async fn stored_checksums(&self, hashes: &[Vec<u8>]) -> Result<HashMap<Vec<u8>, i64>> {
+        let mut checksums = HashMap::new();
+
+        for batch in hashes.chunks(HAVE_MASK_BATCH) {
+            let placeholders = (2..=batch.len() + 1)
+                .map(|i| format!("${}", i))
+                .collect::<Vec<_>>()
+                .join(",");
+            let sql = format!(
+                "SELECT hash, stored_checksum FROM chunks WHERE length(data) = size AND checksum_algo = $1 AND stored_checksum IS NOT NULL AND hash IN ({})",
+                placeholders
+            );
+
+            let mut query = sqlx::query_as::<_, (Vec<u8>, i64)>(&sql).bind(CHECKSUM_ALGO);
+            for hash in batch {
+                query = query.bind(hash.as_slice());
+            }
+            checksums.extend(query.fetch_all(&self.pool()).await?);
+        }
+        Ok(checksums)
+    }
+
+    /// Number of distinct chunks physically stored.
+    pub async fn physical_chunk_count(&self) -> Result<i64> {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM chunks")
+            .fetch_one(&self.pool())
+            .await?;
+        Ok(count)
+    }
+
+    /// The `limit` largest chunks as `(hash, size)`, biggest first.
+    ///
+    /// Many chunks at exactly `max_chunk_size` suggest CDC is not finding cut points.
+    pub async fn largest_chunks(&self, limit: i64) -> Result<Vec<(Vec<u8>, i64)>> {
+        let chunks = sqlx::query_as("SELECT hash, size FROM chunks ORDER BY size DESC LIMIT $1")
+            .bind(limit)
+            .fetch_all(&self.pool())
+            .await?;
+        Ok(chunks)
+    }
+
+    /// The `limit` smallest chunks as `(hash, size)`, smallest first.
+    pub async fn smallest_chunks(&self, limit: i64) -> Result<Vec<(Vec<u8>, i64)>> {
+        let chunks = sqlx::query_as("SELECT hash, size FROM chunks ORDER BY size ASC LIMIT $1")
+            .bind(limit)
+            .fetch_all(&self.pool())
+            .await?;
+        Ok(chunks)
+    }
+
+    /// Histogram of stored chunk sizes as `(lower_bound, upper_bound, count)`.
+    ///
+    /// Buckets are powers of two, `[2^k, 2^(k+1))`, in ascending order; empty
+    /// buckets are left out. Files indexed under different `ChunkConfig`s show
+    /// up as separate peaks, which hurts deduplication across them.
+    pub async fn chunk_size_distribution(&self) -> Result<Vec<(u32, u32, i64)>> {
+        let sizes: Vec<(i64, i64)> =
+            sqlx::query_as("SELECT size, COUNT(*) FROM chunks GROUP BY size ORDER BY size")
+                .fetch_all(&self.pool())
+                .await?;
+
+        let mut buckets: Vec<(u32, u32, i64)> = Vec::new();
+        for (size, count) in sizes {
+            let size = size.clamp(1, u32::MAX as i64) as u32;
+            let lower = 1u32 << size.ilog2();
+            let upper = lower.saturating_mul(2);
+            match buckets.last_mut() {
+                Some(bucket) if bucket.0 == lower => bucket.2 += count,
+                _ => buckets.push((lower, upper, count)),
+            }
+        }
+        Ok(buckets)
     }
 }
 
@@ -85,7 +356,38 @@ impl Fetch<ChunkID, ChunkTableEntry> for DataStore {
 mod tests {
 
     use super::*;
-    use crate::setup;
+    use crate::{ChunkConfig, setup};
+    use common::FileID;
+    use rand::{RngCore, rng};
+    use std::{task::Poll, time::Duration};
+
+    #[tokio::test]
+    async fn test_store_all_cancelled_commits_nothing() -> Result<()> {
+        let store = setup().await;
+        let chunks = |count: u32| {
+            (0..count)
+                .map(|i| ChunkTableEntry {
+                    hash: i.to_be_bytes().to_vec(),
+                    size: 4,
+                    data: i.to_le_bytes().to_vec(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Each poll gets at most a few rows further, far from the end
+        let mut pending = store.store_all(chunks(10_000));
+        for _ in 0..20 {
+            let polled = std::future::poll_fn(|cx| Poll::Ready(pending.as_mut().poll(cx))).await;
+            assert!(polled.is_pending());
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        drop(pending);
+        assert_eq!(store.physical_chunk_count().await?, 0);
+
+        assert_eq!(store.store_all_checked(chunks(3)).await?, 3);
+        assert_eq!(store.physical_chunk_count().await?, 3);
+        Ok(())
+    }
 
     #[tokio::test]
     async fn test_chunk_deduplication_logic() {
@@ -95,11 +397,13 @@ mod tests {
         let chunk_1 = ChunkTableEntry {
             hash: raw_hash.clone(),
             size: 4096,
+            data: vec![0; 4096],
         };
 
         let chunk_2 = ChunkTableEntry {
             hash: raw_hash.clone(),
             size: 4096,
+            data: vec![0; 4096],
         };
         // Store the same chunk multiple times
         store.store(chunk_1).await.unwrap();
@@ -107,7 +411,7 @@ mod tests {
 
         // Verify: Only 1 row should exist in the 'chunks' table
         let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM chunks")
-            .fetch_one(&store.pool)
+            .fetch_one(&store.pool())
             .await
             .unwrap();
 
@@ -122,4 +426,152 @@ mod tests {
         let results = store.fetch_many(&ids).await.expect("Empty fetch failed");
         assert!(results.is_empty());
     }
+
+    #[tokio::test]
+    async fn test_chunks_ordered_by_size() {
+        let store = setup().await;
+        let chunks = [(0x01, 700), (0x02, 2048), (0x03, 512), (0x04, 1500)]
+            .into_iter()
+            .map(|(tag, size)| ChunkTableEntry {
+                hash: vec![tag],
+                size,
+                data: vec![tag; size as usize],
+            })
+            .collect::<Vec<_>>();
+        store.store_all(chunks).await.unwrap();
+
+        let largest = store.largest_chunks(2).await.unwrap();
+        assert_eq!(largest, vec![(vec![0x02], 2048), (vec![0x04], 1500)]);
+
+        let smallest = store.smallest_chunks(3).await.unwrap();
+        let sizes = smallest.iter().map(|(_, size)| *size).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![512, 700, 1500]);
+    }
+
+    #[tokio::test]
+    async fn test_try_fetch_chunk() {
+        let store = setup().await;
+        let data = b"some chunk".to_vec();
+        let hash = blake3::hash(&data);
+        store
+            .store(ChunkTableEntry {
+                hash: hash.as_bytes().to_vec(),
+                size: data.len() as i64,
+                data: data.clone(),
+            })
+            .await
+            .unwrap();
+
+        let present = store.try_fetch_chunk(&ChunkID::from(hash)).await.unwrap();
+        assert_eq!(present.map(|c| c.data), Some(data));
+
+        let absent = ChunkID::from(blake3::hash(b"never stored"));
+        assert!(store.try_fetch_chunk(&absent).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_stored_bytes_detected() {
+        let store = setup().await;
+        let data = vec![0x5A; 1024];
+        let hash = blake3::hash(&data);
+        store
+            .store(ChunkTableEntry {
+                hash: hash.as_bytes().to_vec(),
+                size: data.len() as i64,
+                data,
+            })
+            .await
+            .unwrap();
+
+        // Flip a byte of the stored blob behind the store's back
+        let mut stored = vec![0x5A; 1024];
+        stored[512] ^= 0xFF;
+        sqlx::query("UPDATE chunks SET data = $1 WHERE hash = $2")
+            .bind(stored)
+            .bind(hash.as_bytes().to_vec())
+            .execute(&store.pool())
+            .await
+            .unwrap();
+
+        let err = store
+            .try_fetch_chunk(&ChunkID::from(hash))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::Corrupt { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_chunk_size_distribution_shows_config_drift() {
+        let store = setup().await;
+        let large = ChunkConfig {
+            min_chunk_size: 4096,
+            avg_chunk_size: 8192,
+            max_chunk_size: 16384,
+            ..Default::default()
+        };
+        // Sized so both configs produce a comparable number of chunks
+        for (name, len, config) in [
+            ("small.bin", 64 * 1024, None),
+            ("large.bin", 1024 * 1024, Some(large)),
+        ] {
+            let mut data = vec![0u8; len];
+            rng().fill_bytes(&mut data);
+            store
+                .index_and_store(&FileID::new(), name, name, &data[..], config)
+                .await
+                .unwrap();
+        }
+
+        let buckets = store.chunk_size_distribution().await.unwrap();
+        let total: i64 = buckets.iter().map(|b| b.2).sum();
+        assert_eq!(total, store.physical_chunk_count().await.unwrap());
+        assert!(buckets.iter().all(|(lower, upper, _)| *upper == lower * 2));
+
+        // A peak below 2KB for the default config and one above 4KB for the large one
+        let peak_in = |range: std::ops::Range<u32>| {
+            buckets
+                .iter()
+                .filter(|b| range.contains(&b.0))
+                .map(|b| b.2)
+                .max()
+                .unwrap_or(0)
+        };
+        let small_peak = peak_in(0..2048);
+        let large_peak = peak_in(4096..u32::MAX);
+        let valley = peak_in(2048..4096);
+        assert!(valley < small_peak && valley < large_peak, "{buckets:?}");
+    }
+
+    #[tokio::test]
+    async fn test_have_mask_matches_input_order() {
+        let store = setup().await;
+        assert!(store.have_mask(&[]).await.unwrap().is_empty());
+
+        let held: Vec<ChunkTableEntry> = (0..3u8)
+            .map(|tag| ChunkTableEntry {
+                hash: vec![tag; 32],
+                size: 4,
+                data: vec![tag; 4],
+            })
+            .collect();
+        store.store_all(held).await.unwrap();
+
+        // Spans more than one lookup batch
+        let mut hashes: Vec<Vec<u8>> = (0..HAVE_MASK_BATCH + 10)
+            .map(|i| vec![0xAA, (i / 256) as u8, i as u8])
+            .collect();
+        hashes[1] = vec![0; 32];
+        hashes[7] = vec![1; 32];
+        hashes[HAVE_MASK_BATCH + 3] = vec![2; 32];
+
+        let mask = store.have_mask(&hashes).await.unwrap();
+        assert_eq!(mask.len(), hashes.len());
+        assert_eq!(mask.as_bytes().len(), hashes.len().div_ceil(8));
+        let set: Vec<usize> = (0..mask.len()).filter(|&i| mask.get(i)).collect();
+        assert_eq!(set, [1, 7, HAVE_MASK_BATCH + 3]);
+        assert!(!mask.get(hashes.len()));
+
+        let received = HaveMask::from_bytes(mask.len(), mask.as_bytes()).unwrap();
+        assert_eq!(received, mask);
+    }
 }
@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Chunking algorithms behind a common interface.
+//!
+//! Every chunking path in the store goes through [`Chunker`]. [`ChunkConfig`]
+//! implements it by dispatching on its [`ChunkerKind`], so switching
+//! algorithms is a config change rather than a code change.
+use std::io::{self, Read};
+
+use fastcdc::v2020::Normalization;
+use serde::{Deserialize, Serialize};
+
+use crate::{ChunkConfig, Result};
+
+/// A piece of a source cut by a [`Chunker`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// Position of the first byte within the source.
+    pub offset: u64,
+    /// The chunk's bytes; their length is the chunk's length.
+    pub data: Vec<u8>,
+}
+
+/// Splits a byte stream into consecutive chunks.
+pub trait Chunker {
+    /// Lazily cuts `source` into chunks that together cover it exactly, in order.
+    fn chunks<'a, R: Read + 'a>(&self, source: R) -> Box<dyn Iterator<Item = Result<Chunk>> + 'a>;
+}
+
+/// The chunking algorithm selected by a [`ChunkConfig`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkerKind {
+    /// FastCDC as published in 2020, with normalized chunking.
+    #[default]
+    FastCdc2020,
+    /// The original 2016 FastCDC.
+    FastCdc2016,
+    /// Fixed-size blocks of `avg_chunk_size` bytes. Cheap, but an insertion
+    /// shifts every later boundary, so it only dedups unshifted data.
+    FixedSize,
+}
+
+impl Chunker for ChunkConfig {
+    fn chunks<'a, R: Read + 'a>(&self, source: R) -> Box<dyn Iterator<Item = Result<Chunk>> + 'a> {
+        let ChunkConfig {
+            min_chunk_size,
+            avg_chunk_size,
+            max_chunk_size,
+            ..
+        } = *self;
+
+        match self.chunker {
+            ChunkerKind::FastCdc2020 => {
+                let level = match self.validate() {
+                    Ok(()) => normalization(self.normalization_level),
+                    Err(e) => return Box::new(std::iter::once(Err(e))),
+                };
+                Box::new(
+                    fastcdc::v2020::StreamCDC::with_level(
+                        source,
+                        min_chunk_size,
+                        avg_chunk_size,
+                        max_chunk_size,
+                        level,
+                    )
+                    .map(|chunk| {
+                        let chunk = chunk?;
+                        Ok(Chunk {
+                            offset: chunk.offset,
+                            data: chunk.data,
+                        })
+                    }),
+                )
+            }
+            ChunkerKind::FastCdc2016 => Box::new(
+                fastcdc::v2016::StreamCDC::new(
+                    source,
+                    min_chunk_size,
+                    avg_chunk_size,
+                    max_chunk_size,
+                )
+                .map(|chunk| {
+                    let chunk = chunk.map_err(io::Error::from)?;
+                    Ok(Chunk {
+                        offset: chunk.offset,
+                        data: chunk.data,
+                    })
+                }),
+            ),
+            ChunkerKind::FixedSize => Box::new(FixedSizeChunks {
+                source,
+                size: avg_chunk_size.max(1) as usize,
+                offset: 0,
+                done: false,
+            }),
+        }
+    }
+}
+
+/// Maps an already validated level onto FastCDC's enum.
+fn normalization(level: u8) -> Normalization {
+    match level {
+        0 => Normalization::Level0,
+        1 => Normalization::Level1,
+        2 => Normalization::Level2,
+        _ => Normalization::Level3,
+    }
+}
+
+/// Iterator behind [`ChunkerKind::FixedSize`].
+struct FixedSizeChunks<R> {
+    source: R,
+    size: usize,
+    offset: u64,
+    done: bool,
+}
+
+impl<R: Read> Iterator for FixedSizeChunks<R> {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(self.size);
+        match (&mut self.source)
+            .take(self.size as u64)
+            .read_to_end(&mut data)
+        {
+            Ok(_) if data.is_empty() => {
+                self.done = true;
+                None
+            }
+            Ok(_) => {
+                let chunk = Chunk {
+                    offset: self.offset,
+                    data,
+                };
+                self.offset += chunk.data.len() as u64;
+                Some(Ok(chunk))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
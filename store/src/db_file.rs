@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Noticing when the database file is replaced underneath an open store.
+//!
+//! Backup and restore tools often swap the database by renaming a new file
+//! over the old path. Connections already open keep using the old, now
+//! unlinked file, so the store would silently work on stale data. A store
+//! opened through [`DataStore::with_options`] remembers which file it opened
+//! and can compare that against what the path holds now.
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::PoisonError,
+};
+
+use tokio::sync::Mutex;
+
+use crate::{DataStore, DataStoreError, Result, SqlitePragmas, scan::inode_key, wal};
+
+/// The database file a store was opened on.
+pub(crate) struct DbFile {
+    url: String,
+    pragmas: SqlitePragmas,
+    path: PathBuf,
+    /// Held for a whole reopen, so concurrent checks reopen only once.
+    identity: Mutex<Option<(i64, i64)>>,
+}
+
+impl DbFile {
+    /// Records the file behind `url`, or `None` for in-memory databases.
+    pub(crate) fn open(url: &str, pragmas: SqlitePragmas) -> Result<Option<Self>> {
+        let Some(path) = db_path(url) else {
+            return Ok(None);
+        };
+        let identity = inode_key(&fs::metadata(&path)?);
+        Ok(Some(Self {
+            url: url.to_string(),
+            pragmas,
+            path,
+            identity: Mutex::new(identity),
+        }))
+    }
+}
+
+/// The file path in a `sqlite:` URL, unless the database lives in memory.
+fn db_path(url: &str) -> Option<PathBuf> {
+    let rest = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))?;
+    let (path, params) = rest.split_once('?').unwrap_or((rest, ""));
+    let in_memory = path.is_empty()
+        || path == ":memory:"
+        || params.split('&').any(|param| param == "mode=memory");
+    (!in_memory).then(|| PathBuf::from(path))
+}
+
+/// The write-ahead log SQLite keeps beside the database at `path`.
+fn wal_path(path: &Path) -> PathBuf {
+    let mut wal = OsString::from(path);
+    wal.push("-wal");
+    PathBuf::from(wal)
+}
+
+impl DataStore {
+    /// Reopens the database if the file at its path is no longer the one the
+    /// store opened, e.g. because a restore renamed another file over it.
+    ///
+    /// Meant for a periodic health check. Returns whether the store reopened;
+    /// stores built from a pool with [`DataStore::new`], in-memory databases
+    /// and platforms without inode numbers never do. Fails if the path no
+    /// longer holds any file.
+    ///
+    /// The old connections still have the old database's `-wal` file open
+    /// under the path the replacement is reached by, and a new connection
+    /// would replay its frames into the replacement. So the log is first
+    /// checkpointed into the old file and every old connection closed; queries
+    /// issued meanwhile fail with a closed pool. If the log cannot be emptied,
+    /// because a reader holds it or a write slipped in before the close, this
+    /// fails with [`DataStoreError::ReopenBlocked`]. In the first case the old
+    /// database stays usable and the next check tries again; in the second the
+    /// store stays closed.
+    ///
+    /// A running WAL maintenance task moves on to the new database.
+    pub async fn reopen_if_replaced(&self) -> Result<bool> {
+        let Some(db_file) = &self.db_file else {
+            return Ok(false);
+        };
+        let mut identity = db_file.identity.lock().await;
+        let current = inode_key(&fs::metadata(&db_file.path)?);
+        if current.is_none() || current == *identity {
+            return Ok(false);
+        }
+
+        log::warn!(
+            "Database file {} was replaced, reopening",
+            db_file.path.display()
+        );
+        let blocked = || DataStoreError::ReopenBlocked {
+            path: db_file.path.clone(),
+        };
+        let old = self.pool();
+        if !wal::truncate_wal(&old).await? {
+            return Err(blocked());
+        }
+        old.close().await;
+        if fs::metadata(wal_path(&db_file.path)).is_ok_and(|wal| wal.len() > 0) {
+            return Err(blocked());
+        }
+
+        let pool = DataStore::connect(&db_file.url, db_file.pragmas).await?;
+        sqlx::migrate!("db/migrations").run(&pool).await?;
+        *self.pool.write().unwrap_or_else(PoisonError::into_inner) = pool;
+
+        *identity = current;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileTableEntry, JournalMode, Persist};
+    use common::FileID;
+    use std::{sync::Arc, time::Duration};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_db_path_from_url() {
+        assert_eq!(
+            db_path("sqlite:///tmp/index.db?mode=rwc"),
+            Some(PathBuf::from("/tmp/index.db"))
+        );
+        assert_eq!(db_path("sqlite:index.db"), Some(PathBuf::from("index.db")));
+        assert_eq!(db_path("sqlite::memory:"), None);
+        assert_eq!(db_path("sqlite://shared?mode=memory&cache=shared"), None);
+        assert_eq!(db_path("postgres://localhost/skie"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_replaced_db_file_is_reopened() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("index.db");
+        let url = |path: &Path| format!("sqlite://{}?mode=rwc", path.display());
+        // WAL, as the service runs it, with a short wait on blocked checkpoints
+        let pragmas = SqlitePragmas {
+            busy_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        assert_eq!(pragmas.journal_mode, JournalMode::Wal);
+        let entry = |file_id: &FileID| FileTableEntry {
+            file_id: file_id.to_string(),
+            name: "f".to_string(),
+            path: format!("/{file_id}"),
+            hash: vec![0; 32],
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
+        };
+
+        let store = Arc::new(DataStore::with_options(&url(&path), pragmas).await?);
+        let original = FileID::new();
+        store.store(entry(&original)).await?;
+        assert!(!store.reopen_if_replaced().await?);
+        // The write is still only in the old log
+        assert!(fs::metadata(wal_path(&path))?.len() > 0);
+
+        // A restore writes the replacement elsewhere and renames it into place
+        let replacement_path = dir.path().join("restored.db");
+        let replacement = DataStore::with_options(&url(&replacement_path), pragmas).await?;
+        let restored = FileID::new();
+        replacement.store(entry(&restored)).await?;
+        replacement.pool().close().await;
+        fs::rename(&replacement_path, &path)?;
+
+        // A reader keeps the old log from being emptied, so nothing reopens
+        let mut reader = store.pool().begin().await?;
+        sqlx::query("SELECT COUNT(*) FROM files")
+            .execute(&mut *reader)
+            .await?;
+        assert!(matches!(
+            store.reopen_if_replaced().await,
+            Err(DataStoreError::ReopenBlocked { .. })
+        ));
+        assert!(store.try_fetch_file(&original).await?.is_some());
+        reader.rollback().await?;
+
+        assert!(store.reopen_if_replaced().await?);
+        assert!(!wal_path(&path).exists() || fs::metadata(wal_path(&path))?.len() == 0);
+        assert!(store.try_fetch_file(&original).await?.is_none());
+        assert!(store.try_fetch_file(&restored).await?.is_some());
+        assert!(!store.reopen_if_replaced().await?);
+        Ok(())
+    }
+}
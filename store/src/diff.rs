@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Updating a tracked file in place from its new content.
+//!
+//! Re-indexing with [`DataStore::index_and_store`] rewrites every section of
+//! the file. [`DataStore::apply_diff`] instead compares the new chunk list
+//! with the stored sections and only touches what changed, which keeps the
+//! write volume of a small edit to a large file small.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+
+use common::{ChunkIndex, FileID};
+
+use crate::{
+    ChunkConfig, ChunkMetadata, DataStore, Fetch, FileTableEntry, QuickChecksum, Result,
+    StoreEvent, chunk_iter, chunk_store, file_section, file_store,
+};
+
+/// The changes [`DataStore::apply_diff`] made to a file's sections.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ManifestDiff {
+    /// New or changed sections, keyed by their chunk index in the new content.
+    pub changed: BTreeMap<ChunkIndex, ChunkMetadata>,
+    /// Offsets of old sections that no chunk starts at anymore, including
+    /// those past the new end of the file.
+    pub removed: Vec<u64>,
+    /// Number of sections left as they were.
+    pub unchanged: usize,
+    /// Number of chunks that were not in the store before.
+    pub new_chunks: u64,
+}
+
+impl ManifestDiff {
+    /// Whether the new content matched the stored sections exactly.
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl DataStore {
+    /// Brings the stored sections of `file_id` in line with the content at
+    /// `path`, writing only what differs.
+    ///
+    /// Sections are matched by offset: one whose chunk hash and length are
+    /// unchanged is kept, any other is upserted, and old sections no new chunk
+    /// starts at are deleted. The file's hash and index stats are refreshed.
+    /// The file is read and hashed before the write transaction opens, so
+    /// other writers are not held up by the read; only the changed chunks are
+    /// kept in memory until then. All of it runs under the file's ingestion
+    /// lock. The section limit and free-space guard apply as for
+    /// [`DataStore::index_and_store`]. Fails with
+    /// [`DataStoreError::NotFound`](crate::DataStoreError::NotFound) if the
+    /// file is not tracked.
+    pub async fn apply_diff(
+        &self,
+        file_id: &FileID,
+        path: &Path,
+        chunk_config: Option<ChunkConfig>,
+    ) -> Result<ManifestDiff> {
+        self.with_file_lock(file_id, async {
+            let file: FileTableEntry = self.fetch_by(file_id).await?;
+            let source = BufReader::new(File::open(path)?);
+
+            let mut old: HashMap<i64, (Vec<u8>, i64)> = sqlx::query_as::<_, (i64, Vec<u8>, i64)>(
+                "SELECT offset, chunk_hash, length FROM file_sections WHERE file_id = $1",
+            )
+            .bind(&file.file_id)
+            .fetch_all(&self.pool())
+            .await?
+            .into_iter()
+            .map(|(offset, hash, length)| (offset, (hash, length)))
+            .collect();
+
+            let mut diff = ManifestDiff::default();
+            let mut hasher = blake3::Hasher::new();
+            let mut checksum = QuickChecksum::new();
+            let mut chunk_count = 0;
+            let mut writes = Vec::new();
+            for (index, chunk) in chunk_iter(file_id, source, 0, chunk_config).enumerate() {
+                let (chunk, section) = chunk?;
+                hasher.update(&chunk.data);
+                checksum.update(&chunk.data);
+                chunk_count += 1;
+                self.check_section_count(chunk_count)?;
+
+                if let Some((hash, length)) = old.remove(&section.offset)
+                    && hash == section.chunk_hash
+                    && length == section.length
+                {
+                    diff.unchanged += 1;
+                    continue;
+                }
+
+                diff.changed.insert(
+                    index,
+                    ChunkMetadata {
+                        hash: section.chunk_hash.clone(),
+                        offset: section.offset as u64,
+                        length: section.length as u64,
+                    },
+                );
+                writes.push((chunk, section));
+            }
+            self.ensure_free_space(writes.iter().map(|(chunk, _)| chunk.size as u64).sum())?;
+
+            let _slot = self.transaction_slot().await;
+            let mut tx = self.pool().begin().await?;
+            for (chunk, section) in writes {
+                let checksum = chunk_store::stored_checksum(&chunk.data);
+                diff.new_chunks += sqlx::query(chunk_store::INSERT_QUERY)
+                    .bind(chunk.hash)
+                    .bind(chunk.size)
+                    .bind(chunk.data)
+                    .bind(checksum)
+                    .bind(chunk_store::CHECKSUM_ALGO)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+                sqlx::query(file_section::UPSERT_QUERY)
+                    .bind(section.file_id)
+                    .bind(section.chunk_hash)
+                    .bind(section.length)
+                    .bind(section.offset)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+
+            for offset in old.into_keys() {
+                sqlx::query("DELETE FROM file_sections WHERE file_id = $1 AND offset = $2")
+                    .bind(&file.file_id)
+                    .bind(offset)
+                    .execute(&mut *tx)
+                    .await?;
+                diff.removed.push(offset as u64);
+            }
+            diff.removed.sort_unstable();
+
+            sqlx::query("UPDATE files SET hash = $1 WHERE file_id = $2")
+                .bind(hasher.finalize().as_bytes().to_vec())
+                .bind(&file.file_id)
+                .execute(&mut *tx)
+                .await?;
+            file_store::record_indexed(
+                &mut tx,
+                &file.file_id,
+                chunk_count,
+                Some(checksum.finish()),
+            )
+            .await?;
+            tx.commit().await?;
+            self.emit(StoreEvent::FileStored(*file_id));
+            Ok(diff)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use rand::{RngCore, rng};
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn test_apply_diff_rewrites_only_the_edited_region() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let mut data = vec![0u8; 64 * 1024];
+        rng().fill_bytes(&mut data);
+        store
+            .index_and_store(&file_id, "d.bin", "/d.bin", &data[..], None)
+            .await?;
+        let sections_before = store.logical_section_count().await?;
+        let chunks_before = store.physical_chunk_count().await?;
+
+        let mut edited = data.clone();
+        rng().fill_bytes(&mut edited[30_000..30_100]);
+        let file = NamedTempFile::new()?;
+        std::fs::write(file.path(), &edited)?;
+
+        let diff = store.apply_diff(&file_id, file.path(), None).await?;
+        assert!(!diff.is_empty());
+        assert!(
+            diff.changed.len() < 8,
+            "{} sections changed",
+            diff.changed.len()
+        );
+        assert!(diff.unchanged as i64 > sections_before - 8);
+        assert_eq!(
+            store.physical_chunk_count().await?,
+            chunks_before + diff.new_chunks as i64
+        );
+        assert_eq!(
+            store.read_range(&file_id, 0, edited.len() as u64).await?,
+            edited
+        );
+        let entry: FileTableEntry = store.fetch_by(&file_id).await?;
+        assert_eq!(entry.hash, blake3::hash(&edited).as_bytes().to_vec());
+
+        // Applying the same content again changes nothing
+        assert!(
+            store
+                .apply_diff(&file_id, file.path(), None)
+                .await?
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_diff_drops_sections_past_new_end() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let mut data = vec![0u8; 32 * 1024];
+        rng().fill_bytes(&mut data);
+        store
+            .index_and_store(&file_id, "t.bin", "/t.bin", &data[..], None)
+            .await?;
+
+        let truncated = &data[..10_000];
+        let file = NamedTempFile::new()?;
+        std::fs::write(file.path(), truncated)?;
+        let diff = store.apply_diff(&file_id, file.path(), None).await?;
+        assert!(diff.removed.iter().any(|&offset| offset >= 10_000));
+        assert_eq!(store.file_size(&file_id).await?, 10_000);
+        assert_eq!(store.read_range(&file_id, 0, 10_000).await?, truncated);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_diff_respects_section_limit() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let mut data = vec![0u8; 16 * 1024];
+        rng().fill_bytes(&mut data);
+        store
+            .index_and_store(&file_id, "l.bin", "/l.bin", &data[..], None)
+            .await?;
+        let sections = store.logical_section_count().await?;
+        let entry: FileTableEntry = store.fetch_by(&file_id).await?;
+
+        let store = store.with_max_sections_per_file(Some(4));
+        let file = NamedTempFile::new()?;
+        std::fs::write(file.path(), &data[..12 * 1024])?;
+        let err = store
+            .apply_diff(&file_id, file.path(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            crate::DataStoreError::TooManySections { count: 5, limit: 4 }
+        ));
+
+        // Nothing was written
+        assert_eq!(store.logical_section_count().await?, sections);
+        let after: FileTableEntry = store.fetch_by(&file_id).await?;
+        assert_eq!(after.hash, entry.hash);
+        Ok(())
+    }
+}
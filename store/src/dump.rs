@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Deterministic dumps of the whole store, for debugging and golden tests.
+//!
+//! Each table is read in keyset pages of [`DUMP_PAGE_SIZE`] rows inside one
+//! read transaction, so the dump is a consistent snapshot and no single query
+//! materializes the whole table. Callers that cannot hold the full result in
+//! memory can walk [`DataStore::sections_page`] themselves.
+use sqlx::AnyConnection;
+
+use crate::{ChunkTableEntry, DataStore, FileSectionEntry, FileTableEntry, Result};
+
+/// Rows fetched per query while dumping.
+pub const DUMP_PAGE_SIZE: i64 = 1000;
+
+impl DataStore {
+    /// Every section in the store, ordered by `(file_id, offset)`.
+    pub async fn dump_sections(&self) -> Result<Vec<FileSectionEntry>> {
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+        let mut sections: Vec<FileSectionEntry> = Vec::new();
+        loop {
+            let page = sections_after(&mut tx, sections.last(), DUMP_PAGE_SIZE).await?;
+            let done = (page.len() as i64) < DUMP_PAGE_SIZE;
+            sections.extend(page);
+            if done {
+                break;
+            }
+        }
+        tx.commit().await?;
+        Ok(sections)
+    }
+
+    /// At most `limit` sections following `after` in `(file_id, offset)` order,
+    /// starting from the first section when `after` is `None`.
+    pub async fn sections_page(
+        &self,
+        after: Option<&FileSectionEntry>,
+        limit: i64,
+    ) -> Result<Vec<FileSectionEntry>> {
+        let mut conn = self.pool().acquire().await?;
+        sections_after(&mut conn, after, limit).await
+    }
+
+    /// Every file row, ordered by `file_id`.
+    pub async fn dump_files(&self) -> Result<Vec<FileTableEntry>> {
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+        let mut files: Vec<FileTableEntry> = Vec::new();
+        loop {
+            let after = files.last().map(|f| f.file_id.clone()).unwrap_or_default();
+            let page = sqlx::query_as::<_, FileTableEntry>(
+                "SELECT * FROM files WHERE file_id > $1 ORDER BY file_id LIMIT $2",
+            )
+            .bind(after)
+            .bind(DUMP_PAGE_SIZE)
+            .fetch_all(&mut *tx)
+            .await?;
+            let done = (page.len() as i64) < DUMP_PAGE_SIZE;
+            files.extend(page);
+            if done {
+                break;
+            }
+        }
+        tx.commit().await?;
+        Ok(files)
+    }
+
+    /// Every chunk row, ordered by `hash`.
+    ///
+    /// Chunks whose data lives in an external [`ChunkSink`](crate::ChunkSink)
+    /// are dumped with empty `data`.
+    pub async fn dump_chunks(&self) -> Result<Vec<ChunkTableEntry>> {
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+        let mut chunks: Vec<ChunkTableEntry> = Vec::new();
+        loop {
+            let after = chunks.last().map(|c| c.hash.clone()).unwrap_or_default();
+            let page = sqlx::query_as::<_, ChunkTableEntry>(
+                r#"
+                SELECT hash, size, COALESCE(data, x'') AS data FROM chunks
+                WHERE hash > $1
+                ORDER BY hash
+                LIMIT $2
+                "#,
+            )
+            .bind(after)
+            .bind(DUMP_PAGE_SIZE)
+            .fetch_all(&mut *tx)
+            .await?;
+            let done = (page.len() as i64) < DUMP_PAGE_SIZE;
+            chunks.extend(page);
+            if done {
+                break;
+            }
+        }
+        tx.commit().await?;
+        Ok(chunks)
+    }
+}
+
+async fn sections_after(
+    conn: &mut AnyConnection,
+    after: Option<&FileSectionEntry>,
+    limit: i64,
+) -> Result<Vec<FileSectionEntry>> {
+    let page = sqlx::query_as::<_, FileSectionEntry>(
+        r#"
+        SELECT file_id, chunk_hash, length, offset FROM file_sections
+        WHERE $1 IS NULL OR file_id > $1 OR (file_id = $1 AND offset > $2)
+        ORDER BY file_id, offset
+        LIMIT $3
+        "#,
+    )
+    .bind(after.map(|s| s.file_id.clone()))
+    .bind(after.map_or(0, |s| s.offset))
+    .bind(limit)
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Persist, setup};
+    use common::FileID;
+
+    #[tokio::test]
+    async fn test_dump_is_ordered_snapshot() -> Result<()> {
+        let store = setup().await;
+        let mut ids = [FileID::new(), FileID::new()];
+        ids.sort_by_key(|id| id.to_string());
+        let chunk = |byte: u8| ChunkTableEntry {
+            hash: vec![byte; 32],
+            size: 2,
+            data: vec![byte; 2],
+        };
+        let section = |file_id: &FileID, byte: u8, offset| FileSectionEntry {
+            file_id: file_id.to_string(),
+            chunk_hash: vec![byte; 32],
+            length: 2,
+            offset,
+        };
+
+        store.store_all(vec![chunk(3), chunk(1), chunk(2)]).await?;
+        for (i, file_id) in ids.iter().enumerate().rev() {
+            store
+                .store(FileTableEntry {
+                    file_id: file_id.to_string(),
+                    name: format!("{i}.bin"),
+                    path: format!("/{i}.bin"),
+                    hash: vec![i as u8; 32],
+                    content_type: None,
+                    chunk_count: 0,
+                    last_indexed_at: None,
+                })
+                .await?;
+        }
+        // Stored out of order on purpose
+        store
+            .store_all(vec![
+                section(&ids[1], 1, 2),
+                section(&ids[0], 3, 2),
+                section(&ids[1], 2, 0),
+                section(&ids[0], 1, 0),
+            ])
+            .await?;
+
+        assert_eq!(
+            store.dump_sections().await?,
+            vec![
+                section(&ids[0], 1, 0),
+                section(&ids[0], 3, 2),
+                section(&ids[1], 2, 0),
+                section(&ids[1], 1, 2),
+            ]
+        );
+        let chunks = store.dump_chunks().await?;
+        assert_eq!(chunks, vec![chunk(1), chunk(2), chunk(3)]);
+        assert!(chunks.iter().all(|c| c.data == c.hash[..2]));
+        let files = store.dump_files().await?;
+        assert_eq!(
+            files.iter().map(|f| f.file_id.clone()).collect::<Vec<_>>(),
+            ids.map(|id| id.to_string())
+        );
+
+        let first = store.sections_page(None, 3).await?;
+        let rest = store.sections_page(first.last(), 3).await?;
+        assert_eq!([first, rest].concat(), store.dump_sections().await?);
+        Ok(())
+    }
+}
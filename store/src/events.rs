@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Change notifications for consumers that would otherwise poll the store.
+//!
+//! Events are sent on a broadcast channel after the transaction that made the
+//! change has committed, so a subscriber never hears about a write that was
+//! rolled back. Subscribers that fall more than [`STORE_EVENT_CAPACITY`]
+//! events behind miss the oldest ones and get `RecvError::Lagged` instead,
+//! after which a full reload is in order.
+use common::FileID;
+use tokio::sync::broadcast;
+
+use crate::DataStore;
+
+/// Events buffered per subscriber before the oldest are dropped.
+pub const STORE_EVENT_CAPACITY: usize = 256;
+
+/// A committed change to a tracked file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// The file row was written, with or without new sections.
+    FileStored(FileID),
+    /// The file and its sections were deleted.
+    FileDeleted(FileID),
+    /// Sections of the file were written without touching its row.
+    SectionsChanged(FileID),
+}
+
+impl DataStore {
+    /// Receives every [`StoreEvent`] committed from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<StoreEvent> {
+        self.events.subscribe()
+    }
+
+    /// Announces a committed change. Call only after the commit.
+    pub(crate) fn emit(&self, event: StoreEvent) {
+        // Without subscribers there is nobody to tell
+        let _ = self.events.send(event);
+    }
+
+    /// Like [`DataStore::emit`], for a file id as stored in a row.
+    ///
+    /// Rows written with ids that are not valid [`FileID`]s cannot be named
+    /// in an event and are skipped.
+    pub(crate) fn emit_for(&self, file_id: &str, event: fn(FileID) -> StoreEvent) {
+        if let Ok(file_id) = file_id.parse() {
+            self.emit(event(file_id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EvictionPolicy, FileTableEntry, Persist, Result, setup};
+    use rand::{RngCore, rng};
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn entry(file_id: &FileID) -> FileTableEntry {
+        FileTableEntry {
+            file_id: file_id.to_string(),
+            name: "f".to_string(),
+            path: format!("/{file_id}"),
+            hash: vec![0; 32],
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_follow_commits() -> Result<()> {
+        let store = setup().await;
+        let mut events = store.subscribe();
+
+        let stored = FileID::new();
+        store.store(entry(&stored)).await?;
+        assert_eq!(events.recv().await.unwrap(), StoreEvent::FileStored(stored));
+
+        let indexed = FileID::new();
+        store
+            .index_and_store(&indexed, "i.bin", "/i.bin", &[1u8; 4096][..], None)
+            .await?;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            StoreEvent::FileStored(indexed)
+        );
+
+        // Nothing is announced for a transaction that is rolled back
+        let mut tx = store.transaction().await?;
+        tx.store_file(entry(&FileID::new())).await?;
+        tx.rollback().await?;
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        let mut tx = store.transaction().await?;
+        tx.delete_file(&stored).await?;
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+        tx.commit().await?;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            StoreEvent::FileDeleted(stored)
+        );
+
+        store.purge_file(&indexed).await?;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            StoreEvent::FileDeleted(indexed)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_set_moves_and_evictions_are_announced() -> Result<()> {
+        let store = setup().await;
+        let kept = FileID::new();
+        let evicted = FileID::new();
+        // Random, so neither file dedups against itself or the other
+        for (file_id, path) in [(&evicted, "/e.bin"), (&kept, "/k.bin")] {
+            let mut data = vec![0u8; 4096];
+            rng().fill_bytes(&mut data);
+            store
+                .index_and_store(file_id, &path[1..], path, &data[..], None)
+                .await?;
+        }
+        let mut events = store.subscribe();
+
+        store.set_sync_set(&kept, "archive").await?;
+        assert_eq!(events.recv().await.unwrap(), StoreEvent::FileStored(kept));
+
+        // The oldest file has to go to get under a budget of one file's data
+        let report = store
+            .enforce_size_cap(4096, EvictionPolicy::LeastRecentlyIndexedFiles)
+            .await?;
+        assert_eq!(report.evicted_files, vec![evicted]);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            StoreEvent::FileDeleted(evicted)
+        );
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+        Ok(())
+    }
+}
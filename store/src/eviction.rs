@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Keeping the stored chunk data under a size budget.
+//!
+//! The budget covers the chunk bytes held in the `chunks` table; chunks kept
+//! in an external [`ChunkSink`](crate::ChunkSink) take no space here. A chunk
+//! that any section still references is never evicted, so every tracked file
+//! stays reconstructible.
+//!
+//! Ingestion commits chunks ahead of the sections that reference them (see
+//! [`WriteBatching`](crate::WriteBatching)), so those chunks look unreferenced
+//! for a moment. Enforce the cap while no ingestion is running.
+use common::FileID;
+
+use crate::{DataStore, Result, StoreEvent};
+
+/// What [`DataStore::enforce_size_cap`] may remove to get under budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Only evict chunks no file references.
+    #[default]
+    OrphansOnly,
+    /// If that is not enough, also drop whole files, least recently indexed
+    /// first, and evict the chunks they leave unreferenced.
+    LeastRecentlyIndexedFiles,
+}
+
+/// Outcome of [`DataStore::enforce_size_cap`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct EvictionReport {
+    /// Number of chunks removed.
+    pub evicted_chunks: usize,
+    /// Bytes of chunk data removed.
+    pub evicted_bytes: i64,
+    /// Files dropped from the index, in the order they were dropped.
+    pub evicted_files: Vec<FileID>,
+    /// Bytes of chunk data left afterwards.
+    pub stored_bytes: i64,
+}
+
+impl DataStore {
+    /// Evicts data until at most `max_bytes` of chunk data remain, if `policy` allows.
+    ///
+    /// Unreferenced chunks go first, largest first. Check
+    /// [`EvictionReport::stored_bytes`] to see whether the budget was met.
+    /// Every dropped file is announced as [`StoreEvent::FileDeleted`].
+    pub async fn enforce_size_cap(
+        &self,
+        max_bytes: i64,
+        policy: EvictionPolicy,
+    ) -> Result<EvictionReport> {
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+        let mut report = EvictionReport {
+            stored_bytes: sqlx::query_scalar("SELECT COALESCE(SUM(length(data)), 0) FROM chunks")
+                .fetch_one(&mut *tx)
+                .await?,
+            ..Default::default()
+        };
+
+        loop {
+            let orphans: Vec<(Vec<u8>, i64)> = sqlx::query_as(
+                r#"
+                SELECT hash, COALESCE(length(data), 0) AS stored FROM chunks
+                WHERE hash NOT IN (SELECT chunk_hash FROM file_sections)
+                ORDER BY stored DESC
+                "#,
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+
+            for (hash, stored) in orphans {
+                if report.stored_bytes <= max_bytes {
+                    break;
+                }
+                sqlx::query("DELETE FROM chunks WHERE hash = $1")
+                    .bind(hash)
+                    .execute(&mut *tx)
+                    .await?;
+                report.evicted_chunks += 1;
+                report.evicted_bytes += stored;
+                report.stored_bytes -= stored;
+            }
+
+            if report.stored_bytes <= max_bytes || policy == EvictionPolicy::OrphansOnly {
+                break;
+            }
+
+            // Files never indexed sort first, then the stalest
+            let oldest: Option<String> = sqlx::query_scalar(
+                "SELECT file_id FROM files ORDER BY COALESCE(last_indexed_at, 0) ASC, rowid ASC LIMIT 1",
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(file_id) = oldest else {
+                break;
+            };
+            sqlx::query("DELETE FROM file_sections WHERE file_id = $1")
+                .bind(&file_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM files WHERE file_id = $1")
+                .bind(&file_id)
+                .execute(&mut *tx)
+                .await?;
+            report.evicted_files.push(file_id.parse()?);
+        }
+
+        tx.commit().await?;
+        for file_id in &report.evicted_files {
+            self.emit(StoreEvent::FileDeleted(*file_id));
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChunkTableEntry, Persist, setup};
+    use rand::{RngCore, rng};
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[tokio::test]
+    async fn test_cap_evicts_orphans_but_keeps_referenced_chunks() -> Result<()> {
+        let store = setup().await;
+        let data = random(8 * 1024);
+        let file_id = FileID::new();
+        store
+            .index_and_store(&file_id, "kept.bin", "/kept.bin", &data[..], None)
+            .await?;
+        let referenced: i64 = sqlx::query_scalar("SELECT SUM(length(data)) FROM chunks")
+            .fetch_one(&store.pool())
+            .await?;
+
+        let orphans: Vec<ChunkTableEntry> = (0..4u8)
+            .map(|i| ChunkTableEntry {
+                hash: vec![i; 32],
+                size: 1024,
+                data: random(1024),
+            })
+            .collect();
+        store.store_all(orphans).await?;
+
+        let report = store
+            .enforce_size_cap(referenced + 1024, EvictionPolicy::OrphansOnly)
+            .await?;
+        assert_eq!(report.evicted_chunks, 3);
+        assert_eq!(report.stored_bytes, referenced + 1024);
+
+        // A cap below the referenced data cannot be met without dropping files
+        let report = store
+            .enforce_size_cap(0, EvictionPolicy::OrphansOnly)
+            .await?;
+        assert_eq!(report.evicted_chunks, 1);
+        assert_eq!(report.stored_bytes, referenced);
+        assert!(report.evicted_files.is_empty());
+        assert_eq!(
+            store.read_range(&file_id, 0, data.len() as u64).await?,
+            data
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cap_drops_least_recently_indexed_files() -> Result<()> {
+        let store = setup().await;
+        let old = FileID::new();
+        let new = FileID::new();
+        let new_data = random(4 * 1024);
+        store
+            .index_and_store(&old, "old.bin", "/old.bin", &random(4 * 1024)[..], None)
+            .await?;
+        store
+            .index_and_store(&new, "new.bin", "/new.bin", &new_data[..], None)
+            .await?;
+        sqlx::query("UPDATE files SET last_indexed_at = 1 WHERE file_id = $1")
+            .bind(old.to_string())
+            .execute(&store.pool())
+            .await?;
+
+        let report = store
+            .enforce_size_cap(
+                new_data.len() as i64,
+                EvictionPolicy::LeastRecentlyIndexedFiles,
+            )
+            .await?;
+        assert_eq!(report.evicted_files, vec![old]);
+        assert!(report.stored_bytes <= new_data.len() as i64);
+        assert!(store.try_fetch_file(&old).await?.is_none());
+        assert_eq!(
+            store.read_range(&new, 0, new_data.len() as u64).await?,
+            new_data
+        );
+        Ok(())
+    }
+}
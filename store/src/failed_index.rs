@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Bookkeeping for files that repeatedly fail to index.
+//!
+//! A corrupt file or one whose permissions keep flapping should neither be
+//! retried forever nor silently forgotten. Each failure is counted per path in
+//! the `failed_index` table; once a path reaches the caller's retry limit it
+//! is a dead letter, skipped until someone looks at it. Indexing the path
+//! successfully by any means clears its entry.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlx::FromRow;
+
+use crate::{DataStore, Result};
+
+/// A path whose indexing has failed, as kept in the `failed_index` table.
+#[derive(FromRow, Clone, Debug, PartialEq, Eq)]
+pub struct FailedIndex {
+    pub path: String,
+    /// Failures in a row since the path was last indexed successfully.
+    pub attempts: i64,
+    pub last_error: String,
+    /// Milliseconds since the Unix epoch.
+    pub last_failed_at: i64,
+}
+
+impl DataStore {
+    /// Counts another failure to index `path` and returns how many there have
+    /// been in a row.
+    pub async fn record_index_failure(&self, path: &str, error: &str) -> Result<i64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let attempts = sqlx::query_scalar(
+            r#"
+            INSERT INTO failed_index (path, attempts, last_error, last_failed_at)
+            VALUES ($1, 1, $2, $3)
+            ON CONFLICT(path) DO UPDATE SET
+                attempts = attempts + 1,
+                last_error = excluded.last_error,
+                last_failed_at = excluded.last_failed_at
+            RETURNING attempts
+            "#,
+        )
+        .bind(path)
+        .bind(error)
+        .bind(now)
+        .fetch_one(&self.pool())
+        .await?;
+        Ok(attempts)
+    }
+
+    /// The failures recorded for `path`, or `None` if it has none.
+    pub async fn index_failure(&self, path: &str) -> Result<Option<FailedIndex>> {
+        let entry = sqlx::query_as("SELECT * FROM failed_index WHERE path = $1")
+            .bind(path)
+            .fetch_optional(&self.pool())
+            .await?;
+        Ok(entry)
+    }
+
+    /// Paths that failed at least `max_attempts` times in a row, by path.
+    pub async fn dead_letters(&self, max_attempts: i64) -> Result<Vec<FailedIndex>> {
+        let entries =
+            sqlx::query_as("SELECT * FROM failed_index WHERE attempts >= $1 ORDER BY path")
+                .bind(max_attempts)
+                .fetch_all(&self.pool())
+                .await?;
+        Ok(entries)
+    }
+
+    /// Forgets the failures recorded for `path`, e.g. after an operator fixed
+    /// it. Returns whether there were any.
+    pub async fn clear_index_failure(&self, path: &str) -> Result<bool> {
+        let rows = sqlx::query("DELETE FROM failed_index WHERE path = $1")
+            .bind(path)
+            .execute(&self.pool())
+            .await?
+            .rows_affected();
+        Ok(rows > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use common::FileID;
+
+    #[tokio::test]
+    async fn test_repeated_failures_dead_letter_until_success() -> Result<()> {
+        let store = setup().await;
+        let path = "/sync/flaky.bin";
+
+        for attempt in 1..=3 {
+            let attempts = store
+                .record_index_failure(path, &format!("permission denied #{attempt}"))
+                .await?;
+            assert_eq!(attempts, attempt);
+        }
+        let dead = store.dead_letters(3).await?;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].path, path);
+        assert_eq!(dead[0].last_error, "permission denied #3");
+        assert!(store.dead_letters(4).await?.is_empty());
+
+        store
+            .index_and_store(&FileID::new(), "flaky.bin", path, &b"finally"[..], None)
+            .await?;
+        assert!(store.dead_letters(3).await?.is_empty());
+        assert_eq!(store.index_failure(path).await?, None);
+        assert!(!store.clear_index_failure(path).await?);
+        Ok(())
+    }
+}
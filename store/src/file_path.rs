@@ -5,7 +5,7 @@
 //! This module provides structures and implementations to fetch file entries by their
 //! paths. It is particularly useful when handling file renames, allowing the data store
 //! to resolve path changes and map them to the corresponding file IDs.
-use crate::{DataStore, DataStoreError, Fetch, Persist, Result};
+use crate::{DataStore, DataStoreError, Fetch, Persist, Result, StoreEvent};
 use async_trait::async_trait;
 use camino::Utf8PathBuf;
 
@@ -21,13 +21,14 @@ impl Persist<PathEntry> for DataStore {
         // Update the path for the given file ID
         let rows = sqlx::query("UPDATE files SET path = $1 WHERE file_id = $2")
             .bind(item.path)
-            .bind(item.file_id)
-            .execute(&self.pool)
+            .bind(&item.file_id)
+            .execute(&self.pool())
             .await?
             .rows_affected();
         if rows == 0 {
             return Err(DataStoreError::NotFound);
         }
+        self.emit_for(&item.file_id, StoreEvent::FileStored);
         Ok(())
     }
 
@@ -35,19 +36,25 @@ impl Persist<PathEntry> for DataStore {
         if items.is_empty() {
             return Ok(());
         }
-        let mut tx = self.pool.begin().await?;
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+        let mut moved = Vec::with_capacity(items.len());
         for item in items {
             let rows = sqlx::query("UPDATE files SET path = $1 WHERE file_id = $2")
                 .bind(item.path)
-                .bind(item.file_id)
+                .bind(&item.file_id)
                 .execute(&mut *tx)
                 .await?
                 .rows_affected();
             if rows == 0 {
                 return Err(DataStoreError::NotFound);
             }
+            moved.push(item.file_id);
         }
         tx.commit().await?;
+        for file_id in moved {
+            self.emit_for(&file_id, StoreEvent::FileStored);
+        }
         Ok(())
     }
 }
@@ -56,7 +63,7 @@ impl Persist<PathEntry> for DataStore {
 impl Fetch<Utf8PathBuf, PathEntry> for DataStore {
     async fn fetch_by(&self, key: &Utf8PathBuf) -> Result<PathEntry> {
         // Delegate to fetch_many for a single key
-        let mut results = self.fetch_many(&[key.clone()]).await?;
+        let mut results = self.fetch_many(std::slice::from_ref(key)).await?;
         // Return the single entry or NotFound error
         results.pop().ok_or(DataStoreError::NotFound)
     }
@@ -82,7 +89,7 @@ impl Fetch<Utf8PathBuf, PathEntry> for DataStore {
             query = query.bind(p.to_string());
         }
         // Execute and return all matching entries
-        let entries = query.fetch_all(&self.pool).await?;
+        let entries = query.fetch_all(&self.pool()).await?;
         Ok(entries)
     }
 }
@@ -108,6 +115,9 @@ mod tests {
                 name: "test.txt".into(),
                 path: "/old/path.txt".into(),
                 hash: vec![0xAA],
+                content_type: None,
+                chunk_count: 0,
+                last_indexed_at: None,
             })
             .await?;
 
@@ -142,6 +152,9 @@ mod tests {
                 name: "a".into(),
                 path: "/p1".into(),
                 hash: vec![1],
+                content_type: None,
+                chunk_count: 0,
+                last_indexed_at: None,
             })
             .await?;
         store
@@ -150,6 +163,9 @@ mod tests {
                 name: "b".into(),
                 path: "/p2".into(),
                 hash: vec![2],
+                content_type: None,
+                chunk_count: 0,
+                last_indexed_at: None,
             })
             .await?;
 
@@ -186,15 +202,21 @@ mod tests {
 
         // Empty PathEntry batch should not change row count, and empty fetch_many yields no entries
         let before: i64 = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM files")
-            .fetch_one(&store.pool)
+            .fetch_one(&store.pool())
             .await?;
         store.store_all(Vec::<PathEntry>::new()).await?;
         let after: i64 = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM files")
-            .fetch_one(&store.pool)
+            .fetch_one(&store.pool())
             .await?;
-        assert_eq!(before, after, "Empty store_all should not modify files count");
+        assert_eq!(
+            before, after,
+            "Empty store_all should not modify files count"
+        );
         let empty = store.fetch_many(&Vec::<Utf8PathBuf>::new()).await?;
-        assert!(empty.is_empty(), "fetch_many on empty input should return empty Vec");
+        assert!(
+            empty.is_empty(),
+            "fetch_many on empty input should return empty Vec"
+        );
 
         Ok(())
     }
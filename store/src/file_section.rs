@@ -1,11 +1,120 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use crate::{DataStore, DataStoreError, Fetch, Persist, Result};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    ChunkMetadata, DataStore, DataStoreError, Fetch, Persist, Result, StoreEvent, file_store,
+};
 use async_trait::async_trait;
 use common::FileID;
 use sqlx::prelude::FromRow;
 
-#[derive(FromRow)]
+// The conflict target is the PRIMARY KEY: (file_id, offset)
+pub(crate) const UPSERT_QUERY: &str = r#"
+    INSERT INTO file_sections (file_id, chunk_hash, length, offset)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT(file_id, offset) DO UPDATE SET
+        chunk_hash = excluded.chunk_hash,
+        length = excluded.length
+"#;
+
+/// Ids looked up per query by [`DataStore::chunk_counts`], well under SQLite's bind limit.
+const CHUNK_COUNTS_BATCH: usize = 500;
+
+/// Result of [`DataStore::chunk_overlap`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OverlapReport {
+    /// Distinct chunks referenced by both files.
+    pub shared_chunks: usize,
+    /// Total size of the shared chunks.
+    pub shared_bytes: i64,
+    /// Total size of the distinct chunks only the first file references.
+    pub a_unique_bytes: i64,
+    /// Total size of the distinct chunks only the second file references.
+    pub b_unique_bytes: i64,
+}
+
+/// Result of [`DataStore::file_dedup_savings`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileDedupInfo {
+    /// Number of sections in the file.
+    pub total_chunks: usize,
+    /// Distinct chunks no other file references.
+    pub unique_to_file: usize,
+    /// Distinct chunks at least one other file also references.
+    pub shared_with_others: usize,
+    /// Logical size of the file minus the bytes only it keeps stored.
+    pub bytes_saved: i64,
+}
+
+/// Byte position at which a section starts in its file.
+///
+/// Kept apart from [`Length`] so the two cannot be swapped when building a
+/// [`FileSectionEntry`]. Only values that fit the `i64` columns can be built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Offset(u64);
+
+/// Number of bytes a section covers. See [`Offset`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Length(u64);
+
+/// Checks that `value` fits the `i64` section columns.
+fn section_bound(value: u64) -> Result<u64> {
+    if value > i64::MAX as u64 {
+        return Err(DataStoreError::SectionBoundOutOfRange {
+            value: value.into(),
+        });
+    }
+    Ok(value)
+}
+
+impl Offset {
+    pub fn new(offset: u64) -> Result<Self> {
+        section_bound(offset).map(Self)
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl Length {
+    pub fn new(length: u64) -> Result<Self> {
+        section_bound(length).map(Self)
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl TryFrom<i64> for Offset {
+    type Error = DataStoreError;
+
+    /// Reads a stored offset, rejecting negative values.
+    fn try_from(offset: i64) -> Result<Self> {
+        u64::try_from(offset)
+            .map(Self)
+            .map_err(|_| DataStoreError::SectionBoundOutOfRange {
+                value: offset.into(),
+            })
+    }
+}
+
+impl TryFrom<i64> for Length {
+    type Error = DataStoreError;
+
+    /// Reads a stored length, rejecting negative values.
+    fn try_from(length: i64) -> Result<Self> {
+        u64::try_from(length)
+            .map(Self)
+            .map_err(|_| DataStoreError::SectionBoundOutOfRange {
+                value: length.into(),
+            })
+    }
+}
+
+#[derive(FromRow, Clone, Debug, PartialEq, Eq)]
 pub struct FileSectionEntry {
     pub file_id: String,
     pub chunk_hash: Vec<u8>,
@@ -13,26 +122,66 @@ pub struct FileSectionEntry {
     pub offset: i64,
 }
 
+impl FileSectionEntry {
+    /// The section placing `length` bytes of chunk `chunk_hash` at `offset`
+    /// in `file_id`.
+    ///
+    /// Fails with [`DataStoreError::OffsetOverflow`] if the section would end
+    /// past the largest offset the store can hold.
+    pub fn new(
+        file_id: &FileID,
+        chunk_hash: Vec<u8>,
+        offset: Offset,
+        length: Length,
+    ) -> Result<Self> {
+        let offset = offset.get() as i64;
+        let length = length.get() as i64;
+        if offset.checked_add(length).is_none() {
+            return Err(DataStoreError::OffsetOverflow { offset });
+        }
+        Ok(Self {
+            file_id: file_id.to_string(),
+            chunk_hash,
+            length,
+            offset,
+        })
+    }
+
+    /// The section's offset and length, failing if either column is negative.
+    pub fn bounds(&self) -> Result<(Offset, Length)> {
+        Ok((self.offset.try_into()?, self.length.try_into()?))
+    }
+
+    /// The section placing the chunk described by `meta` in `file_id`.
+    ///
+    /// Fails like [`FileSectionEntry::new`] if `meta`'s bounds do not fit the
+    /// store.
+    pub fn from_metadata(file_id: &FileID, meta: &ChunkMetadata) -> Result<Self> {
+        Self::new(
+            file_id,
+            meta.hash.clone(),
+            Offset::new(meta.offset)?,
+            Length::new(meta.length)?,
+        )
+    }
+}
+
 #[async_trait]
 impl Persist<FileSectionEntry> for DataStore {
     async fn store(&self, entry: FileSectionEntry) -> Result<()> {
-        // The conflict target is the PRIMARY KEY: (file_id, length)
-        sqlx::query(
-            r#"
-            INSERT INTO file_sections (file_id, chunk_hash, length, offset)
-            VALUES ($1, $2, $3, $4)
-            ON CONFLICT(file_id, offset) DO UPDATE SET
-                chunk_hash = excluded.chunk_hash,
-                length = excluded.length
-            "#,
-        )
-        .bind(entry.file_id)
-        .bind(entry.chunk_hash)
-        .bind(entry.length)
-        .bind(entry.offset)
-        .execute(&self.pool)
-        .await?;
+        sqlx::query(UPSERT_QUERY)
+            .bind(&entry.file_id)
+            .bind(entry.chunk_hash)
+            .bind(entry.length)
+            .bind(entry.offset)
+            .execute(&self.pool())
+            .await?;
+        sqlx::query(file_store::CLEAR_QUICK_CHECKSUM_QUERY)
+            .bind(&entry.file_id)
+            .execute(&self.pool())
+            .await?;
 
+        self.emit_for(&entry.file_id, StoreEvent::SectionsChanged);
         Ok(())
     }
 
@@ -41,27 +190,31 @@ impl Persist<FileSectionEntry> for DataStore {
             return Ok(());
         }
 
-        let mut tx = self.pool.begin().await?;
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
 
+        let mut changed = BTreeSet::new();
         for entry in entries {
-            sqlx::query(
-                r#"
-                INSERT INTO file_sections (file_id, chunk_hash, length, offset) 
-                VALUES ($1, $2, $3, $4)
-                ON CONFLICT(file_id, offset) DO UPDATE SET 
-                    chunk_hash = excluded.chunk_hash,
-                    length = excluded.length
-                "#,
-            )
-            .bind(entry.file_id)
-            .bind(entry.chunk_hash)
-            .bind(entry.length)
-            .bind(entry.offset)
-            .execute(&mut *tx)
-            .await?;
+            sqlx::query(UPSERT_QUERY)
+                .bind(&entry.file_id)
+                .bind(entry.chunk_hash)
+                .bind(entry.length)
+                .bind(entry.offset)
+                .execute(&mut *tx)
+                .await?;
+            changed.insert(entry.file_id);
+        }
+        for file_id in &changed {
+            sqlx::query(file_store::CLEAR_QUICK_CHECKSUM_QUERY)
+                .bind(file_id)
+                .execute(&mut *tx)
+                .await?;
         }
 
         tx.commit().await?;
+        for file_id in changed {
+            self.emit_for(&file_id, StoreEvent::SectionsChanged);
+        }
         Ok(())
     }
 }
@@ -79,7 +232,7 @@ impl Fetch<FileID, Vec<FileSectionEntry>> for DataStore {
             "#,
         )
         .bind(file_id.to_string())
-        .fetch_all(&self.pool)
+        .fetch_all(&self.pool())
         .await?;
 
         if entries.is_empty() {
@@ -111,7 +264,7 @@ impl Fetch<FileID, Vec<FileSectionEntry>> for DataStore {
             query = query.bind(id.to_string());
         }
 
-        let flat_entries = query.fetch_all(&self.pool).await?;
+        let flat_entries = query.fetch_all(&self.pool()).await?;
 
         // Grouping Logic: Converting the flat list into Vec<Vec<...>>
         let mut grouped: Vec<Vec<FileSectionEntry>> = Vec::new();
@@ -139,6 +292,252 @@ impl Fetch<FileID, Vec<FileSectionEntry>> for DataStore {
     }
 }
 
+impl DataStore {
+    /// Sections whose chunk is missing from `chunks`.
+    ///
+    /// Foreign keys rule these out while enforcement is on, but rows written
+    /// by a connection without it (or before it was turned on) can dangle.
+    /// Any section returned here makes its file unreadable.
+    pub async fn check_referential_integrity(&self) -> Result<Vec<FileSectionEntry>> {
+        let dangling = sqlx::query_as::<_, FileSectionEntry>(
+            r#"
+            SELECT s.* FROM file_sections s
+            LEFT JOIN chunks c ON c.hash = s.chunk_hash
+            WHERE c.hash IS NULL
+            ORDER BY s.file_id, s.offset
+            "#,
+        )
+        .fetch_all(&self.pool())
+        .await?;
+        Ok(dangling)
+    }
+
+    /// Sections whose file is missing from `files`.
+    ///
+    /// The counterpart of [`DataStore::check_referential_integrity`]: deleting
+    /// a file while foreign keys are off leaves its sections behind, where
+    /// nothing can read them but they still keep their chunks referenced.
+    pub async fn find_orphan_sections(&self) -> Result<Vec<FileSectionEntry>> {
+        let orphans = sqlx::query_as::<_, FileSectionEntry>(
+            r#"
+            SELECT * FROM file_sections
+            WHERE file_id NOT IN (SELECT file_id FROM files)
+            ORDER BY file_id, offset
+            "#,
+        )
+        .fetch_all(&self.pool())
+        .await?;
+        Ok(orphans)
+    }
+
+    /// Deletes the sections [`DataStore::find_orphan_sections`] reports, in
+    /// one transaction, returning how many were removed.
+    ///
+    /// Chunks only they referenced become orphans themselves and are left for
+    /// [`DataStore::enforce_size_cap`] to collect.
+    pub async fn gc_orphan_sections(&self) -> Result<u64> {
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+        let removed = sqlx::query(
+            "DELETE FROM file_sections WHERE file_id NOT IN (SELECT file_id FROM files)",
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        Ok(removed)
+    }
+
+    /// Number of sections across all files, i.e. chunk references before deduplication.
+    pub async fn logical_section_count(&self) -> Result<i64> {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM file_sections")
+            .fetch_one(&self.pool())
+            .await?;
+        Ok(count)
+    }
+
+    /// How much chunk data the files `a` and `b` have in common.
+    ///
+    /// Works on each file's set of distinct chunks, so a chunk repeated within
+    /// one file is only counted once.
+    pub async fn chunk_overlap(&self, a: &FileID, b: &FileID) -> Result<OverlapReport> {
+        let chunks_a = self.distinct_chunk_sizes(a).await?;
+        let chunks_b = self.distinct_chunk_sizes(b).await?;
+
+        let mut report = OverlapReport::default();
+        for (hash, size) in &chunks_a {
+            if chunks_b.contains_key(hash) {
+                report.shared_chunks += 1;
+                report.shared_bytes += size;
+            } else {
+                report.a_unique_bytes += size;
+            }
+        }
+        report.b_unique_bytes = chunks_b
+            .iter()
+            .filter(|(hash, _)| !chunks_a.contains_key(*hash))
+            .map(|(_, size)| size)
+            .sum();
+
+        Ok(report)
+    }
+
+    /// Whether the sections of `file_id` tile the file without gaps or
+    /// overlaps, starting at offset 0.
+    ///
+    /// Offsets are accumulated with checked arithmetic, so corrupted lengths
+    /// fail with [`DataStoreError::OffsetOverflow`] instead of wrapping around
+    /// into a bogus verdict.
+    pub async fn validate_sections(&self, file_id: &FileID) -> Result<bool> {
+        let sections: Vec<FileSectionEntry> = self.fetch_by(file_id).await?;
+
+        let mut next_offset: i64 = 0;
+        for section in sections {
+            if section.offset != next_offset || section.length < 0 {
+                return Ok(false);
+            }
+            next_offset =
+                next_offset
+                    .checked_add(section.length)
+                    .ok_or(DataStoreError::OffsetOverflow {
+                        offset: section.offset,
+                    })?;
+        }
+        Ok(true)
+    }
+
+    /// Number of sections of each of `ids`, in as few queries as the bind
+    /// limit allows.
+    ///
+    /// Files without sections (empty or untracked) are absent from the map.
+    pub async fn chunk_counts(&self, ids: &[FileID]) -> Result<HashMap<FileID, i64>> {
+        let mut counts = HashMap::with_capacity(ids.len());
+        for batch in ids.chunks(CHUNK_COUNTS_BATCH) {
+            let placeholders = (1..=batch.len())
+                .map(|i| format!("${i}"))
+                .collect::<Vec<_>>()
+                .join(",");
+            let sql = format!(
+                "SELECT file_id, COUNT(*) FROM file_sections WHERE file_id IN ({placeholders}) GROUP BY file_id"
+            );
+
+            let mut query = sqlx::query_as::<_, (String, i64)>(&sql);
+            for id in batch {
+                query = query.bind(id.to_string());
+            }
+            for (file_id, count) in query.fetch_all(&self.pool()).await? {
+                counts.insert(file_id.parse()?, count);
+            }
+        }
+        Ok(counts)
+    }
+
+    /// How much of `file_id` deduplication saves storing.
+    ///
+    /// Chunks shared with other files and chunks repeated within the file
+    /// both count as saved; what remains is the file's incremental cost, the
+    /// chunks that would be freed if it were dropped. Fails with
+    /// [`DataStoreError::NotFound`] if the file is not tracked.
+    pub async fn file_dedup_savings(&self, file_id: &FileID) -> Result<FileDedupInfo> {
+        let rows: Vec<(Vec<u8>, i64, i64)> = sqlx::query_as(
+            r#"
+            SELECT s.chunk_hash, s.length, EXISTS (
+                SELECT 1 FROM file_sections o
+                WHERE o.chunk_hash = s.chunk_hash AND o.file_id <> s.file_id
+            )
+            FROM file_sections s
+            WHERE s.file_id = $1
+            "#,
+        )
+        .bind(file_id.to_string())
+        .fetch_all(&self.pool())
+        .await?;
+
+        if rows.is_empty() {
+            // An empty file has no sections, but it must still exist
+            let tracked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM files WHERE file_id = $1")
+                .bind(file_id.to_string())
+                .fetch_one(&self.pool())
+                .await?;
+            if tracked == 0 {
+                return Err(DataStoreError::NotFound);
+            }
+        }
+
+        let mut info = FileDedupInfo {
+            total_chunks: rows.len(),
+            ..Default::default()
+        };
+        let mut distinct = HashMap::new();
+        for (hash, length, shared) in rows {
+            info.bytes_saved += length;
+            distinct.insert(hash, (length, shared != 0));
+        }
+        for (length, shared) in distinct.into_values() {
+            if shared {
+                info.shared_with_others += 1;
+            } else {
+                info.unique_to_file += 1;
+                info.bytes_saved -= length;
+            }
+        }
+        Ok(info)
+    }
+
+    async fn distinct_chunk_sizes(&self, file_id: &FileID) -> Result<HashMap<Vec<u8>, i64>> {
+        let rows: Vec<(Vec<u8>, i64)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT s.chunk_hash, c.size
+            FROM file_sections s
+            JOIN chunks c ON c.hash = s.chunk_hash
+            WHERE s.file_id = $1
+            "#,
+        )
+        .bind(file_id.to_string())
+        .fetch_all(&self.pool())
+        .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    /// Every section, across all files, that points at the chunk `hash`.
+    ///
+    /// A chunk is only safe to delete once this comes back empty. Rows are
+    /// ordered by file and offset.
+    pub async fn sections_for_chunk(&self, hash: &[u8]) -> Result<Vec<FileSectionEntry>> {
+        let entries = sqlx::query_as::<_, FileSectionEntry>(
+            r#"
+            SELECT file_id, chunk_hash, length, offset
+            FROM file_sections
+            WHERE chunk_hash = $1
+            ORDER BY file_id, offset
+            "#,
+        )
+        .bind(hash)
+        .fetch_all(&self.pool())
+        .await?;
+        Ok(entries)
+    }
+
+    /// The distinct chunks needed to reconstruct `file_id`, with their sizes.
+    ///
+    /// Chunks are listed in the order the file first uses them, each once no
+    /// matter how often it repeats. Untracked and empty files need nothing.
+    pub async fn required_chunks(&self, file_id: &FileID) -> Result<Vec<(Vec<u8>, i64)>> {
+        let sections: Vec<(Vec<u8>, i64)> = sqlx::query_as(
+            "SELECT chunk_hash, length FROM file_sections WHERE file_id = $1 ORDER BY offset",
+        )
+        .bind(file_id.to_string())
+        .fetch_all(&self.pool())
+        .await?;
+
+        let mut seen = HashSet::new();
+        Ok(sections
+            .into_iter()
+            .filter(|(hash, _)| seen.insert(hash.clone()))
+            .collect())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -146,6 +545,272 @@ mod tests {
     use common::FileID;
     use tempfile::NamedTempFile;
 
+    #[test]
+    fn test_section_from_typed_bounds() -> Result<()> {
+        let file_id = FileID::new();
+        let section = FileSectionEntry::new(&file_id, vec![1], Offset::new(100)?, Length::new(1)?)?;
+        assert_eq!((section.offset, section.length), (100, 1));
+        assert_eq!(section.bounds()?, (Offset::new(100)?, Length::new(1)?));
+
+        assert!(matches!(
+            Offset::new(u64::MAX),
+            Err(DataStoreError::SectionBoundOutOfRange { .. })
+        ));
+        assert!(matches!(
+            Length::try_from(-1),
+            Err(DataStoreError::SectionBoundOutOfRange { value: -1 })
+        ));
+        let near_end = Offset::new(i64::MAX as u64 - 1)?;
+        assert!(matches!(
+            FileSectionEntry::new(&file_id, vec![1], near_end, Length::new(2)?),
+            Err(DataStoreError::OffsetOverflow { .. })
+        ));
+
+        let corrupted = FileSectionEntry {
+            offset: -5,
+            ..section
+        };
+        assert!(corrupted.bounds().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_validate_sections_guards_offset_overflow() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let data = (0..9000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        store
+            .index_and_store(&file_id, "v.bin", "/v.bin", &data[..], None)
+            .await?;
+        assert!(store.validate_sections(&file_id).await?);
+
+        // A gap is reported, not an error
+        sqlx::query("DELETE FROM file_sections WHERE file_id = $1 AND offset = 0")
+            .bind(file_id.to_string())
+            .execute(&store.pool())
+            .await?;
+        assert!(!store.validate_sections(&file_id).await?);
+
+        // Two crafted sections whose lengths sum past i64::MAX
+        let crafted = FileID::new();
+        let tempfile = NamedTempFile::new()?;
+        let hash = vec![0x42; 32];
+        seed_db(
+            &store,
+            &tempfile,
+            &crafted.to_string(),
+            std::slice::from_ref(&hash),
+        )
+        .await;
+        store
+            .store_all(vec![
+                FileSectionEntry {
+                    file_id: crafted.to_string(),
+                    chunk_hash: hash.clone(),
+                    length: i64::MAX,
+                    offset: 0,
+                },
+                FileSectionEntry {
+                    file_id: crafted.to_string(),
+                    chunk_hash: hash,
+                    length: 10,
+                    offset: i64::MAX,
+                },
+            ])
+            .await?;
+        assert!(matches!(
+            store.validate_sections(&crafted).await,
+            Err(DataStoreError::OffsetOverflow { offset: i64::MAX })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_counts_grouped_per_file() -> Result<()> {
+        let store = setup().await;
+        let mut expected = HashMap::new();
+        let mut ids = Vec::new();
+        for len in [300usize, 5000, 12_000] {
+            let file_id = FileID::new();
+            let data = (0..len).map(|i| (i * 7 % 253) as u8).collect::<Vec<_>>();
+            store
+                .index_and_store(&file_id, "c.bin", &format!("/{len}.bin"), &data[..], None)
+                .await?;
+            let sections: Vec<FileSectionEntry> = store.fetch_by(&file_id).await?;
+            expected.insert(file_id, sections.len() as i64);
+            ids.push(file_id);
+        }
+        ids.push(FileID::new());
+
+        let counts = store.chunk_counts(&ids).await?;
+        assert_eq!(counts, expected);
+        assert!(counts[&ids[2]] > counts[&ids[0]]);
+        assert!(store.chunk_counts(&[]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_dedup_savings() -> Result<()> {
+        let store = setup().await;
+        let data = (0..16 * 1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect::<Vec<_>>();
+        let a = FileID::new();
+        let b = FileID::new();
+        store
+            .index_and_store(&a, "a.bin", "/a.bin", &data[..], None)
+            .await?;
+
+        let alone = store.file_dedup_savings(&a).await?;
+        assert!(alone.total_chunks > 1);
+        assert_eq!(alone.shared_with_others, 0);
+        assert_eq!(alone.unique_to_file, alone.total_chunks);
+        assert_eq!(alone.bytes_saved, 0);
+
+        store
+            .index_and_store(&b, "b.bin", "/b.bin", &data[..], None)
+            .await?;
+        let copy = store.file_dedup_savings(&b).await?;
+        assert_eq!(copy.unique_to_file, 0);
+        assert_eq!(copy.shared_with_others, copy.total_chunks);
+        assert_eq!(copy.bytes_saved, data.len() as i64);
+
+        assert!(matches!(
+            store.file_dedup_savings(&FileID::new()).await,
+            Err(DataStoreError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_orphan_sections_found_and_collected() -> Result<()> {
+        let store = setup().await;
+        let kept = FileID::new();
+        let deleted = FileID::new();
+        for file_id in [&kept, &deleted] {
+            store
+                .index_and_store(
+                    file_id,
+                    "o.bin",
+                    &format!("/{file_id}"),
+                    &[3u8; 4096][..],
+                    None,
+                )
+                .await?;
+        }
+        assert!(store.find_orphan_sections().await?.is_empty());
+
+        // Deleting without enforcement skips the cascade to the file's sections
+        let mut conn = store.pool().acquire().await?;
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("DELETE FROM files WHERE file_id = $1")
+            .bind(deleted.to_string())
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("PRAGMA foreign_keys = ON")
+            .execute(&mut *conn)
+            .await?;
+        drop(conn);
+
+        let orphans = store.find_orphan_sections().await?;
+        assert!(!orphans.is_empty());
+        assert!(orphans.iter().all(|s| s.file_id == deleted.to_string()));
+
+        let sections_before = store.logical_section_count().await?;
+        assert_eq!(store.gc_orphan_sections().await?, orphans.len() as u64);
+        assert!(store.find_orphan_sections().await?.is_empty());
+        assert_eq!(
+            store.logical_section_count().await?,
+            sections_before - orphans.len() as i64
+        );
+        assert!(store.validate_sections(&kept).await?);
+        assert_eq!(store.gc_orphan_sections().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_required_chunks_collapse_repeats() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let [a, b] = [&b"aaaa"[..], b"bb"].map(|data| ChunkTableEntry {
+            hash: blake3::hash(data).as_bytes().to_vec(),
+            size: data.len() as i64,
+            data: data.to_vec(),
+        });
+        let (hash_a, hash_b) = (a.hash.clone(), b.hash.clone());
+        store.store_all(vec![a, b]).await?;
+        store
+            .store(FileTableEntry {
+                file_id: file_id.to_string(),
+                name: "r.bin".to_string(),
+                path: "/r.bin".to_string(),
+                hash: vec![0; 32],
+                content_type: None,
+                chunk_count: 0,
+                last_indexed_at: None,
+            })
+            .await?;
+        let section = |hash: &[u8], offset, length| {
+            FileSectionEntry::new(&file_id, hash.to_vec(), offset, length)
+        };
+        let (len_a, len_b) = (Length::new(4)?, Length::new(2)?);
+        store
+            .store_all(vec![
+                section(&hash_b, Offset::new(0)?, len_b)?,
+                section(&hash_a, Offset::new(2)?, len_a)?,
+                section(&hash_b, Offset::new(6)?, len_b)?,
+                section(&hash_a, Offset::new(8)?, len_a)?,
+            ])
+            .await?;
+
+        let required = store.required_chunks(&file_id).await?;
+        assert_eq!(required, vec![(hash_b, 2), (hash_a, 4)]);
+        assert_eq!(store.logical_section_count().await?, 4);
+        assert!(store.required_chunks(&FileID::new()).await?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_metadata() {
+        let file_id = FileID::new();
+        let meta = ChunkMetadata {
+            hash: vec![9; 32],
+            offset: 4096,
+            length: 1500,
+        };
+
+        let section = FileSectionEntry::from_metadata(&file_id, &meta).unwrap();
+        assert_eq!(section.file_id, file_id.to_string());
+        assert_eq!(section.chunk_hash, meta.hash);
+        assert_eq!(section.offset, 4096);
+        assert_eq!(section.length, 1500);
+
+        let chunk = ChunkTableEntry::from_metadata(&meta, vec![0; 1500]).unwrap();
+        assert_eq!(chunk.hash, meta.hash);
+        assert_eq!(chunk.size, 1500);
+
+        // Bounds past the i64 columns are refused rather than wrapped
+        let huge = ChunkMetadata {
+            hash: vec![9; 32],
+            offset: u64::MAX,
+            length: 1,
+        };
+        assert!(matches!(
+            FileSectionEntry::from_metadata(&file_id, &huge),
+            Err(DataStoreError::SectionBoundOutOfRange { .. })
+        ));
+        let ending_past_max = ChunkMetadata {
+            offset: i64::MAX as u64,
+            ..huge.clone()
+        };
+        assert!(matches!(
+            FileSectionEntry::from_metadata(&file_id, &ending_past_max),
+            Err(DataStoreError::OffsetOverflow { .. })
+        ));
+    }
+
     #[tokio::test]
     async fn test_store() -> Result<()> {
         let store = setup().await;
@@ -161,6 +826,9 @@ mod tests {
                 name: "test.bin".into(),
                 path: "/tmp/test.bin".into(),
                 hash: vec![0x99],
+                content_type: None,
+                chunk_count: 0,
+                last_indexed_at: None,
             })
             .await?;
 
@@ -169,23 +837,15 @@ mod tests {
             .store(ChunkTableEntry {
                 hash: hash.clone(),
                 size: 100,
+                data: vec![0; 100],
             })
             .await?;
 
         // 2. NOW TEST SECTIONS
+        // The 100-byte chunk placed twice, back to back
         let file_sections = vec![
-            FileSectionEntry {
-                file_id: file_id_str.clone(),
-                length: 0,
-                chunk_hash: hash.clone(),
-                offset: 0,
-            },
-            FileSectionEntry {
-                file_id: file_id_str.clone(),
-                length: 1,
-                chunk_hash: hash.clone(),
-                offset: 100,
-            },
+            FileSectionEntry::new(&file_id, hash.clone(), Offset::new(0)?, Length::new(100)?)?,
+            FileSectionEntry::new(&file_id, hash.clone(), Offset::new(100)?, Length::new(100)?)?,
         ];
 
         store.store_all(file_sections).await?;
@@ -194,8 +854,9 @@ mod tests {
         let fetched_sections: Vec<FileSectionEntry> = store.fetch_by(&file_id).await?;
 
         assert_eq!(fetched_sections.len(), 2);
-        assert_eq!(fetched_sections[0].length, 0);
-        assert_eq!(fetched_sections[1].length, 1);
+        assert_eq!(fetched_sections[0].offset, 0);
+        assert_eq!(fetched_sections[1].offset, 100);
+        assert!(fetched_sections.iter().all(|section| section.length == 100));
         Ok(())
     }
 
@@ -207,6 +868,9 @@ mod tests {
                 name: tempfile.path().to_string_lossy().to_ascii_lowercase(),
                 path: tempfile.path().to_string_lossy().to_ascii_lowercase(),
                 hash: vec![0x99],
+                content_type: None,
+                chunk_count: 0,
+                last_indexed_at: None,
             })
             .await
             .unwrap();
@@ -216,6 +880,7 @@ mod tests {
                 .store(ChunkTableEntry {
                     hash: h.clone(),
                     size: 1024,
+                    data: vec![0; 1024],
                 })
                 .await
                 .unwrap();
@@ -239,21 +904,13 @@ mod tests {
         .await;
 
         // 1. Initial State: Index 0 points to Hash V1
-        let initial = FileSectionEntry {
-            file_id: fid.to_string(),
-            length: 0,
-            chunk_hash: hash_v1.clone(),
-            offset: 0,
-        };
+        let initial =
+            FileSectionEntry::new(&fid, hash_v1.clone(), Offset::new(0)?, Length::new(1024)?)?;
         store.store(initial).await?;
 
         // 2. Update: Same FileID and Index, but new Hash (V2)
-        let update = FileSectionEntry {
-            file_id: fid.to_string(),
-            length: 0,
-            chunk_hash: hash_v2.clone(),
-            offset: 0,
-        };
+        let update =
+            FileSectionEntry::new(&fid, hash_v2.clone(), Offset::new(0)?, Length::new(1024)?)?;
         store.store(update).await?;
 
         // 3. Verify: We should still only have 1 row, and it should have Hash V2
@@ -280,42 +937,28 @@ mod tests {
             &store,
             &named_temp_file_a,
             &fid_a.to_string(),
-            &[hash.clone()],
+            std::slice::from_ref(&hash),
         )
         .await;
         seed_db(
             &store,
             &named_temp_file_b,
             &fid_b.to_string(),
-            &[hash.clone()],
+            std::slice::from_ref(&hash),
         )
         .await;
 
         // Store 2 chunks for File A and 1 chunk for File B
+        let chunk_length = Length::new(1024)?;
         let sections = vec![
-            FileSectionEntry {
-                file_id: fid_a.to_string(),
-                length: 0,
-                chunk_hash: hash.clone(),
-                offset: 0,
-            },
-            FileSectionEntry {
-                file_id: fid_a.to_string(),
-                length: 1,
-                chunk_hash: hash.clone(),
-                offset: 100,
-            },
-            FileSectionEntry {
-                file_id: fid_b.to_string(),
-                length: 0,
-                chunk_hash: hash.clone(),
-                offset: 0,
-            },
+            FileSectionEntry::new(&fid_a, hash.clone(), Offset::new(0)?, chunk_length)?,
+            FileSectionEntry::new(&fid_a, hash.clone(), Offset::new(1024)?, chunk_length)?,
+            FileSectionEntry::new(&fid_b, hash.clone(), Offset::new(0)?, chunk_length)?,
         ];
         store.store_all(sections).await?;
 
         // Fetch both
-        let results: Vec<Vec<_>> = store.fetch_many(&[fid_a.clone(), fid_b.clone()]).await?;
+        let results: Vec<Vec<_>> = store.fetch_many(&[fid_a, fid_b]).await?;
 
         // Verify structure: Vec<Vec<FileSectionEntry>>
         assert_eq!(
@@ -347,4 +990,98 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_sections_for_chunk() -> Result<()> {
+        let store = setup().await;
+        let (file_a, file_b) = (NamedTempFile::new()?, NamedTempFile::new()?);
+        let (id_a, id_b) = (FileID::new(), FileID::new());
+        let (fid_a, fid_b) = (id_a.to_string(), id_b.to_string());
+        let shared = vec![0xAA];
+        let other = vec![0xBB];
+        seed_db(&store, &file_a, &fid_a, &[shared.clone(), other.clone()]).await;
+        seed_db(&store, &file_b, &fid_b, &[]).await;
+
+        // seed_db stores every chunk with a size of 1024
+        let section = |file_id: &FileID, chunk_hash: &[u8], offset| -> Result<FileSectionEntry> {
+            FileSectionEntry::new(
+                file_id,
+                chunk_hash.to_vec(),
+                Offset::new(offset)?,
+                Length::new(1024)?,
+            )
+        };
+        store
+            .store_all(vec![
+                section(&id_a, &other, 0)?,
+                section(&id_a, &shared, 1024)?,
+                section(&id_b, &shared, 4096)?,
+            ])
+            .await?;
+
+        let mut found = store.sections_for_chunk(&shared).await?;
+        found.sort_by_key(|s| s.offset);
+        let found = found
+            .iter()
+            .map(|s| (s.file_id.as_str(), s.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![(fid_a.as_str(), 1024), (fid_b.as_str(), 4096)]);
+
+        assert!(store.sections_for_chunk(&[0xCC]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_overlap() -> Result<()> {
+        let store = setup().await;
+        let (file_a, file_b) = (NamedTempFile::new()?, NamedTempFile::new()?);
+        let (id_a, id_b) = (FileID::new(), FileID::new());
+        let (shared, only_a, only_b) = (vec![0x01], vec![0x02], vec![0x03]);
+        // seed_db stores every chunk with a size of 1024
+        seed_db(
+            &store,
+            &file_a,
+            &id_a.to_string(),
+            &[shared.clone(), only_a.clone()],
+        )
+        .await;
+        seed_db(
+            &store,
+            &file_b,
+            &id_b.to_string(),
+            std::slice::from_ref(&only_b),
+        )
+        .await;
+
+        let section = |file_id: &FileID, chunk_hash: &[u8], offset| -> Result<FileSectionEntry> {
+            FileSectionEntry::new(
+                file_id,
+                chunk_hash.to_vec(),
+                Offset::new(offset)?,
+                Length::new(1024)?,
+            )
+        };
+        store
+            .store_all(vec![
+                section(&id_a, &shared, 0)?,
+                section(&id_a, &only_a, 1024)?,
+                // A repeat within one file does not count twice
+                section(&id_a, &only_a, 2048)?,
+                section(&id_b, &only_b, 0)?,
+                section(&id_b, &shared, 1024)?,
+            ])
+            .await?;
+
+        let report = store.chunk_overlap(&id_a, &id_b).await?;
+        assert_eq!(
+            report,
+            OverlapReport {
+                shared_chunks: 1,
+                shared_bytes: 1024,
+                a_unique_bytes: 1024,
+                b_unique_bytes: 1024,
+            }
+        );
+        Ok(())
+    }
 }
@@ -1,24 +1,86 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
 use common::FileID;
+use sqlx::AnyConnection;
 
-use crate::{DataStore, DataStoreError, Fetch, Persist, Result};
-const UPSERT_QUERY: &str = r#"
-    INSERT INTO files (file_id, name, path, hash)
-    VALUES ($1, $2, $3, $4)
+use crate::{DataStore, DataStoreError, Fetch, Persist, Result, StoreEvent};
+// The row may now describe other content, so its quick checksum is dropped
+pub(crate) const UPSERT_QUERY: &str = r#"
+    INSERT INTO files (file_id, name, path, hash, content_type, sync_set)
+    VALUES ($1, $2, $3, $4, $5, $6)
     ON CONFLICT(file_id) DO UPDATE SET
         name = excluded.name,
         path = excluded.path,
-        hash = excluded.hash
+        hash = excluded.hash,
+        content_type = excluded.content_type,
+        quick_checksum = NULL
 "#;
 
-#[derive(sqlx::FromRow)]
+/// Forgets a file's quick checksum after its sections were written without
+/// reading the file.
+pub(crate) const CLEAR_QUICK_CHECKSUM_QUERY: &str =
+    "UPDATE files SET quick_checksum = NULL WHERE file_id = $1";
+
+#[derive(sqlx::FromRow, Clone, Debug, PartialEq, Eq)]
 pub struct FileTableEntry {
     pub file_id: String,
     pub name: String,
     pub path: String,
     pub hash: Vec<u8>,
+    /// MIME type sniffed from the file's leading bytes, if recognized.
+    pub content_type: Option<String>,
+    /// Number of sections the file was split into when last indexed.
+    pub chunk_count: i64,
+    /// When the file's sections were last written, in Unix epoch milliseconds.
+    pub last_indexed_at: Option<i64>,
+}
+
+/// Refreshes a file's `chunk_count`, `last_indexed_at` and `quick_checksum`
+/// after its sections were rewritten, and clears any recorded index failure
+/// for its path.
+///
+/// `quick_checksum` is `None` when the sections were written without reading
+/// the file. Must run in the same transaction as the section writes. The
+/// `Persist` implementations leave the first two columns alone, since they do
+/// not replace a file's sections wholesale.
+pub(crate) async fn record_indexed(
+    conn: &mut AnyConnection,
+    file_id: &str,
+    chunk_count: usize,
+    quick_checksum: Option<u32>,
+) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    sqlx::query(
+        r#"
+        UPDATE files SET chunk_count = $1, last_indexed_at = $2, quick_checksum = $3
+        WHERE file_id = $4
+        "#,
+    )
+    .bind(chunk_count as i64)
+    .bind(now)
+    .bind(quick_checksum.map(i64::from))
+    .bind(file_id)
+    .execute(&mut *conn)
+    .await?;
+    // A successful index ends any run of failures for the path
+    sqlx::query(
+        "DELETE FROM failed_index WHERE path = (SELECT path FROM files WHERE file_id = $1)",
+    )
+    .bind(file_id)
+    .execute(conn)
+    .await?;
+    Ok(())
 }
 
 #[async_trait]
@@ -26,35 +88,47 @@ impl Persist<FileTableEntry> for DataStore {
     /// Implementation Detail: Uses an UPSERT (ON CONFLICT) strategy.
     /// This ensures that if a file is moved or renamed, we update the existing
     /// metadata rather than creating duplicate entries for the same file_id.
+    /// New files join the store's sync set; existing ones keep theirs.
     async fn store_all(&self, items: Vec<FileTableEntry>) -> Result<()> {
         // Start a transaction. If any insert fails, the whole thing rolls back.
-        let mut transaction = self.pool.begin().await?;
+        let _slot = self.transaction_slot().await;
+        let mut transaction = self.pool().begin().await?;
 
         // Loop through our DOD arrays.
+        let mut stored = Vec::with_capacity(items.len());
         for entry in items {
             sqlx::query(UPSERT_QUERY)
-                .bind(entry.file_id)
+                .bind(&entry.file_id)
                 .bind(&entry.name)
                 .bind(entry.path)
                 .bind(entry.hash)
+                .bind(entry.content_type)
+                .bind(&self.sync_set)
                 .execute(&mut *transaction)
                 .await?;
+            stored.push(entry.file_id);
         }
 
         // Commit everything to disk
         transaction.commit().await?;
+        for file_id in stored {
+            self.emit_for(&file_id, StoreEvent::FileStored);
+        }
         Ok(())
     }
 
     async fn store(&self, item: FileTableEntry) -> Result<()> {
         // Start a transaction. If any insert fails, the whole thing rolls back.
         sqlx::query(UPSERT_QUERY)
-            .bind(item.file_id)
+            .bind(&item.file_id)
             .bind(&item.name)
             .bind(item.path)
             .bind(item.hash)
-            .execute(&self.pool)
+            .bind(item.content_type)
+            .bind(&self.sync_set)
+            .execute(&self.pool())
             .await?;
+        self.emit_for(&item.file_id, StoreEvent::FileStored);
         Ok(())
     }
 }
@@ -62,11 +136,9 @@ impl Persist<FileTableEntry> for DataStore {
 #[async_trait]
 impl Fetch<FileID, FileTableEntry> for DataStore {
     async fn fetch_by(&self, key: &FileID) -> Result<FileTableEntry> {
-        // Reuse fetch_many logic for a single key
-        let mut results = self.fetch_many(&[*key]).await?;
-
-        // Take the first item out of the vector or return NotFound
-        results.pop().ok_or(DataStoreError::NotFound)
+        self.try_fetch_file(key)
+            .await?
+            .ok_or(DataStoreError::NotFound)
     }
 
     async fn fetch_many(&self, keys: &[FileID]) -> Result<Vec<FileTableEntry>> {
@@ -89,16 +161,192 @@ impl Fetch<FileID, FileTableEntry> for DataStore {
             query = query.bind(id);
         }
 
-        let entries = query.fetch_all(&self.pool).await?;
+        let entries = query.fetch_all(&self.pool()).await?;
 
         Ok(entries)
     }
 }
 
+impl DataStore {
+    /// Fetches a file's metadata, returning `Ok(None)` if it is not tracked.
+    ///
+    /// Unlike `fetch_by`, absence is not an error; `Err` is reserved for real
+    /// database failures.
+    pub async fn try_fetch_file(&self, id: &FileID) -> Result<Option<FileTableEntry>> {
+        // Reuse fetch_many logic for a single key
+        let mut results = self.fetch_many(&[*id]).await?;
+        Ok(results.pop())
+    }
+
+    /// Lists files whose content type starts with `mime_prefix`.
+    ///
+    /// A trailing `*` is ignored, so `"image/*"` and `"image/"` both select all
+    /// images. Files without a detected type never match.
+    pub async fn files_by_type(&self, mime_prefix: &str) -> Result<Vec<FileTableEntry>> {
+        let prefix = mime_prefix.trim_end_matches('*');
+        let entries = sqlx::query_as::<_, FileTableEntry>(
+            "SELECT * FROM files WHERE substr(content_type, 1, length($1)) = $1 ORDER BY path",
+        )
+        .bind(prefix)
+        .fetch_all(&self.pool())
+        .await?;
+
+        Ok(entries)
+    }
+
+    /// The `limit` most recently indexed files, newest first.
+    ///
+    /// Files indexed in the same millisecond are ordered by `file_id`, and
+    /// files that were never indexed come last.
+    pub async fn recent_files(&self, limit: i64) -> Result<Vec<FileTableEntry>> {
+        let entries = sqlx::query_as::<_, FileTableEntry>(
+            "SELECT * FROM files ORDER BY last_indexed_at DESC, file_id ASC LIMIT $1",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool())
+        .await?;
+
+        Ok(entries)
+    }
+
+    /// Finds files tracked more than once under different spellings of one path.
+    ///
+    /// `path` is unique, but `/a//b.txt` and `/a/./b.txt` both name `/a/b.txt`,
+    /// so a file re-tracked under another spelling gets a second `FileID`.
+    /// Returns each normalized path with its ids, least recently indexed
+    /// first; entries never indexed come first, and ties keep tracking order.
+    pub async fn find_duplicate_paths(&self) -> Result<Vec<(String, Vec<FileID>)>> {
+        let mut conn = self.pool().acquire().await?;
+        duplicate_paths(&mut conn).await
+    }
+
+    /// Removes all but the most recently indexed entry for each duplicated path.
+    ///
+    /// The surviving entry's sections describe the file's current content; the
+    /// others' sections and file rows are deleted in one transaction. Returns
+    /// the number of entries removed.
+    pub async fn dedup_by_path(&self) -> Result<usize> {
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+        let mut removed = 0;
+        let mut deleted = Vec::new();
+
+        for (_, mut ids) in duplicate_paths(&mut tx).await? {
+            ids.pop();
+            for id in ids {
+                sqlx::query("DELETE FROM file_sections WHERE file_id = $1")
+                    .bind(id.to_string())
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM files WHERE file_id = $1")
+                    .bind(id.to_string())
+                    .execute(&mut *tx)
+                    .await?;
+                removed += 1;
+                deleted.push(id);
+            }
+        }
+
+        tx.commit().await?;
+        for id in deleted {
+            self.emit(StoreEvent::FileDeleted(id));
+        }
+        Ok(removed)
+    }
+
+    /// Forgets `file_id` completely: its row, its sections, and every chunk
+    /// that no other file references afterwards.
+    ///
+    /// Runs in one transaction. Chunks that were already unreferenced before
+    /// the purge are left for [`DataStore::enforce_size_cap`]. Fails with
+    /// [`DataStoreError::NotFound`] if the file is not tracked; otherwise
+    /// returns the number of chunks reclaimed.
+    pub async fn purge_file(&self, file_id: &FileID) -> Result<u64> {
+        let event = StoreEvent::FileDeleted(*file_id);
+        let file_id = file_id.to_string();
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+
+        let hashes: Vec<Vec<u8>> =
+            sqlx::query_scalar("SELECT DISTINCT chunk_hash FROM file_sections WHERE file_id = $1")
+                .bind(&file_id)
+                .fetch_all(&mut *tx)
+                .await?;
+        sqlx::query("DELETE FROM file_sections WHERE file_id = $1")
+            .bind(&file_id)
+            .execute(&mut *tx)
+            .await?;
+        let rows = sqlx::query("DELETE FROM files WHERE file_id = $1")
+            .bind(&file_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if rows == 0 {
+            return Err(DataStoreError::NotFound);
+        }
+
+        let mut reclaimed = 0;
+        for hash in hashes {
+            reclaimed += sqlx::query(
+                r#"
+                DELETE FROM chunks WHERE hash = $1
+                AND NOT EXISTS (SELECT 1 FROM file_sections WHERE chunk_hash = $1)
+                "#,
+            )
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+
+        tx.commit().await?;
+        self.emit(event);
+        Ok(reclaimed)
+    }
+
+    /// Lists tracked files whose path no longer exists on disk.
+    ///
+    /// Catches deletions the watcher never saw, e.g. while the daemon was off.
+    pub async fn find_missing_on_disk(&self) -> Result<Vec<FileTableEntry>> {
+        let entries = sqlx::query_as::<_, FileTableEntry>("SELECT * FROM files ORDER BY path")
+            .fetch_all(&self.pool())
+            .await?;
+
+        Ok(entries
+            .into_iter()
+            .filter(|entry| !Path::new(&entry.path).exists())
+            .collect())
+    }
+}
+
+async fn duplicate_paths(conn: &mut AnyConnection) -> Result<Vec<(String, Vec<FileID>)>> {
+    // The last id per path is the most recently indexed; rowid follows
+    // insertion order for entries indexed at the same time or never
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT file_id, path FROM files ORDER BY last_indexed_at NULLS FIRST, rowid",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut by_path: BTreeMap<String, Vec<FileID>> = BTreeMap::new();
+    for (file_id, path) in rows {
+        let normalized: Utf8PathBuf = Utf8Path::new(&path).components().collect();
+        by_path
+            .entry(normalized.into_string())
+            .or_default()
+            .push(file_id.parse()?);
+    }
+
+    Ok(by_path
+        .into_iter()
+        .filter(|(_, ids)| ids.len() > 1)
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::setup;
+    use crate::{ChunkTableEntry, FileSectionEntry, setup};
     use common::FileID;
     #[tokio::test]
     async fn test_file_metadata_lifecycle() {
@@ -110,6 +358,9 @@ mod tests {
             name: "init.txt".into(),
             path: "/a/init.txt".into(),
             hash: vec![0xCC],
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
         };
 
         // Test: Persist
@@ -125,10 +376,261 @@ mod tests {
             path: "/b/moved.txt".into(),
             file_id: id.to_string(),
             hash: vec![0xCC],
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
         };
         store.store(updated).await.expect("Update failed");
 
         let fetched_updated: FileTableEntry = store.fetch_by(&id).await.unwrap();
         assert_eq!(fetched_updated.name, "moved.txt");
     }
+
+    #[tokio::test]
+    async fn test_files_by_type() {
+        let store = setup().await;
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        png.resize(2048, 0);
+        let text = b"plain old notes\n".repeat(100);
+
+        store
+            .index_and_store(&FileID::new(), "a.png", "/a.png", &png[..], None)
+            .await
+            .unwrap();
+        store
+            .index_and_store(&FileID::new(), "b.txt", "/b.txt", &text[..], None)
+            .await
+            .unwrap();
+
+        let images = store.files_by_type("image/*").await.unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].path, "/a.png");
+        assert_eq!(images[0].content_type.as_deref(), Some("image/png"));
+
+        let texts = store.files_by_type("text/").await.unwrap();
+        assert_eq!(texts.len(), 1);
+        assert_eq!(texts[0].path, "/b.txt");
+    }
+
+    #[tokio::test]
+    async fn test_try_fetch_file() {
+        let store = setup().await;
+        let id = FileID::new();
+        store
+            .store(FileTableEntry {
+                file_id: id.to_string(),
+                name: "present.txt".into(),
+                path: "/present.txt".into(),
+                hash: vec![0x01],
+                content_type: None,
+                chunk_count: 0,
+                last_indexed_at: None,
+            })
+            .await
+            .unwrap();
+
+        let present = store.try_fetch_file(&id).await.unwrap();
+        assert_eq!(present.map(|e| e.name).as_deref(), Some("present.txt"));
+        assert!(
+            store
+                .try_fetch_file(&FileID::new())
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        // Real failures still surface as errors
+        store.pool().close().await;
+        let err = store.try_fetch_file(&id).await.unwrap_err();
+        assert!(matches!(err, DataStoreError::DbError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_find_missing_on_disk() {
+        let store = setup().await;
+        let dir = tempfile::TempDir::new().unwrap();
+        let kept = dir.path().join("kept.txt");
+        let deleted = dir.path().join("deleted.txt");
+        std::fs::write(&kept, b"still here").unwrap();
+        std::fs::write(&deleted, b"about to go").unwrap();
+
+        for path in [&kept, &deleted] {
+            let data = std::fs::read(path).unwrap();
+            store
+                .index_and_store(
+                    &FileID::new(),
+                    "name",
+                    path.to_str().unwrap(),
+                    &data[..],
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+        std::fs::remove_file(&deleted).unwrap();
+
+        let missing = store.find_missing_on_disk().await.unwrap();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(Path::new(&missing[0].path), deleted);
+    }
+
+    #[tokio::test]
+    async fn test_dedup_by_path() {
+        let store = setup().await;
+        let (old, new, other) = (FileID::new(), FileID::new(), FileID::new());
+        for (id, path) in [(old, "/a//b.txt"), (new, "/a/./b.txt"), (other, "/a/c.txt")] {
+            store
+                .index_and_store(&id, "name", path, &b"same bytes"[..], None)
+                .await
+                .unwrap();
+        }
+
+        let duplicates = store.find_duplicate_paths().await.unwrap();
+        assert_eq!(duplicates, vec![("/a/b.txt".to_string(), vec![old, new])]);
+
+        assert_eq!(store.dedup_by_path().await.unwrap(), 1);
+        assert!(store.find_duplicate_paths().await.unwrap().is_empty());
+        assert!(store.try_fetch_file(&old).await.unwrap().is_none());
+        assert!(store.try_fetch_file(&new).await.unwrap().is_some());
+        assert_eq!(store.logical_section_count().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dedup_by_path_keeps_most_recently_indexed() {
+        let store = setup().await;
+        let (stale, fresh, never) = (FileID::new(), FileID::new(), FileID::new());
+        // Tracked in this order, but `fresh` was indexed last
+        for (id, path) in [
+            (fresh, "/a//b.txt"),
+            (stale, "/a/./b.txt"),
+            (never, "/a/b.txt"),
+        ] {
+            store
+                .index_and_store(&id, "name", path, &b"same bytes"[..], None)
+                .await
+                .unwrap();
+        }
+        for (id, indexed_at) in [
+            (fresh, Some(2_000_i64)),
+            (stale, Some(1_000)),
+            (never, None),
+        ] {
+            sqlx::query("UPDATE files SET last_indexed_at = $1 WHERE file_id = $2")
+                .bind(indexed_at)
+                .bind(id.to_string())
+                .execute(&store.pool())
+                .await
+                .unwrap();
+        }
+
+        let duplicates = store.find_duplicate_paths().await.unwrap();
+        assert_eq!(
+            duplicates,
+            vec![("/a/b.txt".to_string(), vec![never, stale, fresh])]
+        );
+
+        assert_eq!(store.dedup_by_path().await.unwrap(), 2);
+        assert!(store.try_fetch_file(&fresh).await.unwrap().is_some());
+        assert!(store.try_fetch_file(&stale).await.unwrap().is_none());
+        assert!(store.try_fetch_file(&never).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_index_records_chunk_count_and_time() {
+        let store = setup().await;
+        let id = FileID::new();
+        let data: Vec<u8> = (0..16 * 1024).map(|i| (i * 31 % 253) as u8).collect();
+
+        store
+            .index_and_store(&id, "stats.bin", "/stats.bin", &data[..], None)
+            .await
+            .unwrap();
+        let first: FileTableEntry = store.fetch_by(&id).await.unwrap();
+        assert_eq!(
+            first.chunk_count,
+            store.logical_section_count().await.unwrap()
+        );
+        let first_indexed = first.last_indexed_at.expect("indexed files are stamped");
+
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        store
+            .index_and_store(&id, "stats.bin", "/stats.bin", &data[..4096], None)
+            .await
+            .unwrap();
+        let second: FileTableEntry = store.fetch_by(&id).await.unwrap();
+        assert!(second.chunk_count < first.chunk_count);
+        assert!(second.last_indexed_at.unwrap() > first_indexed);
+    }
+
+    #[tokio::test]
+    async fn test_purge_file_reclaims_only_unshared_chunks() {
+        let store = setup().await;
+        let shared = vec![1u8; 32];
+        let owned = vec![2u8; 32];
+        let chunks = [&shared, &owned]
+            .into_iter()
+            .map(|hash| ChunkTableEntry {
+                hash: hash.clone(),
+                size: 4,
+                data: b"data".to_vec(),
+            })
+            .collect();
+        store.store_all(chunks).await.unwrap();
+
+        let purged = FileID::new();
+        let kept = FileID::new();
+        let mut sections = Vec::new();
+        for (file_id, hashes) in [(&purged, vec![&shared, &owned]), (&kept, vec![&shared])] {
+            store
+                .store(FileTableEntry {
+                    file_id: file_id.to_string(),
+                    name: file_id.to_string(),
+                    path: format!("/{file_id}"),
+                    hash: vec![0; 32],
+                    content_type: None,
+                    chunk_count: 0,
+                    last_indexed_at: None,
+                })
+                .await
+                .unwrap();
+            for (i, hash) in hashes.into_iter().enumerate() {
+                sections.push(FileSectionEntry {
+                    file_id: file_id.to_string(),
+                    chunk_hash: hash.clone(),
+                    length: 4,
+                    offset: i as i64 * 4,
+                });
+            }
+        }
+        store.store_all(sections).await.unwrap();
+
+        assert_eq!(store.purge_file(&purged).await.unwrap(), 1);
+        assert!(store.try_fetch_file(&purged).await.unwrap().is_none());
+        assert_eq!(store.physical_chunk_count().await.unwrap(), 1);
+        assert_eq!(store.read_range(&kept, 0, 4).await.unwrap(), b"data");
+        assert!(matches!(
+            store.purge_file(&purged).await,
+            Err(DataStoreError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_recent_files_newest_first() {
+        let store = setup().await;
+        let mut ids = Vec::new();
+        for name in ["first.txt", "second.txt", "third.txt"] {
+            let id = FileID::new();
+            store
+                .index_and_store(&id, name, name, name.as_bytes(), None)
+                .await
+                .unwrap();
+            ids.push(id.to_string());
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        let recent = store.recent_files(10).await.unwrap();
+        let recent: Vec<&str> = recent.iter().map(|f| f.file_id.as_str()).collect();
+        assert_eq!(recent, [&ids[2], &ids[1], &ids[0]]);
+        assert_eq!(store.recent_files(1).await.unwrap()[0].file_id, ids[2]);
+    }
 }
@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Checking the whole store in one pass.
+//!
+//! [`DataStore::fsck`] combines what [`DataStore::validate_sections`],
+//! [`DataStore::check_referential_integrity`] and per-chunk verification do
+//! for single files, for an operator who wants one answer about the store.
+//! Sections and chunks are read in keyset pages of [`DUMP_PAGE_SIZE`] rows
+//! inside one read transaction, so memory use does not grow with the store.
+use crate::{DUMP_PAGE_SIZE, DataStore, Result};
+
+/// How bad an [`FsckIssue`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Wastes space but loses nothing.
+    Warning,
+    /// Some file cannot be reconstructed correctly.
+    Error,
+}
+
+/// A single problem found by [`DataStore::fsck`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FsckIssue {
+    /// The section at `offset` does not start where the previous one ended,
+    /// leaving a gap or an overlap.
+    Discontiguous { file_id: String, offset: i64 },
+    /// The section at `offset` refers to a chunk that is not stored.
+    MissingChunk {
+        file_id: String,
+        offset: i64,
+        chunk_hash: Vec<u8>,
+    },
+    /// The chunk's data no longer hashes to its key, or its size is wrong.
+    CorruptChunk { hash: Vec<u8> },
+    /// No section refers to the chunk.
+    OrphanChunk { hash: Vec<u8> },
+}
+
+impl FsckIssue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            FsckIssue::OrphanChunk { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+/// Outcome of [`DataStore::fsck`].
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub sections_checked: u64,
+    pub chunks_checked: u64,
+    /// Problems in the order they were found: sections by `(file_id, offset)`,
+    /// then chunks by hash.
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    /// Issues of severity [`Severity::Error`].
+    pub fn errors(&self) -> impl Iterator<Item = &FsckIssue> {
+        self.with_severity(Severity::Error)
+    }
+
+    /// Issues of severity [`Severity::Warning`].
+    pub fn warnings(&self) -> impl Iterator<Item = &FsckIssue> {
+        self.with_severity(Severity::Warning)
+    }
+
+    /// Whether no errors were found. Warnings do not count.
+    pub fn is_ok(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    fn with_severity(&self, severity: Severity) -> impl Iterator<Item = &FsckIssue> {
+        self.issues
+            .iter()
+            .filter(move |issue| issue.severity() == severity)
+    }
+}
+
+/// A section as read by fsck, with whether its chunk exists.
+#[derive(sqlx::FromRow)]
+struct SectionRow {
+    file_id: String,
+    chunk_hash: Vec<u8>,
+    length: i64,
+    offset: i64,
+    /// 0 or 1; the `Any` driver decodes SQLite booleans as integers.
+    chunk_stored: i64,
+}
+
+/// A chunk as read by fsck, with whether any section refers to it.
+#[derive(sqlx::FromRow)]
+struct ChunkRow {
+    hash: Vec<u8>,
+    size: i64,
+    data: Vec<u8>,
+    referenced: i64,
+}
+
+impl DataStore {
+    /// Checks every section and chunk in the store.
+    ///
+    /// Each file's sections must tile it from offset 0 without gaps or
+    /// overlaps and refer to stored chunks, and each chunk's data must hash to
+    /// its key. Unreferenced chunks are reported as warnings. Chunks whose
+    /// data lives in an external [`ChunkSink`](crate::ChunkSink) have no
+    /// local data and are not hashed.
+    pub async fn fsck(&self) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+
+        // (file_id, offset) of the last section seen, and where the next must start
+        let mut last: Option<(String, i64)> = None;
+        let mut expected = Some(0i64);
+        loop {
+            let page: Vec<SectionRow> = sqlx::query_as(
+                r#"
+                SELECT s.file_id, s.chunk_hash, s.length, s.offset,
+                       c.hash IS NOT NULL AS chunk_stored
+                FROM file_sections s
+                LEFT JOIN chunks c ON c.hash = s.chunk_hash
+                WHERE $1 IS NULL OR s.file_id > $1 OR (s.file_id = $1 AND s.offset > $2)
+                ORDER BY s.file_id, s.offset
+                LIMIT $3
+                "#,
+            )
+            .bind(last.as_ref().map(|(file_id, _)| file_id.clone()))
+            .bind(last.as_ref().map_or(0, |(_, offset)| *offset))
+            .bind(DUMP_PAGE_SIZE)
+            .fetch_all(&mut *tx)
+            .await?;
+            let done = (page.len() as i64) < DUMP_PAGE_SIZE;
+
+            for section in page {
+                report.sections_checked += 1;
+                let same_file = last
+                    .as_ref()
+                    .is_some_and(|(file_id, _)| *file_id == section.file_id);
+                if !same_file {
+                    expected = Some(0);
+                }
+                if expected != Some(section.offset) {
+                    report.issues.push(FsckIssue::Discontiguous {
+                        file_id: section.file_id.clone(),
+                        offset: section.offset,
+                    });
+                }
+                expected = section.offset.checked_add(section.length);
+                if section.chunk_stored == 0 {
+                    report.issues.push(FsckIssue::MissingChunk {
+                        file_id: section.file_id.clone(),
+                        offset: section.offset,
+                        chunk_hash: section.chunk_hash,
+                    });
+                }
+                last = Some((section.file_id, section.offset));
+            }
+            if done {
+                break;
+            }
+        }
+
+        let mut after = Vec::new();
+        loop {
+            let page: Vec<ChunkRow> = sqlx::query_as(
+                r#"
+                SELECT c.hash, c.size, COALESCE(c.data, x'') AS data,
+                       EXISTS (SELECT 1 FROM file_sections s WHERE s.chunk_hash = c.hash) AS referenced
+                FROM chunks c
+                WHERE c.hash > $1
+                ORDER BY c.hash
+                LIMIT $2
+                "#,
+            )
+            .bind(&after)
+            .bind(DUMP_PAGE_SIZE)
+            .fetch_all(&mut *tx)
+            .await?;
+            let done = (page.len() as i64) < DUMP_PAGE_SIZE;
+
+            for chunk in page {
+                report.chunks_checked += 1;
+                let external = chunk.data.is_empty() && chunk.size > 0;
+                if !external
+                    && (chunk.data.len() as i64 != chunk.size
+                        || blake3::hash(&chunk.data).as_bytes()[..] != chunk.hash[..])
+                {
+                    report.issues.push(FsckIssue::CorruptChunk {
+                        hash: chunk.hash.clone(),
+                    });
+                }
+                if chunk.referenced == 0 {
+                    report.issues.push(FsckIssue::OrphanChunk {
+                        hash: chunk.hash.clone(),
+                    });
+                }
+                after = chunk.hash;
+            }
+            if done {
+                break;
+            }
+        }
+
+        tx.commit().await?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChunkTableEntry, FileSectionEntry, FileTableEntry, Persist, setup};
+    use common::FileID;
+
+    fn chunk(data: &[u8]) -> ChunkTableEntry {
+        ChunkTableEntry {
+            hash: blake3::hash(data).as_bytes().to_vec(),
+            size: data.len() as i64,
+            data: data.to_vec(),
+        }
+    }
+
+    fn file(file_id: &str) -> FileTableEntry {
+        FileTableEntry {
+            file_id: file_id.to_string(),
+            name: file_id.to_string(),
+            path: format!("/{file_id}"),
+            hash: vec![0; 32],
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
+        }
+    }
+
+    fn section(file_id: &str, chunk_hash: &[u8], length: i64, offset: i64) -> FileSectionEntry {
+        FileSectionEntry {
+            file_id: file_id.to_string(),
+            chunk_hash: chunk_hash.to_vec(),
+            length,
+            offset,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fsck_reports_each_defect() -> Result<()> {
+        let store = setup().await;
+        store
+            .index_and_store(&FileID::new(), "ok.bin", "/ok.bin", &[7u8; 8192][..], None)
+            .await?;
+        let clean = store.fsck().await?;
+        assert!(clean.issues.is_empty(), "{:?}", clean.issues);
+        assert!(clean.sections_checked > 0);
+
+        let [good, corrupt, orphan] =
+            [&b"good"[..], b"corrupt", b"orphan"].map(|data| chunk(data).hash);
+        store
+            .store_all(vec![
+                chunk(b"good"),
+                ChunkTableEntry {
+                    data: b"CORRUPT".to_vec(),
+                    ..chunk(b"corrupt")
+                },
+                chunk(b"orphan"),
+            ])
+            .await?;
+        store
+            .store_all(vec![file("gappy"), file("dangling")])
+            .await?;
+        store
+            .store_all(vec![
+                section("gappy", &good, 4, 0),
+                section("gappy", &corrupt, 7, 5),
+            ])
+            .await?;
+        // Only a connection without foreign key enforcement can leave a section dangling
+        let missing = blake3::hash(b"missing").as_bytes().to_vec();
+        let mut conn = store.pool().acquire().await?;
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(
+            "INSERT INTO file_sections (file_id, chunk_hash, length, offset) VALUES ('dangling', $1, 7, 0)",
+        )
+        .bind(&missing)
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query("PRAGMA foreign_keys = ON")
+            .execute(&mut *conn)
+            .await?;
+        drop(conn);
+
+        let report = store.fsck().await?;
+        assert_eq!(
+            report.issues[..2],
+            [
+                FsckIssue::MissingChunk {
+                    file_id: "dangling".to_string(),
+                    offset: 0,
+                    chunk_hash: missing,
+                },
+                FsckIssue::Discontiguous {
+                    file_id: "gappy".to_string(),
+                    offset: 5,
+                },
+            ]
+        );
+        // Chunks are checked in hash order
+        let mut chunk_issues = [
+            FsckIssue::CorruptChunk { hash: corrupt },
+            FsckIssue::OrphanChunk { hash: orphan },
+        ];
+        chunk_issues.sort_by_key(|issue| match issue {
+            FsckIssue::CorruptChunk { hash } | FsckIssue::OrphanChunk { hash } => hash.clone(),
+            _ => unreachable!(),
+        });
+        assert_eq!(report.issues[2..], chunk_issues);
+        assert_eq!(report.errors().count(), 3);
+        assert_eq!(report.warnings().count(), 1);
+        assert!(!report.is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Combined ingestion path: chunk a source and persist everything for one file.
+//!
+//! Unlike the individual `Persist` implementations, ingestion writes the file
+//! row and the file's sections in a single transaction. Chunks are streamed in
+//! ahead of it in bounded batches (see [`WriteBatching`]). Runs for the
+//! same `FileID` are serialized through a per-file lock, so two quick watcher
+//! events for the same file cannot interleave their section writes.
+use std::{
+    io::{Cursor, Read},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use common::FileID;
+use sqlx::{Any, Transaction};
+
+use crate::{
+    ChunkConfig, ChunkTableEntry, DataStore, DataStoreError, FileSectionEntry, FileTableEntry,
+    QuickChecksum, Result, StoreEvent, chunk_iter, chunk_store, file_section, file_store,
+};
+
+/// When chunks of a file being ingested are committed ahead of the file itself.
+///
+/// Chunks are flushed in their own transaction once `batch_max_rows` have
+/// accumulated or the batch is `batch_max_age` old, whichever comes first.
+/// The age is checked as chunks arrive. The file row, its sections and any
+/// remaining chunks are always committed together at EOF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteBatching {
+    pub batch_max_rows: usize,
+    pub batch_max_age: Duration,
+}
+
+impl Default for WriteBatching {
+    /// Flush every 1024 chunks (about 1MB with the default chunk sizes) or every second.
+    fn default() -> Self {
+        Self {
+            batch_max_rows: 1024,
+            batch_max_age: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Keeps ingestion from filling up the disk holding the database.
+#[derive(Clone, Debug)]
+pub struct FreeSpaceGuard {
+    /// A directory on the filesystem the database lives on.
+    pub data_dir: PathBuf,
+    /// Free space, in bytes, that must remain after storing a file.
+    pub min_free_bytes: u64,
+}
+
+/// How many leading bytes are inspected to guess a file's content type.
+const SNIFF_LEN: u64 = 8 * 1024;
+
+/// Guesses a MIME type from the leading bytes of a file.
+///
+/// Binary formats are recognized by their magic bytes; anything else that is
+/// valid UTF-8 is reported as `text/plain`.
+pub(crate) fn sniff_content_type(head: &[u8]) -> Option<String> {
+    if let Some(kind) = infer::get(head) {
+        return Some(kind.mime_type().to_string());
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) if !head.is_empty() => Some("text/plain".to_string()),
+        // The sample may end in the middle of a multi-byte character
+        Err(e) if e.error_len().is_none() => Some("text/plain".to_string()),
+        _ => None,
+    }
+}
+
+impl DataStore {
+    /// Refuses to index files that would drop free disk space below `guard.min_free_bytes`.
+    pub fn with_free_space_guard(mut self, guard: FreeSpaceGuard) -> Self {
+        self.free_space_guard = Some(guard);
+        self
+    }
+
+    /// Bounds how many chunks ingestion holds before committing them.
+    pub fn with_write_batching(mut self, batching: WriteBatching) -> Self {
+        self.write_batching = batching;
+        self
+    }
+
+    /// Aborts indexing a file once it has more than `limit` sections.
+    ///
+    /// Guards against a misconfigured chunker, such as a tiny `min_chunk_size`,
+    /// flooding the database with section rows. The check runs as chunks are
+    /// cut, so the file row and its sections are never written. Chunk batches
+    /// already flushed by [`WriteBatching`] stay, unreferenced. `None`, the
+    /// default, allows any number.
+    pub fn with_max_sections_per_file(mut self, limit: Option<usize>) -> Self {
+        self.max_sections_per_file = limit;
+        self
+    }
+
+    /// Fails with [`DataStoreError::TooManySections`] once `count` passes the
+    /// configured limit.
+    pub(crate) fn check_section_count(&self, count: usize) -> Result<()> {
+        match self.max_sections_per_file {
+            Some(limit) if count > limit => Err(DataStoreError::TooManySections { count, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a file of `file_size` bytes can be stored without breaching the guard.
+    ///
+    /// The file size is an upper bound for the new chunk data; deduplication
+    /// usually stores less. Every path that writes to the database checks it
+    /// before each write transaction, with the chunk data it is about to write,
+    /// or 0 when it only writes metadata. Always succeeds when no guard is configured.
+    pub fn ensure_free_space(&self, file_size: u64) -> Result<()> {
+        let Some(guard) = &self.free_space_guard else {
+            return Ok(());
+        };
+        let available = fs2::available_space(&guard.data_dir)?;
+        let needed = file_size.saturating_add(guard.min_free_bytes);
+        if available < needed {
+            return Err(DataStoreError::InsufficientSpace { needed, available });
+        }
+        Ok(())
+    }
+
+    /// Chunks `source` and stores its chunks, file metadata and sections atomically.
+    ///
+    /// Any sections previously stored for `file_id` are replaced, so a file that
+    /// shrank does not keep stale trailing sections. Concurrent calls for the same
+    /// `file_id` run one after another; the later call sees the state committed by
+    /// the earlier one.
+    pub async fn index_and_store<R: Read>(
+        &self,
+        file_id: &FileID,
+        name: &str,
+        path: &str,
+        source: R,
+        chunk_config: Option<ChunkConfig>,
+    ) -> Result<()> {
+        self.with_file_lock(
+            file_id,
+            self.index_and_store_locked(file_id, name, path, source, chunk_config),
+        )
+        .await
+        .map(|_commits| ())
+    }
+
+    /// Stores `bytes` as a new file under a fresh [`FileID`], which is returned.
+    ///
+    /// A convenience over [`DataStore::index_and_store`] for content already
+    /// in memory, such as uploaded blobs.
+    pub async fn ingest_bytes(
+        &self,
+        name: &str,
+        path: &str,
+        bytes: &[u8],
+        chunk_config: Option<ChunkConfig>,
+    ) -> Result<FileID> {
+        let file_id = FileID::new();
+        self.index_and_store(&file_id, name, path, bytes, chunk_config)
+            .await?;
+        Ok(file_id)
+    }
+
+    /// Runs `task` while holding the ingestion lock of `file_id`.
+    pub(crate) async fn with_file_lock<T>(
+        &self,
+        file_id: &FileID,
+        task: impl Future<Output = T>,
+    ) -> T {
+        let lock = self.file_locks.entry(*file_id).or_default().clone();
+        let guard = lock.lock().await;
+
+        let result = task.await;
+
+        drop(guard);
+        drop(lock);
+        // Forget the lock once nobody else is holding or waiting on it
+        self.file_locks
+            .remove_if(file_id, |_, lock| Arc::strong_count(lock) == 1);
+
+        result
+    }
+
+    /// Streams `source` into the store and returns the number of transactions committed.
+    async fn index_and_store_locked<R: Read>(
+        &self,
+        file_id: &FileID,
+        name: &str,
+        path: &str,
+        mut source: R,
+        chunk_config: Option<ChunkConfig>,
+    ) -> Result<usize> {
+        let (head, content_type) = read_head(&mut source)?;
+
+        let WriteBatching {
+            batch_max_rows,
+            batch_max_age,
+        } = self.write_batching;
+        let mut hasher = blake3::Hasher::new();
+        let mut checksum = QuickChecksum::new();
+        let mut file_sections = Vec::new();
+        let mut batch = Vec::new();
+        let mut batch_started = Instant::now();
+        let mut commits = 0;
+
+        let source = Cursor::new(head).chain(source);
+        for chunk in chunk_iter(file_id, source, 0, chunk_config) {
+            let (chunk, section) = chunk?;
+            hasher.update(&chunk.data);
+            checksum.update(&chunk.data);
+            file_sections.push(section);
+            self.check_section_count(file_sections.len())?;
+            batch.push(chunk);
+
+            // Chunks are content-addressed, so committing them early is harmless
+            // even if the rest of the file never arrives
+            if batch.len() >= batch_max_rows || batch_started.elapsed() >= batch_max_age {
+                self.ensure_free_space(batch_bytes(&batch))?;
+                let _slot = self.transaction_slot().await;
+                let mut tx = self.pool().begin().await?;
+                insert_chunks(&mut tx, batch.drain(..)).await?;
+                tx.commit().await?;
+                commits += 1;
+                batch_started = Instant::now();
+            }
+        }
+        let file_hash = hasher.finalize().as_bytes().to_vec();
+
+        self.ensure_free_space(batch_bytes(&batch))?;
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+
+        // Remaining chunks first to satisfy the section foreign keys
+        insert_chunks(&mut tx, batch.drain(..)).await?;
+        let file = FileTableEntry {
+            file_id: file_id.to_string(),
+            name: name.to_string(),
+            path: path.to_string(),
+            hash: file_hash,
+            content_type,
+            chunk_count: 0,
+            last_indexed_at: None,
+        };
+        write_file(
+            &mut tx,
+            &self.sync_set,
+            file,
+            file_sections,
+            Some(checksum.finish()),
+        )
+        .await?;
+
+        tx.commit().await?;
+        self.emit(StoreEvent::FileStored(*file_id));
+        Ok(commits + 1)
+    }
+}
+
+/// Reads the leading bytes of `source` and guesses its content type from them.
+///
+/// The bytes are returned so the caller can chain them back in front of `source`.
+pub(crate) fn read_head<R: Read>(source: &mut R) -> Result<(Vec<u8>, Option<String>)> {
+    let mut head = Vec::new();
+    source.take(SNIFF_LEN).read_to_end(&mut head)?;
+    let content_type = sniff_content_type(&head);
+    Ok((head, content_type))
+}
+
+/// Upserts `file` and replaces its sections with `file_sections`.
+///
+/// `chunk_count` and `last_indexed_at` of `file` are ignored and refreshed
+/// from the new sections instead. A new file joins `sync_set`.
+/// `quick_checksum` replaces the stored one, if any.
+pub(crate) async fn write_file(
+    tx: &mut Transaction<'_, Any>,
+    sync_set: &str,
+    file: FileTableEntry,
+    file_sections: Vec<FileSectionEntry>,
+    quick_checksum: Option<u32>,
+) -> Result<()> {
+    sqlx::query(file_store::UPSERT_QUERY)
+        .bind(&file.file_id)
+        .bind(file.name)
+        .bind(file.path)
+        .bind(file.hash)
+        .bind(file.content_type)
+        .bind(sync_set)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM file_sections WHERE file_id = $1")
+        .bind(&file.file_id)
+        .execute(&mut **tx)
+        .await?;
+
+    let chunk_count = file_sections.len();
+    for section in file_sections {
+        sqlx::query(file_section::UPSERT_QUERY)
+            .bind(section.file_id)
+            .bind(section.chunk_hash)
+            .bind(section.length)
+            .bind(section.offset)
+            .execute(&mut **tx)
+            .await?;
+    }
+    file_store::record_indexed(tx, &file.file_id, chunk_count, quick_checksum).await
+}
+
+/// Bytes of chunk data in `batch`.
+fn batch_bytes(batch: &[ChunkTableEntry]) -> u64 {
+    batch.iter().map(|chunk| chunk.size as u64).sum()
+}
+
+async fn insert_chunks(
+    tx: &mut Transaction<'_, Any>,
+    chunks: impl Iterator<Item = ChunkTableEntry>,
+) -> Result<()> {
+    for chunk in chunks {
+        let checksum = chunk_store::stored_checksum(&chunk.data);
+        sqlx::query(chunk_store::INSERT_QUERY)
+            .bind(chunk.hash)
+            .bind(chunk.size)
+            .bind(chunk.data)
+            .bind(checksum)
+            .bind(chunk_store::CHECKSUM_ALGO)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_ingest_bytes_round_trip() -> Result<()> {
+        let store = setup().await;
+        let data: Vec<u8> = (0..20_000).map(|i| (i * 13 % 256) as u8).collect();
+
+        let file_id = store
+            .ingest_bytes("blob", "/uploads/blob", &data, None)
+            .await?;
+        let mut restored = Vec::new();
+        store
+            .reconstruct_file(&file_id, &mut restored, None)
+            .await?;
+        assert_eq!(restored, data);
+
+        let other = store
+            .ingest_bytes("empty", "/uploads/empty", &[], None)
+            .await?;
+        assert_ne!(other, file_id);
+        assert_eq!(store.file_size(&other).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_refused_below_min_free_space() -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::write(dir.path().join("a.bin"), vec![7u8; 4096])?;
+
+        // No filesystem has this much room left over, so indexing must be refused
+        let store = setup().await.with_free_space_guard(FreeSpaceGuard {
+            data_dir: dir.path().to_path_buf(),
+            min_free_bytes: u64::MAX / 2,
+        });
+        let err = store
+            .index_directory(dir.path(), None, Default::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::InsufficientSpace { .. }));
+        assert_eq!(store.physical_chunk_count().await?, 0);
+
+        let store = store.with_free_space_guard(FreeSpaceGuard {
+            data_dir: dir.path().to_path_buf(),
+            min_free_bytes: 1,
+        });
+        let report = store
+            .index_directory(dir.path(), None, Default::default())
+            .await?;
+        assert_eq!(report.indexed.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunks_flushed_in_batches() -> Result<()> {
+        let store = setup().await.with_write_batching(WriteBatching {
+            batch_max_rows: 4,
+            batch_max_age: Duration::from_secs(3600),
+        });
+        let file_id = FileID::new();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+
+        let commits = store
+            .index_and_store_locked(&file_id, "big.bin", "/big.bin", &data[..], None)
+            .await?;
+        let sections = store.logical_section_count().await?;
+        assert!(sections > 8, "Expected many chunks, got {sections}");
+        // One commit per full batch plus the final one with the file row
+        assert_eq!(commits as i64, sections / 4 + 1);
+
+        assert_eq!(
+            store.read_range(&file_id, 0, data.len() as u64).await?,
+            data
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_too_many_sections_aborts_before_commit() -> Result<()> {
+        let store = setup().await.with_max_sections_per_file(Some(16));
+        let tiny = ChunkConfig {
+            min_chunk_size: 64,
+            avg_chunk_size: 256,
+            max_chunk_size: 1024,
+            ..Default::default()
+        };
+        let file_id = FileID::new();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+
+        let err = store
+            .index_and_store(&file_id, "tiny.bin", "/tiny.bin", &data[..], Some(tiny))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            DataStoreError::TooManySections {
+                count: 17,
+                limit: 16
+            }
+        ));
+        assert_eq!(store.physical_chunk_count().await?, 0);
+        assert_eq!(store.logical_section_count().await?, 0);
+        let files: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM files")
+            .fetch_one(&store.pool())
+            .await?;
+        assert_eq!(files, 0);
+
+        // Without a limit the same file is stored as usual
+        let store = store.with_max_sections_per_file(None);
+        store
+            .index_and_store(&file_id, "tiny.bin", "/tiny.bin", &data[..], Some(tiny))
+            .await?;
+        assert!(store.logical_section_count().await? > 16);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_every_ingest_path_checks_free_space() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = setup().await.with_free_space_guard(FreeSpaceGuard {
+            data_dir: dir.path().to_path_buf(),
+            min_free_bytes: u64::MAX / 2,
+        });
+
+        let err = store
+            .ingest_bytes("blob", "/blob", &[9u8; 4096], None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::InsufficientSpace { .. }));
+
+        let sink = crate::MemoryChunkSink::new();
+        let err = store
+            .index_to_sink(&sink, &FileID::new(), "s", "/s", &[9u8; 4096][..], None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::InsufficientSpace { .. }));
+
+        let other = setup().await;
+        other
+            .ingest_bytes("blob", "/blob", &[9u8; 4096], None)
+            .await?;
+        let err = store.merge_from(&other).await.unwrap_err();
+        assert!(matches!(err, DataStoreError::InsufficientSpace { .. }));
+
+        assert_eq!(store.physical_chunk_count().await?, 0);
+        assert_eq!(store.logical_section_count().await?, 0);
+        Ok(())
+    }
+}
@@ -1,22 +1,76 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 mod chunk_store;
+mod chunker;
+mod db_file;
+mod diff;
+mod dump;
+mod events;
+mod eviction;
+mod failed_index;
 mod file_path;
 mod file_section;
 mod file_store;
+mod fsck;
+mod ingest;
+mod manifest;
+mod merge;
+mod meta;
+mod options;
+mod quick_checksum;
+mod reader;
+mod reconstruct;
+mod scan;
+mod schema;
+mod sink;
+mod stream;
+mod sync_set;
+mod temp;
+mod transaction;
+mod wal;
 
-use blake3::CHUNK_LEN;
 pub use chunk_store::*;
+pub use chunker::*;
+pub use diff::*;
+pub use dump::*;
+pub use events::*;
+pub use eviction::*;
+pub use failed_index::*;
+pub use file_path::*;
 pub use file_section::*;
 pub use file_store::*;
+pub use fsck::*;
+pub use ingest::*;
+pub use manifest::*;
+pub use merge::*;
+pub use options::*;
+pub use quick_checksum::*;
+pub use reader::*;
+pub use scan::*;
+pub use sink::*;
+pub use stream::*;
+pub use sync_set::*;
+pub use temp::*;
+pub use transaction::*;
+pub use wal::*;
 
 use async_trait::async_trait;
-use common::FileID;
-use fastcdc::v2020::StreamCDC;
+use common::{ChunkIndex, FileID};
+use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use sqlx::{AnyPool, migrate::MigrateError};
-use std::io::Read;
+use sqlx::{
+    AnyPool, Executor,
+    any::{AnyPoolOptions, install_default_drivers},
+    migrate::MigrateError,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::{Arc, PoisonError, RwLock},
+};
 use thiserror::Error;
+use tokio::sync::{Mutex, Semaphore, broadcast};
 
 /// A Result type specialized for DataStore operations.
 pub(crate) type Result<T> = std::result::Result<T, DataStoreError>;
@@ -25,7 +79,8 @@ pub(crate) type Result<T> = std::result::Result<T, DataStoreError>;
 ///
 /// These values determine the granularity of the deduplication. Smaller chunks
 /// provide better deduplication ratios but increase database metadata overhead.
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ChunkConfig {
     /// The minimum size of a chunk in bytes.
     pub min_chunk_size: u32,
@@ -34,17 +89,100 @@ pub struct ChunkConfig {
     pub avg_chunk_size: u32,
     /// The maximum size a chunk can reach before being forced to cut.
     pub max_chunk_size: u32,
+    /// Hash chunks of at least `parallel_hash_threshold` bytes across the rayon pool.
+    /// Only pays off for large chunks, such as archival presets with 256KB max chunks.
+    pub hash_large_chunks_in_parallel: bool,
+    /// The chunk size in bytes from which parallel hashing kicks in.
+    pub parallel_hash_threshold: u32,
+    /// The algorithm that finds chunk boundaries.
+    pub chunker: ChunkerKind,
+    /// How tightly FastCDC 2020 keeps chunks near `avg_chunk_size`, from 0 to
+    /// [`MAX_NORMALIZATION_LEVEL`]. Higher levels narrow the size spread at some
+    /// cost in throughput. Other chunkers ignore it.
+    pub normalization_level: u8,
 }
 
+/// Highest normalization level FastCDC 2020 supports.
+pub const MAX_NORMALIZATION_LEVEL: u8 = 3;
+
+/// The level `fastcdc::v2020::StreamCDC::new` picks, kept as the default so
+/// existing indexes keep their boundaries.
+pub const DEFAULT_NORMALIZATION_LEVEL: u8 = 1;
+
 impl Default for ChunkConfig {
     /// Returns the recommended default settings for general-purpose file sync.
-    /// (512B min, 1KB avg, 2KB max)
+    /// (512B min, 1KB avg, 2KB max, shared through `common`)
     fn default() -> Self {
         Self {
-            min_chunk_size: 512,
-            avg_chunk_size: 1024,
-            max_chunk_size: 2048,
+            min_chunk_size: common::DEFAULT_MIN_CHUNK_SIZE,
+            avg_chunk_size: common::DEFAULT_AVG_CHUNK_SIZE,
+            max_chunk_size: common::DEFAULT_MAX_CHUNK_SIZE,
+            hash_large_chunks_in_parallel: false,
+            parallel_hash_threshold: 128 * 1024,
+            chunker: ChunkerKind::default(),
+            normalization_level: DEFAULT_NORMALIZATION_LEVEL,
+        }
+    }
+}
+
+impl ChunkConfig {
+    /// Whether switching from `other` to `self` moves chunk boundaries.
+    ///
+    /// Only the chunker and its size limits decide where cuts fall. The parallel hashing
+    /// settings change how fast a chunk is hashed, never its digest, so
+    /// configs differing only there can share an index without re-indexing.
+    pub fn affects_chunking(&self, other: &Self) -> bool {
+        self.min_chunk_size != other.min_chunk_size
+            || self.avg_chunk_size != other.avg_chunk_size
+            || self.max_chunk_size != other.max_chunk_size
+            || self.chunker != other.chunker
+            || self.normalization_level != other.normalization_level
+    }
+
+    /// A stable name for everything [`ChunkConfig::affects_chunking`] looks at,
+    /// e.g. `fastcdc2020/512/1024/2048`. Two configs with the same protocol
+    /// cut any input at the same boundaries. A non-default normalization level
+    /// is appended as e.g. `/nc3`, so protocols recorded before it existed still match.
+    pub fn chunk_protocol(&self) -> String {
+        let chunker = match self.chunker {
+            ChunkerKind::FastCdc2020 => "fastcdc2020",
+            ChunkerKind::FastCdc2016 => "fastcdc2016",
+            ChunkerKind::FixedSize => "fixed",
+        };
+        let mut protocol = format!(
+            "{chunker}/{}/{}/{}",
+            self.min_chunk_size, self.avg_chunk_size, self.max_chunk_size
+        );
+        if self.normalization_level != DEFAULT_NORMALIZATION_LEVEL {
+            protocol.push_str(&format!("/nc{}", self.normalization_level));
+        }
+        protocol
+    }
+
+    /// Rejects a normalization level FastCDC does not support.
+    pub fn validate(&self) -> Result<()> {
+        if self.normalization_level > MAX_NORMALIZATION_LEVEL {
+            return Err(DataStoreError::InvalidNormalizationLevel {
+                level: self.normalization_level,
+            });
         }
+        Ok(())
+    }
+}
+
+/// Hashes a single chunk, using multiple threads for large chunks if configured.
+///
+/// Both paths produce the same digest; the parallel one just gets there faster
+/// on chunks well above BLAKE3's 128KiB parallelism sweet spot.
+pub fn hash_chunk(data: &[u8], chunk_config: &ChunkConfig) -> blake3::Hash {
+    if chunk_config.hash_large_chunks_in_parallel
+        && data.len() >= chunk_config.parallel_hash_threshold as usize
+    {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_rayon(data);
+        hasher.finalize()
+    } else {
+        blake3::hash(data)
     }
 }
 
@@ -77,37 +215,317 @@ pub fn chunk_source<R: Read>(
     source: R,
     chunk_config: Option<ChunkConfig>,
 ) -> Result<ChunkedSource> {
+    chunk_from_offset(file_id, source, 0, chunk_config)
+}
+
+/// Like [`chunk_source`], but starts chunking `start_offset` bytes into the source.
+///
+/// Used to resume an interrupted or append-only index: the source is seeked to
+/// `start_offset` and every emitted section offset is absolute, so the result
+/// continues seamlessly from sections already stored for the prefix. For this to
+/// line up, `start_offset` should be a cut point of the previous index (the end
+/// of its last complete section). `file_hash` covers only the bytes read.
+pub fn chunk_source_from<R: Read + Seek>(
+    file_id: &FileID,
+    mut source: R,
+    start_offset: u64,
+    chunk_config: Option<ChunkConfig>,
+) -> Result<ChunkedSource> {
+    source.seek(SeekFrom::Start(start_offset))?;
+    chunk_from_offset(file_id, source, start_offset, chunk_config)
+}
+
+/// How well content-defined chunking found cut points in a source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChunkStats {
+    /// Number of chunks produced.
+    pub chunk_count: usize,
+    /// Chunks that are exactly `max_chunk_size` long, i.e. cut by force.
+    pub forced_cuts: usize,
+    /// Bytes covered by all chunks together, i.e. the source length.
+    pub total_bytes: u64,
+    /// Length of the shortest chunk, or 0 without chunks.
+    pub min_size: u64,
+    /// Length of the longest chunk.
+    pub max_size: u64,
+}
+
+impl ChunkStats {
+    /// Counts one more chunk of `len` bytes.
+    fn record(&mut self, len: u64, max_chunk_size: u64) {
+        self.min_size = if self.chunk_count == 0 {
+            len
+        } else {
+            self.min_size.min(len)
+        };
+        self.max_size = self.max_size.max(len);
+        self.chunk_count += 1;
+        self.total_bytes += len;
+        if len == max_chunk_size {
+            self.forced_cuts += 1;
+        }
+    }
+
+    /// Mean chunk length, or 0.0 without chunks.
+    pub fn avg_size(&self) -> f64 {
+        if self.chunk_count == 0 {
+            return 0.0;
+        }
+        self.total_bytes as f64 / self.chunk_count as f64
+    }
+
+    /// Share of chunks that ended at a real content-defined cut point.
+    ///
+    /// 1.0 is healthy; values towards 0.0 mean most chunks were cut at
+    /// `max_chunk_size` (e.g. long runs of identical bytes), so deduplication
+    /// degrades to fixed-size blocks. A source without chunks counts as healthy.
+    pub fn cut_point_health(&self) -> f64 {
+        if self.chunk_count == 0 {
+            return 1.0;
+        }
+        1.0 - self.forced_cuts as f64 / self.chunk_count as f64
+    }
+}
+
+/// Like [`chunk_source`], but also reports [`ChunkStats`] about the cut points.
+pub fn chunk_source_with_stats<R: Read>(
+    file_id: &FileID,
+    source: R,
+    chunk_config: Option<ChunkConfig>,
+) -> Result<(ChunkedSource, ChunkStats)> {
+    let max_chunk_size = chunk_config.unwrap_or_default().max_chunk_size as u64;
+    let chunked = chunk_source(file_id, source, chunk_config)?;
+
+    let mut stats = ChunkStats::default();
+    for chunk in &chunked.chunks {
+        stats.record(chunk.size as u64, max_chunk_size);
+    }
+    Ok((chunked, stats))
+}
+
+/// Dry-runs chunking over `source` and reports [`ChunkStats`] only.
+///
+/// Nothing is hashed or stored, and each chunk is dropped as soon as it is
+/// measured, so this is cheap enough for tuning chunk sizes on large files.
+pub fn analyze_chunks<R: Read>(source: R, chunk_config: Option<ChunkConfig>) -> Result<ChunkStats> {
     let chunk_config = chunk_config.unwrap_or_default();
+    let mut stats = ChunkStats::default();
+    for chunk in chunk_config.chunks(source) {
+        stats.record(chunk?.data.len() as u64, chunk_config.max_chunk_size as u64);
+    }
+    Ok(stats)
+}
 
-    let ChunkConfig {
-        min_chunk_size,
-        avg_chunk_size,
-        max_chunk_size,
-    } = chunk_config;
+/// While reusing hashes, every this many chunks one is hashed anyway as a spot check.
+const REUSE_SPOT_CHECK_INTERVAL: usize = 16;
 
-    let mut hasher = blake3::Hasher::new();
+/// How many chunk hashes [`chunk_source_reusing`] or [`chunk_source_warm`]
+/// took over instead of computing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HashReuse {
+    /// Chunks whose hash was copied from the prior manifest.
+    pub reused: usize,
+    /// Chunks that were hashed.
+    pub hashed: usize,
+}
 
-    let chunker = StreamCDC::new(source, min_chunk_size, avg_chunk_size, max_chunk_size);
+/// Like [`chunk_source`], but reuses chunk hashes from `prior`, the file's
+/// previous manifest, for the unchanged prefix of the file.
+///
+/// Meant for append-heavy files: the source is still read in full to advance
+/// FastCDC and to compute `file_hash`. For the leading run of chunks that start
+/// and end exactly where a prior chunk did, the chunk's xxh3 is compared with
+/// the prior chunk's entry in `stored_checksums` (see
+/// [`DataStore::stored_checksums`]); only if they agree is the prior hash taken
+/// without hashing the chunk. A chunk that differs or has no known checksum is
+/// hashed. Every 16th chunk of the run is hashed anyway as a spot check; if one
+/// disagrees with its prior hash, reuse stops for the rest of the file.
+///
+/// Matching boundaries alone say nothing about the bytes between them: an
+/// in-place overwrite keeps every cut point. The checksum catches such edits,
+/// but xxh3 is not cryptographic, so do not use this on adversarial input.
+pub fn chunk_source_reusing<R: Read>(
+    file_id: &FileID,
+    source: R,
+    chunk_config: Option<ChunkConfig>,
+    prior: &BTreeMap<ChunkIndex, ChunkMetadata>,
+    stored_checksums: &HashMap<Vec<u8>, i64>,
+) -> Result<(ChunkedSource, HashReuse)> {
+    let chunk_config = chunk_config.unwrap_or_default();
+    let chunker = chunk_config.chunks(source);
+
+    let mut hasher = blake3::Hasher::new();
     let mut chunks = Vec::new();
     let mut file_sections = Vec::new();
+    let mut reuse = HashReuse::default();
+    let mut prior = prior.values();
+    let mut reusing = true;
 
-    for chunk in chunker {
+    for (index, chunk) in chunker.enumerate() {
         let chunk = chunk?;
         hasher.update(&chunk.data);
 
-        let hash = hasher.finalize().as_bytes().to_vec();
-
-        chunks.push(ChunkTableEntry {
-            hash: hash.clone(),
-            size: chunk.length as i64,
+        let matching = prior
+            .next()
+            .filter(|prev| prev.offset == chunk.offset && prev.length == chunk.data.len() as u64);
+        reusing &= matching.is_some();
+        let unchanged = matching.filter(|prev| {
+            stored_checksums.get(&prev.hash) == Some(&chunk_store::stored_checksum(&chunk.data))
         });
 
-        file_sections.push(FileSectionEntry {
-            file_id: file_id.to_string(),
-            chunk_hash: hash,
-            length: chunk.length as i64,
-            offset: chunk.offset as i64,
+        let hash = match unchanged {
+            Some(prev) if reusing && index % REUSE_SPOT_CHECK_INTERVAL != 0 => {
+                reuse.reused += 1;
+                prev.hash.clone()
+            }
+            _ => {
+                reuse.hashed += 1;
+                let hash = hash_chunk(&chunk.data, &chunk_config).as_bytes().to_vec();
+                if unchanged.is_some_and(|prev| prev.hash != hash) {
+                    reusing = false;
+                }
+                hash
+            }
+        };
+
+        let (chunk, section) = chunk_entries(file_id, hash, chunk, 0)?;
+        chunks.push(chunk);
+        file_sections.push(section);
+    }
+
+    let chunked = ChunkedSource {
+        chunks,
+        file_sections,
+        file_hash: hasher.finalize().as_bytes().to_vec(),
+    };
+    Ok((chunked, reuse))
+}
+
+/// Like [`chunk_source_reusing`], but also reuses hashes for the unchanged
+/// tail of the file, so an edit in the middle only costs hashing around it.
+///
+/// The prefix is the leading run of chunks that start and end where the prior
+/// chunk at the same index did. The suffix is the trailing run whose lengths
+/// match the prior trailing chunks and whose offsets match once shifted by the
+/// change in file length. An insertion or deletion moves the cut points right
+/// after it, but content-defined cuts fall back in step within a few chunks,
+/// so the rest of the file lines up again as the suffix. Everything between
+/// the two runs is hashed, and sections are numbered by their new position.
+///
+/// Lining up is only a candidate: a chunk in either run is reused only if its
+/// xxh3 matches the prior chunk's entry in `stored_checksums`, so an in-place
+/// overwrite, which keeps every boundary, is hashed like any other change.
+/// Within each run the chunk bordering the changed middle and every 16th chunk
+/// are hashed anyway; if one disagrees with its prior hash, reuse ends for the
+/// rest of that run.
+///
+/// Limits of the heuristic: the whole source is chunked before any hashing, so
+/// its chunk data is held in memory at once, as [`chunk_source`] does too.
+/// Fixed-size chunking never falls back in step after an insertion, so there
+/// only the prefix is reused. As with [`chunk_source_reusing`], xxh3 is not
+/// cryptographic, so do not use this on adversarial input.
+pub fn chunk_source_warm<R: Read>(
+    file_id: &FileID,
+    source: R,
+    chunk_config: Option<ChunkConfig>,
+    prior: &BTreeMap<ChunkIndex, ChunkMetadata>,
+    stored_checksums: &HashMap<Vec<u8>, i64>,
+) -> Result<(ChunkedSource, HashReuse)> {
+    let chunk_config = chunk_config.unwrap_or_default();
+
+    let mut hasher = blake3::Hasher::new();
+    let mut cut = Vec::new();
+    for chunk in chunk_config.chunks(source) {
+        let chunk = chunk?;
+        hasher.update(&chunk.data);
+        cut.push(chunk);
+    }
+
+    let prior: Vec<&ChunkMetadata> = prior.values().collect();
+    let new_len = cut.last().map_or(0, |c| c.offset + c.data.len() as u64);
+    let old_len = prior.last().map_or(0, |c| c.offset + c.length);
+
+    let prefix = cut
+        .iter()
+        .zip(&prior)
+        .take_while(|(chunk, prev)| {
+            prev.offset == chunk.offset && prev.length == chunk.data.len() as u64
+        })
+        .count();
+    // Compared as `new - new_len == old - old_len`, rearranged to stay unsigned
+    let suffix = cut[prefix..]
+        .iter()
+        .rev()
+        .zip(prior[prefix..].iter().rev())
+        .take_while(|(chunk, prev)| {
+            prev.length == chunk.data.len() as u64
+                && chunk.offset + old_len == prev.offset + new_len
+        })
+        .count();
+
+    let mut reuse = HashReuse::default();
+    let mut hashes: Vec<Option<Vec<u8>>> = vec![None; cut.len()];
+    let prefix_run: Vec<_> = (0..prefix).map(|i| (i, prior[i])).collect();
+    let suffix_run: Vec<_> = (1..=suffix)
+        .map(|back| (cut.len() - back, prior[prior.len() - back]))
+        .collect();
+    for run in [prefix_run, suffix_run] {
+        let edge = run.len().saturating_sub(1);
+        let mut reusing = true;
+        for (step, (index, prev)) in run.into_iter().enumerate() {
+            let unchanged = stored_checksums.get(&prev.hash)
+                == Some(&chunk_store::stored_checksum(&cut[index].data));
+            if reusing && unchanged && step % REUSE_SPOT_CHECK_INTERVAL != 0 && step != edge {
+                reuse.reused += 1;
+                hashes[index] = Some(prev.hash.clone());
+            } else {
+                reuse.hashed += 1;
+                let hash = hash_chunk(&cut[index].data, &chunk_config)
+                    .as_bytes()
+                    .to_vec();
+                if unchanged {
+                    reusing &= hash == prev.hash;
+                }
+                hashes[index] = Some(hash);
+            }
+        }
+    }
+
+    let mut chunks = Vec::with_capacity(cut.len());
+    let mut file_sections = Vec::with_capacity(cut.len());
+    for (chunk, hash) in cut.into_iter().zip(hashes) {
+        let hash = hash.unwrap_or_else(|| {
+            reuse.hashed += 1;
+            hash_chunk(&chunk.data, &chunk_config).as_bytes().to_vec()
         });
+        let (chunk, section) = chunk_entries(file_id, hash, chunk, 0)?;
+        chunks.push(chunk);
+        file_sections.push(section);
+    }
+
+    let chunked = ChunkedSource {
+        chunks,
+        file_sections,
+        file_hash: hasher.finalize().as_bytes().to_vec(),
+    };
+    Ok((chunked, reuse))
+}
+
+fn chunk_from_offset<R: Read>(
+    file_id: &FileID,
+    source: R,
+    start_offset: u64,
+    chunk_config: Option<ChunkConfig>,
+) -> Result<ChunkedSource> {
+    let mut hasher = blake3::Hasher::new();
+    let mut chunks = Vec::new();
+    let mut file_sections = Vec::new();
+
+    for chunk in chunk_iter(file_id, source, start_offset, chunk_config) {
+        let (chunk, section) = chunk?;
+        hasher.update(&chunk.data);
+        chunks.push(chunk);
+        file_sections.push(section);
     }
     let file_hash = hasher.finalize().as_bytes().to_vec();
 
@@ -118,6 +536,51 @@ pub fn chunk_source<R: Read>(
     })
 }
 
+/// Lazily chunks `source`, yielding each chunk together with the section placing it.
+///
+/// The building block of [`chunk_source`] for callers that persist chunks as
+/// they go instead of collecting the whole file first.
+pub(crate) fn chunk_iter<'a, R: Read + 'a>(
+    file_id: &FileID,
+    source: R,
+    start_offset: u64,
+    chunk_config: Option<ChunkConfig>,
+) -> impl Iterator<Item = Result<(ChunkTableEntry, FileSectionEntry)>> + 'a {
+    let chunk_config = chunk_config.unwrap_or_default();
+    let file_id = *file_id;
+
+    chunk_config.chunks(source).map(move |chunk| {
+        let chunk = chunk?;
+
+        // Chunks are keyed by their own content so identical data deduplicates
+        let hash = hash_chunk(&chunk.data, &chunk_config).as_bytes().to_vec();
+        chunk_entries(&file_id, hash, chunk, start_offset)
+    })
+}
+
+/// Splits a chunk into its chunk row and the section placing it in the file.
+fn chunk_entries(
+    file_id: &FileID,
+    hash: Vec<u8>,
+    chunk: Chunk,
+    start_offset: u64,
+) -> Result<(ChunkTableEntry, FileSectionEntry)> {
+    let offset =
+        start_offset
+            .checked_add(chunk.offset)
+            .ok_or(DataStoreError::SectionBoundOutOfRange {
+                value: i128::from(start_offset) + i128::from(chunk.offset),
+            })?;
+    let length = Length::new(chunk.data.len() as u64)?;
+    let section = FileSectionEntry::new(file_id, hash.clone(), Offset::new(offset)?, length)?;
+    let chunk = ChunkTableEntry {
+        hash,
+        size: section.length,
+        data: chunk.data,
+    };
+    Ok((chunk, section))
+}
+
 /// `DataStore` is the central "Universal Hub" for database interactions.
 ///
 /// ### Architectural Intent:
@@ -130,17 +593,120 @@ pub fn chunk_source<R: Read>(
 /// 1. Connection management is centralized.
 /// 2. We avoid "Borrow Checker" hell by passing an immutable reference (`&self`).
 pub struct DataStore {
-    pool: AnyPool,
+    /// Swapped out by [`DataStore::reopen_if_replaced`]; read it through
+    /// [`DataStore::pool`]. Shared with the WAL maintenance task, which reads
+    /// the current pool on every tick.
+    pool: Arc<RwLock<AnyPool>>,
+    /// Per-file ingestion locks, so only one index of a given file runs at a time.
+    file_locks: DashMap<FileID, Arc<Mutex<()>>>,
+    free_space_guard: Option<FreeSpaceGuard>,
+    write_batching: WriteBatching,
+    /// Sections one file may have; see [`DataStore::with_max_sections_per_file`].
+    max_sections_per_file: Option<usize>,
+    /// The file opened by [`DataStore::with_options`], to notice replacement.
+    db_file: Option<db_file::DbFile>,
+    /// Sync set new files are stored in; see [`DataStore::with_sync_set`].
+    sync_set: String,
+    /// Bounds open transactions; see [`DataStore::with_max_transactions`].
+    transaction_slots: Arc<Semaphore>,
+    /// Committed changes; see [`DataStore::subscribe`].
+    events: broadcast::Sender<StoreEvent>,
 }
 
 impl DataStore {
     /// Initializes a new DataStore and runs migrations.
     /// Ensures the 3NF schema is ready before any operations begin.
+    ///
+    /// The pool's connections are used as configured. SQLite only enforces
+    /// foreign keys with `PRAGMA foreign_keys = ON`, which
+    /// [`DataStore::with_options`] sets on every connection; a warning is
+    /// logged if the pool left them off.
     pub async fn new(pool: AnyPool) -> Result<Self> {
         let migrator = sqlx::migrate!("db/migrations");
         migrator.run(&pool).await?;
+        warn_if_foreign_keys_off(&pool).await?;
+        let transaction_slots =
+            Arc::new(Semaphore::new(transaction::default_max_transactions(&pool)));
 
-        Ok(Self { pool })
+        Ok(Self {
+            pool: Arc::new(RwLock::new(pool)),
+            file_locks: DashMap::new(),
+            free_space_guard: None,
+            write_batching: WriteBatching::default(),
+            max_sections_per_file: None,
+            db_file: None,
+            sync_set: DEFAULT_SYNC_SET.to_string(),
+            transaction_slots,
+            events: broadcast::channel(STORE_EVENT_CAPACITY).0,
+        })
+    }
+
+    /// Connects to the SQLite database at `url` and applies `pragmas` to every
+    /// pooled connection before running migrations.
+    ///
+    /// The pool holds up to [`DEFAULT_POOL_SIZE`] connections, so with the
+    /// default WAL journal reads are not blocked by an open write transaction.
+    ///
+    /// A file-backed store remembers which file it opened; see
+    /// [`DataStore::reopen_if_replaced`].
+    pub async fn with_options(url: &str, pragmas: SqlitePragmas) -> Result<Self> {
+        let pool = Self::connect(url, pragmas).await?;
+        let mut store = Self::new(pool).await?;
+        store.db_file = db_file::DbFile::open(url, pragmas)?;
+        Ok(store)
+    }
+
+    /// The pool queries run on. Cheap to clone, and a clone keeps working on
+    /// the old database if the store reopens meanwhile.
+    fn pool(&self) -> AnyPool {
+        self.pool
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    async fn connect(url: &str, pragmas: SqlitePragmas) -> Result<AnyPool> {
+        install_default_drivers();
+        let statements = pragmas.statements();
+        let pool = AnyPoolOptions::new()
+            .max_connections(DEFAULT_POOL_SIZE)
+            .after_connect(move |conn, _meta| {
+                let statements = statements.clone();
+                Box::pin(async move {
+                    for statement in &statements {
+                        conn.execute(statement.as_str()).await?;
+                    }
+                    Ok(())
+                })
+            })
+            .connect(url)
+            .await?;
+        Ok(pool)
+    }
+
+    /// Durability barrier: moves everything committed so far into the main database file.
+    ///
+    /// Commits in WAL mode may only reach the write-ahead log; this runs a
+    /// `FULL` checkpoint so they are in the database itself. Call it at chosen
+    /// points, such as after a large `store_all`, rather than on every commit.
+    /// A no-op on backends other than SQLite.
+    pub async fn flush(&self) -> Result<()> {
+        let mut conn = self.pool().acquire().await?;
+        if conn.backend_name() != "SQLite" {
+            return Ok(());
+        }
+
+        let (busy, _, _): (i64, i64, i64) = sqlx::query_as("PRAGMA wal_checkpoint(FULL)")
+            .fetch_one(&mut *conn)
+            .await?;
+        if busy != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "WAL checkpoint was blocked by another connection",
+            )
+            .into());
+        }
+        Ok(())
     }
 }
 
@@ -157,8 +723,25 @@ impl DataStore {
 pub trait Persist<Data: Send + Sync> {
     /// DOD Batch Insert: Processes a collection of data in a single transaction.
     /// Best used for high-throughput operations like initial folder scans.
+    ///
+    /// ### Cancel Safety:
+    /// Dropping the future before it completes rolls the transaction back, so
+    /// either every item is committed or none is.
     async fn store_all(&self, items: Vec<Data>) -> Result<()>;
 
+    /// Like `store_all`, returning how many items it set out to store.
+    ///
+    /// Since a cancelled `store_all` commits nothing, a caller that records
+    /// this count before awaiting knows how much work a cancellation undid.
+    async fn store_all_checked(&self, items: Vec<Data>) -> Result<usize>
+    where
+        Data: 'async_trait,
+    {
+        let intended = items.len();
+        self.store_all(items).await?;
+        Ok(intended)
+    }
+
     /// Atomic Single Insert: Persists a single record to the database.
     /// Best used for incremental updates or low-frequency events.
     async fn store(&self, item: Data) -> Result<()>;
@@ -195,16 +778,84 @@ pub enum DataStoreError {
     MigrationError(#[from] MigrateError),
     #[error("Requested record was not found in the store")]
     NotFound,
+    #[error("I/O Error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serialization Error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Invalid FileID: {0}")]
+    InvalidFileId(#[from] uuid::Error),
+    #[error("Stored data of chunk {hash:02x?} does not match its checksum")]
+    Corrupt { hash: Vec<u8> },
+    #[error("Not enough disk space: {needed} bytes needed, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    #[error("{} chunks are missing from the store: {hashes:02x?}", hashes.len())]
+    MissingChunks { hashes: Vec<Vec<u8>> },
+    #[error("Section at offset {offset} is {expected} bytes long, but its chunk holds {actual}")]
+    LengthMismatch {
+        offset: u64,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Section lengths overflow the file offset range after offset {offset}")]
+    OffsetOverflow { offset: i64 },
+    #[error("{value} is out of range for a section offset or length")]
+    SectionBoundOutOfRange { value: i128 },
+    #[error("Reconstructed file does not match its recorded hash; kept at {}", path.display())]
+    ReconstructedHashMismatch { path: PathBuf },
+    #[error("Normalization level {level} is outside 0..={MAX_NORMALIZATION_LEVEL}")]
+    InvalidNormalizationLevel { level: u8 },
+    #[error("File was cut into {count} sections, more than the limit of {limit}")]
+    TooManySections { count: usize, limit: usize },
+    #[error(
+        "Database file {} was replaced, but the old write-ahead log could not be emptied",
+        path.display()
+    )]
+    ReopenBlocked { path: PathBuf },
 }
 
+/// Logs a warning if a SQLite connection from `pool` does not enforce
+/// foreign keys, so sections could point at chunks that are gone.
+async fn warn_if_foreign_keys_off(pool: &AnyPool) -> Result<()> {
+    let mut conn = pool.acquire().await?;
+    if conn.backend_name() != "SQLite" {
+        return Ok(());
+    }
+    let enabled: i64 = sqlx::query_scalar("PRAGMA foreign_keys")
+        .fetch_one(&mut *conn)
+        .await?;
+    if enabled == 0 {
+        log::warn!(
+            "Foreign keys are off; run PRAGMA foreign_keys = ON on every connection, \
+             as DataStore::with_options does"
+        );
+    }
+    Ok(())
+}
+
+/// An in-memory store for unit tests.
+///
+/// Unlike [`DataStore::with_options`], this pool has a single connection:
+/// every `sqlite::memory:` connection opens its own empty database, so a
+/// second one would not see the schema. Reads therefore queue behind any open
+/// transaction here.
 #[cfg(test)]
 async fn setup() -> DataStore {
-    use sqlx::any::{AnyPoolOptions, install_default_drivers};
+    use sqlx::{
+        Executor,
+        any::{AnyPoolOptions, install_default_drivers},
+    };
     // Use PoolOptions to ensure the connection stays alive
     install_default_drivers();
     let pool = AnyPoolOptions::new()
         .max_connections(1) // Force a single connection for stability in memory
         .idle_timeout(None) // Never let the connection drop due to inactivity
+        // Enforce foreign keys as DataStore::with_options does
+        .after_connect(|conn, _meta| {
+            Box::pin(async move {
+                conn.execute("PRAGMA foreign_keys = ON").await?;
+                Ok(())
+            })
+        })
         .connect("sqlite::memory:")
         .await
         .expect("Could not create pool");
@@ -213,3 +864,105 @@ async fn setup() -> DataStore {
         .await
         .expect("Failed to create test store")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{RngCore, rng};
+
+    #[test]
+    fn test_affects_chunking() {
+        let config = ChunkConfig::default();
+        assert_eq!(config, ChunkConfig::default());
+        assert!(!config.affects_chunking(&ChunkConfig::default()));
+
+        let parallel = ChunkConfig {
+            hash_large_chunks_in_parallel: true,
+            ..config
+        };
+        assert_ne!(parallel, config);
+        assert!(!parallel.affects_chunking(&config));
+
+        let larger = ChunkConfig {
+            avg_chunk_size: 2048,
+            ..config
+        };
+        assert!(larger.affects_chunking(&config));
+        assert!(config.affects_chunking(&larger));
+
+        let fixed = ChunkConfig {
+            chunker: ChunkerKind::FixedSize,
+            ..config
+        };
+        assert!(fixed.affects_chunking(&config));
+
+        let tighter = ChunkConfig {
+            normalization_level: 3,
+            ..config
+        };
+        assert!(tighter.affects_chunking(&config));
+        assert_ne!(tighter.chunk_protocol(), config.chunk_protocol());
+        assert_eq!(config.chunk_protocol(), "fastcdc2020/512/1024/2048");
+    }
+
+    #[test]
+    fn test_normalization_level_range() {
+        for level in 0..=MAX_NORMALIZATION_LEVEL {
+            let config = ChunkConfig {
+                normalization_level: level,
+                ..Default::default()
+            };
+            assert!(config.validate().is_ok());
+        }
+        let config = ChunkConfig {
+            normalization_level: MAX_NORMALIZATION_LEVEL + 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(DataStoreError::InvalidNormalizationLevel { level: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_parallel_chunk_hash_matches_serial() {
+        let mut data = vec![0u8; 512 * 1024];
+        rng().fill_bytes(&mut data);
+
+        let serial = ChunkConfig::default();
+        let parallel = ChunkConfig {
+            hash_large_chunks_in_parallel: true,
+            parallel_hash_threshold: 256 * 1024,
+            ..Default::default()
+        };
+
+        assert_eq!(hash_chunk(&data, &parallel), hash_chunk(&data, &serial));
+        assert_eq!(hash_chunk(&data, &parallel), blake3::hash(&data));
+        // Below the threshold the serial path is used, with the same result
+        assert_eq!(
+            hash_chunk(&data[..1024], &parallel),
+            blake3::hash(&data[..1024])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setup_enforces_foreign_keys() {
+        let store = setup().await;
+        let enabled: i64 = sqlx::query_scalar("PRAGMA foreign_keys")
+            .fetch_one(&store.pool())
+            .await
+            .unwrap();
+        assert_eq!(enabled, 1);
+
+        let err = store
+            .store(FileSectionEntry {
+                file_id: FileID::new().to_string(),
+                chunk_hash: vec![1; 32],
+                length: 1,
+                offset: 0,
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("FOREIGN KEY"), "{err}");
+    }
+}
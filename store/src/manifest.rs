@@ -0,0 +1,753 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! File manifests: a self-contained description of one tracked file.
+//!
+//! A manifest combines the `files` row with the ordered list of chunks that
+//! make up the file. Manifests are exchanged as newline-delimited JSON, one
+//! file per line, so large stores can be exported without buffering.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use common::{ChunkIndex, FileID};
+use serde::{Deserialize, Serialize};
+
+use sqlx::{Any, Transaction};
+
+use crate::{
+    ChunkConfig, Chunker, DataStore, DataStoreError, Fetch, FileSectionEntry, FileTableEntry,
+    Result, StoreEvent, file_section, file_store, hash_chunk,
+};
+
+/// Number of files fetched per page while exporting.
+const EXPORT_PAGE_SIZE: i64 = 256;
+
+/// Location and identity of one chunk within a file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkMetadata {
+    pub hash: Vec<u8>,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Everything needed to describe a tracked file, independent of the database.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub file_id: FileID,
+    pub name: String,
+    pub path: String,
+    pub hash: Vec<u8>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// Chunks keyed by their position in the file, in offset order.
+    pub chunks: BTreeMap<ChunkIndex, ChunkMetadata>,
+}
+
+/// Outcome of [`DataStore::import_manifests`].
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Number of manifests whose file and sections were written.
+    pub files_imported: u64,
+    /// Number of section rows written across all imported files.
+    pub sections_imported: u64,
+    /// Manifests that were not imported because some of their chunks are absent.
+    pub skipped_files: Vec<FileID>,
+    /// Distinct chunk hashes referenced by a manifest but missing from the store.
+    pub missing_chunks: BTreeSet<Vec<u8>>,
+}
+
+impl FileMetadata {
+    /// Builds a manifest from a file row and its sections.
+    ///
+    /// Sections are sorted by offset, so the resulting indices follow file order
+    /// regardless of the order they were fetched in.
+    pub fn from_entries(file: FileTableEntry, sections: Vec<FileSectionEntry>) -> Result<Self> {
+        let chunks = chunk_map(sections);
+
+        Ok(Self {
+            file_id: file.file_id.parse()?,
+            name: file.name,
+            path: file.path,
+            hash: file.hash,
+            content_type: file.content_type,
+            chunks,
+        })
+    }
+
+    /// Root of a binary Merkle tree over the file's chunk hashes, in file order.
+    ///
+    /// The leaves are the chunk hashes themselves (a hash that is not 32
+    /// bytes long is hashed first). Each level pairs up neighbours from the
+    /// left and replaces each pair with `blake3(left || right)`; an odd node
+    /// out at the end of a level is promoted to the next level unchanged. The
+    /// single node left is the root, so a one-chunk file's root is its chunk
+    /// hash. A file without chunks has the root `blake3("")`.
+    pub fn merkle_root(&self) -> blake3::Hash {
+        let mut level: Vec<blake3::Hash> = self
+            .chunks
+            .values()
+            .map(|chunk| match <[u8; 32]>::try_from(chunk.hash.as_slice()) {
+                Ok(bytes) => blake3::Hash::from_bytes(bytes),
+                Err(_) => blake3::hash(&chunk.hash),
+            })
+            .collect();
+        if level.is_empty() {
+            return blake3::hash(b"");
+        }
+
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut hasher = blake3::Hasher::new();
+                        hasher.update(left.as_bytes());
+                        hasher.update(right.as_bytes());
+                        hasher.finalize()
+                    }
+                    [odd] => *odd,
+                    _ => unreachable!("chunks(2) yields one or two nodes"),
+                })
+                .collect();
+        }
+        level[0]
+    }
+}
+
+/// Indexes sections by their position in the file, after sorting them by offset.
+fn chunk_map(mut sections: Vec<FileSectionEntry>) -> BTreeMap<ChunkIndex, ChunkMetadata> {
+    sections.sort_by_key(|section| section.offset);
+
+    sections
+        .into_iter()
+        .enumerate()
+        .map(|(index, section)| {
+            let metadata = ChunkMetadata {
+                hash: section.chunk_hash,
+                offset: section.offset as u64,
+                length: section.length as u64,
+            };
+            (index, metadata)
+        })
+        .collect()
+}
+
+/// Chunks `source` and returns the chunk list it would have as a manifest.
+///
+/// Nothing is stored and chunk data is dropped as soon as it is hashed, so
+/// this works on streams of any size. Offsets come from the running total of
+/// chunk lengths; the source never needs to seek.
+pub fn chunk_manifest<R: Read>(
+    source: R,
+    chunk_config: Option<ChunkConfig>,
+) -> Result<BTreeMap<ChunkIndex, ChunkMetadata>> {
+    chunk_metadata_iter(source, chunk_config)
+        .enumerate()
+        .map(|(index, chunk)| chunk.map(|chunk| (index, chunk)))
+        .collect()
+}
+
+/// [`chunk_manifest`] of everything piped into standard input, e.g. for `skie hash -`.
+pub fn hash_stdin(
+    chunk_config: Option<ChunkConfig>,
+) -> Result<BTreeMap<ChunkIndex, ChunkMetadata>> {
+    chunk_manifest(BufReader::new(io::stdin().lock()), chunk_config)
+}
+
+/// Hashes the `length` bytes at `offset` in the file at `path` as one chunk.
+///
+/// No chunking runs; the window is taken as given, so this is a cheap spot
+/// check of a single section against its file. Fails with an
+/// [`io::ErrorKind::UnexpectedEof`] error if the file ends inside the window.
+pub fn get_single_chunk_hash(
+    path: &Path,
+    offset: u64,
+    length: u64,
+    chunk_config: Option<ChunkConfig>,
+) -> Result<ChunkMetadata> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    // Read at most `length` bytes, so a bogus length cannot allocate past the file
+    let mut data = Vec::new();
+    (&mut file).take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file ends inside the requested chunk",
+        )
+        .into());
+    }
+
+    Ok(ChunkMetadata {
+        hash: hash_chunk(&data, &chunk_config.unwrap_or_default())
+            .as_bytes()
+            .to_vec(),
+        offset,
+        length,
+    })
+}
+
+/// Lazily chunks and hashes `source`, yielding where each chunk lies.
+pub(crate) fn chunk_metadata_iter<'a, R: Read + 'a>(
+    source: R,
+    chunk_config: Option<ChunkConfig>,
+) -> impl Iterator<Item = Result<ChunkMetadata>> + 'a {
+    let chunk_config = chunk_config.unwrap_or_default();
+
+    chunk_config.chunks(source).map(move |chunk| {
+        let chunk = chunk?;
+        Ok(ChunkMetadata {
+            hash: hash_chunk(&chunk.data, &chunk_config).as_bytes().to_vec(),
+            offset: chunk.offset,
+            length: chunk.data.len() as u64,
+        })
+    })
+}
+
+impl DataStore {
+    /// Fetches the chunk lists of several files in one query.
+    ///
+    /// The bulk counterpart of building [`FileMetadata`] file by file. Files
+    /// without sections (empty or untracked) are absent from the result.
+    pub async fn fetch_manifests(
+        &self,
+        ids: &[FileID],
+    ) -> Result<HashMap<FileID, BTreeMap<ChunkIndex, ChunkMetadata>>> {
+        let grouped: Vec<Vec<FileSectionEntry>> = self.fetch_many(ids).await?;
+
+        grouped
+            .into_iter()
+            .filter_map(|sections| {
+                let file_id = sections.first()?.file_id.parse::<FileID>();
+                Some(
+                    file_id
+                        .map(|id| (id, chunk_map(sections)))
+                        .map_err(Into::into),
+                )
+            })
+            .collect()
+    }
+
+    /// The [`FileMetadata::merkle_root`] of a tracked file, from its stored sections.
+    pub async fn file_merkle_root(&self, file_id: &FileID) -> Result<blake3::Hash> {
+        let file: FileTableEntry = self.fetch_by(file_id).await?;
+        let sections: Vec<FileSectionEntry> = self.fetch_by(file_id).await?;
+        Ok(FileMetadata::from_entries(file, sections)?.merkle_root())
+    }
+
+    /// Writes the manifest of every tracked file to `out` as NDJSON.
+    ///
+    /// Files are read page by page, so memory use stays bounded by the page
+    /// size rather than the number of tracked files. Returns the number of
+    /// manifests written.
+    pub async fn export_manifests<W: Write>(&self, out: &mut W) -> Result<u64> {
+        let mut written = 0;
+        let mut last_id = String::new();
+
+        loop {
+            let files = sqlx::query_as::<_, FileTableEntry>(
+                "SELECT * FROM files WHERE file_id > $1 ORDER BY file_id LIMIT $2",
+            )
+            .bind(&last_id)
+            .bind(EXPORT_PAGE_SIZE)
+            .fetch_all(&self.pool())
+            .await?;
+
+            let Some(last) = files.last() else {
+                break;
+            };
+            last_id = last.file_id.clone();
+
+            for file in files {
+                let sections = sqlx::query_as::<_, FileSectionEntry>(
+                    "SELECT * FROM file_sections WHERE file_id = $1 ORDER BY offset ASC",
+                )
+                .bind(&file.file_id)
+                .fetch_all(&self.pool())
+                .await?;
+
+                let manifest = FileMetadata::from_entries(file, sections)?;
+                serde_json::to_writer(&mut *out, &manifest)?;
+                out.write_all(b"\n")?;
+                written += 1;
+            }
+        }
+
+        out.flush()?;
+        Ok(written)
+    }
+
+    /// Reads NDJSON manifests from `input` and upserts their files and sections.
+    ///
+    /// Chunk data is not part of a manifest, so every referenced chunk must
+    /// already be in the store. A manifest referencing absent chunks is skipped
+    /// and reported in the [`ImportReport`] instead of leaving dangling sections.
+    /// Each manifest is written in its own transaction, replacing any sections
+    /// previously stored for that file. A manifest with more chunks than
+    /// [`DataStore::with_max_sections_per_file`] allows fails the import with
+    /// [`DataStoreError::TooManySections`]; earlier manifests stay imported.
+    pub async fn import_manifests<R: BufRead>(&self, input: R) -> Result<ImportReport> {
+        let mut report = ImportReport::default();
+
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let manifest: FileMetadata = serde_json::from_str(&line)?;
+            let file_id = manifest.file_id.to_string();
+            self.check_section_count(manifest.chunks.len())?;
+
+            // Manifests carry no chunk data, only rows
+            self.ensure_free_space(0)?;
+            let _slot = self.transaction_slot().await;
+            let mut tx = self.pool().begin().await?;
+
+            let missing = missing_chunks(&mut tx, &manifest).await?;
+            if !missing.is_empty() {
+                report.skipped_files.push(manifest.file_id);
+                report.missing_chunks.extend(missing);
+                continue;
+            }
+
+            sqlx::query(file_store::UPSERT_QUERY)
+                .bind(&file_id)
+                .bind(&manifest.name)
+                .bind(&manifest.path)
+                .bind(&manifest.hash)
+                .bind(&manifest.content_type)
+                .bind(&self.sync_set)
+                .execute(&mut *tx)
+                .await?;
+            write_sections(&mut tx, &manifest).await?;
+
+            tx.commit().await?;
+            self.emit(StoreEvent::FileStored(manifest.file_id));
+            report.files_imported += 1;
+            report.sections_imported += manifest.chunks.len() as u64;
+        }
+
+        Ok(report)
+    }
+
+    /// Rebuilds a tracked file's sections from its exported manifest.
+    ///
+    /// For repairing a damaged `file_sections` table: the file row is left as
+    /// it is, and its sections are replaced by those listed in `manifest` in
+    /// one transaction. If any referenced chunk is gone, nothing is written
+    /// and [`DataStoreError::MissingChunks`] lists the missing hashes.
+    pub async fn restore_sections_from_manifest(&self, manifest: &FileMetadata) -> Result<()> {
+        self.check_section_count(manifest.chunks.len())?;
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+
+        let tracked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM files WHERE file_id = $1")
+            .bind(manifest.file_id.to_string())
+            .fetch_one(&mut *tx)
+            .await?;
+        if tracked == 0 {
+            return Err(DataStoreError::NotFound);
+        }
+
+        let missing = missing_chunks(&mut tx, manifest).await?;
+        if !missing.is_empty() {
+            return Err(DataStoreError::MissingChunks {
+                hashes: missing.into_iter().collect(),
+            });
+        }
+
+        write_sections(&mut tx, manifest).await?;
+        tx.commit().await?;
+        self.emit(StoreEvent::SectionsChanged(manifest.file_id));
+        Ok(())
+    }
+}
+
+/// Distinct chunks referenced by `manifest` that are not in the store.
+async fn missing_chunks(
+    tx: &mut Transaction<'_, Any>,
+    manifest: &FileMetadata,
+) -> Result<BTreeSet<Vec<u8>>> {
+    let mut missing = BTreeSet::new();
+    for chunk in manifest.chunks.values() {
+        let present: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM chunks WHERE hash = $1")
+            .bind(&chunk.hash)
+            .fetch_one(&mut **tx)
+            .await?;
+        if present == 0 {
+            missing.insert(chunk.hash.clone());
+        }
+    }
+    Ok(missing)
+}
+
+/// Replaces the sections of the manifest's file with the manifest's chunk list.
+async fn write_sections(tx: &mut Transaction<'_, Any>, manifest: &FileMetadata) -> Result<()> {
+    let file_id = manifest.file_id.to_string();
+    sqlx::query("DELETE FROM file_sections WHERE file_id = $1")
+        .bind(&file_id)
+        .execute(&mut **tx)
+        .await?;
+
+    for chunk in manifest.chunks.values() {
+        let section = FileSectionEntry::from_metadata(&manifest.file_id, chunk)?;
+        sqlx::query(file_section::UPSERT_QUERY)
+            .bind(section.file_id)
+            .bind(section.chunk_hash)
+            .bind(section.length)
+            .bind(section.offset)
+            .execute(&mut **tx)
+            .await?;
+    }
+    // Manifests carry no content to checksum
+    file_store::record_indexed(tx, &file_id, manifest.chunks.len(), None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChunkedSource, Persist, chunk_source, setup};
+    use rand::{RngCore, rng};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_chunk_manifest_of_piped_input_is_contiguous() -> Result<()> {
+        let mut data = vec![0u8; 24 * 1024];
+        rng().fill_bytes(&mut data);
+
+        // A Cursor stands in for stdin; it is only ever read forwards
+        let chunks = chunk_manifest(BufReader::new(Cursor::new(&data)), None)?;
+        assert!(chunks.len() > 1);
+        let mut expected_offset = 0;
+        for (index, (position, chunk)) in chunks.iter().enumerate() {
+            assert_eq!(*position, index);
+            assert_eq!(chunk.offset, expected_offset);
+            let range = chunk.offset as usize..(chunk.offset + chunk.length) as usize;
+            assert_eq!(chunk.hash, blake3::hash(&data[range]).as_bytes().to_vec());
+            expected_offset += chunk.length;
+        }
+        assert_eq!(expected_offset, data.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_chunk_hash_of_mid_file_window() -> Result<()> {
+        let mut data = vec![0u8; 16 * 1024];
+        rng().fill_bytes(&mut data);
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), &data)?;
+
+        let chunk = get_single_chunk_hash(file.path(), 5000, 1234, None)?;
+        assert_eq!(
+            chunk.hash,
+            blake3::hash(&data[5000..6234]).as_bytes().to_vec()
+        );
+        assert_eq!((chunk.offset, chunk.length), (5000, 1234));
+
+        let past_end = get_single_chunk_hash(file.path(), 16 * 1024 - 10, 20, None);
+        assert!(matches!(past_end, Err(DataStoreError::IoError(_))));
+
+        // A length far past the file fails instead of allocating it up front
+        let huge = get_single_chunk_hash(file.path(), 0, u64::MAX, None);
+        assert!(matches!(
+            huge,
+            Err(DataStoreError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_manifests_ndjson() -> Result<()> {
+        let store = setup().await;
+        let first = FileID::new();
+        let second = FileID::new();
+
+        store
+            .index_and_store(
+                &first,
+                "a.txt",
+                "/a.txt",
+                Cursor::new(vec![1u8; 3000]),
+                None,
+            )
+            .await?;
+        store
+            .index_and_store(
+                &second,
+                "b.txt",
+                "/b.txt",
+                Cursor::new(vec![2u8; 500]),
+                None,
+            )
+            .await?;
+
+        let mut out = Vec::new();
+        let count = store.export_manifests(&mut out).await?;
+        assert_eq!(count, 2);
+
+        let manifests = out
+            .lines()
+            .map(|line| serde_json::from_str::<FileMetadata>(&line.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(manifests.len(), 2);
+
+        let exported = manifests.iter().find(|m| m.file_id == first).unwrap();
+        assert_eq!(exported.path, "/a.txt");
+        let total: u64 = exported.chunks.values().map(|c| c.length).sum();
+        assert_eq!(total, 3000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_round_trip() -> Result<()> {
+        let source = setup().await;
+        let target = setup().await;
+        let file_id = FileID::new();
+        let data = (0..6000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        source
+            .index_and_store(&file_id, "c.bin", "/c.bin", Cursor::new(&data), None)
+            .await?;
+        let mut exported = Vec::new();
+        source.export_manifests(&mut exported).await?;
+
+        // Without the chunks the manifest must be reported, not imported
+        let report = target.import_manifests(Cursor::new(&exported)).await?;
+        assert_eq!(report.files_imported, 0);
+        assert_eq!(report.skipped_files, vec![file_id]);
+        assert!(!report.missing_chunks.is_empty());
+
+        // Chunk data travels separately; seed it and import again
+        let ChunkedSource { chunks, .. } = chunk_source(&file_id, Cursor::new(&data), None)?;
+        target.store_all(chunks).await?;
+        let limited = target.with_max_sections_per_file(Some(2));
+        let err = limited
+            .import_manifests(Cursor::new(&exported))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            DataStoreError::TooManySections { limit: 2, .. }
+        ));
+        assert_eq!(limited.logical_section_count().await?, 0);
+        let target = limited.with_max_sections_per_file(None);
+
+        let report = target.import_manifests(Cursor::new(&exported)).await?;
+        assert_eq!(report.files_imported, 1);
+        assert!(report.missing_chunks.is_empty());
+
+        let original: FileTableEntry = source.fetch_by(&file_id).await?;
+        let imported: FileTableEntry = target.fetch_by(&file_id).await?;
+        assert_eq!(imported.path, original.path);
+        assert_eq!(imported.hash, original.hash);
+
+        let original: Vec<FileSectionEntry> = source.fetch_by(&file_id).await?;
+        let imported: Vec<FileSectionEntry> = target.fetch_by(&file_id).await?;
+        assert_eq!(report.sections_imported as usize, original.len());
+        let key = |s: &FileSectionEntry| (s.offset, s.length, s.chunk_hash.clone());
+        assert_eq!(
+            imported.iter().map(key).collect::<Vec<_>>(),
+            original.iter().map(key).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    async fn stored_quick_checksum(store: &DataStore, file_id: &FileID) -> Result<Option<i64>> {
+        Ok(
+            sqlx::query_scalar("SELECT quick_checksum FROM files WHERE file_id = $1")
+                .bind(file_id.to_string())
+                .fetch_one(&store.pool())
+                .await?,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_import_forgets_quick_checksum() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let before = vec![1u8; 6000];
+        let after = (0..6000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        // The manifest describes content the store has chunks for, but the
+        // checksum on record is of different bytes
+        store
+            .index_and_store(&file_id, "q.bin", "/q.bin", Cursor::new(&after), None)
+            .await?;
+        let mut exported = Vec::new();
+        store.export_manifests(&mut exported).await?;
+        store
+            .index_and_store(&file_id, "q.bin", "/q.bin", Cursor::new(&before), None)
+            .await?;
+        assert!(stored_quick_checksum(&store, &file_id).await?.is_some());
+
+        let report = store.import_manifests(Cursor::new(&exported)).await?;
+        assert_eq!(report.files_imported, 1);
+        assert_eq!(stored_quick_checksum(&store, &file_id).await?, None);
+
+        // Restoring sections alone forgets it as well
+        store
+            .index_and_store(&file_id, "q.bin", "/q.bin", Cursor::new(&before), None)
+            .await?;
+        let manifest = FileMetadata::from_entries(
+            store.fetch_by(&file_id).await?,
+            store.fetch_by(&file_id).await?,
+        )?;
+        store.restore_sections_from_manifest(&manifest).await?;
+        assert_eq!(stored_quick_checksum(&store, &file_id).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_sections_from_manifest() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let mut data = vec![0u8; 20 * 1024];
+        rng().fill_bytes(&mut data);
+        store
+            .index_and_store(&file_id, "r.bin", "/r.bin", Cursor::new(&data), None)
+            .await?;
+
+        let file: FileTableEntry = store.fetch_by(&file_id).await?;
+        let sections: Vec<FileSectionEntry> = store.fetch_by(&file_id).await?;
+        let manifest = FileMetadata::from_entries(file, sections)?;
+
+        sqlx::query("DELETE FROM file_sections WHERE file_id = $1")
+            .bind(file_id.to_string())
+            .execute(&store.pool())
+            .await?;
+        store.restore_sections_from_manifest(&manifest).await?;
+        assert_eq!(
+            store.read_range(&file_id, 0, data.len() as u64).await?,
+            data
+        );
+
+        // A lost chunk aborts the restore and leaves the sections alone
+        let lost = manifest.chunks[&0].hash.clone();
+        sqlx::query("DELETE FROM file_sections WHERE chunk_hash = $1")
+            .bind(&lost)
+            .execute(&store.pool())
+            .await?;
+        sqlx::query("DELETE FROM chunks WHERE hash = $1")
+            .bind(&lost)
+            .execute(&store.pool())
+            .await?;
+        let before = store.logical_section_count().await?;
+        match store.restore_sections_from_manifest(&manifest).await {
+            Err(DataStoreError::MissingChunks { hashes }) => assert_eq!(hashes, vec![lost]),
+            other => panic!("expected MissingChunks, got {other:?}"),
+        }
+        assert_eq!(store.logical_section_count().await?, before);
+        Ok(())
+    }
+
+    fn manifest_of(hashes: &[Vec<u8>]) -> FileMetadata {
+        FileMetadata {
+            file_id: FileID::new(),
+            name: "m.bin".to_string(),
+            path: "/m.bin".to_string(),
+            hash: vec![0; 32],
+            content_type: None,
+            chunks: hashes
+                .iter()
+                .enumerate()
+                .map(|(i, hash)| {
+                    let chunk = ChunkMetadata {
+                        hash: hash.clone(),
+                        offset: i as u64 * 10,
+                        length: 10,
+                    };
+                    (i, chunk)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let hashes: Vec<Vec<u8>> = (0..5u8)
+            .map(|i| blake3::hash(&[i]).as_bytes().to_vec())
+            .collect();
+        let root = manifest_of(&hashes).merkle_root();
+        assert_eq!(manifest_of(&hashes).merkle_root(), root);
+
+        // Five leaves: two full pairs, then the fifth promoted twice
+        let node = |l: &[u8], r: &[u8]| blake3::hash(&[l, r].concat());
+        let ab = node(&hashes[0], &hashes[1]);
+        let cd = node(&hashes[2], &hashes[3]);
+        let abcd = node(ab.as_bytes(), cd.as_bytes());
+        assert_eq!(root, node(abcd.as_bytes(), &hashes[4]));
+
+        let mut changed = hashes.clone();
+        changed[2] = blake3::hash(b"edited").as_bytes().to_vec();
+        assert_ne!(manifest_of(&changed).merkle_root(), root);
+
+        let single = manifest_of(&hashes[..1]).merkle_root();
+        assert_eq!(single.as_bytes().as_slice(), hashes[0].as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_file_merkle_root_matches_for_identical_content() -> Result<()> {
+        let store = setup().await;
+        let data = (0..12_000u32).map(|i| (i % 241) as u8).collect::<Vec<_>>();
+        let (a, b) = (FileID::new(), FileID::new());
+        store
+            .index_and_store(&a, "a.bin", "/a.bin", Cursor::new(&data), None)
+            .await?;
+        store
+            .index_and_store(&b, "b.bin", "/b.bin", Cursor::new(&data), None)
+            .await?;
+        assert_eq!(
+            store.file_merkle_root(&a).await?,
+            store.file_merkle_root(&b).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_manifests_groups_by_file() -> Result<()> {
+        let store = setup().await;
+        let mut files = Vec::new();
+        for (i, len) in [3000usize, 9000, 5000].into_iter().enumerate() {
+            let file_id = FileID::new();
+            let data = (0..len)
+                .map(|b| (b * (i + 3) % 251) as u8)
+                .collect::<Vec<_>>();
+            let path = format!("/{i}.bin");
+            store
+                .index_and_store(&file_id, "f", &path, Cursor::new(data), None)
+                .await?;
+            files.push((file_id, len as u64));
+        }
+        let untracked = FileID::new();
+
+        let ids = files
+            .iter()
+            .map(|(id, _)| *id)
+            .chain([untracked])
+            .collect::<Vec<_>>();
+        let manifests = store.fetch_manifests(&ids).await?;
+        assert_eq!(manifests.len(), 3);
+        assert!(!manifests.contains_key(&untracked));
+
+        for (file_id, len) in files {
+            let chunks = &manifests[&file_id];
+            // Indices run 0..n and the chunks tile the file without gaps
+            assert_eq!(
+                chunks.keys().copied().collect::<Vec<_>>(),
+                (0..chunks.len()).collect::<Vec<_>>()
+            );
+            let mut next = 0;
+            for chunk in chunks.values() {
+                assert_eq!(chunk.offset, next);
+                next += chunk.length;
+            }
+            assert_eq!(next, len);
+        }
+        Ok(())
+    }
+}
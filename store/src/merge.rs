@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Consolidating one store into another, e.g. per-device stores into a
+//! central one.
+//!
+//! Chunks are content-addressed, so a chunk both stores hold is kept once.
+//! Files are identified by `file_id`: a file `self` already tracks is treated
+//! as the same file and takes over the other store's content.
+use crate::{
+    DUMP_PAGE_SIZE, DataStore, FileSectionEntry, Result, StoreEvent, chunk_store::INSERT_QUERY,
+};
+
+/// What [`DataStore::merge_from`] copied.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Files that were not tracked before.
+    pub files_added: u64,
+    /// Files already tracked under the same id, now holding the merged content.
+    pub files_updated: u64,
+    pub chunks_added: u64,
+    /// Chunks of the other store that were already stored.
+    pub chunks_deduplicated: u64,
+    pub sections_added: u64,
+}
+
+/// A chunk row as copied between stores.
+#[derive(sqlx::FromRow)]
+struct ChunkRow {
+    hash: Vec<u8>,
+    size: i64,
+    data: Vec<u8>,
+    stored_checksum: Option<i64>,
+    checksum_algo: Option<String>,
+}
+
+/// A file row as copied between stores. Device and inode numbers are left
+/// out, since they only mean something on the machine that recorded them.
+#[derive(sqlx::FromRow)]
+struct FileRow {
+    file_id: String,
+    name: String,
+    path: String,
+    hash: Vec<u8>,
+    content_type: Option<String>,
+    chunk_count: i64,
+    last_indexed_at: Option<i64>,
+    sync_set: String,
+    quick_checksum: Option<i64>,
+}
+
+impl DataStore {
+    /// Copies every file, chunk and section of `other` into this store.
+    ///
+    /// Runs in a single transaction, reading `other` from one snapshot. Files
+    /// keep their ids and sync sets; for a file tracked in both stores, the
+    /// row and sections of `other` replace the local ones, while its sync set
+    /// stays as it is here. Fails, merging nothing, if a file of `other` has
+    /// the path of a different file in this store.
+    pub async fn merge_from(&self, other: &DataStore) -> Result<MergeReport> {
+        let mut report = MergeReport::default();
+        let _source_slot = other.transaction_slot().await;
+        let mut source = other.pool().begin().await?;
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+
+        // Chunks first, so the sections' foreign keys hold
+        let mut after = Vec::new();
+        loop {
+            let page: Vec<ChunkRow> = sqlx::query_as(
+                r#"
+                SELECT hash, size, data, stored_checksum, checksum_algo FROM chunks
+                WHERE hash > $1
+                ORDER BY hash
+                LIMIT $2
+                "#,
+            )
+            .bind(&after)
+            .bind(DUMP_PAGE_SIZE)
+            .fetch_all(&mut *source)
+            .await?;
+            let done = (page.len() as i64) < DUMP_PAGE_SIZE;
+            self.ensure_free_space(page.iter().map(|chunk| chunk.data.len() as u64).sum())?;
+            for chunk in page {
+                let inserted = sqlx::query(INSERT_QUERY)
+                    .bind(&chunk.hash)
+                    .bind(chunk.size)
+                    .bind(chunk.data)
+                    .bind(chunk.stored_checksum)
+                    .bind(chunk.checksum_algo)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+                if inserted == 0 {
+                    report.chunks_deduplicated += 1;
+                } else {
+                    report.chunks_added += 1;
+                }
+                after = chunk.hash;
+            }
+            if done {
+                break;
+            }
+        }
+
+        let mut merged = Vec::new();
+        let mut after = String::new();
+        loop {
+            let page: Vec<FileRow> = sqlx::query_as(
+                r#"
+                SELECT file_id, name, path, hash, content_type, chunk_count,
+                       last_indexed_at, sync_set, quick_checksum
+                FROM files
+                WHERE file_id > $1
+                ORDER BY file_id
+                LIMIT $2
+                "#,
+            )
+            .bind(&after)
+            .bind(DUMP_PAGE_SIZE)
+            .fetch_all(&mut *source)
+            .await?;
+            let done = (page.len() as i64) < DUMP_PAGE_SIZE;
+            for file in page {
+                let exists: i64 =
+                    sqlx::query_scalar("SELECT COUNT(*) FROM files WHERE file_id = $1")
+                        .bind(&file.file_id)
+                        .fetch_one(&mut *tx)
+                        .await?;
+                if exists == 0 {
+                    report.files_added += 1;
+                } else {
+                    report.files_updated += 1;
+                }
+                sqlx::query(
+                    r#"
+                    INSERT INTO files (file_id, name, path, hash, content_type, chunk_count,
+                                       last_indexed_at, sync_set, quick_checksum)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                    ON CONFLICT(file_id) DO UPDATE SET
+                        name = excluded.name,
+                        path = excluded.path,
+                        hash = excluded.hash,
+                        content_type = excluded.content_type,
+                        chunk_count = excluded.chunk_count,
+                        last_indexed_at = excluded.last_indexed_at,
+                        quick_checksum = excluded.quick_checksum
+                    "#,
+                )
+                .bind(&file.file_id)
+                .bind(file.name)
+                .bind(file.path)
+                .bind(file.hash)
+                .bind(file.content_type)
+                .bind(file.chunk_count)
+                .bind(file.last_indexed_at)
+                .bind(file.sync_set)
+                .bind(file.quick_checksum)
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query("DELETE FROM file_sections WHERE file_id = $1")
+                    .bind(&file.file_id)
+                    .execute(&mut *tx)
+                    .await?;
+                let sections: Vec<FileSectionEntry> = sqlx::query_as(
+                    "SELECT file_id, chunk_hash, length, offset FROM file_sections WHERE file_id = $1",
+                )
+                .bind(&file.file_id)
+                .fetch_all(&mut *source)
+                .await?;
+                for section in sections {
+                    sqlx::query(
+                        "INSERT INTO file_sections (file_id, chunk_hash, length, offset) VALUES ($1, $2, $3, $4)",
+                    )
+                    .bind(section.file_id)
+                    .bind(section.chunk_hash)
+                    .bind(section.length)
+                    .bind(section.offset)
+                    .execute(&mut *tx)
+                    .await?;
+                    report.sections_added += 1;
+                }
+                merged.push(file.file_id.clone());
+                after = file.file_id;
+            }
+            if done {
+                break;
+            }
+        }
+
+        tx.commit().await?;
+        source.commit().await?;
+        for file_id in merged {
+            self.emit_for(&file_id, StoreEvent::FileStored);
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use common::FileID;
+    use rand::{RngCore, rng};
+
+    #[tokio::test]
+    async fn test_merge_overlapping_stores() -> Result<()> {
+        let mut shared = vec![0u8; 16 * 1024];
+        rng().fill_bytes(&mut shared);
+        let mut edited = shared.clone();
+        rng().fill_bytes(&mut edited[8_000..8_100]);
+        let mut own = vec![0u8; 4 * 1024];
+        rng().fill_bytes(&mut own);
+
+        let central = setup().await;
+        let both = FileID::new();
+        let local = FileID::new();
+        central
+            .index_and_store(&both, "s.bin", "/s.bin", &shared[..], None)
+            .await?;
+        central
+            .index_and_store(&local, "own.bin", "/own.bin", &own[..], None)
+            .await?;
+
+        let device = setup().await;
+        let remote = FileID::new();
+        device
+            .index_and_store(&both, "s.bin", "/s.bin", &edited[..], None)
+            .await?;
+        device
+            .index_and_store(&remote, "copy.bin", "/copy.bin", &shared[..], None)
+            .await?;
+
+        let before = central.physical_chunk_count().await?;
+        let report = central.merge_from(&device).await?;
+        assert_eq!(report.files_added, 1);
+        assert_eq!(report.files_updated, 1);
+        assert!(report.chunks_deduplicated > 0);
+        assert_eq!(
+            report.chunks_added + report.chunks_deduplicated,
+            device.physical_chunk_count().await? as u64
+        );
+        assert_eq!(
+            central.physical_chunk_count().await?,
+            before + report.chunks_added as i64
+        );
+        assert_eq!(
+            report.sections_added as i64,
+            device.logical_section_count().await?
+        );
+
+        assert_eq!(central.list_files(None).await?.len(), 3);
+        assert_eq!(
+            central.read_range(&both, 0, edited.len() as u64).await?,
+            edited
+        );
+        assert_eq!(
+            central.read_range(&remote, 0, shared.len() as u64).await?,
+            shared
+        );
+        assert_eq!(central.read_range(&local, 0, own.len() as u64).await?, own);
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A small key-value store for application state kept next to the index.
+//!
+//! Values are opaque bytes; callers pick their own encoding. Keys share one
+//! namespace, so features should prefix theirs, e.g. `checkpoint/...`.
+//!
+//! The store itself keeps the chunk protocol here, to notice when the chunk
+//! config changed between runs; see [`DataStore::record_chunk_protocol`].
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::FileID;
+
+use crate::{ChunkConfig, DataStore, Result};
+
+/// Key holding the [`ChunkConfig::chunk_protocol`] files are indexed with.
+const CHUNK_PROTOCOL_KEY: &str = "chunking/protocol";
+/// Key holding when the protocol last changed, in milliseconds since the epoch.
+const CHUNK_PROTOCOL_SINCE_KEY: &str = "chunking/protocol_since";
+
+impl DataStore {
+    /// Stores `value` under `key`, replacing any previous value.
+    pub async fn kv_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO meta (key, value) VALUES ($1, $2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool())
+        .await?;
+        Ok(())
+    }
+
+    /// The value stored under `key`, or `None` if there is none.
+    pub async fn kv_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let value = sqlx::query_scalar("SELECT value FROM meta WHERE key = $1")
+            .bind(key)
+            .fetch_optional(&self.pool())
+            .await?;
+        Ok(value)
+    }
+
+    /// Records that files are now chunked with `chunk_config`, and returns
+    /// whether that differs from the protocol recorded before.
+    ///
+    /// Call it when opening the store with the configured chunking. After a
+    /// change, [`DataStore::reindex_all_needed`] lists the files still chunked
+    /// the old way. The first call on a store only records the protocol.
+    pub async fn record_chunk_protocol(&self, chunk_config: &ChunkConfig) -> Result<bool> {
+        let protocol = chunk_config.chunk_protocol();
+        let previous = self.kv_get(CHUNK_PROTOCOL_KEY).await?;
+        if previous.as_deref() == Some(protocol.as_bytes()) {
+            return Ok(false);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let mut entries = vec![(CHUNK_PROTOCOL_KEY, protocol.into_bytes())];
+        if previous.is_some() {
+            entries.push((CHUNK_PROTOCOL_SINCE_KEY, now.to_be_bytes().to_vec()));
+        }
+        let _slot = self.transaction_slot().await;
+        let mut tx = self.pool().begin().await?;
+        for (key, value) in entries {
+            sqlx::query(
+                "INSERT INTO meta (key, value) VALUES ($1, $2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            )
+            .bind(key)
+            .bind(value)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(previous.is_some())
+    }
+
+    /// Files not re-indexed since the chunk protocol last changed, by path.
+    ///
+    /// Each file drops out of the list as soon as it is indexed again. Empty
+    /// until [`DataStore::record_chunk_protocol`] has seen a change.
+    pub async fn reindex_all_needed(&self) -> Result<Vec<FileID>> {
+        let since = match self.kv_get(CHUNK_PROTOCOL_SINCE_KEY).await? {
+            Some(bytes) => i64::from_be_bytes(bytes.try_into().unwrap_or_default()),
+            None => return Ok(Vec::new()),
+        };
+        let ids: Vec<String> = sqlx::query_scalar(
+            "SELECT file_id FROM files WHERE COALESCE(last_indexed_at, 0) <= $1 ORDER BY path",
+        )
+        .bind(since)
+        .fetch_all(&self.pool())
+        .await?;
+        ids.into_iter()
+            .map(|id| id.parse().map_err(Into::into))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ChunkConfig, Result, setup};
+    use common::FileID;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_chunk_protocol_change_lists_files_to_reindex() -> Result<()> {
+        let store = setup().await;
+        let config = ChunkConfig::default();
+        assert!(!store.record_chunk_protocol(&config).await?);
+
+        let mut ids = Vec::new();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            let id = FileID::new();
+            store
+                .index_and_store(&id, name, name, name.as_bytes(), Some(config))
+                .await?;
+            ids.push(id);
+        }
+        assert!(!store.record_chunk_protocol(&config).await?);
+        assert!(store.reindex_all_needed().await?.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let changed = ChunkConfig {
+            avg_chunk_size: 2048,
+            max_chunk_size: 4096,
+            ..config
+        };
+        assert!(store.record_chunk_protocol(&changed).await?);
+        assert_eq!(store.reindex_all_needed().await?, ids);
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        store
+            .index_and_store(&ids[1], "b.txt", "b.txt", &b"b.txt"[..], Some(changed))
+            .await?;
+        assert_eq!(store.reindex_all_needed().await?, [ids[0], ids[2]]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_set_get_and_overwrite() -> Result<()> {
+        let store = setup().await;
+        assert_eq!(store.kv_get("missing").await?, None);
+
+        store.kv_set("checkpoint/root", b"first").await?;
+        assert_eq!(
+            store.kv_get("checkpoint/root").await?.as_deref(),
+            Some(&b"first"[..])
+        );
+
+        store.kv_set("checkpoint/root", b"second").await?;
+        assert_eq!(
+            store.kv_get("checkpoint/root").await?.as_deref(),
+            Some(&b"second"[..])
+        );
+
+        store.kv_set("empty", b"").await?;
+        assert_eq!(store.kv_get("empty").await?, Some(Vec::new()));
+        Ok(())
+    }
+}
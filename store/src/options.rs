@@ -0,0 +1,300 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Connection-level tuning for SQLite-backed stores.
+//!
+//! SQLite PRAGMAs are per connection, so they are applied to every pooled
+//! connection through sqlx's `after_connect` hook rather than once at startup.
+use std::{fmt::Display, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// Connections opened by [`DataStore::with_options`](crate::DataStore::with_options).
+///
+/// With WAL journaling, readers on other connections proceed while one
+/// connection holds a write transaction, so a long `store_all` does not stall
+/// lookups. SQLite still allows a single writer; further writers wait up to
+/// `busy_timeout` for it.
+pub const DEFAULT_POOL_SIZE: u32 = 8;
+
+/// SQLite `journal_mode` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl Display for JournalMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        };
+        f.write_str(mode)
+    }
+}
+
+/// SQLite `synchronous` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Display for Synchronous {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        };
+        f.write_str(level)
+    }
+}
+
+/// PRAGMAs applied to each SQLite connection opened by the store.
+///
+/// `journal_mode` and `synchronous` trade write throughput against durability;
+/// `busy_timeout` controls how long a connection waits on a locked database
+/// before failing with `SQLITE_BUSY`. Foreign key enforcement is always turned
+/// on, since SQLite only enforces foreign keys per connection and on request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SqlitePragmas {
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    pub busy_timeout: Duration,
+}
+
+impl Default for SqlitePragmas {
+    /// WAL journaling with `synchronous=NORMAL` and a 5 second busy timeout.
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Normal,
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl SqlitePragmas {
+    /// The PRAGMA statements to run on a freshly opened connection.
+    pub(crate) fn statements(&self) -> Vec<String> {
+        vec![
+            format!("PRAGMA journal_mode = {}", self.journal_mode),
+            format!("PRAGMA synchronous = {}", self.synchronous),
+            format!("PRAGMA busy_timeout = {}", self.busy_timeout.as_millis()),
+            "PRAGMA foreign_keys = ON".to_string(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChunkTableEntry, DataStore, FileSectionEntry, FileTableEntry, Persist};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_wal_journal_mode_applied() {
+        let dir = TempDir::new().unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("store.db").display()
+        );
+
+        let store = DataStore::with_options(&url, SqlitePragmas::default())
+            .await
+            .expect("Failed to open store");
+
+        let mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&store.pool())
+            .await
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        let synchronous: i64 = sqlx::query_scalar("PRAGMA synchronous")
+            .fetch_one(&store.pool())
+            .await
+            .unwrap();
+        assert_eq!(synchronous, 1, "synchronous should be NORMAL");
+    }
+
+    #[tokio::test]
+    async fn test_reads_proceed_during_write_transaction() {
+        let dir = TempDir::new().unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("store.db").display()
+        );
+        let store = DataStore::with_options(&url, SqlitePragmas::default())
+            .await
+            .unwrap();
+        let entry = |file_id: &str| FileTableEntry {
+            file_id: file_id.to_string(),
+            name: file_id.to_string(),
+            path: format!("/{file_id}"),
+            hash: vec![0; 32],
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
+        };
+        store.store(entry("committed")).await.unwrap();
+
+        // A slow writer holds its transaction open
+        let mut tx = store.pool().begin().await.unwrap();
+        sqlx::query(
+            "INSERT INTO files (file_id, name, path, hash) VALUES ('pending', 'p', '/p', x'00')",
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+
+        let read = tokio::time::timeout(Duration::from_secs(1), async {
+            let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM files")
+                .fetch_one(&store.pool())
+                .await?;
+            crate::Result::Ok(count)
+        })
+        .await
+        .expect("read waited for the write transaction")
+        .unwrap();
+        assert_eq!(read, 1, "uncommitted row must not be visible");
+
+        tx.commit().await.unwrap();
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM files")
+            .fetch_one(&store.pool())
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_flush_checkpoints_wal() {
+        let dir = TempDir::new().unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("store.db").display()
+        );
+        let store = DataStore::with_options(&url, SqlitePragmas::default())
+            .await
+            .unwrap();
+
+        let chunks = (0u8..64)
+            .map(|tag| ChunkTableEntry {
+                hash: vec![tag],
+                size: 1024,
+                data: vec![tag; 1024],
+            })
+            .collect();
+        store.store_all(chunks).await.unwrap();
+        store.flush().await.unwrap();
+
+        // Nothing is left in the WAL that has not been copied back
+        let (busy, log, checkpointed): (i64, i64, i64) =
+            sqlx::query_as("PRAGMA wal_checkpoint(PASSIVE)")
+                .fetch_one(&store.pool())
+                .await
+                .unwrap();
+        assert_eq!(busy, 0);
+        assert_eq!(log, checkpointed);
+        assert_eq!(store.physical_chunk_count().await.unwrap(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_foreign_keys_enforced_and_dangling_sections_found() {
+        let dir = TempDir::new().unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("store.db").display()
+        );
+        let store = DataStore::with_options(&url, SqlitePragmas::default())
+            .await
+            .unwrap();
+        store
+            .store(FileTableEntry {
+                file_id: "file".to_string(),
+                name: "file".to_string(),
+                path: "/file".to_string(),
+                hash: vec![0; 32],
+                content_type: None,
+                chunk_count: 0,
+                last_indexed_at: None,
+            })
+            .await
+            .unwrap();
+        let insert_dangling = "INSERT INTO file_sections (file_id, chunk_hash, length, offset) VALUES ('file', x'ff', 1, $1)";
+
+        // Without enforcement, a section can point at a chunk that does not exist
+        let mut conn = store.pool().acquire().await.unwrap();
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query(insert_dangling)
+            .bind(0)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query("PRAGMA foreign_keys = ON")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let err = sqlx::query(insert_dangling)
+            .bind(1)
+            .execute(&store.pool())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("FOREIGN KEY"));
+
+        let dangling = store.check_referential_integrity().await.unwrap();
+        assert_eq!(dangling.len(), 1);
+        assert_eq!(dangling[0].chunk_hash, vec![0xff]);
+        assert_eq!(dangling[0].offset, 0);
+    }
+
+    #[tokio::test]
+    async fn test_section_for_unknown_file_rejected() {
+        let dir = TempDir::new().unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("store.db").display()
+        );
+        let store = DataStore::with_options(&url, SqlitePragmas::default())
+            .await
+            .unwrap();
+        store
+            .store(ChunkTableEntry {
+                hash: vec![1],
+                size: 1,
+                data: vec![1],
+            })
+            .await
+            .unwrap();
+
+        let err = store
+            .store(FileSectionEntry {
+                file_id: "no-such-file".to_string(),
+                chunk_hash: vec![1],
+                length: 1,
+                offset: 0,
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("FOREIGN KEY"), "{err}");
+        assert_eq!(store.logical_section_count().await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Cheap change detection for files that look unchanged.
+//!
+//! A matching size and an old modification time usually mean a file has not
+//! changed, but tools that restore timestamps (`cp -p`, `rsync -t`, archive
+//! extraction) break that rule. Every indexing pass also computes an Adler-32
+//! checksum of the file, which is far cheaper to recompute than the BLAKE3
+//! file hash and settles those ambiguous cases.
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::{DataStore, Result};
+
+/// Largest prime below 2^16.
+const ADLER_MOD: u32 = 65521;
+/// Bytes that can be summed before `b` could overflow a `u32`.
+const ADLER_NMAX: usize = 5552;
+
+/// Streaming Adler-32 checksum.
+#[derive(Clone, Copy, Debug)]
+pub struct QuickChecksum {
+    a: u32,
+    b: u32,
+}
+
+impl Default for QuickChecksum {
+    fn default() -> Self {
+        Self { a: 1, b: 0 }
+    }
+}
+
+impl QuickChecksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for block in data.chunks(ADLER_NMAX) {
+            for &byte in block {
+                self.a += u32::from(byte);
+                self.b += self.a;
+            }
+            self.a %= ADLER_MOD;
+            self.b %= ADLER_MOD;
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+/// The [`QuickChecksum`] of everything `source` yields.
+pub fn quick_checksum<R: Read>(mut source: R) -> Result<u32> {
+    let mut checksum = QuickChecksum::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = source.read(&mut buf)?;
+        if read == 0 {
+            return Ok(checksum.finish());
+        }
+        checksum.update(&buf[..read]);
+    }
+}
+
+impl DataStore {
+    /// Whether the file at `path` differs from what was indexed for it.
+    ///
+    /// Untracked files, files whose size changed and files modified after
+    /// they were last indexed need a re-index. When size and modification
+    /// time both suggest nothing changed, the file's quick checksum is
+    /// recomputed and compared with the stored one; files indexed before
+    /// checksums were recorded are then trusted to be unchanged.
+    pub async fn needs_reindex(&self, path: &Path) -> Result<bool> {
+        let Some(path_str) = path.to_str() else {
+            return Ok(true);
+        };
+        let row: Option<(String, Option<i64>, Option<i64>)> = sqlx::query_as(
+            "SELECT file_id, last_indexed_at, quick_checksum FROM files WHERE path = $1",
+        )
+        .bind(path_str)
+        .fetch_optional(&self.pool())
+        .await?;
+        let Some((file_id, last_indexed_at, stored)) = row else {
+            return Ok(true);
+        };
+
+        let metadata = fs::metadata(path)?;
+        if metadata.len() != self.file_size(&file_id.parse()?).await? {
+            return Ok(true);
+        }
+        let modified_ms = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        if last_indexed_at.is_none_or(|at| at < modified_ms) {
+            return Ok(true);
+        }
+
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+        let current = quick_checksum(BufReader::new(File::open(path)?))?;
+        Ok(i64::from(current) != stored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use common::FileID;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_adler32_known_value() {
+        let mut checksum = QuickChecksum::new();
+        checksum.update(b"Wiki");
+        checksum.update(b"pedia");
+        assert_eq!(checksum.finish(), 0x11E6_0398);
+        assert_eq!(quick_checksum(&b""[..]).unwrap(), 1);
+
+        // Long runs of 0xff must not overflow between reductions
+        let data = vec![0xffu8; 100_000];
+        let mut split = QuickChecksum::new();
+        data.chunks(7).for_each(|part| split.update(part));
+        assert_eq!(split.finish(), quick_checksum(&data[..]).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_checksum_catches_same_size_and_mtime() -> Result<()> {
+        let store = setup().await;
+        let dir = TempDir::new()?;
+        let path = dir.path().join("a.txt");
+        let path_str = path.to_str().unwrap();
+        let past = SystemTime::now() - Duration::from_secs(3600);
+
+        fs::write(&path, b"first version")?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(past)?;
+        store
+            .index_and_store(&FileID::new(), "a.txt", path_str, File::open(&path)?, None)
+            .await?;
+        assert!(!store.needs_reindex(&path).await?);
+
+        // Same length, and the old timestamp restored as `cp -p` would
+        fs::write(&path, b"other version")?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(past)?;
+        assert!(store.needs_reindex(&path).await?);
+
+        assert!(store.needs_reindex(&dir.path().join("untracked")).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A blocking [`Read`] + [`Seek`] view of a stored file.
+//!
+//! The store is async, so the reader drives its queries on a Tokio runtime
+//! through a [`Handle`]. `Handle::block_on` panics when called from inside
+//! the runtime, so use the reader from a plain thread or from
+//! `tokio::task::spawn_blocking`, never directly in async code.
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use common::FileID;
+use tokio::runtime::Handle;
+
+use crate::{DataStore, DataStoreError, Result, reconstruct::SectionData};
+
+/// Reads a stored file chunk by chunk, fetching each chunk only when needed.
+pub struct StoredFileReader {
+    store: Arc<DataStore>,
+    runtime: Handle,
+    file_id: FileID,
+    len: u64,
+    pos: u64,
+    /// The most recently fetched section, as `(offset, data)`.
+    current: Option<(u64, Vec<u8>)>,
+}
+
+impl StoredFileReader {
+    /// Opens `file_id` for reading, running store queries on `runtime`.
+    ///
+    /// Fails with [`DataStoreError::NotFound`] if the file is not tracked.
+    pub fn open(store: Arc<DataStore>, file_id: FileID, runtime: Handle) -> Result<Self> {
+        let len = runtime.block_on(store.file_size(&file_id))?;
+
+        Ok(Self {
+            store,
+            runtime,
+            file_id,
+            len,
+            pos: 0,
+            current: None,
+        })
+    }
+
+    /// Total length of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Fetches the section covering byte `pos` of the file.
+    fn fetch_section_at(&self, pos: u64) -> Result<(u64, Vec<u8>)> {
+        let section = self.runtime.block_on(
+            sqlx::query_as::<_, SectionData>(
+                r#"
+                SELECT s.offset, s.length, s.chunk_hash, c.data, c.stored_checksum, c.checksum_algo
+                FROM file_sections s
+                JOIN chunks c ON c.hash = s.chunk_hash
+                WHERE s.file_id = $1 AND s.offset <= $2
+                ORDER BY s.offset DESC
+                LIMIT 1
+                "#,
+            )
+            .bind(self.file_id.to_string())
+            .bind(pos as i64)
+            .fetch_optional(&self.store.pool()),
+        )?;
+
+        let section = section.ok_or(DataStoreError::NotFound)?;
+        section.verify()?;
+        Ok((section.offset as u64, section.data))
+    }
+}
+
+impl Read for StoredFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+
+        let covers_pos = |(offset, data): &(u64, Vec<u8>)| {
+            *offset <= self.pos && self.pos < offset + data.len() as u64
+        };
+        if !self.current.as_ref().is_some_and(covers_pos) {
+            let section = self.fetch_section_at(self.pos).map_err(io::Error::other)?;
+            if !covers_pos(&section) {
+                // Sections are contiguous, so a gap means the data is missing
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stored file has no section at the current position",
+                ));
+            }
+            self.current = Some(section);
+        }
+
+        let (offset, data) = self.current.as_ref().expect("section was just loaded");
+        let start = (self.pos - offset) as usize;
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for StoredFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        let target = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        // The section is looked up lazily on the next read
+        self.pos = target;
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use rand::{RngCore, rng};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_and_seek_stored_file() {
+        let store = Arc::new(setup().await);
+        let file_id = FileID::new();
+        let mut data = vec![0u8; 20 * 1024];
+        rng().fill_bytes(&mut data);
+        store
+            .index_and_store(&file_id, "seek.bin", "/seek.bin", &data[..], None)
+            .await
+            .unwrap();
+
+        let runtime = Handle::current();
+        let original = data.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut reader = StoredFileReader::open(store, file_id, runtime).unwrap();
+            assert_eq!(reader.len(), original.len() as u64);
+
+            let mut all = Vec::new();
+            reader.read_to_end(&mut all).unwrap();
+            assert_eq!(all, original);
+
+            // Jump back into the middle of the file, across chunk boundaries
+            reader.seek(SeekFrom::Start(5000)).unwrap();
+            let mut window = vec![0u8; 7000];
+            reader.read_exact(&mut window).unwrap();
+            assert_eq!(window, &original[5000..12000]);
+
+            reader.seek(SeekFrom::End(-100)).unwrap();
+            let mut tail = Vec::new();
+            reader.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, &original[original.len() - 100..]);
+
+            reader.seek(SeekFrom::Current(-150)).unwrap();
+            let mut back = [0u8; 50];
+            reader.read_exact(&mut back).unwrap();
+            assert_eq!(back, original[original.len() - 150..original.len() - 100]);
+
+            assert!(reader.seek(SeekFrom::Current(-1_000_000)).is_err());
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,731 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Reading file contents back out of the store.
+//!
+//! Files are rebuilt from their sections: each section names the chunk that
+//! holds the bytes for `[offset, offset + length)` of the file.
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufWriter, Seek, Write},
+    path::Path,
+};
+
+use common::FileID;
+use memmap2::MmapMut;
+
+use crate::{DataStore, DataStoreError, Fetch, FileTableEntry, Result, ScopedTemp, chunk_store};
+
+/// A section joined with the data of the chunk it points at.
+#[derive(sqlx::FromRow)]
+pub(crate) struct SectionData {
+    pub(crate) offset: i64,
+    pub(crate) length: i64,
+    pub(crate) chunk_hash: Vec<u8>,
+    pub(crate) data: Vec<u8>,
+    pub(crate) stored_checksum: Option<i64>,
+    pub(crate) checksum_algo: Option<String>,
+}
+
+impl SectionData {
+    /// Checks the chunk data against its stored checksum.
+    pub(crate) fn verify(&self) -> Result<()> {
+        chunk_store::verify_stored(
+            &self.chunk_hash,
+            &self.data,
+            self.stored_checksum,
+            self.checksum_algo.as_deref(),
+        )
+    }
+}
+
+impl DataStore {
+    /// Reads `len` bytes of a file starting at byte `start`.
+    ///
+    /// Only the sections overlapping `[start, start + len)` are fetched, and the
+    /// first and last chunk are trimmed to the requested window. A range running
+    /// past the end of the file is clamped, and a zero-length range is empty.
+    pub async fn read_range(&self, file_id: &FileID, start: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let start = start.min(i64::MAX as u64) as i64;
+        let end = start.saturating_add(len.min(i64::MAX as u64) as i64);
+
+        let sections = sqlx::query_as::<_, SectionData>(
+            r#"
+            SELECT s.offset, s.length, s.chunk_hash, c.data, c.stored_checksum, c.checksum_algo
+            FROM file_sections s
+            JOIN chunks c ON c.hash = s.chunk_hash
+            WHERE s.file_id = $1 AND s.offset < $2 AND s.offset + s.length > $3
+            ORDER BY s.offset ASC
+            "#,
+        )
+        .bind(file_id.to_string())
+        .bind(end)
+        .bind(start)
+        .fetch_all(&self.pool())
+        .await?;
+
+        // Sized from what the file holds, not the caller's possibly huge `len`
+        let capacity = sections
+            .iter()
+            .map(|section| end.min(section.offset + section.length) - start.max(section.offset))
+            .sum::<i64>();
+        let mut out = Vec::with_capacity(capacity as usize);
+        for section in sections {
+            section.verify()?;
+            check_section_length(section.offset, section.length, &section.data)?;
+            // Trim the parts of the chunk that fall outside the window
+            let from = (start.max(section.offset) - section.offset) as usize;
+            let to = (end.min(section.offset + section.length) - section.offset) as usize;
+            out.extend_from_slice(&section.data[from..to]);
+        }
+
+        Ok(out)
+    }
+
+    /// Computes the BLAKE3 hash of a stored file from its chunk data.
+    ///
+    /// Chunks are fed through a single hasher in offset order and fetched one
+    /// at a time, so at most one chunk is held in memory.
+    pub async fn compute_file_hash(&self, file_id: &FileID) -> Result<blake3::Hash> {
+        let hashes: Vec<Vec<u8>> = sqlx::query_scalar(
+            "SELECT chunk_hash FROM file_sections WHERE file_id = $1 ORDER BY offset ASC",
+        )
+        .bind(file_id.to_string())
+        .fetch_all(&self.pool())
+        .await?;
+
+        if hashes.is_empty() {
+            // An empty file has no sections, but it must still exist
+            let _: FileTableEntry = self.fetch_by(file_id).await?;
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        for hash in hashes {
+            let (data, checksum, algo): (Vec<u8>, Option<i64>, Option<String>) = sqlx::query_as(
+                "SELECT data, stored_checksum, checksum_algo FROM chunks WHERE hash = $1",
+            )
+            .bind(&hash)
+            .fetch_optional(&self.pool())
+            .await?
+            .ok_or(DataStoreError::NotFound)?;
+            chunk_store::verify_stored(&hash, &data, checksum, algo.as_deref())?;
+            hasher.update(&data);
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    /// Size of a stored file in bytes, from the end of its last section.
+    ///
+    /// Fails with [`DataStoreError::NotFound`] if the file is not tracked.
+    pub async fn file_size(&self, file_id: &FileID) -> Result<u64> {
+        if self.try_fetch_file(file_id).await?.is_none() {
+            return Err(DataStoreError::NotFound);
+        }
+        let len: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(MAX(offset + length), 0) FROM file_sections WHERE file_id = $1",
+        )
+        .bind(file_id.to_string())
+        .fetch_one(&self.pool())
+        .await?;
+        Ok(len as u64)
+    }
+
+    /// Writes a whole stored file to `out`, returning the number of bytes written.
+    ///
+    /// Chunks are fetched and written one at a time. After each one, `progress`
+    /// is called with the cumulative number of bytes written; pair it with
+    /// [`DataStore::file_size`] to report a percentage.
+    ///
+    /// A chunk whose length differs from its section's is not written; the
+    /// call fails with [`DataStoreError::LengthMismatch`] instead.
+    pub async fn reconstruct_file<W: Write>(
+        &self,
+        file_id: &FileID,
+        out: &mut W,
+        mut progress: Option<&mut dyn FnMut(u64)>,
+    ) -> Result<u64> {
+        let sections = self.ordered_sections(file_id).await?;
+
+        if sections.is_empty() {
+            // An empty file has no sections, but it must still exist
+            let _: FileTableEntry = self.fetch_by(file_id).await?;
+        }
+
+        let mut written = 0;
+        for (offset, hash, length) in sections {
+            let data = self.section_data(offset, &hash, length).await?;
+            out.write_all(&data)?;
+            written += data.len() as u64;
+            if let Some(progress) = progress.as_mut() {
+                progress(written);
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Like [`DataStore::reconstruct_file`], but asks `resolver` for chunks
+    /// the store does not hold.
+    ///
+    /// `resolver` is called with the hash of every chunk whose data is not held
+    /// here: chunks without a row, and rows recorded for an external
+    /// [`ChunkSink`](crate::ChunkSink), which have a size but no data. Such
+    /// chunks live on a peer or in another storage tier. The
+    /// data it returns must hash to that chunk hash, or the call fails with
+    /// [`DataStoreError::Corrupt`], and must be as long as the section. Resolved
+    /// chunks are only written to `out`, not stored.
+    pub async fn reconstruct_with<W: Write, F>(
+        &self,
+        file_id: &FileID,
+        out: &mut W,
+        resolver: F,
+    ) -> Result<u64>
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>>,
+    {
+        let sections = self.ordered_sections(file_id).await?;
+
+        if sections.is_empty() {
+            // An empty file has no sections, but it must still exist
+            let _: FileTableEntry = self.fetch_by(file_id).await?;
+        }
+
+        let mut written = 0;
+        for (offset, hash, length) in sections {
+            // Rows of external chunks have a size but no data
+            let local = self
+                .chunk_data(&hash)
+                .await?
+                .filter(|data| !data.is_empty() || length == 0);
+            let data = match local {
+                Some(data) => data,
+                None => {
+                    let data = resolver(&hash)?;
+                    if blake3::hash(&data).as_bytes()[..] != hash[..] {
+                        return Err(DataStoreError::Corrupt { hash });
+                    }
+                    data
+                }
+            };
+            check_section_length(offset, length, &data)?;
+            out.write_all(&data)?;
+            written += data.len() as u64;
+        }
+
+        Ok(written)
+    }
+
+    /// Restores a stored file to `out_path` through a memory map.
+    ///
+    /// The output is created (or truncated) and sized to [`DataStore::file_size`]
+    /// up front, then each chunk is copied straight to its section's offset.
+    /// Changes are flushed to disk before returning.
+    pub async fn reconstruct_mmap(&self, file_id: &FileID, out_path: &Path) -> Result<()> {
+        let size = self.file_size(file_id).await?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(out_path)?;
+        file.set_len(size)?;
+        if size == 0 {
+            // Zero-length files cannot be mapped, and there is nothing to write
+            return Ok(());
+        }
+
+        // SAFETY: the file was just created or truncated by us and is only
+        // written through this map until it is dropped. Another process
+        // resizing it concurrently would be undefined behavior, as with any mmap.
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+
+        for (offset, hash, length) in self.ordered_sections(file_id).await? {
+            let data = self.section_data(offset, &hash, length).await?;
+            let start = offset as usize;
+            let target = map.get_mut(start..start + data.len()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "section extends past the end of the file",
+                )
+            })?;
+            target.copy_from_slice(&data);
+        }
+
+        map.flush()?;
+        Ok(())
+    }
+
+    /// Restores a stored file to `final_path` without ever exposing a partial file there.
+    ///
+    /// The file is written to a temporary sibling of `final_path`, synced,
+    /// read back and checked against the hash recorded for the file, and only
+    /// then renamed over `final_path`. On a hash mismatch the temporary file
+    /// is kept for inspection and [`DataStoreError::ReconstructedHashMismatch`]
+    /// names it; on any other failure it is removed.
+    pub async fn reconstruct_atomic(&self, file_id: &FileID, final_path: &Path) -> Result<()> {
+        let entry: FileTableEntry = self.fetch_by(file_id).await?;
+        let dir = final_path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let mut temp = ScopedTemp::new_in(dir)?;
+
+        let mut out = BufWriter::new(temp.file());
+        self.reconstruct_file(file_id, &mut out, None).await?;
+        out.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+
+        // Hash what reached the disk, not what was meant to
+        let file = temp.file();
+        file.rewind()?;
+        let mut hasher = blake3::Hasher::new();
+        io::copy(file, &mut hasher)?;
+        if hasher.finalize().as_bytes()[..] != entry.hash[..] {
+            return Err(DataStoreError::ReconstructedHashMismatch { path: temp.keep() });
+        }
+
+        // Close the handle before renaming; Windows refuses to rename open files
+        let temp_path = temp.keep();
+        if let Err(err) = fs::rename(&temp_path, final_path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err.into());
+        }
+        #[cfg(unix)]
+        fs::File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// `(offset, chunk_hash, length)` of each section of `file_id`, in offset order.
+    async fn ordered_sections(&self, file_id: &FileID) -> Result<Vec<(i64, Vec<u8>, i64)>> {
+        let sections = sqlx::query_as(
+            "SELECT offset, chunk_hash, length FROM file_sections WHERE file_id = $1 ORDER BY offset ASC",
+        )
+        .bind(file_id.to_string())
+        .fetch_all(&self.pool())
+        .await?;
+        Ok(sections)
+    }
+
+    /// The verified data of the chunk a section points at, which must be as
+    /// long as the section says.
+    async fn section_data(&self, offset: i64, hash: &[u8], length: i64) -> Result<Vec<u8>> {
+        let data = self
+            .chunk_data(hash)
+            .await?
+            .ok_or(DataStoreError::NotFound)?;
+        check_section_length(offset, length, &data)?;
+        Ok(data)
+    }
+
+    /// The verified data of the chunk `hash`, or `None` if it is not stored.
+    async fn chunk_data(&self, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        let row: Option<(Vec<u8>, Option<i64>, Option<String>)> = sqlx::query_as(
+            "SELECT data, stored_checksum, checksum_algo FROM chunks WHERE hash = $1",
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool())
+        .await?;
+        let Some((data, checksum, algo)) = row else {
+            return Ok(None);
+        };
+        chunk_store::verify_stored(hash, &data, checksum, algo.as_deref())?;
+        Ok(Some(data))
+    }
+
+    /// Checks that the stored chunk data still hashes to the file's recorded hash.
+    pub async fn verify_file(&self, file_id: &FileID) -> Result<bool> {
+        let entry: FileTableEntry = self.fetch_by(file_id).await?;
+        let computed = self.compute_file_hash(file_id).await?;
+        Ok(computed.as_bytes().as_slice() == entry.hash.as_slice())
+    }
+}
+
+/// Fails with [`DataStoreError::LengthMismatch`] unless `data` is as long as
+/// the section at `offset` says.
+fn check_section_length(offset: i64, length: i64, data: &[u8]) -> Result<()> {
+    if data.len() as i64 != length {
+        return Err(DataStoreError::LengthMismatch {
+            offset: offset as u64,
+            expected: length as u64,
+            actual: data.len() as u64,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChunkSink, MemoryChunkSink, setup};
+    use rand::{RngCore, rng};
+    use std::io::Cursor;
+
+    async fn stored_random_file(store: &DataStore, len: usize) -> (FileID, Vec<u8>) {
+        let file_id = FileID::new();
+        let mut data = vec![0u8; len];
+        rng().fill_bytes(&mut data);
+        store
+            .index_and_store(
+                &file_id,
+                "range.bin",
+                "/range.bin",
+                Cursor::new(&data),
+                None,
+            )
+            .await
+            .unwrap();
+        (file_id, data)
+    }
+
+    #[tokio::test]
+    async fn test_read_range_across_chunks() -> Result<()> {
+        let store = setup().await;
+        let (file_id, data) = stored_random_file(&store, 16 * 1024).await;
+
+        // Default chunks are at most 2KB, so this window spans several of them
+        let window = store.read_range(&file_id, 1000, 6000).await?;
+        assert_eq!(window, &data[1000..7000]);
+
+        assert!(store.read_range(&file_id, 1000, 0).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_range_within_one_chunk() -> Result<()> {
+        let store = setup().await;
+        let (file_id, data) = stored_random_file(&store, 8 * 1024).await;
+
+        // Chunks are at least 512 bytes, so 10 bytes at offset 1 stay in the first
+        let window = store.read_range(&file_id, 1, 10).await?;
+        assert_eq!(window, &data[1..11]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_range_clamped_at_eof() -> Result<()> {
+        let store = setup().await;
+        let (file_id, data) = stored_random_file(&store, 4 * 1024).await;
+
+        let window = store.read_range(&file_id, 3000, 10_000).await?;
+        assert_eq!(window, &data[3000..]);
+
+        assert!(store.read_range(&file_id, 10_000, 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_range_huge_len_and_short_chunk() -> Result<()> {
+        let store = setup().await;
+        let (file_id, data) = stored_random_file(&store, 4 * 1024).await;
+
+        // The buffer follows the file, not the requested length
+        assert_eq!(store.read_range(&file_id, 0, u64::MAX).await?, data);
+        assert_eq!(
+            store.read_range(&file_id, 100, u64::MAX).await?,
+            &data[100..]
+        );
+
+        // A chunk shorter than its section is an error, not a truncated read
+        let (hash, offset): (Vec<u8>, i64) = sqlx::query_as(
+            "SELECT chunk_hash, offset FROM file_sections WHERE file_id = $1 ORDER BY offset LIMIT 1",
+        )
+        .bind(file_id.to_string())
+        .fetch_one(&store.pool())
+        .await?;
+        sqlx::query("UPDATE chunks SET data = $1, stored_checksum = NULL WHERE hash = $2")
+            .bind(vec![0u8; 10])
+            .bind(&hash)
+            .execute(&store.pool())
+            .await?;
+        let err = store
+            .read_range(&file_id, offset as u64 + 100, 50)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::LengthMismatch { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconstruct_file_reports_progress() -> Result<()> {
+        let store = setup().await;
+        let (file_id, data) = stored_random_file(&store, 12 * 1024).await;
+        let total = store.file_size(&file_id).await?;
+        assert_eq!(total, data.len() as u64);
+
+        let mut reported = Vec::new();
+        let mut out = Vec::new();
+        let written = store
+            .reconstruct_file(&file_id, &mut out, Some(&mut |n| reported.push(n)))
+            .await?;
+        assert_eq!(out, data);
+        assert_eq!(written, total);
+        assert!(reported.len() > 1);
+        assert!(reported.is_sorted());
+        assert_eq!(reported.last(), Some(&total));
+
+        let mut quiet = Vec::new();
+        store.reconstruct_file(&file_id, &mut quiet, None).await?;
+        assert_eq!(quiet, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconstruct_rejects_section_length_mismatch() -> Result<()> {
+        let store = setup().await;
+        let (file_id, _) = stored_random_file(&store, 8 * 1024).await;
+        let (offset, length): (i64, i64) = sqlx::query_as(
+            "SELECT offset, length FROM file_sections WHERE file_id = $1 ORDER BY offset DESC LIMIT 1",
+        )
+        .bind(file_id.to_string())
+        .fetch_one(&store.pool())
+        .await?;
+        sqlx::query(
+            "UPDATE file_sections SET length = length + 1 WHERE file_id = $1 AND offset = $2",
+        )
+        .bind(file_id.to_string())
+        .bind(offset)
+        .execute(&store.pool())
+        .await?;
+
+        let mut out = Vec::new();
+        let err = store
+            .reconstruct_file(&file_id, &mut out, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            DataStoreError::LengthMismatch { offset: o, expected, actual }
+                if o == offset as u64 && expected == length as u64 + 1 && actual == length as u64
+        ));
+        // Everything before the bad section went out; the bad chunk did not
+        assert_eq!(out.len() as i64, offset);
+
+        let dir = tempfile::TempDir::new()?;
+        let err = store
+            .reconstruct_mmap(&file_id, &dir.path().join("out.bin"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::LengthMismatch { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconstruct_with_resolves_missing_chunk() -> Result<()> {
+        let store = setup().await;
+        let (file_id, data) = stored_random_file(&store, 16 * 1024).await;
+        let (hash, offset, length): (Vec<u8>, i64, i64) = sqlx::query_as(
+            "SELECT chunk_hash, offset, length FROM file_sections WHERE file_id = $1 ORDER BY offset LIMIT 1 OFFSET 2",
+        )
+        .bind(file_id.to_string())
+        .fetch_one(&store.pool())
+        .await?;
+        let remote = data[offset as usize..(offset + length) as usize].to_vec();
+
+        // Only a connection without foreign key enforcement can drop a referenced chunk
+        let mut conn = store.pool().acquire().await?;
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("DELETE FROM chunks WHERE hash = $1")
+            .bind(&hash)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("PRAGMA foreign_keys = ON")
+            .execute(&mut *conn)
+            .await?;
+        drop(conn);
+
+        let mut out = Vec::new();
+        let err = store
+            .reconstruct_file(&file_id, &mut out, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::NotFound));
+
+        let asked = std::cell::Cell::new(0);
+        let mut out = Vec::new();
+        let written = store
+            .reconstruct_with(&file_id, &mut out, |wanted| {
+                asked.set(asked.get() + 1);
+                assert_eq!(wanted, hash);
+                Ok(remote.clone())
+            })
+            .await?;
+        assert_eq!(out, data);
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(asked.get(), 1);
+
+        // Data that does not match the chunk hash is refused
+        let mut out = Vec::new();
+        let err = store
+            .reconstruct_with(&file_id, &mut out, |_| Ok(vec![0u8; length as usize]))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::Corrupt { hash: h } if h == hash));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconstruct_with_resolves_sink_chunks() -> Result<()> {
+        let store = setup().await;
+        let sink = MemoryChunkSink::new();
+        let mut data = vec![0u8; 16 * 1024];
+        rng().fill_bytes(&mut data);
+        let file_id = FileID::new();
+        store
+            .index_to_sink(&sink, &file_id, "tier.bin", "/tier.bin", &data[..], None)
+            .await?;
+
+        // Locally only sizes are recorded, so a plain reconstruct cannot succeed
+        let mut out = Vec::new();
+        let err = store
+            .reconstruct_file(&file_id, &mut out, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::LengthMismatch { .. }));
+
+        // The resolver is synchronous, so fetch what the sink holds up front
+        let mut remote = std::collections::HashMap::new();
+        for (_, hash, _) in store.ordered_sections(&file_id).await? {
+            let data = sink
+                .get_chunk(&hash)
+                .await?
+                .expect("sink holds every chunk");
+            remote.insert(hash, data);
+        }
+
+        let asked = std::cell::Cell::new(0);
+        let mut out = Vec::new();
+        let written = store
+            .reconstruct_with(&file_id, &mut out, |wanted| {
+                asked.set(asked.get() + 1);
+                remote.get(wanted).cloned().ok_or(DataStoreError::NotFound)
+            })
+            .await?;
+        assert_eq!(out, data);
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(asked.get() as i64, store.logical_section_count().await?);
+
+        // Data that does not match the chunk hash is refused
+        let mut out = Vec::new();
+        let err = store
+            .reconstruct_with(&file_id, &mut out, |_| Ok(vec![0u8; 16]))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::Corrupt { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconstruct_mmap() -> Result<()> {
+        let store = setup().await;
+        let dir = tempfile::TempDir::new()?;
+        let (file_id, data) = stored_random_file(&store, 40 * 1024).await;
+
+        let out = dir.path().join("restored.bin");
+        // Stale, longer content at the destination is replaced
+        std::fs::write(&out, vec![0xEE; 64 * 1024])?;
+        store.reconstruct_mmap(&file_id, &out).await?;
+        assert_eq!(std::fs::read(&out)?, data);
+
+        let empty = FileID::new();
+        store
+            .index_and_store(&empty, "empty", "/empty", &[][..], None)
+            .await?;
+        let out = dir.path().join("empty.bin");
+        store.reconstruct_mmap(&empty, &out).await?;
+        assert_eq!(std::fs::metadata(&out)?.len(), 0);
+
+        let err = store
+            .reconstruct_mmap(&FileID::new(), &dir.path().join("none"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::NotFound));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconstruct_atomic() -> Result<()> {
+        let store = setup().await;
+        let dir = tempfile::TempDir::new()?;
+        let (file_id, data) = stored_random_file(&store, 24 * 1024).await;
+        let entries = |dir: &Path| -> io::Result<Vec<_>> {
+            fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect()
+        };
+
+        let out = dir.path().join("restored.bin");
+        std::fs::write(&out, b"previous version")?;
+        store.reconstruct_atomic(&file_id, &out).await?;
+        assert_eq!(std::fs::read(&out)?, data);
+        assert_eq!(entries(dir.path())?, ["restored.bin"]);
+
+        // A failed rename leaves no temporary file behind
+        let occupied = dir.path().join("occupied");
+        std::fs::create_dir(&occupied)?;
+        std::fs::write(occupied.join("inner"), b"in the way")?;
+        assert!(store.reconstruct_atomic(&file_id, &occupied).await.is_err());
+        std::fs::remove_dir_all(&occupied)?;
+        assert_eq!(entries(dir.path())?, ["restored.bin"]);
+
+        sqlx::query("UPDATE files SET hash = $1 WHERE file_id = $2")
+            .bind(vec![0u8; 32])
+            .bind(file_id.to_string())
+            .execute(&store.pool())
+            .await?;
+        let err = store.reconstruct_atomic(&file_id, &out).await.unwrap_err();
+        let DataStoreError::ReconstructedHashMismatch { path } = err else {
+            panic!("expected a hash mismatch, got {err:?}");
+        };
+        // The destination is untouched and the suspect copy is kept beside it
+        assert_eq!(std::fs::read(&out)?, data);
+        assert_eq!(std::fs::read(&path)?, data);
+        assert_eq!(path.parent(), Some(dir.path()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compute_file_hash_detects_corruption() -> Result<()> {
+        let store = setup().await;
+        let (file_id, data) = stored_random_file(&store, 8 * 1024).await;
+
+        let computed = store.compute_file_hash(&file_id).await?;
+        assert_eq!(computed, blake3::hash(&data));
+        let entry: FileTableEntry = store.fetch_by(&file_id).await?;
+        assert_eq!(computed.as_bytes().to_vec(), entry.hash);
+        assert!(store.verify_file(&file_id).await?);
+
+        // Flip the content of one of the file's chunks
+        let chunk_hash: Vec<u8> = sqlx::query_scalar(
+            "SELECT chunk_hash FROM file_sections WHERE file_id = $1 ORDER BY offset LIMIT 1",
+        )
+        .bind(file_id.to_string())
+        .fetch_one(&store.pool())
+        .await?;
+        sqlx::query("UPDATE chunks SET data = $1 WHERE hash = $2")
+            .bind(vec![0u8; 16])
+            .bind(&chunk_hash)
+            .execute(&store.pool())
+            .await?;
+        // The stored checksum no longer matches, so reads refuse the chunk
+        let err = store.compute_file_hash(&file_id).await.unwrap_err();
+        assert!(matches!(err, DataStoreError::Corrupt { .. }));
+        let err = store.read_range(&file_id, 0, 16).await.unwrap_err();
+        assert!(matches!(err, DataStoreError::Corrupt { .. }));
+
+        // Bytes consistent with their checksum can still hash to the wrong content
+        sqlx::query("UPDATE chunks SET stored_checksum = $1 WHERE hash = $2")
+            .bind(chunk_store::stored_checksum(&[0u8; 16]))
+            .bind(&chunk_hash)
+            .execute(&store.pool())
+            .await?;
+        assert_ne!(store.compute_file_hash(&file_id).await?, computed);
+        assert!(!store.verify_file(&file_id).await?);
+        Ok(())
+    }
+}
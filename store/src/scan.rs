@@ -0,0 +1,505 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Batch indexing of a directory tree.
+//!
+//! Files can be locked, unreadable or deleted while a scan runs. Those are
+//! per-file problems, so by default they are collected into the report and
+//! the scan carries on; database errors still abort the whole scan.
+//!
+//! Every file is committed on its own, so the `last_indexed_at` column doubles
+//! as a checkpoint: [`DataStore::resume_index_directory`] skips files indexed
+//! after their last modification and can be cancelled between files.
+//!
+//! On Unix, each file's device and inode number are recorded as well. A path
+//! that is a hard link to an already indexed, unchanged file is tracked by
+//! copying that file's sections instead of chunking the same data again.
+use std::{
+    fs::{self, File, Metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::UNIX_EPOCH,
+};
+
+use camino::Utf8PathBuf;
+use common::FileID;
+
+use crate::{
+    ChunkConfig, DataStore, DataStoreError, Fetch, FileSectionEntry, FileTableEntry, PathEntry,
+    Result, StoreEvent, ingest::write_file,
+};
+
+/// What to do when a single file cannot be read during a scan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScanPolicy {
+    /// Stop at the first unreadable file and return its error.
+    AbortOnFirst,
+    /// Record the failure in the [`ScanReport`] and continue with the rest.
+    #[default]
+    SkipAndCollect,
+}
+
+/// Outcome of [`DataStore::index_directory`].
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Files that were indexed and stored.
+    pub indexed: Vec<PathBuf>,
+    /// Hard links to an indexed file, tracked by sharing its sections.
+    pub linked: Vec<PathBuf>,
+    /// Files (or directories) that could not be read, with the reason.
+    pub failures: Vec<(PathBuf, io::Error)>,
+    /// Files left alone because they were indexed after their last change.
+    pub skipped: Vec<PathBuf>,
+    /// Whether the scan stopped early because it was cancelled.
+    pub cancelled: bool,
+}
+
+/// Asks a running [`DataStore::resume_index_directory`] to stop.
+///
+/// Clones share the same flag. The scan checks it before each file, so the
+/// file being indexed when it is set is still committed.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl DataStore {
+    /// Recursively indexes every regular file below `root`.
+    ///
+    /// Files already tracked keep their `FileID`; new files get a fresh one.
+    /// Unreadable entries are handled according to `policy`.
+    pub async fn index_directory(
+        &self,
+        root: &Path,
+        chunk_config: Option<ChunkConfig>,
+        policy: ScanPolicy,
+    ) -> Result<ScanReport> {
+        self.scan(root, chunk_config, policy, None).await
+    }
+
+    /// Like [`DataStore::index_directory`], but picks up where an earlier
+    /// scan of `root` left off.
+    ///
+    /// Files whose `last_indexed_at` is not older than their modification time
+    /// are listed in [`ScanReport::skipped`] instead of being indexed again.
+    /// Once `cancel` is set, the scan returns before the next file with
+    /// [`ScanReport::cancelled`] set; everything indexed so far stays committed.
+    pub async fn resume_index_directory(
+        &self,
+        root: &Path,
+        chunk_config: Option<ChunkConfig>,
+        policy: ScanPolicy,
+        cancel: &CancelToken,
+    ) -> Result<ScanReport> {
+        self.scan(root, chunk_config, policy, Some(cancel)).await
+    }
+
+    /// Walks `root`, resuming from the `last_indexed_at` checkpoint when `resume` is set.
+    async fn scan(
+        &self,
+        root: &Path,
+        chunk_config: Option<ChunkConfig>,
+        policy: ScanPolicy,
+        resume: Option<&CancelToken>,
+    ) -> Result<ScanReport> {
+        let mut report = ScanReport::default();
+        let mut pending = vec![root.to_path_buf()];
+
+        while let Some(dir) = pending.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    record_failure(&mut report, policy, dir, e)?;
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                let path = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(e) => {
+                        record_failure(&mut report, policy, dir.clone(), e)?;
+                        continue;
+                    }
+                };
+
+                match fs::metadata(&path) {
+                    Ok(metadata) if metadata.is_dir() => pending.push(path),
+                    Ok(metadata) if metadata.is_file() => {
+                        if let Some(cancel) = resume {
+                            if cancel.is_cancelled() {
+                                report.cancelled = true;
+                                return Ok(report);
+                            }
+                            if self.is_up_to_date(&path, &metadata).await? {
+                                report.skipped.push(path);
+                                continue;
+                            }
+                        }
+                        match self.index_path(&path, chunk_config).await? {
+                            Ok(Indexed::Chunked) => report.indexed.push(path),
+                            Ok(Indexed::Linked) => report.linked.push(path),
+                            Err(e) => record_failure(&mut report, policy, path, e)?,
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => record_failure(&mut report, policy, path, e)?,
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Whether the file at `path` was indexed after it was last modified.
+    async fn is_up_to_date(&self, path: &Path, metadata: &Metadata) -> Result<bool> {
+        let (Some(path), Some(modified_ms)) = (path.to_str(), modified_ms(metadata)) else {
+            return Ok(false);
+        };
+        let last_indexed_at: Option<Option<i64>> =
+            sqlx::query_scalar("SELECT last_indexed_at FROM files WHERE path = $1")
+                .bind(path)
+                .fetch_optional(&self.pool())
+                .await?;
+        Ok(last_indexed_at
+            .flatten()
+            .is_some_and(|at| at >= modified_ms))
+    }
+
+    /// Indexes one file, returning I/O problems separately from store errors.
+    ///
+    /// The outer `Result` carries fatal store errors, the inner `io::Result`
+    /// problems reading this particular file.
+    async fn index_path(
+        &self,
+        path: &Path,
+        chunk_config: Option<ChunkConfig>,
+    ) -> Result<std::result::Result<Indexed, io::Error>> {
+        let Some(path_str) = path.to_str() else {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "path is not valid UTF-8");
+            return Ok(Err(e));
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => return Ok(Err(e)),
+        };
+        let metadata = match file.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        let file_id = match self.fetch_by(&Utf8PathBuf::from(path_str)).await {
+            Ok(PathEntry { file_id, .. }) => file_id.parse::<FileID>()?,
+            Err(DataStoreError::NotFound) => FileID::new(),
+            Err(e) => return Err(e),
+        };
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let inode = inode_key(&metadata);
+        if let Some((device, inode)) = inode {
+            let linked_to = self
+                .find_indexed_link(device, inode, path_str, &metadata)
+                .await?;
+            if let Some(source) = linked_to {
+                self.link_file(&source, &file_id, &name, path_str).await?;
+                self.record_inode(&file_id, device, inode).await?;
+                return Ok(Ok(Indexed::Linked));
+            }
+        }
+        self.ensure_free_space(metadata.len())?;
+
+        match self
+            .index_and_store(&file_id, &name, path_str, file, chunk_config)
+            .await
+        {
+            Ok(()) => {
+                if let Some((device, inode)) = inode {
+                    self.record_inode(&file_id, device, inode).await?;
+                }
+                Ok(Ok(Indexed::Chunked))
+            }
+            Err(DataStoreError::IoError(e))
+            | Err(DataStoreError::ChunkingError(fastcdc::v2020::Error::IoError(e))) => Ok(Err(e)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Another path to the same inode whose row is not older than the file's last change.
+    async fn find_indexed_link(
+        &self,
+        device: i64,
+        inode: i64,
+        path: &str,
+        metadata: &Metadata,
+    ) -> Result<Option<String>> {
+        let Some(modified_ms) = modified_ms(metadata) else {
+            return Ok(None);
+        };
+        let file_id = sqlx::query_scalar(
+            r#"
+            SELECT file_id FROM files
+            WHERE device = $1 AND inode = $2 AND path <> $3 AND last_indexed_at >= $4
+            LIMIT 1
+            "#,
+        )
+        .bind(device)
+        .bind(inode)
+        .bind(path)
+        .bind(modified_ms)
+        .fetch_optional(&self.pool())
+        .await?;
+        Ok(file_id)
+    }
+
+    /// Tracks `path` under `file_id` with the content and sections of `source`.
+    async fn link_file(
+        &self,
+        source: &str,
+        file_id: &FileID,
+        name: &str,
+        path: &str,
+    ) -> Result<()> {
+        self.with_file_lock(file_id, async {
+            let _slot = self.transaction_slot().await;
+            let mut tx = self.pool().begin().await?;
+            let source: FileTableEntry = sqlx::query_as("SELECT * FROM files WHERE file_id = $1")
+                .bind(source)
+                .fetch_one(&mut *tx)
+                .await?;
+            let sections: Vec<FileSectionEntry> =
+                sqlx::query_as("SELECT * FROM file_sections WHERE file_id = $1")
+                    .bind(&source.file_id)
+                    .fetch_all(&mut *tx)
+                    .await?;
+            let checksum: Option<i64> =
+                sqlx::query_scalar("SELECT quick_checksum FROM files WHERE file_id = $1")
+                    .bind(&source.file_id)
+                    .fetch_one(&mut *tx)
+                    .await?;
+
+            let event = StoreEvent::FileStored(*file_id);
+            let file_id = file_id.to_string();
+            let sections = sections
+                .into_iter()
+                .map(|section| FileSectionEntry {
+                    file_id: file_id.clone(),
+                    ..section
+                })
+                .collect();
+            let file = FileTableEntry {
+                file_id,
+                name: name.to_string(),
+                path: path.to_string(),
+                ..source
+            };
+            write_file(
+                &mut tx,
+                &self.sync_set,
+                file,
+                sections,
+                checksum.map(|checksum| checksum as u32),
+            )
+            .await?;
+            tx.commit().await?;
+            self.emit(event);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn record_inode(&self, file_id: &FileID, device: i64, inode: i64) -> Result<()> {
+        sqlx::query("UPDATE files SET device = $1, inode = $2 WHERE file_id = $3")
+            .bind(device)
+            .bind(inode)
+            .bind(file_id.to_string())
+            .execute(&self.pool())
+            .await?;
+        Ok(())
+    }
+}
+
+/// How a scanned file came to be tracked.
+enum Indexed {
+    Chunked,
+    Linked,
+}
+
+fn modified_ms(metadata: &Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    Some(
+        modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64,
+    )
+}
+
+/// `(device, inode)` of the file, or `None` where hard links cannot be detected.
+#[cfg(unix)]
+pub(crate) fn inode_key(metadata: &Metadata) -> Option<(i64, i64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev() as i64, metadata.ino() as i64))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn inode_key(_metadata: &Metadata) -> Option<(i64, i64)> {
+    None
+}
+
+fn record_failure(
+    report: &mut ScanReport,
+    policy: ScanPolicy,
+    path: PathBuf,
+    error: io::Error,
+) -> Result<()> {
+    match policy {
+        ScanPolicy::AbortOnFirst => Err(error.into()),
+        ScanPolicy::SkipAndCollect => {
+            report.failures.push((path, error));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_scan_skips_unreadable_file() -> Result<()> {
+        let store = setup().await;
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("a.txt"), vec![1u8; 3000])?;
+        fs::create_dir(dir.path().join("nested"))?;
+        fs::write(dir.path().join("nested").join("b.txt"), vec![2u8; 1000])?;
+        // A dangling symlink is listed by the scan but cannot be opened
+        let broken = dir.path().join("broken.txt");
+        std::os::unix::fs::symlink(dir.path().join("gone.txt"), &broken)?;
+
+        let report = store
+            .index_directory(dir.path(), None, ScanPolicy::SkipAndCollect)
+            .await?;
+        assert_eq!(report.indexed.len(), 2);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0, broken);
+        assert_eq!(report.failures[0].1.kind(), io::ErrorKind::NotFound);
+
+        let err = store
+            .index_directory(dir.path(), None, ScanPolicy::AbortOnFirst)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DataStoreError::IoError(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_skips_checkpointed_files() -> Result<()> {
+        let store = setup().await;
+        let dir = TempDir::new()?;
+        let files: Vec<PathBuf> = (1..=5u8)
+            .map(|i| {
+                let path = dir.path().join(format!("{i}.txt"));
+                fs::write(&path, vec![i; 3000]).map(|()| path)
+            })
+            .collect::<io::Result<_>>()?;
+
+        // Cancelled as soon as the first file is committed, mid-scan
+        let cancel = CancelToken::new();
+        let mut events = store.subscribe();
+        let (report, _) = tokio::join!(
+            store.resume_index_directory(dir.path(), None, ScanPolicy::SkipAndCollect, &cancel),
+            async {
+                events.recv().await.unwrap();
+                cancel.cancel();
+            }
+        );
+        let first = report?;
+        assert!(first.cancelled);
+        assert!(!first.indexed.is_empty() && first.indexed.len() < files.len());
+        // Exactly the files reported as indexed are tracked
+        for path in &files {
+            let tracked = store
+                .fetch_by(&Utf8PathBuf::from(path.to_str().unwrap()))
+                .await
+                .map(|_: PathEntry| ());
+            assert_eq!(tracked.is_ok(), first.indexed.contains(path), "{path:?}");
+        }
+
+        // Resuming indexes the rest and skips what the first run committed
+        let cancel = CancelToken::new();
+        let report = store
+            .resume_index_directory(dir.path(), None, ScanPolicy::SkipAndCollect, &cancel)
+            .await?;
+        assert!(!report.cancelled);
+        let sorted = |paths: &[PathBuf]| {
+            let mut paths = paths.to_vec();
+            paths.sort();
+            paths
+        };
+        assert_eq!(sorted(&report.skipped), sorted(&first.indexed));
+        assert_eq!(
+            sorted(&[first.indexed, report.indexed].concat()),
+            sorted(&files)
+        );
+
+        // Only files that are new since the interrupted scan are processed
+        fs::write(dir.path().join("c.txt"), vec![3u8; 3000])?;
+        let report = store
+            .resume_index_directory(dir.path(), None, ScanPolicy::SkipAndCollect, &cancel)
+            .await?;
+        assert_eq!(report.indexed, vec![dir.path().join("c.txt")]);
+        assert_eq!(report.skipped.len(), files.len());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hard_link_shares_indexed_content() -> Result<()> {
+        let store = setup().await;
+        let dir = TempDir::new()?;
+        let original = dir.path().join("original.bin");
+        let link = dir.path().join("link.bin");
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(&original, &data)?;
+        fs::hard_link(&original, &link)?;
+
+        let report = store
+            .index_directory(dir.path(), None, ScanPolicy::SkipAndCollect)
+            .await?;
+        // One path is chunked, the other only shares its sections
+        assert_eq!(report.indexed.len(), 1);
+        assert_eq!(report.linked.len(), 1);
+
+        for path in [&original, &link] {
+            let entry: PathEntry = store
+                .fetch_by(&Utf8PathBuf::from(path.to_str().unwrap()))
+                .await?;
+            let file_id: FileID = entry.file_id.parse()?;
+            assert_eq!(
+                store.read_range(&file_id, 0, data.len() as u64).await?,
+                data
+            );
+        }
+        Ok(())
+    }
+}
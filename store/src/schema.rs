@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The SQL schema, for apps that keep skie's tables in their own database.
+//!
+//! [`DataStore::new`] creates the schema through migrations, recorded in
+//! sqlx's `_sqlx_migrations` table. A host that shares its SQLite file with
+//! the store can check [`DataStore::table_names`] against its own tables, or
+//! run [`DataStore::schema_sql`] to create them up front.
+use crate::DataStore;
+
+/// Every migration in order, as a single script.
+const SCHEMA_SQL: &str = concat!(
+    include_str!("../db/migrations/01_create_tables.sql"),
+    "\n",
+    include_str!("../db/migrations/02_chunk_data.sql"),
+    "\n",
+    include_str!("../db/migrations/03_file_content_type.sql"),
+    "\n",
+    include_str!("../db/migrations/04_chunk_stored_checksum.sql"),
+    "\n",
+    include_str!("../db/migrations/05_file_index_stats.sql"),
+    "\n",
+    include_str!("../db/migrations/06_file_inode.sql"),
+    "\n",
+    include_str!("../db/migrations/07_meta.sql"),
+    "\n",
+    include_str!("../db/migrations/08_failed_index.sql"),
+    "\n",
+    include_str!("../db/migrations/09_file_sync_set.sql"),
+    "\n",
+    include_str!("../db/migrations/10_file_quick_checksum.sql"),
+);
+
+/// Tables created by [`SCHEMA_SQL`].
+const TABLE_NAMES: &[&str] = &["files", "chunks", "file_sections", "meta", "failed_index"];
+
+impl DataStore {
+    /// The statements creating the store's tables and indexes.
+    ///
+    /// This is every migration concatenated in order, so running it on an
+    /// empty database gives the schema [`DataStore::new`] would. It does not
+    /// record the migrations as applied; a store opened on top of it later
+    /// tries to run them again and fails, so pick one way to create the tables.
+    pub fn schema_sql() -> &'static str {
+        SCHEMA_SQL
+    }
+
+    /// Names of the tables in [`DataStore::schema_sql`]. Migrations also add
+    /// sqlx's `_sqlx_migrations`, which is not listed.
+    pub fn table_names() -> &'static [&'static str] {
+        TABLE_NAMES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Result;
+    use sqlx::{
+        AnyPool, Executor,
+        any::{AnyPoolOptions, install_default_drivers},
+    };
+
+    async fn memory_pool() -> Result<AnyPool> {
+        install_default_drivers();
+        Ok(AnyPoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?)
+    }
+
+    /// Every table and index, except sqlx's bookkeeping.
+    async fn schema_of(pool: &AnyPool) -> Result<Vec<(String, String, String)>> {
+        Ok(sqlx::query_as(
+            r#"
+            SELECT type, name, COALESCE(sql, '') FROM sqlite_master
+            WHERE name NOT LIKE '_sqlx_migrations%' AND name NOT LIKE 'sqlite_%'
+            ORDER BY type, name
+            "#,
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    #[tokio::test]
+    async fn test_schema_sql_matches_migrations() -> Result<()> {
+        let migrator = sqlx::migrate!("db/migrations");
+        for migration in migrator.iter() {
+            assert!(
+                SCHEMA_SQL.contains(migration.sql.trim()),
+                "migration {} {} is missing from SCHEMA_SQL",
+                migration.version,
+                migration.description
+            );
+        }
+
+        let scripted = memory_pool().await?;
+        scripted.execute(DataStore::schema_sql()).await?;
+        let migrated = memory_pool().await?;
+        migrator.run(&migrated).await?;
+        assert_eq!(schema_of(&scripted).await?, schema_of(&migrated).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_schema_sql_creates_listed_tables() -> Result<()> {
+        let tables = DataStore::table_names();
+        for core in ["files", "chunks", "file_sections", "meta"] {
+            assert!(tables.contains(&core), "{core} is not listed");
+        }
+        assert!(!DataStore::schema_sql().trim().is_empty());
+
+        let pool = memory_pool().await?;
+        pool.execute(DataStore::schema_sql()).await?;
+
+        let mut created: Vec<String> =
+            sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table'")
+                .fetch_all(&pool)
+                .await?;
+        let mut listed: Vec<String> = tables.iter().map(|t| t.to_string()).collect();
+        created.sort();
+        listed.sort();
+        assert_eq!(created, listed);
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Pluggable storage for chunk data.
+//!
+//! A [`ChunkSink`] holds the bytes of each chunk, keyed by its hash. The
+//! `files` and `file_sections` metadata always stays in the database; only the
+//! chunk data moves, so object stores such as S3 can take the place of the
+//! `chunks` table.
+//!
+//! Sections reference their chunk through a foreign key, so ingesting into an
+//! external sink still records each chunk's hash and size in `chunks`, just
+//! without its data.
+use std::io::{Cursor, Read};
+
+use async_trait::async_trait;
+use common::FileID;
+use dashmap::DashMap;
+
+use crate::{
+    ChunkConfig, DataStore, DataStoreError, Fetch, FileTableEntry, QuickChecksum, Result,
+    StoreEvent, chunk_iter, chunk_store,
+    ingest::{read_head, write_file},
+};
+
+/// A deduplicating store for chunk data.
+#[async_trait]
+pub trait ChunkSink: Send + Sync {
+    /// Stores `data` under `hash`. Storing a hash that is already present is a no-op.
+    async fn put_chunk(&self, hash: &[u8], data: &[u8]) -> Result<()>;
+
+    /// Whether a chunk with `hash` is stored.
+    async fn has_chunk(&self, hash: &[u8]) -> Result<bool>;
+
+    /// The data stored under `hash`, or `None` if there is none.
+    async fn get_chunk(&self, hash: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
+/// The SQLite `chunks` table.
+#[async_trait]
+impl ChunkSink for DataStore {
+    async fn put_chunk(&self, hash: &[u8], data: &[u8]) -> Result<()> {
+        sqlx::query(chunk_store::INSERT_QUERY)
+            .bind(hash)
+            .bind(data.len() as i64)
+            .bind(data)
+            .bind(chunk_store::stored_checksum(data))
+            .bind(chunk_store::CHECKSUM_ALGO)
+            .execute(&self.pool())
+            .await?;
+        Ok(())
+    }
+
+    async fn has_chunk(&self, hash: &[u8]) -> Result<bool> {
+        // Rows recorded for an external sink have a size but no data
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM chunks WHERE hash = $1 AND length(data) = size",
+        )
+        .bind(hash)
+        .fetch_one(&self.pool())
+        .await?;
+        Ok(count > 0)
+    }
+
+    async fn get_chunk(&self, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        let row: Option<(Vec<u8>, Option<i64>, Option<String>)> = sqlx::query_as(
+            "SELECT data, stored_checksum, checksum_algo FROM chunks WHERE hash = $1 AND length(data) = size",
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool())
+        .await?;
+
+        let Some((data, checksum, algo)) = row else {
+            return Ok(None);
+        };
+        chunk_store::verify_stored(hash, &data, checksum, algo.as_deref())?;
+        Ok(Some(data))
+    }
+}
+
+/// Keeps chunks in process memory. Meant for tests and short-lived tools.
+#[derive(Debug, Default)]
+pub struct MemoryChunkSink {
+    chunks: DashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryChunkSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of distinct chunks held.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+#[async_trait]
+impl ChunkSink for MemoryChunkSink {
+    async fn put_chunk(&self, hash: &[u8], data: &[u8]) -> Result<()> {
+        self.chunks
+            .entry(hash.to_vec())
+            .or_insert_with(|| data.to_vec());
+        Ok(())
+    }
+
+    async fn has_chunk(&self, hash: &[u8]) -> Result<bool> {
+        Ok(self.chunks.contains_key(hash))
+    }
+
+    async fn get_chunk(&self, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.chunks.get(hash).map(|data| data.clone()))
+    }
+}
+
+impl DataStore {
+    /// Like [`DataStore::index_and_store`], but puts chunk data into `sink`.
+    ///
+    /// Chunks the sink already holds are not sent again. The file row and its
+    /// sections are written to the database in one transaction once every
+    /// chunk has been accepted by the sink.
+    pub async fn index_to_sink<S: ChunkSink, R: Read>(
+        &self,
+        sink: &S,
+        file_id: &FileID,
+        name: &str,
+        path: &str,
+        mut source: R,
+        chunk_config: Option<ChunkConfig>,
+    ) -> Result<()> {
+        self.with_file_lock(file_id, async {
+            let (head, content_type) = read_head(&mut source)?;
+
+            let mut hasher = blake3::Hasher::new();
+            let mut checksum = QuickChecksum::new();
+            let mut chunk_sizes = Vec::new();
+            let mut file_sections = Vec::new();
+            let source = Cursor::new(head).chain(source);
+            for chunk in chunk_iter(file_id, source, 0, chunk_config) {
+                let (chunk, section) = chunk?;
+                file_sections.push(section);
+                self.check_section_count(file_sections.len())?;
+                hasher.update(&chunk.data);
+                checksum.update(&chunk.data);
+                if !sink.has_chunk(&chunk.hash).await? {
+                    sink.put_chunk(&chunk.hash, &chunk.data).await?;
+                }
+                chunk_sizes.push((chunk.hash, chunk.size));
+            }
+
+            let file = FileTableEntry {
+                file_id: file_id.to_string(),
+                name: name.to_string(),
+                path: path.to_string(),
+                hash: hasher.finalize().as_bytes().to_vec(),
+                content_type,
+                chunk_count: 0,
+                last_indexed_at: None,
+            };
+            // Chunk data went to the sink; only metadata reaches the database
+            self.ensure_free_space(0)?;
+            let _slot = self.transaction_slot().await;
+            let mut tx = self.pool().begin().await?;
+            for (hash, size) in chunk_sizes {
+                sqlx::query("INSERT OR IGNORE INTO chunks (hash, size) VALUES ($1, $2)")
+                    .bind(hash)
+                    .bind(size)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            write_file(
+                &mut tx,
+                &self.sync_set,
+                file,
+                file_sections,
+                Some(checksum.finish()),
+            )
+            .await?;
+            tx.commit().await?;
+            self.emit(StoreEvent::FileStored(*file_id));
+            Ok(())
+        })
+        .await
+    }
+
+    /// Rebuilds a whole file from its sections, reading chunk data from `sink`.
+    pub async fn read_file_from<S: ChunkSink>(
+        &self,
+        sink: &S,
+        file_id: &FileID,
+    ) -> Result<Vec<u8>> {
+        let hashes: Vec<Vec<u8>> = sqlx::query_scalar(
+            "SELECT chunk_hash FROM file_sections WHERE file_id = $1 ORDER BY offset ASC",
+        )
+        .bind(file_id.to_string())
+        .fetch_all(&self.pool())
+        .await?;
+
+        if hashes.is_empty() {
+            // An empty file has no sections, but it must still exist
+            let _: FileTableEntry = self.fetch_by(file_id).await?;
+        }
+
+        let mut out = Vec::new();
+        for hash in hashes {
+            let data = sink
+                .get_chunk(&hash)
+                .await?
+                .ok_or(DataStoreError::NotFound)?;
+            out.extend_from_slice(&data);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use rand::{RngCore, rng};
+
+    #[tokio::test]
+    async fn test_ingest_and_reconstruct_through_memory_sink() -> Result<()> {
+        let store = setup().await;
+        let sink = MemoryChunkSink::new();
+        let mut data = vec![0u8; 32 * 1024];
+        rng().fill_bytes(&mut data);
+
+        let file_id = FileID::new();
+        store
+            .index_to_sink(&sink, &file_id, "s3.bin", "/s3.bin", &data[..], None)
+            .await?;
+        // A copy under another id only adds sections; its chunks are deduplicated
+        let held = sink.len();
+        store
+            .index_to_sink(
+                &sink,
+                &FileID::new(),
+                "copy.bin",
+                "/copy.bin",
+                &data[..],
+                None,
+            )
+            .await?;
+        assert_eq!(sink.len(), held);
+
+        // Chunk data went to the sink, metadata to the database
+        assert!(!sink.is_empty());
+        assert!((sink.len() as i64) < store.logical_section_count().await?);
+        let stored_bytes: i64 = sqlx::query_scalar("SELECT SUM(length(data)) FROM chunks")
+            .fetch_one(&store.pool())
+            .await?;
+        assert_eq!(stored_bytes, 0);
+        let (hash, _) = &store.largest_chunks(1).await?[0];
+        assert!(!store.has_chunk(hash).await?);
+
+        assert_eq!(store.read_file_from(&sink, &file_id).await?, data);
+        let entry: FileTableEntry = store.fetch_by(&file_id).await?;
+        assert_eq!(entry.hash, blake3::hash(&data).as_bytes().to_vec());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_is_a_sink() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let data = b"stored in sqlite".repeat(200);
+
+        store
+            .index_to_sink(&store, &file_id, "db.txt", "/db.txt", &data[..], None)
+            .await?;
+        assert_eq!(store.read_file_from(&store, &file_id).await?, data);
+        assert_eq!(
+            store.read_range(&file_id, 0, data.len() as u64).await?,
+            data
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_index_fills_in_sink_rows() -> Result<()> {
+        let store = setup().await;
+        let sink = MemoryChunkSink::new();
+        let mut data = vec![0u8; 16 * 1024];
+        rng().fill_bytes(&mut data);
+
+        let remote = FileID::new();
+        store
+            .index_to_sink(&sink, &remote, "a.bin", "/remote/a.bin", &data[..], None)
+            .await?;
+        // The same content indexed locally must bring its data along
+        let local = FileID::new();
+        store
+            .index_and_store(&local, "a.bin", "/local/a.bin", &data[..], None)
+            .await?;
+
+        assert_eq!(store.read_range(&local, 0, data.len() as u64).await?, data);
+        let mut out = Vec::new();
+        store.reconstruct_file(&local, &mut out, None).await?;
+        assert_eq!(out, data);
+        assert!(store.verify_file(&remote).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Chunking from async code without blocking the runtime.
+//!
+//! FastCDC and BLAKE3 are synchronous and CPU bound. [`chunk_file_async`] runs
+//! them on Tokio's blocking pool and hands the results back over a channel,
+//! so a `#[tokio::main]` caller can consume chunks as they are found.
+use std::{fs::File, path::PathBuf};
+
+use tokio::sync::mpsc;
+
+use crate::{ChunkConfig, ChunkMetadata, Result, manifest::chunk_metadata_iter};
+
+/// Chunks found ahead of the consumer before chunking pauses.
+const CHUNK_CHANNEL_CAPACITY: usize = 64;
+
+/// Chunks the file at `source` on the blocking pool, yielding each chunk's location in order.
+///
+/// Receive with `while let Some(chunk) = chunks.recv().await`. A failure to
+/// open or read the file is delivered as the last item. Dropping the receiver
+/// stops chunking at the next chunk.
+///
+/// # Panics
+/// Must be called from within a Tokio runtime.
+pub fn chunk_file_async(
+    source: PathBuf,
+    chunk_config: Option<ChunkConfig>,
+) -> mpsc::Receiver<Result<ChunkMetadata>> {
+    let (tx, rx) = mpsc::channel(CHUNK_CHANNEL_CAPACITY);
+
+    tokio::task::spawn_blocking(move || {
+        let file = match File::open(&source) {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = tx.blocking_send(Err(e.into()));
+                return;
+            }
+        };
+        for item in chunk_metadata_iter(file, chunk_config) {
+            let failed = item.is_err();
+            if tx.blocking_send(item).is_err() || failed {
+                // The receiver is gone, or there is nothing more to read
+                return;
+            }
+        }
+    });
+
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataStoreError, chunk_source};
+    use common::FileID;
+    use rand::{RngCore, rng};
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn test_chunk_file_async_matches_sync_chunking() -> Result<()> {
+        let mut data = vec![0u8; 32 * 1024];
+        rng().fill_bytes(&mut data);
+        let file = NamedTempFile::new()?;
+        std::fs::write(file.path(), &data)?;
+
+        let mut chunks = chunk_file_async(file.path().to_path_buf(), None);
+        let mut received = Vec::new();
+        while let Some(chunk) = chunks.recv().await {
+            received.push(chunk?);
+        }
+
+        let expected = chunk_source(&FileID::new(), &data[..], None)?;
+        assert_eq!(received.len(), expected.file_sections.len());
+        for (chunk, section) in received.iter().zip(&expected.file_sections) {
+            assert_eq!(chunk.hash, section.chunk_hash);
+            assert_eq!(chunk.offset, section.offset as u64);
+        }
+
+        let mut missing = chunk_file_async(file.path().with_extension("gone"), None);
+        assert!(matches!(
+            missing.recv().await,
+            Some(Err(DataStoreError::IoError(_)))
+        ));
+        assert!(missing.recv().await.is_none());
+        Ok(())
+    }
+}
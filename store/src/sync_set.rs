@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Scoping files into named sync sets.
+//!
+//! Multi-folder or multi-device setups group their files into sync sets so
+//! bulk operations can target one group. A set only scopes the `files` table:
+//! chunks stay deduplicated across every set, so two sets holding the same
+//! content still store it once.
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Component, Path},
+};
+
+use common::FileID;
+
+use crate::{DataStore, DataStoreError, FileTableEntry, Result, StoreEvent};
+
+/// Sync set of files stored without one configured, and of files indexed
+/// before sync sets existed.
+pub const DEFAULT_SYNC_SET: &str = "default";
+
+/// Storage use of the files in one sync set, or of the whole store.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DedupReport {
+    pub files: i64,
+    /// Bytes of every section, i.e. the size of the files themselves.
+    pub logical_bytes: i64,
+    /// Bytes of the distinct chunks those sections reference.
+    pub stored_bytes: i64,
+}
+
+impl DataStore {
+    /// Stores new files in `sync_set` instead of [`DEFAULT_SYNC_SET`].
+    ///
+    /// Files already tracked keep their set when they are re-indexed; move
+    /// them with [`DataStore::set_sync_set`].
+    pub fn with_sync_set(mut self, sync_set: impl Into<String>) -> Self {
+        self.sync_set = sync_set.into();
+        self
+    }
+
+    /// Moves `file_id` into `sync_set`, announced as [`StoreEvent::FileStored`].
+    /// Fails with [`DataStoreError::NotFound`] if the file is not tracked.
+    pub async fn set_sync_set(&self, file_id: &FileID, sync_set: &str) -> Result<()> {
+        let rows = sqlx::query("UPDATE files SET sync_set = $1 WHERE file_id = $2")
+            .bind(sync_set)
+            .bind(file_id.to_string())
+            .execute(&self.pool())
+            .await?
+            .rows_affected();
+        if rows == 0 {
+            return Err(DataStoreError::NotFound);
+        }
+        self.emit(StoreEvent::FileStored(*file_id));
+        Ok(())
+    }
+
+    /// Files in `sync_set`, or every file when `None`, ordered by path.
+    pub async fn list_files(&self, sync_set: Option<&str>) -> Result<Vec<FileTableEntry>> {
+        let files = sqlx::query_as::<_, FileTableEntry>(
+            "SELECT * FROM files WHERE $1 IS NULL OR sync_set = $1 ORDER BY path",
+        )
+        .bind(sync_set)
+        .fetch_all(&self.pool())
+        .await?;
+        Ok(files)
+    }
+
+    /// How much the files in `sync_set`, or all files when `None`, take up
+    /// before and after deduplication.
+    ///
+    /// A chunk also used by another set counts towards both sets'
+    /// `stored_bytes`, so the reports of several sets do not add up to the
+    /// store's.
+    pub async fn dedup_report(&self, sync_set: Option<&str>) -> Result<DedupReport> {
+        let (files, logical_bytes): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT COUNT(DISTINCT f.file_id), COALESCE(SUM(s.length), 0)
+            FROM files f
+            LEFT JOIN file_sections s ON s.file_id = f.file_id
+            WHERE $1 IS NULL OR f.sync_set = $1
+            "#,
+        )
+        .bind(sync_set)
+        .fetch_one(&self.pool())
+        .await?;
+        let stored_bytes: i64 = sqlx::query_scalar(
+            r#"
+            SELECT COALESCE(SUM(c.size), 0) FROM chunks c
+            WHERE c.hash IN (
+                SELECT s.chunk_hash FROM file_sections s
+                JOIN files f ON f.file_id = s.file_id
+                WHERE $1 IS NULL OR f.sync_set = $1
+            )
+            "#,
+        )
+        .bind(sync_set)
+        .fetch_one(&self.pool())
+        .await?;
+        Ok(DedupReport {
+            files,
+            logical_bytes,
+            stored_bytes,
+        })
+    }
+
+    /// Restores every file in `sync_set`, or every file when `None`, below
+    /// `dest`, returning how many were written.
+    ///
+    /// Each file lands at its stored path taken relative to `dest`; missing
+    /// directories are created and existing files overwritten.
+    pub async fn reconstruct_all(&self, sync_set: Option<&str>, dest: &Path) -> Result<u64> {
+        let mut restored = 0;
+        for file in self.list_files(sync_set).await? {
+            let relative: std::path::PathBuf = Path::new(&file.path)
+                .components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .collect();
+            let out_path = dest.join(relative);
+            if let Some(parent) = out_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut out = BufWriter::new(File::create(&out_path)?);
+            self.reconstruct_file(&file.file_id.parse()?, &mut out, None)
+                .await?;
+            out.into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_all()?;
+            restored += 1;
+        }
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup;
+    use rand::{RngCore, rng};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_sync_sets_filter_files_but_share_chunks() -> Result<()> {
+        let mut data = vec![0u8; 16 * 1024];
+        rng().fill_bytes(&mut data);
+        let store = setup().await.with_sync_set("laptop");
+        let laptop = FileID::new();
+        store
+            .index_and_store(&laptop, "a.bin", "/docs/a.bin", &data[..], None)
+            .await?;
+        let chunks = store.physical_chunk_count().await?;
+
+        let store = store.with_sync_set("phone");
+        let phone = FileID::new();
+        store
+            .index_and_store(&phone, "b.bin", "/photos/b.bin", &data[..], None)
+            .await?;
+        // Same content in another set is deduplicated against the first
+        assert_eq!(store.physical_chunk_count().await?, chunks);
+
+        let listed = store.list_files(Some("laptop")).await?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].file_id, laptop.to_string());
+        assert_eq!(store.list_files(None).await?.len(), 2);
+        assert!(store.list_files(Some("tablet")).await?.is_empty());
+
+        let report = store.dedup_report(Some("phone")).await?;
+        assert_eq!(report.files, 1);
+        assert_eq!(report.logical_bytes, data.len() as i64);
+        let all = store.dedup_report(None).await?;
+        assert_eq!(all.logical_bytes, 2 * data.len() as i64);
+        assert_eq!(all.stored_bytes, report.stored_bytes);
+
+        let dest = TempDir::new()?;
+        assert_eq!(store.reconstruct_all(Some("phone"), dest.path()).await?, 1);
+        assert_eq!(fs::read(dest.path().join("photos/b.bin"))?, data);
+        assert!(!dest.path().join("docs").exists());
+
+        // Re-indexing keeps a file in its set; moving it is explicit
+        store
+            .index_and_store(&laptop, "a.bin", "/docs/a.bin", &data[..], None)
+            .await?;
+        assert_eq!(store.list_files(Some("laptop")).await?.len(), 1);
+        store.set_sync_set(&laptop, "phone").await?;
+        assert_eq!(store.list_files(Some("phone")).await?.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Scratch files that clean up after themselves.
+//!
+//! Indexing code that spills large buffers to disk should hold the file through
+//! a [`ScopedTemp`], so the data is removed even when indexing returns early or
+//! panics halfway through.
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+/// A temporary file that is deleted when the guard is dropped.
+pub struct ScopedTemp {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl ScopedTemp {
+    /// Creates a new, uniquely named temporary file in `dir`.
+    pub fn new_in(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(format!(".skie-tmp-{}", Uuid::new_v4()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        Ok(Self {
+            path,
+            file: Some(file),
+        })
+    }
+
+    /// Creates a new temporary file in the system temp directory.
+    pub fn new() -> io::Result<Self> {
+        Self::new_in(&std::env::temp_dir())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The open handle to the backing file.
+    pub fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("file is only taken on drop")
+    }
+
+    /// Closes the file and leaves it on disk, returning its path.
+    pub fn keep(mut self) -> PathBuf {
+        drop(self.file.take());
+        let path = std::mem::take(&mut self.path);
+        // Nothing is left for drop to clean up
+        std::mem::forget(self);
+        path
+    }
+}
+
+impl Drop for ScopedTemp {
+    fn drop(&mut self) {
+        // Close the handle first; Windows refuses to delete open files
+        drop(self.file.take());
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn spill_then_fail(dir: &Path, spilled: &mut Option<PathBuf>) -> io::Result<()> {
+        let mut temp = ScopedTemp::new_in(dir)?;
+        temp.file().write_all(&[0xAB; 4096])?;
+        *spilled = Some(temp.path().to_path_buf());
+
+        Err(io::Error::other("indexing failed after spilling"))
+    }
+
+    #[test]
+    fn test_removed_on_early_return() {
+        let dir = TempDir::new().unwrap();
+        let mut spilled = None;
+
+        assert!(spill_then_fail(dir.path(), &mut spilled).is_err());
+        let spilled = spilled.unwrap();
+        assert!(
+            !spilled.exists(),
+            "Spill file must be removed on early return"
+        );
+    }
+
+    #[test]
+    fn test_removed_on_panic() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let spilled = std::thread::spawn(move || {
+            let temp = ScopedTemp::new_in(&path).unwrap();
+            let spilled = temp.path().to_path_buf();
+            let result = std::panic::catch_unwind(move || {
+                let _temp = temp;
+                panic!("hashing blew up");
+            });
+            assert!(result.is_err());
+            spilled
+        })
+        .join()
+        .unwrap();
+
+        assert!(!spilled.exists(), "Spill file must be removed on panic");
+    }
+}
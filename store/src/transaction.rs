@@ -0,0 +1,328 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Explicit transactions spanning several store operations.
+//!
+//! The `Persist` implementations each run in their own transaction. A
+//! [`StoreTx`] holds one open transaction instead, so callers can combine
+//! writes to different tables into a single atomic unit. Nothing is visible
+//! to other connections until [`StoreTx::commit`]; dropping the handle
+//! without committing rolls everything back.
+use std::sync::Arc;
+
+use common::FileID;
+use sqlx::{Any, AnyPool, Transaction};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast};
+
+use crate::{
+    ChunkTableEntry, DataStore, DataStoreError, FileSectionEntry, FileTableEntry, PathEntry,
+    Result, StoreEvent, chunk_store, file_section, file_store,
+};
+
+/// An open transaction on a [`DataStore`].
+pub struct StoreTx {
+    tx: Transaction<'static, Any>,
+    sync_set: String,
+    _slot: OwnedSemaphorePermit,
+    events: broadcast::Sender<StoreEvent>,
+    /// Events to send once the transaction commits.
+    pending: Vec<StoreEvent>,
+}
+
+/// Pool connections kept out of reach of transactions by default, so reads
+/// still find a connection while writers queue up.
+pub const RESERVED_READ_CONNECTIONS: u32 = 1;
+
+/// The default transaction limit for `pool`: its size less
+/// [`RESERVED_READ_CONNECTIONS`], but at least one.
+pub(crate) fn default_max_transactions(pool: &AnyPool) -> usize {
+    let size = pool.options().get_max_connections();
+    size.saturating_sub(RESERVED_READ_CONNECTIONS).max(1) as usize
+}
+
+impl DataStore {
+    /// Begins a transaction that several writes can be staged in.
+    ///
+    /// Waits for a free transaction slot first; see
+    /// [`DataStore::with_max_transactions`].
+    pub async fn transaction(&self) -> Result<StoreTx> {
+        let slot = self.transaction_slot().await;
+        Ok(StoreTx {
+            tx: self.pool().begin().await?,
+            sync_set: self.sync_set.clone(),
+            _slot: slot,
+            events: self.events.clone(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Allows at most `max` transactions to be open at once.
+    ///
+    /// Every transaction holds a pooled connection until it ends. Beyond the
+    /// limit, callers queue for a slot instead of for a connection, so they
+    /// neither starve plain reads of connections nor run into the pool's
+    /// acquire timeout. Defaults to the pool size less
+    /// [`RESERVED_READ_CONNECTIONS`].
+    ///
+    /// # Panics
+    /// Panics if `max` is zero.
+    pub fn with_max_transactions(mut self, max: usize) -> Self {
+        assert!(max > 0, "at least one transaction must be allowed");
+        self.transaction_slots = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// Waits until another transaction may be opened. Hold the permit until
+    /// the transaction has ended.
+    pub(crate) async fn transaction_slot(&self) -> OwnedSemaphorePermit {
+        self.transaction_slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("transaction slots are never closed")
+    }
+}
+
+impl StoreTx {
+    /// Upserts a file row. Like `Persist<FileTableEntry>`, this leaves the
+    /// file's sections alone and forgets its quick checksum.
+    pub async fn store_file(&mut self, item: FileTableEntry) -> Result<()> {
+        sqlx::query(file_store::UPSERT_QUERY)
+            .bind(&item.file_id)
+            .bind(item.name)
+            .bind(item.path)
+            .bind(item.hash)
+            .bind(item.content_type)
+            .bind(&self.sync_set)
+            .execute(&mut *self.tx)
+            .await?;
+        self.stage(&item.file_id, StoreEvent::FileStored);
+        Ok(())
+    }
+
+    /// Inserts a chunk. Chunks that are already stored are left untouched.
+    pub async fn store_chunk(&mut self, item: ChunkTableEntry) -> Result<()> {
+        let checksum = chunk_store::stored_checksum(&item.data);
+        sqlx::query(chunk_store::INSERT_QUERY)
+            .bind(item.hash)
+            .bind(item.size)
+            .bind(item.data)
+            .bind(checksum)
+            .bind(chunk_store::CHECKSUM_ALGO)
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Upserts a section, keyed by its file and offset.
+    pub async fn store_section(&mut self, entry: FileSectionEntry) -> Result<()> {
+        sqlx::query(file_section::UPSERT_QUERY)
+            .bind(&entry.file_id)
+            .bind(entry.chunk_hash)
+            .bind(entry.length)
+            .bind(entry.offset)
+            .execute(&mut *self.tx)
+            .await?;
+        sqlx::query(file_store::CLEAR_QUICK_CHECKSUM_QUERY)
+            .bind(&entry.file_id)
+            .execute(&mut *self.tx)
+            .await?;
+        self.stage(&entry.file_id, StoreEvent::SectionsChanged);
+        Ok(())
+    }
+
+    /// Moves a tracked file to a new path.
+    ///
+    /// Fails with [`DataStoreError::NotFound`] if the file is not tracked.
+    pub async fn store_path(&mut self, item: PathEntry) -> Result<()> {
+        let rows = sqlx::query("UPDATE files SET path = $1 WHERE file_id = $2")
+            .bind(item.path)
+            .bind(&item.file_id)
+            .execute(&mut *self.tx)
+            .await?
+            .rows_affected();
+        if rows == 0 {
+            return Err(DataStoreError::NotFound);
+        }
+        self.stage(&item.file_id, StoreEvent::FileStored);
+        Ok(())
+    }
+
+    /// Deletes a file row together with its sections.
+    ///
+    /// The chunks stay, since other files may still reference them. Returns
+    /// whether the file existed.
+    pub async fn delete_file(&mut self, file_id: &FileID) -> Result<bool> {
+        let event = StoreEvent::FileDeleted(*file_id);
+        let file_id = file_id.to_string();
+        sqlx::query("DELETE FROM file_sections WHERE file_id = $1")
+            .bind(&file_id)
+            .execute(&mut *self.tx)
+            .await?;
+        let rows = sqlx::query("DELETE FROM files WHERE file_id = $1")
+            .bind(&file_id)
+            .execute(&mut *self.tx)
+            .await?
+            .rows_affected();
+        if rows > 0 {
+            self.pending.push(event);
+        }
+        Ok(rows > 0)
+    }
+
+    /// Makes every staged write visible at once, then announces them to
+    /// [`DataStore::subscribe`]rs.
+    pub async fn commit(self) -> Result<()> {
+        self.tx.commit().await?;
+        for event in self.pending {
+            let _ = self.events.send(event);
+        }
+        Ok(())
+    }
+
+    /// Queues an event for `file_id` until the transaction commits.
+    fn stage(&mut self, file_id: &str, event: fn(FileID) -> StoreEvent) {
+        if let Ok(file_id) = file_id.parse() {
+            self.pending.push(event(file_id));
+        }
+    }
+
+    /// Discards every staged write.
+    pub async fn rollback(self) -> Result<()> {
+        self.tx.rollback().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fetch, setup};
+
+    fn file_entry(file_id: &FileID, path: &str) -> FileTableEntry {
+        FileTableEntry {
+            file_id: file_id.to_string(),
+            name: path.trim_start_matches('/').to_string(),
+            path: path.to_string(),
+            hash: vec![7; 32],
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rollback_discards_all_writes() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let chunk = ChunkTableEntry {
+            hash: vec![1; 32],
+            size: 4,
+            data: b"data".to_vec(),
+        };
+
+        let mut tx = store.transaction().await?;
+        tx.store_file(file_entry(&file_id, "/rolled_back.txt"))
+            .await?;
+        tx.store_chunk(chunk).await?;
+        tx.rollback().await?;
+
+        assert!(store.try_fetch_file(&file_id).await?.is_none());
+        assert_eq!(store.physical_chunk_count().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commit_applies_all_writes() -> Result<()> {
+        let store = setup().await;
+        let file_id = FileID::new();
+        let hash = vec![2; 32];
+
+        let mut tx = store.transaction().await?;
+        tx.store_file(file_entry(&file_id, "/a.txt")).await?;
+        tx.store_chunk(ChunkTableEntry {
+            hash: hash.clone(),
+            size: 3,
+            data: b"abc".to_vec(),
+        })
+        .await?;
+        tx.store_section(FileSectionEntry {
+            file_id: file_id.to_string(),
+            chunk_hash: hash,
+            length: 3,
+            offset: 0,
+        })
+        .await?;
+        tx.store_path(PathEntry {
+            path: "/b.txt".to_string(),
+            file_id: file_id.to_string(),
+        })
+        .await?;
+        tx.commit().await?;
+
+        let entry: FileTableEntry = store.fetch_by(&file_id).await?;
+        assert_eq!(entry.path, "/b.txt");
+        assert_eq!(store.read_range(&file_id, 0, 3).await?, b"abc");
+
+        let mut tx = store.transaction().await?;
+        assert!(tx.delete_file(&file_id).await?);
+        assert!(!tx.delete_file(&FileID::new()).await?);
+        tx.commit().await?;
+        assert!(store.try_fetch_file(&file_id).await?.is_none());
+        assert_eq!(store.logical_section_count().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_more_transactions_than_connections_all_complete() -> Result<()> {
+        use crate::SqlitePragmas;
+        use sqlx::any::{AnyPoolOptions, install_default_drivers};
+        use std::time::Duration;
+
+        let dir = tempfile::TempDir::new()?;
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("store.db").display()
+        );
+        install_default_drivers();
+        let statements = SqlitePragmas::default().statements();
+        // A pool that gives up quickly, so waiting on it would fail the test
+        let pool = AnyPoolOptions::new()
+            .max_connections(3)
+            .acquire_timeout(Duration::from_millis(200))
+            .after_connect(move |conn, _meta| {
+                let statements = statements.clone();
+                Box::pin(async move {
+                    for statement in &statements {
+                        sqlx::Executor::execute(&mut *conn, statement.as_str()).await?;
+                    }
+                    Ok(())
+                })
+            })
+            .connect(&url)
+            .await?;
+        let store = Arc::new(DataStore::new(pool).await?);
+
+        let writers: Vec<_> = (0..12)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let mut tx = store.transaction().await?;
+                    tx.store_file(file_entry(&FileID::new(), &format!("/{i}")))
+                        .await?;
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    tx.commit().await
+                })
+            })
+            .collect();
+
+        // A reserved connection keeps reads going while the writers queue
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        store.physical_chunk_count().await?;
+
+        for writer in writers {
+            writer.await.expect("writer panicked")?;
+        }
+        assert_eq!(store.dump_files().await?.len(), 12);
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Keeping the write-ahead log of a file-backed store small.
+//!
+//! SQLite checkpoints the WAL back into the database on its own, but never
+//! shrinks the file, and under sustained writes readers can keep a checkpoint
+//! from ever reaching the end of the log. A periodic
+//! `PRAGMA wal_checkpoint(TRUNCATE)` copies everything back and resets the
+//! log to zero bytes whenever no reader is in the way.
+use std::{
+    sync::{Arc, PoisonError, RwLock, Weak},
+    time::Duration,
+};
+
+use sqlx::AnyPool;
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+use crate::{DataStore, Result};
+
+/// Stops the task started by [`DataStore::start_wal_maintenance`] when dropped.
+pub struct WalMaintenanceHandle {
+    task: JoinHandle<()>,
+}
+
+impl Drop for WalMaintenanceHandle {
+    fn drop(&mut self) {
+        if !self.task.is_finished() {
+            log::debug!("Stopping WAL maintenance");
+        }
+        self.task.abort();
+    }
+}
+
+impl DataStore {
+    /// Checkpoints the WAL and truncates it to zero bytes.
+    ///
+    /// Returns whether the checkpoint completed; it does not while another
+    /// connection is reading from the log. A no-op returning `true` on
+    /// backends other than SQLite.
+    pub async fn truncate_wal(&self) -> Result<bool> {
+        truncate_wal(&self.pool()).await
+    }
+
+    /// Runs [`DataStore::truncate_wal`] every `interval` until the returned
+    /// handle is dropped.
+    ///
+    /// The first checkpoint runs right away. Each tick uses the store's
+    /// current pool, so the task follows [`DataStore::reopen_if_replaced`].
+    /// Failures, and ticks that find the pool closed, are logged and retried
+    /// at the next tick. The task ends once the store is dropped, and at once
+    /// on backends other than SQLite.
+    ///
+    /// # Panics
+    /// Panics if `interval` is zero, or when called outside a Tokio runtime.
+    pub fn start_wal_maintenance(&self, interval: Duration) -> WalMaintenanceHandle {
+        let pools = Arc::downgrade(&self.pool);
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let task = tokio::spawn(async move {
+            loop {
+                ticks.tick().await;
+                let Some(pool) = current_pool(&pools) else {
+                    log::debug!("Store dropped, stopping WAL maintenance");
+                    break;
+                };
+                if pool.is_closed() {
+                    log::debug!("Pool closed, skipping WAL checkpoint");
+                    continue;
+                }
+                match is_sqlite(&pool).await {
+                    Ok(false) => {
+                        log::debug!("Not a SQLite store, stopping WAL maintenance");
+                        break;
+                    }
+                    Ok(true) => {}
+                    Err(err) => {
+                        log::warn!("WAL maintenance could not connect: {err}");
+                        continue;
+                    }
+                }
+                match truncate_wal(&pool).await {
+                    Ok(true) => {}
+                    Ok(false) => log::debug!("WAL checkpoint blocked by a reader, retrying later"),
+                    Err(err) => log::warn!("WAL checkpoint failed: {err}"),
+                }
+            }
+        });
+        WalMaintenanceHandle { task }
+    }
+}
+
+/// The pool the store currently uses, or `None` once the store is dropped.
+fn current_pool(pools: &Weak<RwLock<AnyPool>>) -> Option<AnyPool> {
+    let pool = pools.upgrade()?;
+    let pool = pool.read().unwrap_or_else(PoisonError::into_inner).clone();
+    Some(pool)
+}
+
+async fn is_sqlite(pool: &AnyPool) -> Result<bool> {
+    let conn = pool.acquire().await?;
+    Ok(conn.backend_name() == "SQLite")
+}
+
+pub(crate) async fn truncate_wal(pool: &AnyPool) -> Result<bool> {
+    let mut conn = pool.acquire().await?;
+    if conn.backend_name() != "SQLite" {
+        return Ok(true);
+    }
+    let (busy, _, _): (i64, i64, i64) = sqlx::query_as("PRAGMA wal_checkpoint(TRUNCATE)")
+        .fetch_one(&mut *conn)
+        .await?;
+    Ok(busy == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChunkTableEntry, Persist, SqlitePragmas};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_wal_maintenance_truncates_log() -> Result<()> {
+        let dir = TempDir::new()?;
+        let db = dir.path().join("store.db");
+        let url = format!("sqlite://{}?mode=rwc", db.display());
+        let store = DataStore::with_options(&url, SqlitePragmas::default()).await?;
+
+        let chunks = (0u8..64)
+            .map(|tag| ChunkTableEntry {
+                hash: vec![tag],
+                size: 1024,
+                data: vec![tag; 1024],
+            })
+            .collect();
+        store.store_all(chunks).await?;
+        let wal = dir.path().join("store.db-wal");
+        assert!(std::fs::metadata(&wal)?.len() > 0);
+
+        let handle = store.start_wal_maintenance(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(handle);
+
+        assert_eq!(std::fs::metadata(&wal)?.len(), 0);
+        assert_eq!(store.physical_chunk_count().await?, 64);
+        assert!(store.truncate_wal().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wal_maintenance_ends_with_the_store() -> Result<()> {
+        let dir = TempDir::new()?;
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("store.db").display()
+        );
+        let store = DataStore::with_options(&url, SqlitePragmas::default()).await?;
+
+        let handle = store.start_wal_maintenance(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!handle.task.is_finished());
+
+        drop(store);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(handle.task.is_finished());
+        Ok(())
+    }
+}
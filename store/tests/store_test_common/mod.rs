@@ -5,12 +5,22 @@ use store::DataStore;
 pub const KB: usize = 1024;
 
 pub async fn setup() -> DataStore {
-    use sqlx::any::{AnyPoolOptions, install_default_drivers};
+    use sqlx::{
+        Executor,
+        any::{AnyPoolOptions, install_default_drivers},
+    };
     // Use PoolOptions to ensure the connection stays alive
     install_default_drivers();
     let pool = AnyPoolOptions::new()
         .max_connections(1) // Force a single connection for stability in memory
         .idle_timeout(None) // Never let the connection drop due to inactivity
+        // Enforce foreign keys as DataStore::with_options does
+        .after_connect(|conn, _meta| {
+            Box::pin(async move {
+                conn.execute("PRAGMA foreign_keys = ON").await?;
+                Ok(())
+            })
+        })
         .connect("sqlite::memory:")
         .await
         .expect("Could not create pool");
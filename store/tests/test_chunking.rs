@@ -5,8 +5,15 @@ use anyhow::Result;
 use common::FileID;
 use fastcdc::v2020::StreamCDC;
 use rand::{RngCore, rng};
-use std::io::Write;
-use store::{ChunkTableEntry, FileSectionEntry, FileTableEntry, Persist};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Cursor, Write},
+};
+use store::{
+    ChunkConfig, ChunkMetadata, ChunkTableEntry, ChunkedSource, ChunkerKind, DataStore,
+    FileSectionEntry, FileTableEntry, Persist, analyze_chunks, chunk_source, chunk_source_from,
+    chunk_source_reusing, chunk_source_warm, chunk_source_with_stats,
+};
 pub use store_test_common::*;
 use tempfile::NamedTempFile;
 
@@ -29,6 +36,9 @@ async fn test_chunking() -> Result<()> {
             name: file.path().to_string_lossy().to_lowercase(),
             path: file.path().to_string_lossy().to_lowercase(),
             hash: hash.as_bytes().to_vec(),
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
         })
         .await?;
 
@@ -49,6 +59,7 @@ async fn test_chunking() -> Result<()> {
         chunk_table_entries.push(ChunkTableEntry {
             hash: chunk_hash.clone(),
             size: chunk.length as i64,
+            data: chunk.data,
         });
 
         file_section_entries.push(FileSectionEntry {
@@ -66,3 +77,294 @@ async fn test_chunking() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_resume_chunking_from_offset() -> Result<()> {
+    let file_id = FileID::new();
+    let mut buffer = vec![0u8; 16 * KB];
+    rng().fill_bytes(&mut buffer);
+
+    // A previous run indexed only the first half before being interrupted
+    let ChunkedSource {
+        file_sections: partial,
+        ..
+    } = chunk_source(&file_id, &buffer[..8 * KB], None)?;
+    let resume_at = partial.last().map(|s| s.offset as u64).unwrap();
+    let kept = &partial[..partial.len() - 1];
+
+    let ChunkedSource {
+        file_sections: resumed,
+        ..
+    } = chunk_source_from(&file_id, Cursor::new(&buffer), resume_at, None)?;
+    assert_eq!(
+        resumed[0].offset as u64, resume_at,
+        "Offsets must be absolute"
+    );
+
+    let mut expected_offset = 0;
+    for section in kept.iter().chain(&resumed) {
+        assert_eq!(
+            section.offset, expected_offset,
+            "Sections must be contiguous"
+        );
+        expected_offset += section.length;
+    }
+    assert_eq!(expected_offset as usize, buffer.len());
+
+    Ok(())
+}
+
+#[test]
+fn test_cut_point_health() -> Result<()> {
+    let file_id = FileID::new();
+
+    // Zeros never trigger a content-defined cut, so every chunk is forced
+    let zeros = vec![0u8; 64 * KB];
+    let (_, stats) = chunk_source_with_stats(&file_id, Cursor::new(&zeros), None)?;
+    assert!(stats.chunk_count > 1);
+    assert!(
+        stats.cut_point_health() < 0.1,
+        "Zeros should be unhealthy, got {stats:?}"
+    );
+
+    let mut random = vec![0u8; 64 * KB];
+    rng().fill_bytes(&mut random);
+    let (_, stats) = chunk_source_with_stats(&file_id, Cursor::new(&random), None)?;
+    assert!(
+        stats.cut_point_health() > 0.5,
+        "Random data should be healthy, got {stats:?}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_analyze_chunks_without_storing() -> Result<()> {
+    let mut data = vec![0u8; 1024 * KB];
+    rng().fill_bytes(&mut data);
+    let config = ChunkConfig {
+        min_chunk_size: 2048,
+        avg_chunk_size: 8192,
+        max_chunk_size: 32768,
+        ..Default::default()
+    };
+
+    let stats = analyze_chunks(Cursor::new(&data), Some(config))?;
+    assert_eq!(stats.total_bytes, data.len() as u64);
+    assert!(stats.min_size <= stats.max_size && stats.max_size <= 32768);
+    let avg = stats.avg_size();
+    assert!(
+        (4096.0..16384.0).contains(&avg),
+        "Average {avg} should be near 8192"
+    );
+
+    // Same numbers as chunking for real, minus the hashing
+    let (_, chunked) = chunk_source_with_stats(&FileID::new(), Cursor::new(&data), Some(config))?;
+    assert_eq!(stats, chunked);
+    Ok(())
+}
+
+/// Stores the chunks of `before` and returns its manifest together with the
+/// checksums hash reuse confirms chunks against.
+async fn stored_prior(
+    store: &DataStore,
+    before: ChunkedSource,
+) -> Result<(BTreeMap<usize, ChunkMetadata>, HashMap<Vec<u8>, i64>)> {
+    let prior: BTreeMap<_, _> = before
+        .file_sections
+        .iter()
+        .map(|section| ChunkMetadata {
+            hash: section.chunk_hash.clone(),
+            offset: section.offset as u64,
+            length: section.length as u64,
+        })
+        .enumerate()
+        .collect();
+    let hashes: Vec<_> = prior.values().map(|chunk| chunk.hash.clone()).collect();
+    store.store_all(before.chunks).await?;
+    let checksums = store.stored_checksums(&hashes).await?;
+    Ok((prior, checksums))
+}
+
+fn chunk_hashes(chunked: &ChunkedSource) -> Vec<Vec<u8>> {
+    chunked.chunks.iter().map(|c| c.hash.clone()).collect()
+}
+
+#[tokio::test]
+async fn test_append_reuses_prefix_hashes() -> Result<()> {
+    let store = setup().await;
+    let file_id = FileID::new();
+    let mut data = vec![0u8; 64 * KB];
+    rng().fill_bytes(&mut data);
+    let before = chunk_source(&file_id, Cursor::new(&data), None)?;
+    let (prior, checksums) = stored_prior(&store, before).await?;
+
+    let mut tail = vec![0u8; 4 * KB];
+    rng().fill_bytes(&mut tail);
+    data.extend_from_slice(&tail);
+    let (reused, stats) =
+        chunk_source_reusing(&file_id, Cursor::new(&data), None, &prior, &checksums)?;
+
+    // Only the old last chunk, the appended tail and the spot checks are hashed
+    assert!(
+        stats.reused > prior.len() / 2,
+        "too little reuse: {stats:?}"
+    );
+    assert_eq!(stats.reused + stats.hashed, reused.chunks.len());
+
+    let fresh = chunk_source(&file_id, Cursor::new(&data), None)?;
+    assert_eq!(chunk_hashes(&reused), chunk_hashes(&fresh));
+    assert_eq!(reused.file_hash, fresh.file_hash);
+
+    // Without checksums nothing can be confirmed, so everything is hashed
+    let (_, stats) =
+        chunk_source_reusing(&file_id, Cursor::new(&data), None, &prior, &HashMap::new())?;
+    assert_eq!(stats.reused, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reusing_catches_in_place_edits() -> Result<()> {
+    let store = setup().await;
+    let file_id = FileID::new();
+    let mut original = vec![0u8; 256 * KB];
+    rng().fill_bytes(&mut original);
+    let before = chunk_source(&file_id, Cursor::new(&original), None)?;
+    let (prior, checksums) = stored_prior(&store, before).await?;
+
+    // Flipping one byte keeps every boundary, but the chunk holding it changed
+    for at in (1..20).map(|i| i * original.len() / 20) {
+        let mut data = original.clone();
+        data[at] ^= 0xFF;
+        let fresh = chunk_source(&file_id, Cursor::new(&data), None)?;
+
+        let (reused, _) =
+            chunk_source_reusing(&file_id, Cursor::new(&data), None, &prior, &checksums)?;
+        assert_eq!(chunk_hashes(&reused), chunk_hashes(&fresh), "edit at {at}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_middle_edit_rehashes_only_around_it() -> Result<()> {
+    let store = setup().await;
+    let file_id = FileID::new();
+    let mut data = vec![0u8; 1024 * KB];
+    rng().fill_bytes(&mut data);
+    let before = chunk_source(&file_id, Cursor::new(&data), None)?;
+    let (prior, checksums) = stored_prior(&store, before).await?;
+
+    let mut insert = vec![0u8; 300];
+    rng().fill_bytes(&mut insert);
+    let middle = data.len() / 2;
+    data.splice(middle..middle, insert);
+    let (warm, stats) = chunk_source_warm(&file_id, Cursor::new(&data), None, &prior, &checksums)?;
+
+    // A full re-index hashes every chunk once
+    let fresh = chunk_source(&file_id, Cursor::new(&data), None)?;
+    assert_eq!(stats.reused + stats.hashed, fresh.chunks.len());
+    assert!(
+        stats.hashed < fresh.chunks.len() / 4,
+        "too much hashing: {stats:?} of {} chunks",
+        fresh.chunks.len()
+    );
+
+    assert_eq!(chunk_hashes(&warm), chunk_hashes(&fresh));
+    assert_eq!(warm.file_sections, fresh.file_sections);
+    assert_eq!(warm.file_hash, fresh.file_hash);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_warm_catches_in_place_edits() -> Result<()> {
+    let store = setup().await;
+    let file_id = FileID::new();
+    let mut original = vec![0u8; 256 * KB];
+    rng().fill_bytes(&mut original);
+    let before = chunk_source(&file_id, Cursor::new(&original), None)?;
+    let (prior, checksums) = stored_prior(&store, before).await?;
+
+    // Same length, so prefix and suffix line up across the whole file
+    for at in (1..20).map(|i| i * original.len() / 20) {
+        let mut data = original.clone();
+        data[at] ^= 0xFF;
+        let fresh = chunk_source(&file_id, Cursor::new(&data), None)?;
+
+        let (warm, stats) =
+            chunk_source_warm(&file_id, Cursor::new(&data), None, &prior, &checksums)?;
+        assert_eq!(chunk_hashes(&warm), chunk_hashes(&fresh), "edit at {at}");
+        assert!(stats.hashed < fresh.chunks.len() / 4, "{stats:?}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chunker_kinds_produce_reconstructable_sections() -> Result<()> {
+    let store = setup().await;
+    let mut data = vec![0u8; 32 * KB];
+    rng().fill_bytes(&mut data);
+
+    for chunker in [
+        ChunkerKind::FastCdc2020,
+        ChunkerKind::FastCdc2016,
+        ChunkerKind::FixedSize,
+    ] {
+        let config = ChunkConfig {
+            chunker,
+            ..Default::default()
+        };
+        let file_id = FileID::new();
+        let chunked = chunk_source(&file_id, Cursor::new(&data), Some(config))?;
+
+        // Sections tile the input without gaps or overlaps
+        let mut offset = 0;
+        for (section, chunk) in chunked.file_sections.iter().zip(&chunked.chunks) {
+            assert_eq!(section.offset, offset, "{chunker:?}");
+            assert_eq!(section.length as usize, chunk.data.len());
+            offset += section.length;
+        }
+        assert_eq!(offset as usize, data.len(), "{chunker:?}");
+
+        store
+            .index_and_store(
+                &file_id,
+                "kinds.bin",
+                &format!("/{chunker:?}"),
+                &data[..],
+                Some(config),
+            )
+            .await?;
+        assert_eq!(
+            store.read_range(&file_id, 0, data.len() as u64).await?,
+            data
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_higher_normalization_narrows_chunk_sizes() -> Result<()> {
+    let mut data = vec![0u8; 4096 * KB];
+    rng().fill_bytes(&mut data);
+
+    let variance = |normalization_level: u8| -> Result<f64> {
+        let config = ChunkConfig {
+            min_chunk_size: 1024,
+            avg_chunk_size: 8192,
+            max_chunk_size: 65536,
+            normalization_level,
+            ..Default::default()
+        };
+        let chunked = chunk_source(&FileID::new(), Cursor::new(&data), Some(config))?;
+        let sizes: Vec<f64> = chunked.chunks.iter().map(|c| c.data.len() as f64).collect();
+        let mean = sizes.iter().sum::<f64>() / sizes.len() as f64;
+        Ok(sizes.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / sizes.len() as f64)
+    };
+
+    let loose = variance(0)?;
+    let tight = variance(3)?;
+    assert!(
+        tight < loose,
+        "Level 3 variance {tight} should be below level 0 variance {loose}"
+    );
+    Ok(())
+}
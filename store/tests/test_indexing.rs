@@ -31,6 +31,9 @@ async fn test_reconstruction_integrity() -> Result<()> {
         file_id: file_id.to_string(),
         name: "test.bin".to_uppercase(),
         path: "test.path".to_lowercase(),
+        content_type: None,
+        chunk_count: 0,
+        last_indexed_at: None,
     };
 
     // Store metadata and chunks
@@ -47,7 +50,9 @@ async fn test_reconstruction_integrity() -> Result<()> {
             section.offset, current_offset,
             "Chunks must be perfectly contiguous"
         );
-        current_offset += section.length;
+        current_offset = current_offset
+            .checked_add(section.length)
+            .expect("section offsets overflow");
     }
     assert_eq!(current_offset as usize, cursor.get_ref().len());
 
@@ -77,6 +82,9 @@ async fn test_file_data_change_delta() -> Result<()> {
             name: "Testfile".to_string(),
             path: "somepath".to_string(),
             hash,
+            content_type: None,
+            chunk_count: 0,
+            last_indexed_at: None,
         })
         .await?;
     store.store_all(c1).await?;
@@ -121,6 +129,9 @@ async fn test_file_name_and_path_change() -> Result<()> {
         path: "/etc/config.yaml".into(),
         name: "config.yaml".into(),
         hash: hash.clone(),
+        content_type: None,
+        chunk_count: 0,
+        last_indexed_at: None,
     };
 
     // 1. Initial Insert
@@ -132,6 +143,9 @@ async fn test_file_name_and_path_change() -> Result<()> {
         path: "/etc/old_config.yaml".into(),
         name: "old_config.yaml".into(),
         hash: hash.clone(),
+        content_type: None,
+        chunk_count: 0,
+        last_indexed_at: None,
     };
     store.store(entry_v2).await?;
 
@@ -143,3 +157,79 @@ async fn test_file_name_and_path_change() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_concurrent_index_same_file() -> Result<()> {
+    let store = setup().await;
+    let file_id = FileID::new();
+    let mut buffer = vec![0u8; 16 * KB];
+    rng().fill_bytes(&mut buffer);
+
+    // Two watcher events for the same file racing each other
+    let (first, second) = tokio::join!(
+        store.index_and_store(
+            &file_id,
+            "race.bin",
+            "/race.bin",
+            Cursor::new(&buffer),
+            None
+        ),
+        store.index_and_store(
+            &file_id,
+            "race.bin",
+            "/race.bin",
+            Cursor::new(&buffer),
+            None
+        ),
+    );
+    first?;
+    second?;
+
+    let ChunkedSource { file_sections, .. } = chunk_source(&file_id, Cursor::new(&buffer), None)?;
+    let fetched_sections: Vec<FileSectionEntry> = store.fetch_by(&file_id).await?;
+    assert_eq!(
+        fetched_sections.len(),
+        file_sections.len(),
+        "Sections must not be doubled by concurrent indexing"
+    );
+
+    let mut current_offset = 0;
+    for section in fetched_sections {
+        assert_eq!(section.offset, current_offset);
+        current_offset = current_offset
+            .checked_add(section.length)
+            .expect("section offsets overflow");
+    }
+    assert_eq!(current_offset as usize, buffer.len());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cross_file_deduplication() -> Result<()> {
+    let store = setup().await;
+    let mut shared = vec![0u8; 16 * KB];
+    rng().fill_bytes(&mut shared);
+
+    // Two files with identical content up to a different tail
+    let mut first = shared.clone();
+    first.extend_from_slice(b"first tail");
+    let mut second = shared;
+    second.extend_from_slice(b"a different tail for the second file");
+
+    store
+        .index_and_store(&FileID::new(), "a", "/a", Cursor::new(first), None)
+        .await?;
+    store
+        .index_and_store(&FileID::new(), "b", "/b", Cursor::new(second), None)
+        .await?;
+
+    let physical = store.physical_chunk_count().await?;
+    let logical = store.logical_section_count().await?;
+    assert!(
+        physical < logical,
+        "Shared chunks must be stored once ({physical} physical, {logical} logical)"
+    );
+
+    Ok(())
+}